@@ -1,27 +1,67 @@
 use druid::Color;
-use std::cmp::{max, min};
+use std::cmp::{max, min, Reverse};
 use std::collections::{Bound, HashSet};
 use std::io::Read;
 use std::ops::RangeBounds;
-use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 
 use itertools::Itertools;
-use lsp_types::{DiagnosticSeverity, Position, Range};
+use lsp_types::{DiagnosticSeverity, Position, Range, TextDocumentContentChangeEvent, Url};
+use parking_lot::Mutex;
 use ropey::Rope;
+use tree_sitter::{InputEdit, Point};
+use unicode_segmentation::UnicodeSegmentation;
 
-use crate::lsp::{CompletionData, LspCompletion, LspInput};
+use crate::config::{IndentConfig, Pair};
+use crate::fuzzy::fuzzy_score;
+use crate::lsp::{LspCompletion, LspInput, LspSignature, LspSymbol, TextEdit};
 use crate::lsp_ext::{InlayHint, InlayKind};
 use crate::theme::Style;
-use crate::THEME;
+use crate::{lock, LspLang};
 
 pub struct Diagnostic {
     pub bounds: Bounds,
     pub severity: DiagnosticSeverity,
     pub message: String,
+    /// As the server sent it, with its code and data for the code actions.
+    pub original: lsp_types::Diagnostic,
 }
 
 pub struct Diagnotics(pub(crate) Vec<Diagnostic>);
 
+/// Width of a tab on screen and when converting indentation.
+pub const TAB_WIDTH: usize = 4;
+
+/// Visual column after `c` when it is at `col`.
+fn advance_col(col: usize, c: char, tab_width: usize) -> usize {
+    if c == '\t' {
+        col + tab_width - col % tab_width
+    } else {
+        col + 1
+    }
+}
+
+/// Indentation used by the lines of a buffer.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Indentation {
+    /// lines indented only with tabs
+    pub tabs: usize,
+    /// lines indented only with spaces
+    pub spaces: usize,
+    /// lines mixing both, or not using the style of the rest of the file
+    pub inconsistent_lines: Vec<usize>,
+}
+
+impl Indentation {
+    pub fn use_tabs(&self) -> bool {
+        self.tabs > self.spaces
+    }
+
+    pub fn is_mixed(&self) -> bool {
+        !self.inconsistent_lines.is_empty()
+    }
+}
+
 pub struct VirtualText {
     pub handle: Handle,
     pub text: String,
@@ -35,21 +75,43 @@ pub enum Handle {
 }
 
 impl Diagnostic {
-    pub fn color(&self) -> Color {
+    /// The diagnostic sent by the server, at the range its bounds moved to.
+    pub fn to_lsp(&self, buffer: &Buffer) -> lsp_types::Diagnostic {
+        lsp_types::Diagnostic {
+            range: (&self.bounds).into_with_buf(buffer),
+            ..self.original.clone()
+        }
+    }
+
+    /// Theme scope of the severity, an unknown one is an error.
+    pub fn scope(&self) -> &'static str {
         match self.severity {
-            DiagnosticSeverity::ERROR => Color::RED,
-            DiagnosticSeverity::HINT => Color::rgb(255.0, 165.0, 0.0),
-            DiagnosticSeverity::WARNING => Color::rgb(255.0, 165.0, 0.0),
-            DiagnosticSeverity::INFORMATION => Color::rgb(255.0, 165.0, 0.0),
-            _ => Color::RED,
+            DiagnosticSeverity::WARNING => "diagnostic.warning",
+            DiagnosticSeverity::INFORMATION => "diagnostic.info",
+            DiagnosticSeverity::HINT => "diagnostic.hint",
+            _ => "diagnostic.error",
         }
     }
+
+    /// Foreground of the scope of the severity, if the theme has one.
+    pub fn color(&self) -> Color {
+        lock!(theme)
+            .scope(self.scope())
+            .foreground
+            .unwrap_or_else(|| match self.severity {
+                DiagnosticSeverity::WARNING => Color::rgb8(255, 165, 0),
+                DiagnosticSeverity::INFORMATION => Color::rgb8(0x4f, 0xc1, 0xff),
+                DiagnosticSeverity::HINT => Color::rgb8(0x9e, 0x9e, 0x9e),
+                _ => Color::RED,
+            })
+    }
 }
 
 impl Buffer {
     pub fn virtual_texts(&self) -> Vec<VirtualText> {
         let mut lines: HashSet<usize> = Default::default();
         let mut virtual_texts = Vec::new();
+        // the most severe diagnostic of a line is shown
         for diag in self
             .diagnostics
             .0
@@ -79,7 +141,7 @@ impl Buffer {
         }
 
         for (idx, hint) in &self.inlay_hints {
-            let style = THEME.scope("hint");
+            let style = lock!(theme).scope("hint");
 
             let (handle, text) = match hint.kind {
                 InlayKind::TypeHint => (Handle::Char(*idx), format!(" : {} ", hint.label)),
@@ -100,14 +162,94 @@ impl Buffer {
     }
 }
 
+/// Same line breaks as ropey, otherwise lines could end on a separator.
+fn is_newline(c: char) -> bool {
+    matches!(
+        c,
+        '\n' | '\r' | '\u{000B}' | '\u{000C}' | '\u{0085}' | '\u{2028}' | '\u{2029}'
+    )
+}
+
+/// Changes kept for an incremental sync, past that the whole text is sent.
+pub const MAX_PENDING_CHANGES: usize = 1000;
+
+fn is_blank(c: char) -> bool {
+    c.is_whitespace() && !is_newline(c)
+}
+
+/// Chars of the same class form a word.
+fn char_class(c: char) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
 pub struct Buffer {
     id: u32,
     rope: Rope,
     cursor: Cursor,
+    // extra selections edited together with the primary cursor
+    secondary: Vec<Cursor>,
     pub version: AtomicI32,
+    /// edited since the last `didChange` sent to the language server
+    pub dirty_since_sync: AtomicBool,
+    // edits since the last `didChange`, `None` when there were too many
+    pending_changes: Mutex<Option<Vec<TextDocumentContentChangeEvent>>>,
+    // edits since the last syntax parse, `None` when there were too many
+    syntax_edits: Mutex<Option<Vec<InputEdit>>>,
     pub completions: Vec<LspCompletion>,
+    /// position of the selected completion in `sorted_completions`
+    pub completion_selected: usize,
     pub diagnostics: Diagnotics,
+    /// diagnostics dropped by the cap
+    pub diagnostics_hidden: usize,
+    /// completions dropped by the cap
+    pub completions_hidden: usize,
+    /// signature of the call being typed, shown above the cursor
+    pub signature: Option<LspSignature>,
     pub inlay_hints: Vec<(Index, InlayHint)>,
+    /// outline from the language server, as of its last request
+    pub symbols: Vec<LspSymbol>,
+    // tab stops left in the snippet of the last completion, the last one ends it
+    snippet: Vec<Bounds>,
+    /// result of the indentation check, `None` if it was not run
+    pub indentation: Option<Indentation>,
+    /// closed when their opening is typed, none by default
+    pub pairs: Vec<Pair>,
+    /// settings of the buffer language
+    pub indent: IndentConfig,
+    history: History,
+    // text as last loaded or saved, the watcher tells our own writes by it
+    saved_text: Rope,
+}
+
+#[derive(Clone, Debug)]
+enum Edit {
+    Insert { at: Index, text: String },
+    Remove { at: Index, text: String },
+}
+
+struct UndoGroup {
+    edits: Vec<Edit>,
+    cursor_before: Cursor,
+    cursor_after: Cursor,
+}
+
+#[derive(Default)]
+struct History {
+    undo: Vec<UndoGroup>,
+    redo: Vec<UndoGroup>,
+    // inside `Buffer::group`, all edits go to one undo group
+    in_group: bool,
+    group_started: bool,
+    // the last edit was a typed char, the next one can be merged with it
+    typing: bool,
+    // undo groups of the text last loaded or saved, `None` once undo and redo can't reach it
+    saved: Option<usize>,
 }
 
 pub enum Movement {
@@ -115,17 +257,54 @@ pub enum Movement {
     Down,
     Left,
     Right,
+    /// first non blank char of the line, or the line start if already there
+    LineStart,
+    LineEnd,
+    /// lines up (negative) or down, the column is kept and the line clamped
+    Lines(isize),
     Index(Index),
 }
 
+#[derive(Clone)]
 pub enum Action {
     Insert(String),
     Backspace,
     Delete,
+    Undo,
+    Redo,
+    DeleteWordBackward,
+    DeleteWordForward,
+    DeleteToLineEnd,
+    Retab,
+    TrimTrailingWhitespace,
+    DuplicateLines,
+    MoveLines(Direction),
+    DeleteLine,
+    /// clipboard text, its lines indented like the cursor line
+    Paste(String),
+    /// clipboard text inserted as it is
+    PasteRaw(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// Where a cursor sitting exactly at an insertion point ends up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gravity {
+    /// before the inserted text
+    Left,
+    /// after the inserted text, like typing
+    Right,
 }
 
 pub type Index = usize;
 pub type Bounds = (Index, Index);
+/// `(line, column)`, both starting at 0, the column is counted in chars.
+pub type LineCol = (usize, usize);
 
 #[derive(Clone, Debug)]
 pub struct Cursor {
@@ -182,16 +361,25 @@ impl FromWithBuffer<&Bounds> for Range {
 
 impl FromWithBuffer<&Position> for Index {
     fn from_with_buf(pos: &Position, buffer: &Buffer) -> Self {
-        let line = buffer.line_bounds(pos.line as usize);
-        line.0 + pos.character as usize
+        let (line, col) = (pos.line as usize, pos.character as usize);
+        buffer.position_to_index((line, col)).unwrap_or_else(|| {
+            // clamp to the end of the line, or of the buffer
+            if line < buffer.rope.len_lines() {
+                buffer.line_bounds(line).1
+            } else {
+                buffer.rope.len_chars()
+            }
+        })
     }
 }
 
 impl FromWithBuffer<&Index> for Position {
     fn from_with_buf(idx: &Index, buffer: &Buffer) -> Self {
+        let idx = min(*idx, buffer.rope.len_chars());
+        let (line, col) = buffer.index_to_position(idx).unwrap_or((0, 0));
         Position {
-            line: buffer.row_at(*idx) as u32,
-            character: buffer.col_at(*idx) as u32,
+            line: line as u32,
+            character: col as u32,
         }
     }
 }
@@ -203,61 +391,89 @@ impl<T> FromWithBuffer<T> for T {
 }
 
 impl Buffer {
+    /// Move the completion selection by `delta`, wrapping around the list.
+    pub fn select_completion(&mut self, delta: isize) -> bool {
+        let len = self.sorted_completions().map_or(0, |c| c.len()) as isize;
+        if len == 0 {
+            return false;
+        }
+        self.completion_selected =
+            (self.completion_selected as isize + delta).rem_euclid(len) as usize;
+        true
+    }
+
+    pub fn selected_completion(&self) -> anyhow::Result<Option<&LspCompletion>> {
+        Ok(self
+            .sorted_completions()?
+            .get(self.completion_selected)
+            .copied())
+    }
+
+    /// Range of the completions shown in a popup of `rows`, around the selected one.
+    pub fn completion_window(&self, rows: usize) -> (usize, usize) {
+        let len = self.sorted_completions().map_or(0, |c| c.len());
+        let start = min(
+            self.completion_selected.saturating_sub(rows / 2),
+            len.saturating_sub(rows),
+        );
+        (start, min(start + rows, len))
+    }
+
+    /// Completions matching the word before the cursor, the best match first.
+    /// Ties keep the order of the server `sort_text`.
     pub fn sorted_completions(&self) -> anyhow::Result<Vec<&LspCompletion>> {
-        let cursor_idx = self.cursor().head;
-        let before_cursor_idx = cursor_idx.saturating_sub(20);
-        let window = self.text_slice(before_cursor_idx..cursor_idx)?;
-        let win_size = window.len();
+        let head = self.cursor().head;
+        let mut start = head;
+        while start > 0 && char_class(self.rope.char(start - 1)) == 1 {
+            start -= 1;
+        }
+        let word = self.text_slice(start..head)?;
 
-        let completions = &self.completions;
-        let result = completions
+        let result = self
+            .completions
             .iter()
-            .sorted_by_key(|c| match &c.data {
-                CompletionData::Simple(text) => {
-                    let chars_len = text.chars().count();
-                    for nb in (0..chars_len).rev() {
-                        if text.ends_with(&window[(win_size.saturating_sub(nb))..]) {
-                            return nb;
-                        }
-                    }
-                    0
-                }
-                CompletionData::Edits(edits) => {
-                    let edit = edits.get(0).unwrap();
-
-                    let bounds: Bounds = (&edit.range).into_with_buf(&self);
-
-                    let buf_text = self.text_slice(bounds.0..bounds.1);
-                    match buf_text {
-                        Ok(buf_text) => {
-                            if edit.new_text.eq(&buf_text) {
-                                4
-                            } else if edit.new_text.starts_with(&buf_text) {
-                                3
-                            } else if edit.new_text.contains(&buf_text) {
-                                2
-                            } else {
-                                1
-                            }
-                        }
-                        Err(_) => 0,
-                    }
-                }
+            .filter_map(|c| {
+                let item = &c.original_item;
+                let text = item.filter_text.as_ref().unwrap_or(&c.label);
+                Some((fuzzy_score(&word, text)?, c))
             })
-            .rev()
+            .sorted_by_key(|&(score, c)| {
+                let sort_text = c.original_item.sort_text.as_ref().unwrap_or(&c.label);
+                (Reverse(score), sort_text)
+            })
+            .map(|(_, c)| c)
             .collect();
         Ok(result)
     }
 
     pub fn from_reader<R: Read>(id: u32, reader: R) -> Self {
+        let rope = Rope::from_reader(reader).unwrap();
         Self {
             id,
-            rope: Rope::from_reader(reader).unwrap(),
+            saved_text: rope.clone(),
+            rope,
             cursor: Cursor { head: 0, tail: 0 },
+            secondary: vec![],
             version: Default::default(),
+            dirty_since_sync: Default::default(),
+            pending_changes: Mutex::new(Some(vec![])),
+            syntax_edits: Mutex::new(Some(vec![])),
             completions: vec![],
+            completion_selected: 0,
             diagnostics: Diagnotics(vec![]),
+            diagnostics_hidden: 0,
+            completions_hidden: 0,
+            signature: None,
             inlay_hints: vec![],
+            symbols: vec![],
+            snippet: vec![],
+            indentation: None,
+            pairs: vec![],
+            indent: IndentConfig::default(),
+            history: History {
+                saved: Some(0),
+                ..Default::default()
+            },
         }
     }
 
@@ -282,7 +498,7 @@ impl Buffer {
                 break;
             }
             let last = self.rope().chars_at(end - 1).next();
-            if let Some('\n' | '\r') = last {
+            if last.map(is_newline).unwrap_or(false) {
                 end -= 1;
             } else {
                 break;
@@ -292,10 +508,53 @@ impl Buffer {
         (start, end)
     }
 
+    /// Index of the last line, the empty line after a trailing newline included.
+    pub fn last_line(&self) -> Index {
+        self.rope.char_to_line(self.rope.len_chars())
+    }
+
     pub fn rope(&self) -> &Rope {
         &self.rope
     }
 
+    /// `None` if `idx` is past the end of the buffer.
+    pub fn index_to_position(&self, idx: Index) -> Option<LineCol> {
+        if idx > self.rope.len_chars() {
+            return None;
+        }
+        let line = self.rope.char_to_line(idx);
+        let bounds = self.line_bounds(line);
+        Some((line, idx.saturating_sub(bounds.0)))
+    }
+
+    /// `None` if the line does not exist or the column is past the end of the line.
+    pub fn position_to_index(&self, (line, col): LineCol) -> Option<Index> {
+        if line >= self.rope.len_lines() {
+            return None;
+        }
+        let bounds = self.line_bounds(line);
+        if bounds.0 + col > bounds.1 {
+            return None;
+        }
+        Some(bounds.0 + col)
+    }
+
+    /// `None` if `idx` is past the end of the buffer.
+    pub fn index_to_byte(&self, idx: Index) -> Option<usize> {
+        if idx > self.rope.len_chars() {
+            return None;
+        }
+        Some(self.rope.char_to_byte(idx))
+    }
+
+    /// `None` if `byte` is past the end of the buffer, a byte inside a char gives that char.
+    pub fn byte_to_index(&self, byte: usize) -> Option<Index> {
+        if byte > self.rope.len_bytes() {
+            return None;
+        }
+        Some(self.rope.byte_to_char(byte))
+    }
+
     pub fn col(&self) -> Index {
         self.col_at(self.cursor().head)
     }
@@ -314,7 +573,38 @@ impl Buffer {
         cur - bounds.0
     }
 
+    /// Column of `cur` on screen, a tab goes to the next multiple of `tab_width`.
+    pub fn visual_col_at<I: IntoWithBuffer<Index>>(&self, cur: I, tab_width: usize) -> usize {
+        let cur = cur.into_with_buf(self);
+        let start = self.line_bounds(self.row_at(cur)).0;
+        self.rope
+            .slice(start..cur)
+            .chars()
+            .fold(0, |col, c| advance_col(col, c, tab_width))
+    }
+
+    /// Index on `line` closest to the visual column `col` without going past it,
+    /// a column inside a tab is before the tab.
+    fn index_at_visual_col(&self, line: Index, col: usize, tab_width: usize) -> Index {
+        let (start, end) = self.line_bounds(line);
+        let mut visual = 0;
+        let mut idx = start;
+        for c in self.rope.slice(start..end).chars() {
+            let next = advance_col(visual, c, tab_width);
+            if next > col {
+                break;
+            }
+            visual = next;
+            idx += 1;
+        }
+        idx
+    }
+
+    /// Returns whether the cursor or the completions changed, the secondary cursors are dropped.
     pub fn move_cursor(&mut self, m: Movement, keep_selection: bool) -> bool {
+        let old = self.cursor.clone();
+        let had_completions = !self.completions.is_empty();
+        let had_secondary = self.collapse_cursors();
         let line = self.row();
 
         let prev_line = self.line_bounds(line.saturating_sub(1));
@@ -322,20 +612,21 @@ impl Buffer {
         let next_line = self.line_bounds(line.saturating_add(1));
 
         let max = self.rope.len_chars();
+        // vertical moves keep the column on screen, which differs with tabs
+        let tab_width = self.indent.indent_width;
+        let visual_col = self.visual_col_at(self.cursor.head, tab_width);
         let new = match m {
-            Movement::Up => {
-                prev_line.0 + min(prev_line.1 - prev_line.0, self.cursor.head - curr_line.0)
-            }
+            Movement::Up => self.index_at_visual_col(line.saturating_sub(1), visual_col, tab_width),
             Movement::Down => {
-                if line >= self.rope.len_lines() - 1 {
+                if line >= self.last_line() {
                     self.cursor.head
                 } else {
-                    next_line.0 + min(next_line.1 - next_line.0, self.cursor.head - curr_line.0)
+                    self.index_at_visual_col(line + 1, visual_col, tab_width)
                 }
             }
             Movement::Left => {
                 if keep_selection || self.cursor.same() {
-                    let next = self.cursor.head.saturating_sub(1);
+                    let next = self.prev_grapheme(self.cursor.head);
                     if next < curr_line.0 {
                         prev_line.1
                     } else {
@@ -347,7 +638,7 @@ impl Buffer {
             }
             Movement::Right => {
                 if keep_selection || self.cursor.same() {
-                    let next = self.cursor.head.saturating_add(1);
+                    let next = self.next_grapheme(self.cursor.head);
                     if next > curr_line.1 {
                         next_line.0
                     } else {
@@ -357,6 +648,24 @@ impl Buffer {
                     self.cursor.max()
                 }
             }
+            Movement::LineStart => {
+                let indent = curr_line.0 + self.line_indent(line).2;
+                let indent = min(indent, curr_line.1);
+                if self.cursor.head == indent {
+                    curr_line.0
+                } else {
+                    indent
+                }
+            }
+            Movement::LineEnd => curr_line.1,
+            Movement::Lines(n) => {
+                let target = if n < 0 {
+                    line.saturating_sub(n.unsigned_abs())
+                } else {
+                    min(line.saturating_add(n as usize), self.last_line())
+                };
+                self.index_at_visual_col(target, visual_col, tab_width)
+            }
             Movement::Index(idx) => idx,
         };
 
@@ -367,8 +676,13 @@ impl Buffer {
         }
 
         self.completions = vec![];
+        self.completion_selected = 0;
+        self.history.typing = false;
 
-        false
+        had_completions
+            || had_secondary
+            || old.head != self.cursor.head
+            || old.tail != self.cursor.tail
     }
 
     pub fn remove_chars<I: IntoWithBuffer<Bounds>>(&mut self, bounds: I) -> Option<LspInput> {
@@ -401,6 +715,15 @@ impl Buffer {
             end = self.line_bounds(end_line.saturating_add(1)).0;
         }
 
+        let cursor_before = self.cursor.clone();
+        let text = self.rope.slice(start..end).to_string();
+        self.remove_raw(start, end);
+        self.record(Edit::Remove { at: start, text }, cursor_before);
+
+        Some(self.lsp_edit())
+    }
+
+    fn remove_raw(&mut self, start: Index, end: Index) {
         self.transform_idx(|idx| {
             if idx >= end {
                 idx - (end - start)
@@ -411,14 +734,101 @@ impl Buffer {
             }
         });
 
+        self.push_change(start, end, "");
+        let first_line = self.rope.char_to_line(start);
+        let old_last_line = self.rope.char_to_line(end);
+        self.uncount_indentation(first_line, old_last_line);
+        let (start_byte, start_position) = self.byte_point(start);
+        let (old_end_byte, old_end_position) = self.byte_point(end);
         self.rope.remove(start..end);
+        self.recount_indentation(first_line, old_last_line, first_line);
+        self.push_syntax_edit(InputEdit {
+            start_byte,
+            old_end_byte,
+            new_end_byte: start_byte,
+            start_position,
+            old_end_position,
+            new_end_position: start_position,
+        });
+        self.dirty_since_sync.store(true, Ordering::SeqCst);
+    }
 
-        Some(self.lsp_edit())
+    /// Record the replacement of `start..end` by `text`, before the rope is changed.
+    fn push_change(&self, start: Index, end: Index, text: &str) {
+        let mut pending = self.pending_changes.lock();
+        if let Some(changes) = pending.as_mut() {
+            if changes.len() >= MAX_PENDING_CHANGES {
+                *pending = None;
+                return;
+            }
+            let range_length = self.rope.char_to_utf16_cu(end) - self.rope.char_to_utf16_cu(start);
+            changes.push(TextDocumentContentChangeEvent {
+                range: Some(Range::new(
+                    self.utf16_position(start),
+                    self.utf16_position(end),
+                )),
+                range_length: Some(range_length as u32),
+                text: text.to_string(),
+            });
+        }
+    }
+
+    /// Position of `idx` with the column in UTF-16 code units, as the servers count them.
+    fn utf16_position(&self, idx: Index) -> Position {
+        let line = self.rope.char_to_line(idx);
+        let line_start = self.rope.line_to_char(line);
+        let character = self.rope.char_to_utf16_cu(idx) - self.rope.char_to_utf16_cu(line_start);
+        Position::new(line as u32, character as u32)
+    }
+
+    /// Edits since the last call, `None` if the whole text must be sent.
+    pub fn take_changes(&self) -> Option<Vec<TextDocumentContentChangeEvent>> {
+        self.pending_changes.lock().replace(vec![])
+    }
+
+    /// `didOpen` of the buffer as `uri`. The server gets the whole text, the edits waiting
+    /// for a `didChange` are dropped. The rope is shared, not copied.
+    pub fn open_input(&self, uri: Url) -> LspInput {
+        self.take_changes();
+        self.dirty_since_sync.store(false, Ordering::SeqCst);
+        LspInput::OpenFile {
+            uri,
+            content: self.rope.clone(),
+        }
+    }
+
+    /// Byte offset of `idx` and its tree-sitter position, the column in bytes.
+    fn byte_point(&self, idx: Index) -> (usize, Point) {
+        let byte = self.rope.char_to_byte(idx);
+        let row = self.rope.char_to_line(idx);
+        let column = byte - self.rope.line_to_byte(row);
+        (byte, Point { row, column })
+    }
+
+    fn push_syntax_edit(&self, edit: InputEdit) {
+        let mut edits = self.syntax_edits.lock();
+        if let Some(list) = edits.as_mut() {
+            if list.len() >= MAX_PENDING_CHANGES {
+                *edits = None;
+                return;
+            }
+            list.push(edit);
+        }
+    }
+
+    /// Edits since the last call to apply to the syntax tree of the previous parse,
+    /// `None` if the text must be parsed from scratch.
+    pub fn take_syntax_edits(&self) -> Option<Vec<InputEdit>> {
+        self.syntax_edits.lock().replace(vec![])
     }
 
     pub fn transform_idx<F: Fn(Index) -> Index>(&mut self, f: F) {
         self.cursor.head = (f)(self.cursor.head);
         self.cursor.tail = (f)(self.cursor.tail);
+        for cursor in &mut self.secondary {
+            cursor.head = (f)(cursor.head);
+            cursor.tail = (f)(cursor.tail);
+        }
         for diag in &mut self.diagnostics.0 {
             diag.bounds.0 = (f)(diag.bounds.0);
             diag.bounds.1 = (f)(diag.bounds.1);
@@ -426,78 +836,1210 @@ impl Buffer {
         self.inlay_hints
             .iter_mut()
             .for_each(|(idx, _)| *idx = (f)(*idx));
+        for stop in &mut self.snippet {
+            stop.0 = (f)(stop.0);
+            stop.1 = (f)(stop.1);
+        }
     }
 
     pub fn insert<I: IntoWithBuffer<Index>>(&mut self, start: I, chars: &str) -> LspInput {
         let start = start.into_with_buf(self);
 
+        let cursor_before = self.cursor.clone();
+        self.insert_raw(start, chars);
+        self.record(
+            Edit::Insert {
+                at: start,
+                text: chars.to_string(),
+            },
+            cursor_before,
+        );
+
+        self.lsp_edit()
+    }
+
+    /// Insert at `at`, for edits not made by typing at the cursor. The cursors after `at`
+    /// keep pointing at the same chars, a cursor at `at` follows `gravity`.
+    pub fn insert_keeping_cursor(&mut self, at: Index, text: &str, gravity: Gravity) -> LspInput {
+        let at = min(at, self.rope.len_chars());
+        let old = self.cursor.clone();
+        // not part of what the user is typing
+        self.history.typing = false;
+        let lsp = self.insert(at, text);
+        self.history.typing = false;
+        if gravity == Gravity::Left {
+            if old.head == at {
+                self.cursor.head = at;
+            }
+            if old.tail == at {
+                self.cursor.tail = at;
+            }
+            self.set_cursor_after_edit(self.cursor.clone());
+        }
+        lsp
+    }
+
+    /// Remove `start..end`, for edits not made by typing at the cursor. The cursors keep
+    /// pointing at the same chars, the ones inside the range go to `start`, so there is
+    /// no gravity to choose.
+    pub fn remove_keeping_cursor(&mut self, start: Index, end: Index) -> Option<LspInput> {
+        self.history.typing = false;
+        let lsp = self.remove_chars((start, end));
+        self.history.typing = false;
+        lsp
+    }
+
+    fn insert_raw(&mut self, start: Index, chars: &str) {
         let chars_count = chars.chars().count();
 
+        // a secondary selection ending there is next to the cursor typing, it stays apart
+        let ending: Vec<usize> = (0..self.secondary.len())
+            .filter(|i| self.secondary[*i].min() < start && self.secondary[*i].max() == start)
+            .collect();
         self.transform_idx(|idx| if idx >= start { idx + chars_count } else { idx });
+        for i in ending {
+            let cursor = &mut self.secondary[i];
+            if cursor.head > cursor.tail {
+                cursor.head = start;
+            } else {
+                cursor.tail = start;
+            }
+        }
 
+        self.push_change(start, start, chars);
+        let first_line = self.rope.char_to_line(start);
+        self.uncount_indentation(first_line, first_line);
+        let (start_byte, start_position) = self.byte_point(start);
         self.rope.insert(start, chars);
+        let last_line = self.rope.char_to_line(start + chars_count);
+        self.recount_indentation(first_line, first_line, last_line);
+        let (new_end_byte, new_end_position) = self.byte_point(start + chars_count);
+        self.push_syntax_edit(InputEdit {
+            start_byte,
+            old_end_byte: start_byte,
+            new_end_byte,
+            start_position,
+            old_end_position: start_position,
+            new_end_position,
+        });
+        self.dirty_since_sync.store(true, Ordering::SeqCst);
+    }
 
-        self.lsp_edit()
+    fn record(&mut self, edit: Edit, cursor_before: Cursor) {
+        let history = &mut self.history;
+        history.redo.clear();
+
+        let typed = match &edit {
+            Edit::Insert { text, .. } => {
+                let mut chars = text.chars();
+                matches!((chars.next(), chars.next()), (Some(c), None) if !c.is_whitespace())
+            }
+            Edit::Remove { .. } => false,
+        };
+
+        let merge = if history.in_group {
+            std::mem::replace(&mut history.group_started, true)
+        } else if let (true, true, Some(group)) = (typed, history.typing, history.undo.last()) {
+            // consecutive typed chars are undone together
+            match (group.edits.last(), &edit) {
+                (Some(Edit::Insert { at: last, text }), Edit::Insert { at, .. }) => {
+                    last + text.chars().count() == *at
+                }
+                _ => false,
+            }
+        } else {
+            false
+        };
+
+        if let (true, Some(group)) = (merge, history.undo.last_mut()) {
+            group.edits.push(edit);
+            group.cursor_after = self.cursor.clone();
+        } else {
+            // the saved text was undone, it was in the redo stack
+            if history
+                .saved
+                .map_or(false, |saved| history.undo.len() < saved)
+            {
+                history.saved = None;
+            }
+            history.undo.push(UndoGroup {
+                edits: vec![edit],
+                cursor_before,
+                cursor_after: self.cursor.clone(),
+            });
+        }
+        history.typing = typed;
     }
 
-    fn lsp_edit(&mut self) -> LspInput {
-        LspInput::Edit {
-            buffer_id: self.id,
-            version: self.version.fetch_add(1, Ordering::SeqCst),
-            text: self.text().to_string(),
+    /// Remember the text as saved, the history is kept so undo can go past the save.
+    pub fn mark_saved(&mut self) {
+        self.history.saved = Some(self.history.undo.len());
+        self.saved_text = self.rope.clone();
+        // typing after the save must not be merged in the saved undo group
+        self.history.typing = false;
+    }
+
+    /// Whether `text` is the one last loaded or saved, e.g. a file changed on disk by a save.
+    pub fn is_saved_text(&self, text: &str) -> bool {
+        self.saved_text == text
+    }
+
+    /// Whether the text differs from the one last loaded or saved.
+    pub fn is_modified(&self) -> bool {
+        self.history.saved != Some(self.history.undo.len())
+    }
+
+    /// Replace the text by `text`, the file read again after another program changed it.
+    /// Only the changed part is edited, the cursors outside of it stay and the reload is
+    /// undone in one step like any edit.
+    pub fn reload(&mut self, text: &str) -> Option<LspInput> {
+        let old = self.text();
+        let (old_len, new_len) = (old.chars().count(), text.chars().count());
+        let prefix = old
+            .chars()
+            .zip(text.chars())
+            .take_while(|(a, b)| a == b)
+            .count();
+        if prefix == old_len && prefix == new_len {
+            return None;
+        }
+        let suffix = old
+            .chars()
+            .rev()
+            .zip(text.chars().rev())
+            .take(min(old_len, new_len) - prefix)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let inserted: String = text
+            .chars()
+            .skip(prefix)
+            .take(new_len - prefix - suffix)
+            .collect();
+        self.group(|buffer| {
+            buffer.remove_chars((prefix, old_len - suffix));
+            if !inserted.is_empty() {
+                buffer.insert_keeping_cursor(prefix, &inserted, Gravity::Left);
+            }
+        });
+        self.mark_saved();
+        Some(self.lsp_edit())
+    }
+
+    /// Run `f`, every edit done inside is undone in one step.
+    pub fn group<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        if self.history.in_group {
+            return f(self);
+        }
+        self.history.in_group = true;
+        self.history.group_started = false;
+        let result = f(self);
+        self.history.in_group = false;
+        result
+    }
+
+    /// Apply the edits of a language server, their ranges are all relative to the text
+    /// before the first edit so they are applied from the last one. Undone in one step.
+    pub fn apply_edits(&mut self, edits: &[TextEdit]) {
+        let edits = edits
+            .iter()
+            .sorted_by_key(|e| {
+                let bounds: Bounds = (&e.range).into_with_buf(self);
+                bounds.0
+            })
+            .rev()
+            .collect_vec();
+        self.group(|buffer| {
+            for e in edits {
+                buffer.remove_chars(&e.range);
+                buffer.insert(&e.range.start, &e.new_text);
+            }
+        });
+    }
+
+    /// Where the start of `edits[i]` is once all of `edits`, which do not overlap,
+    /// are applied. An insertion at the same place is applied before it.
+    pub fn edited_start(&self, edits: &[TextEdit], i: usize) -> Index {
+        let start: Index = (&edits[i].range.start).into_with_buf(self);
+        edits
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .fold(start, |idx, (_, e)| {
+                let (min, max): Bounds = (&e.range).into_with_buf(self);
+                if max <= start && min < start {
+                    idx + e.new_text.chars().count() - (max - min)
+                } else {
+                    idx
+                }
+            })
+    }
+
+    /// Visit the tab `stops` of a snippet inserted at `at`, the first one is selected.
+    pub fn start_snippet(&mut self, at: Index, stops: &[Bounds]) {
+        self.snippet = stops.iter().map(|s| (at + s.0, at + s.1)).collect();
+        self.next_stop();
+    }
+
+    /// Select the next tab stop of the snippet, returns whether there was one.
+    pub fn next_stop(&mut self) -> bool {
+        if self.snippet.is_empty() {
+            return false;
+        }
+        let stop = self.snippet.remove(0);
+        self.set_selections(vec![stop]);
+        true
+    }
+
+    /// Stop visiting the tab stops of the snippet, returns whether there was one.
+    pub fn end_snippet(&mut self) -> bool {
+        !std::mem::take(&mut self.snippet).is_empty()
+    }
+
+    fn apply(&mut self, edit: &Edit, inverse: bool) {
+        match (edit, inverse) {
+            (Edit::Insert { at, text }, false) | (Edit::Remove { at, text }, true) => {
+                self.insert_raw(*at, text)
+            }
+            (Edit::Insert { at, text }, true) | (Edit::Remove { at, text }, false) => {
+                self.remove_raw(*at, *at + text.chars().count())
+            }
+        }
+    }
+
+    pub fn undo(&mut self) -> Option<LspInput> {
+        let group = self.history.undo.pop()?;
+        for edit in group.edits.iter().rev() {
+            self.apply(edit, true);
+        }
+        self.cursor = group.cursor_before.clone();
+        self.history.redo.push(group);
+        self.history.typing = false;
+        Some(self.lsp_edit())
+    }
+
+    pub fn redo(&mut self) -> Option<LspInput> {
+        let group = self.history.redo.pop()?;
+        for edit in group.edits.iter() {
+            self.apply(edit, false);
         }
+        self.cursor = group.cursor_after.clone();
+        self.history.undo.push(group);
+        self.history.typing = false;
+        Some(self.lsp_edit())
+    }
+
+    fn lsp_edit(&mut self) -> LspInput {
+        // the edit filters the completions again, the best match is first
+        self.completion_selected = 0;
+        LspInput::Edit { buffer_id: self.id }
     }
 
     pub fn do_action(&mut self, a: Action) -> Option<LspInput> {
+        match a {
+            Action::Undo | Action::Redo | Action::Retab => {
+                self.secondary.clear();
+                self.do_cursor_action(a)
+            }
+            _ if !self.secondary.is_empty() => {
+                self.for_each_cursor(|buf| buf.do_cursor_action(a.clone()))
+            }
+            _ => self.do_cursor_action(a),
+        }
+    }
+
+    fn do_cursor_action(&mut self, a: Action) -> Option<LspInput> {
         match a {
             Action::Insert(chars) => {
+                let pair = self.pairs.iter().find(|p| p.open == chars).cloned();
                 if self.cursor.head != self.cursor.tail {
-                    let bounds = (self.cursor.min(), self.cursor.max());
-                    self.remove_chars(bounds);
+                    if let Some(pair) = pair {
+                        return self.wrap_selection(&pair);
+                    }
+                    return self.replace_selection(&chars);
+                }
+                let head = self.cursor.head;
+                if let Some(close) = self.completed_open(&chars) {
+                    return Some(self.insert_pair(&chars, &close));
+                }
+                if self.pairs.iter().any(|p| p.close == chars) && self.text_at(head, &chars) {
+                    let after = head + chars.chars().count();
+                    self.cursor = Cursor {
+                        head: after,
+                        tail: after,
+                    };
+                    return None;
+                }
+                match pair {
+                    Some(pair) if self.can_close(head, &pair) => {
+                        Some(self.insert_pair(&pair.open, &pair.close))
+                    }
+                    _ => self.replace_selection(&chars),
                 }
-                Some(self.insert(self.cursor.head, chars.as_str()))
             }
+            Action::Paste(text) => {
+                let text = self.reindent_paste(&text);
+                self.replace_selection(&text)
+            }
+            Action::PasteRaw(text) => self.replace_selection(&text),
             Action::Backspace => {
                 if self.cursor.head != self.cursor.tail {
                     self.remove_chars((self.cursor.min(), self.cursor.max()))
                 } else {
-                    self.remove_chars((self.cursor.head.saturating_sub(1), self.cursor.head))
+                    self.remove_chars((self.prev_grapheme(self.cursor.head), self.cursor.head))
                 }
             }
             Action::Delete => {
                 if self.cursor.head != self.cursor.tail {
                     self.remove_chars((self.cursor.min(), self.cursor.max()))
                 } else {
-                    self.remove_chars((self.cursor.head, self.cursor.head.saturating_add(1)))
+                    self.remove_chars((self.cursor.head, self.next_grapheme(self.cursor.head)))
+                }
+            }
+            Action::Undo => self.undo(),
+            Action::Redo => self.redo(),
+            Action::DeleteWordBackward => {
+                if self.cursor.head != self.cursor.tail {
+                    self.remove_chars((self.cursor.min(), self.cursor.max()))
+                } else {
+                    let start = self.prev_word_boundary(self.cursor.head);
+                    self.remove_chars((start, self.cursor.head))
+                }
+            }
+            Action::DeleteWordForward => {
+                if self.cursor.head != self.cursor.tail {
+                    self.remove_chars((self.cursor.min(), self.cursor.max()))
+                } else {
+                    let end = self.next_word_boundary(self.cursor.head);
+                    self.remove_chars((self.cursor.head, end))
                 }
             }
+            Action::DeleteToLineEnd => {
+                let end = self.line_bounds(self.row()).1;
+                self.remove_chars((self.cursor.min(), end))
+            }
+            Action::DuplicateLines => {
+                let (from, to) = self.selected_lines();
+                Some(self.duplicate_lines(from, to))
+            }
+            Action::MoveLines(direction) => self.move_lines(self.selected_lines(), direction),
+            Action::DeleteLine => self.delete_line(self.row()),
+            Action::TrimTrailingWhitespace => self.trim_trailing_whitespace(),
+            Action::Retab => {
+                let lsp = self.retab(self.indent.indent_width);
+                if self.indentation.is_some() {
+                    self.indentation = Some(self.check_indentation());
+                }
+                lsp
+            }
         }
     }
 
-    pub fn cursor(&self) -> Cursor {
-        self.cursor.clone()
+    /// Keep the `max` most severe diagnostics.
+    pub fn truncate_diagnostics(&mut self, max: usize) {
+        let diagnostics = &mut self.diagnostics.0;
+        self.diagnostics_hidden = diagnostics.len().saturating_sub(max);
+        if self.diagnostics_hidden > 0 {
+            diagnostics.sort_by_key(|d| d.severity);
+            diagnostics.truncate(max);
+        }
     }
 
-    pub fn text(&self) -> String {
-        self.rope.chars().collect()
+    /// Bounds of the word under `idx`, `None` on whitespace.
+    pub fn word_at(&self, idx: Index) -> Option<Bounds> {
+        let len = self.rope.len_chars();
+        let c = if idx < len && !is_blank(self.rope.char(idx)) {
+            self.rope.char(idx)
+        } else if idx > 0 && idx <= len {
+            self.rope.char(idx - 1)
+        } else {
+            return None;
+        };
+        if c.is_whitespace() {
+            return None;
+        }
+        let class = char_class(c);
+        let same = |i: Index| char_class(self.rope.char(i)) == class;
+        let mut start = min(idx, len);
+        while start > 0 && same(start - 1) {
+            start -= 1;
+        }
+        let mut end = start;
+        while end < len && same(end) {
+            end += 1;
+        }
+        Some((start, end))
     }
 
-    pub fn text_slice<R: RangeBounds<usize>>(&self, range: R) -> anyhow::Result<String> {
-        let start = match range.start_bound() {
-            Bound::Included(n) => Some(*n),
-            Bound::Excluded(n) => Some(*n + 1),
-            Bound::Unbounded => None,
-        };
+    pub fn secondary_cursors(&self) -> &[Cursor] {
+        &self.secondary
+    }
 
-        let end = match range.end_bound() {
-            Bound::Included(n) => Some(*n + 1),
-            Bound::Excluded(n) => Some(*n),
-            Bound::Unbounded => None,
+    /// Run of chars of the same class as the one at `idx`, or the one before at a line end.
+    /// Unlike `word_at`, a run of blanks counts as a word.
+    pub fn word_bounds_at(&self, idx: Index) -> Bounds {
+        let len = self.rope.len_chars();
+        let idx = min(idx, len);
+        let at = if idx < len && !is_newline(self.rope.char(idx)) {
+            idx
+        } else if idx > 0 && !is_newline(self.rope.char(idx - 1)) {
+            idx - 1
+        } else {
+            return (idx, idx);
         };
-
-        if let Some(end) = end {
-            if end > self.rope.len_chars() {
-                anyhow::bail!("rope slice overflow");
+        let class = char_class(self.rope.char(at));
+        let same = |i: Index| {
+            let c = self.rope.char(i);
+            !is_newline(c) && char_class(c) == class
+        };
+        let mut start = at;
+        while start > 0 && same(start - 1) {
+            start -= 1;
+        }
+        let mut end = at + 1;
+        while end < len && same(end) {
+            end += 1;
+        }
+        (start, end)
+    }
+
+    /// Select all `selections`, the one containing the cursor (or the first) is the primary.
+    /// Overlapping and adjacent selections are merged.
+    pub fn set_selections(&mut self, mut selections: Vec<Bounds>) {
+        selections.sort();
+        let mut merged: Vec<Bounds> = vec![];
+        for (start, end) in selections {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = max(last.1, end),
+                _ => merged.push((start, end)),
+            }
+        }
+        if merged.is_empty() {
+            return;
+        }
+        let head = self.cursor.head;
+        let primary = merged
+            .iter()
+            .position(|(start, end)| *start <= head && head <= *end)
+            .unwrap_or(0);
+        let (start, end) = merged.remove(primary);
+        self.cursor = Cursor {
+            head: end,
+            tail: start,
+        };
+        self.secondary = merged
+            .into_iter()
+            .map(|(start, end)| Cursor {
+                head: end,
+                tail: start,
+            })
+            .collect();
+        self.completions = vec![];
+        self.history.typing = false;
+    }
+
+    /// Drop the secondary cursors, returns whether there were any.
+    pub fn collapse_cursors(&mut self) -> bool {
+        !std::mem::take(&mut self.secondary).is_empty()
+    }
+
+    /// Add a selection of `bounds`, a caret when empty, as the primary cursor or a secondary
+    /// one. Returns false when it would overlap a cursor or a caret would touch one, a
+    /// selection can be right next to another one.
+    pub fn add_selection(&mut self, bounds: Bounds, primary: bool) -> bool {
+        let len = self.rope.len_chars();
+        let (start, end) = (min(bounds.0, len), min(bounds.1, len));
+        let covered = std::iter::once(&self.cursor)
+            .chain(&self.secondary)
+            .any(|c| {
+                let touching = c.min() <= end && start <= c.max();
+                let overlapping = c.min() < end && start < c.max();
+                overlapping || (touching && (start == end || c.same()))
+            });
+        if covered {
+            return false;
+        }
+        let selection = Cursor {
+            head: end,
+            tail: start,
+        };
+        if primary {
+            let old = std::mem::replace(&mut self.cursor, selection);
+            self.secondary.push(old);
+        } else {
+            self.secondary.push(selection);
+        }
+        self.completions = vec![];
+        self.history.typing = false;
+        true
+    }
+
+    /// Add a caret on the line above the topmost cursor or below the bottommost one, at the
+    /// column of the primary cursor on screen. Returns false at the first or the last line.
+    pub fn add_cursor_on_adjacent_line(&mut self, direction: Direction) -> bool {
+        let heads = std::iter::once(&self.cursor)
+            .chain(&self.secondary)
+            .map(|c| c.head);
+        let line = match direction {
+            Direction::Up => match self.row_at(heads.min().unwrap_or(0)).checked_sub(1) {
+                Some(line) => line,
+                None => return false,
+            },
+            Direction::Down => {
+                let line = self.row_at(heads.max().unwrap_or(0)) + 1;
+                if line > self.last_line() {
+                    return false;
+                }
+                line
+            }
+        };
+        let tab_width = self.indent.indent_width;
+        let visual_col = self.visual_col_at(self.cursor.head, tab_width);
+        let idx = self.index_at_visual_col(line, visual_col, tab_width);
+        self.add_selection((idx, idx), false)
+    }
+
+    /// Run `f` at every cursor, from the last to the first, in one undo group.
+    fn for_each_cursor(&mut self, f: impl Fn(&mut Self) -> Option<LspInput>) -> Option<LspInput> {
+        let primary = self.cursor.clone();
+        self.secondary.push(primary.clone());
+        self.secondary.sort_by(|a, b| b.min().cmp(&a.min()));
+        let primary_pos = self
+            .secondary
+            .iter()
+            .position(|c| c.head == primary.head && c.tail == primary.tail)
+            .unwrap_or(0);
+
+        let lsp = self.group(|buf| {
+            let mut lsp = None;
+            // the pending cursors stay in `secondary` so the edits move them
+            for i in 0..buf.secondary.len() {
+                buf.cursor = buf.secondary[i].clone();
+                lsp = f(buf).or(lsp);
+                buf.secondary[i] = buf.cursor.clone();
+            }
+            lsp
+        });
+
+        self.cursor = self.secondary.remove(primary_pos);
+        let cursor = self.cursor.clone();
+        self.secondary
+            .retain(|c| c.min() != cursor.min() || c.max() != cursor.max());
+        self.secondary
+            .dedup_by(|a, b| a.min() == b.min() && a.max() == b.max());
+        self.secondary.reverse();
+        lsp
+    }
+
+    /// Copy the lines `from_line..=to_line` below them, the cursor moves onto the copy.
+    pub fn duplicate_lines(&mut self, from_line: Index, to_line: Index) -> LspInput {
+        let start = self.rope.line_to_char(min(from_line, self.last_line()));
+        let end = self.line_bounds(max(to_line, from_line)).1;
+        let text = format!("\n{}", self.rope.slice(start..end));
+        let len = text.chars().count();
+
+        let cursor = self.cursor.clone();
+        let lsp = self.insert(end, &text);
+        self.set_cursor_after_edit(Cursor {
+            head: cursor.head + len,
+            tail: cursor.tail + len,
+        });
+        lsp
+    }
+
+    fn replace_selection(&mut self, text: &str) -> Option<LspInput> {
+        if self.cursor.head == self.cursor.tail {
+            return Some(self.insert(self.cursor.head, text));
+        }
+        self.group(|buf| {
+            let bounds = (buf.cursor.min(), buf.cursor.max());
+            buf.remove_chars(bounds);
+            Some(buf.insert(buf.cursor.head, text))
+        })
+    }
+
+    /// `text` with its lines after the first moved to the indentation of the cursor line,
+    /// keeping their indentation relative to each other.
+    pub fn reindent_paste(&self, text: &str) -> String {
+        let mut lines = text.split('\n');
+        let first = lines.next().unwrap_or_default();
+        let rest = lines.collect_vec();
+        if rest.is_empty() {
+            return text.to_string();
+        }
+
+        let at = self.cursor.min();
+        let row = self.row_at(at);
+        let base: String = self
+            .leading_whitespace(row)
+            .chars()
+            .take(at - self.line_bounds(row).0)
+            .collect();
+        let common = rest
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.len() - line.trim_start_matches(&[' ', '\t'][..]).len())
+            .min()
+            .unwrap_or(0);
+
+        let mut result = first.to_string();
+        for line in rest {
+            result.push('\n');
+            if !line.trim().is_empty() {
+                result.push_str(&base);
+                result.extend(line.chars().skip(common));
+            }
+        }
+        result
+    }
+
+    /// Surround the selection with `pair`, the same text stays selected.
+    fn wrap_selection(&mut self, pair: &Pair) -> Option<LspInput> {
+        let cursor = self.cursor.clone();
+        let shift = pair.open.chars().count();
+        let lsp = self.group(|buf| {
+            buf.insert(cursor.max(), &pair.close);
+            Some(buf.insert(cursor.min(), &pair.open))
+        });
+        self.set_cursor_after_edit(Cursor {
+            head: cursor.head + shift,
+            tail: cursor.tail + shift,
+        });
+        lsp
+    }
+
+    /// Type `open` and `close` at the cursor, which ends up between them.
+    fn insert_pair(&mut self, open: &str, close: &str) -> LspInput {
+        let head = self.cursor.head + open.chars().count();
+        let lsp = self.insert(self.cursor.head, &format!("{}{}", open, close));
+        self.set_cursor_after_edit(Cursor { head, tail: head });
+        lsp
+    }
+
+    /// Closing of a pair opened by several chars which typing `chars` completes.
+    fn completed_open(&self, chars: &str) -> Option<String> {
+        let head = self.cursor.head;
+        self.pairs
+            .iter()
+            .filter(|p| p.open.len() > chars.len() && p.open.ends_with(chars))
+            .find(|p| {
+                let before = p.open.chars().count() - chars.chars().count();
+                head >= before && self.text_at(head - before, &p.open[..p.open.len() - chars.len()])
+            })
+            .map(|p| p.close.clone())
+    }
+
+    /// Auto closing stays out of the way of words, a quote right after a word is an apostrophe.
+    fn can_close(&self, at: Index, pair: &Pair) -> bool {
+        let next = self.rope.get_char(at);
+        let next_ok = match next {
+            None => true,
+            Some(c) => c.is_whitespace() || self.pairs.iter().any(|p| p.close.starts_with(c)),
+        };
+        let prev_ok =
+            pair.open != pair.close || at == 0 || !self.rope.char(at - 1).is_alphanumeric();
+        next_ok && prev_ok
+    }
+
+    fn text_at(&self, at: Index, text: &str) -> bool {
+        let end = at + text.chars().count();
+        end <= self.rope.len_chars() && self.rope.slice(at..end) == text
+    }
+
+    /// Remove `line` and its line break, the cursor goes to the content of the next line.
+    pub fn delete_line(&mut self, line: Index) -> Option<LspInput> {
+        if line > self.last_line() {
+            return None;
+        }
+        let (start, end) = if line < self.last_line() {
+            (
+                self.rope.line_to_char(line),
+                self.rope.line_to_char(line + 1),
+            )
+        } else if line > 0 {
+            // the last line takes the line break before it
+            (self.line_bounds(line - 1).1, self.rope.len_chars())
+        } else {
+            (0, self.rope.len_chars())
+        };
+
+        let lsp = self.remove_chars((start, end));
+        let row = min(line, self.last_line());
+        let idx = self.line_bounds(row).0 + self.leading_whitespace(row).chars().count();
+        self.set_cursor_after_edit(Cursor {
+            head: idx,
+            tail: idx,
+        });
+        lsp
+    }
+
+    /// Swap the lines `from_line..=to_line` with the line above or below,
+    /// `None` if there is no such line.
+    pub fn move_lines(
+        &mut self,
+        (from_line, to_line): (Index, Index),
+        direction: Direction,
+    ) -> Option<LspInput> {
+        let (first, last) = match direction {
+            Direction::Up if from_line > 0 => (from_line - 1, to_line),
+            Direction::Down if to_line < self.last_line() => (from_line, to_line + 1),
+            _ => return None,
+        };
+        let start = self.rope.line_to_char(first);
+        let end = self.line_bounds(last).1;
+        // the moved lines and the other line, with the line break between them
+        let (block, other, shift) = match direction {
+            Direction::Up => {
+                let other_end = self.line_bounds(first).1;
+                let block_start = self.rope.line_to_char(from_line);
+                let other = self.rope.slice(start..other_end).to_string();
+                let sep = self.rope.slice(other_end..block_start).to_string();
+                let block = self.rope.slice(block_start..end).to_string();
+                (
+                    block,
+                    format!("{}{}", sep, other),
+                    -((block_start - start) as isize),
+                )
+            }
+            Direction::Down => {
+                let block_end = self.line_bounds(to_line).1;
+                let other_start = self.rope.line_to_char(last);
+                let block = self.rope.slice(start..block_end).to_string();
+                let sep = self.rope.slice(block_end..other_start).to_string();
+                let other = self.rope.slice(other_start..end).to_string();
+                (
+                    block,
+                    format!("{}{}", other, sep),
+                    (end - block_end) as isize,
+                )
+            }
+        };
+        let text = match direction {
+            Direction::Up => format!("{}{}", block, other),
+            Direction::Down => format!("{}{}", other, block),
+        };
+
+        let cursor = self.cursor.clone();
+        let lsp = self.group(|buf| {
+            buf.remove_chars((start, end));
+            buf.insert(start, &text)
+        });
+        let moved = |idx: Index| (idx as isize + shift) as Index;
+        self.set_cursor_after_edit(Cursor {
+            head: moved(cursor.head),
+            tail: moved(cursor.tail),
+        });
+        Some(lsp)
+    }
+
+    /// Lines covered by the selection, without the line it ends at the start of.
+    fn selected_lines(&self) -> (Index, Index) {
+        let from = self.row_at(self.cursor.min());
+        let mut to = self.row_at(self.cursor.max());
+        if to > from && self.col_at(self.cursor.max()) == 0 {
+            to -= 1;
+        }
+        (from, to)
+    }
+
+    /// Put the cursor where an edit logically leaves it, redo restores it too.
+    fn set_cursor_after_edit(&mut self, cursor: Cursor) {
+        self.cursor = cursor;
+        if let Some(group) = self.history.undo.last_mut() {
+            group.cursor_after = self.cursor.clone();
+        }
+    }
+
+    /// Tabs and spaces at the start of `line`.
+    pub fn leading_whitespace(&self, line: Index) -> String {
+        let bounds = self.line_bounds(line);
+        self.text_slice(bounds.0..bounds.1)
+            .unwrap_or_default()
+            .chars()
+            .take_while(|c| *c == ' ' || *c == '\t')
+            .collect()
+    }
+
+    /// Indentation of a line break inserted at `at`.
+    pub fn newline_indent(&self, at: Index, lang: &LspLang) -> String {
+        let row = self.row_at(at);
+        let start = self.line_bounds(row).0;
+        let before = self.text_slice(start..at).unwrap_or_default();
+        // breaking the line inside its indentation does not indent more
+        let mut indent: String = self
+            .leading_whitespace(row)
+            .chars()
+            .take(at - start)
+            .collect();
+        // a new level is like the line, or like the settings for an unindented one
+        let unit = if indent.contains('\t') || (indent.is_empty() && !self.indent.use_spaces) {
+            "\t".to_string()
+        } else {
+            " ".repeat(self.indent.indent_width)
+        };
+        let code = before.trim();
+
+        match lang {
+            LspLang::Python => {
+                let first_word = code.split_whitespace().next().unwrap_or("");
+                if code.ends_with(':') {
+                    indent.push_str(&unit);
+                } else if ["return", "pass", "break", "continue", "raise"].contains(&first_word) {
+                    // the block ends here
+                    if indent.ends_with(&unit) {
+                        indent.truncate(indent.len() - unit.len());
+                    } else {
+                        indent.pop();
+                    }
+                }
+            }
+            _ => {
+                if code.ends_with('{') {
+                    indent.push_str(&unit);
+                }
+            }
+        }
+        indent
+    }
+
+    /// Tabs and spaces at the start of `line`, and its length in chars.
+    fn line_indent(&self, line: Index) -> (bool, bool, usize) {
+        let (mut tab, mut space, mut len) = (false, false, 0);
+        for c in self.rope.line(line).chars() {
+            match c {
+                '\t' => tab = true,
+                ' ' => space = true,
+                _ => break,
+            }
+            len += 1;
+        }
+        (tab, space, len)
+    }
+
+    /// Indentation levels of `line`, `None` if it is blank.
+    fn line_depth(&self, line: Index) -> Option<usize> {
+        let width = self.indent.indent_width;
+        let mut col = 0;
+        for c in self.rope.line(line).chars() {
+            match c {
+                ' ' | '\t' => col = advance_col(col, c, width),
+                '\n' | '\r' => return None,
+                _ => return Some(col / width),
+            }
+        }
+        None
+    }
+
+    /// Indentation guides of `line`, one per level. A blank line continues the guides
+    /// of the lines around it, plus one when they are not at the same level.
+    pub fn indent_depth(&self, line: Index) -> usize {
+        if let Some(depth) = self.line_depth(line) {
+            return depth;
+        }
+        let above = (0..line)
+            .rev()
+            .find_map(|l| self.line_depth(l))
+            .unwrap_or(0);
+        let below = (line + 1..self.rope.len_lines())
+            .find_map(|l| self.line_depth(l))
+            .unwrap_or(0);
+        if above == below {
+            above
+        } else {
+            min(above, below) + 1
+        }
+    }
+
+    /// Whether the indentation of `line` is different from `use_tabs`.
+    pub fn is_line_inconsistent(&self, line: Index, use_tabs: bool) -> bool {
+        match self.line_indent(line) {
+            (true, true, _) => true,
+            (tab, space, _) => (use_tabs && space) || (!use_tabs && tab),
+        }
+    }
+
+    /// Scan the leading whitespace of every line.
+    pub fn check_indentation(&self) -> Indentation {
+        let mut indentation = Indentation::default();
+        for line in 0..self.rope.len_lines() {
+            match self.line_indent(line) {
+                (true, false, _) => indentation.tabs += 1,
+                (false, true, _) => indentation.spaces += 1,
+                _ => {}
+            }
+        }
+        let use_tabs = indentation.use_tabs();
+        indentation.inconsistent_lines = (0..self.rope.len_lines())
+            .filter(|line| self.is_line_inconsistent(*line, use_tabs))
+            .collect();
+        indentation
+    }
+
+    /// Take lines `first..=last` out of the indentation check, before they are edited.
+    fn uncount_indentation(&mut self, first: usize, last: usize) {
+        if let Some(mut indentation) = self.indentation.take() {
+            for line in first..=last {
+                match self.line_indent(line) {
+                    (true, false, _) => indentation.tabs = indentation.tabs.saturating_sub(1),
+                    (false, true, _) => indentation.spaces = indentation.spaces.saturating_sub(1),
+                    _ => {}
+                }
+            }
+            self.indentation = Some(indentation);
+        }
+    }
+
+    /// Check lines `first..=last` after they replaced the lines `first..=old_last`.
+    fn recount_indentation(&mut self, first: usize, old_last: usize, last: usize) {
+        if let Some(mut indentation) = self.indentation.take() {
+            let use_tabs = indentation.use_tabs();
+            for line in first..=last {
+                match self.line_indent(line) {
+                    (true, false, _) => indentation.tabs += 1,
+                    (false, true, _) => indentation.spaces += 1,
+                    _ => {}
+                }
+            }
+            let use_tabs_now = indentation.use_tabs();
+            indentation.inconsistent_lines = if use_tabs_now != use_tabs {
+                // the style of the file changed, every line is checked again
+                (0..self.rope.len_lines())
+                    .filter(|line| self.is_line_inconsistent(*line, use_tabs_now))
+                    .collect()
+            } else {
+                let lines = &indentation.inconsistent_lines;
+                let before = lines.iter().copied().filter(|line| *line < first);
+                let edited =
+                    (first..=last).filter(|line| self.is_line_inconsistent(*line, use_tabs));
+                let after = lines
+                    .iter()
+                    .filter(|line| **line > old_last)
+                    .map(|line| line - old_last + last);
+                before.chain(edited).chain(after).collect()
+            };
+            self.indentation = Some(indentation);
+        }
+    }
+
+    /// Rewrite the indentation of every line with the style used by most of the file.
+    pub fn retab(&mut self, tab_width: usize) -> Option<LspInput> {
+        let use_tabs = self.check_indentation().use_tabs();
+        self.group(|buf| {
+            let mut lsp = None;
+            for line in 0..buf.rope.len_lines() {
+                let start = buf.rope.line_to_char(line);
+                let (_, _, len) = buf.line_indent(line);
+                let mut width = 0;
+                for c in buf.rope.slice(start..start + len).chars() {
+                    width += if c == '\t' {
+                        tab_width - width % tab_width
+                    } else {
+                        1
+                    };
+                }
+                let indent = if use_tabs {
+                    "\t".repeat(width / tab_width) + &" ".repeat(width % tab_width)
+                } else {
+                    " ".repeat(width)
+                };
+                if buf.rope.slice(start..start + len) != indent.as_str() {
+                    buf.remove_chars((start, start + len));
+                    lsp = Some(buf.insert(start, &indent));
+                }
+            }
+            lsp
+        })
+    }
+
+    /// Index of the bracket matching the one at `idx` within `bounds`, `None` if there is
+    /// no bracket at `idx` or it is not balanced there. Brackets of other kinds in between
+    /// must be balanced too, those for which `ignored` is true, in strings or comments, do
+    /// not count.
+    pub fn matching_bracket(
+        &self,
+        idx: Index,
+        bounds: Bounds,
+        ignored: impl Fn(Index) -> bool,
+    ) -> Option<Index> {
+        const BRACKETS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+        let end = min(bounds.1, self.rope.len_chars());
+        if idx < bounds.0 || idx >= end || ignored(idx) {
+            return None;
+        }
+        let c = self.rope.char(idx);
+        let forward = BRACKETS.iter().any(|(open, _)| *open == c);
+        if !forward && !BRACKETS.iter().any(|(_, close)| *close == c) {
+            return None;
+        }
+        // brackets opened on the way, in the scan direction
+        let mut stack = vec![];
+        let mut i = idx;
+        loop {
+            let c = self.rope.char(i);
+            let pair = BRACKETS.iter().find(|(o, cl)| *o == c || *cl == c);
+            if let Some(pair) = pair.filter(|_| i == idx || !ignored(i)) {
+                if (pair.0 == c) == forward {
+                    stack.push(pair);
+                } else if stack.pop()? != pair {
+                    return None;
+                }
+                if stack.is_empty() {
+                    return Some(i);
+                }
+            }
+            i = if forward {
+                Some(i + 1).filter(|i| *i < end)?
+            } else {
+                Some(i.checked_sub(1)?).filter(|i| *i >= bounds.0)?
+            };
+        }
+    }
+
+    /// Brackets matching each other next to the cursor, the one after it first, see
+    /// `matching_bracket`.
+    pub fn bracket_match(&self, bounds: Bounds, ignored: impl Fn(Index) -> bool) -> Option<Bounds> {
+        let head = self.cursor.head;
+        [Some(head), head.checked_sub(1)]
+            .into_iter()
+            .flatten()
+            .find_map(|idx| Some((idx, self.matching_bracket(idx, bounds, &ignored)?)))
+    }
+
+    /// Text inserted by `Tab` at the cursor: a tab, or spaces up to the next tab stop.
+    pub fn indent_unit(&self) -> String {
+        if self.indent.use_spaces {
+            let width = self.indent.indent_width;
+            let col = self.visual_col_at(self.cursor.head, width);
+            " ".repeat(width - col % width)
+        } else {
+            "\t".to_string()
+        }
+    }
+
+    /// Remove the spaces and tabs at the end of every line.
+    pub fn trim_trailing_whitespace(&mut self) -> Option<LspInput> {
+        self.group(|buf| {
+            let mut lsp = None;
+            for line in 0..buf.rope.len_lines() {
+                let (start, end) = buf.line_bounds(line);
+                let trailing = buf
+                    .rope
+                    .slice(start..end)
+                    .chars()
+                    .rev()
+                    .take_while(|c| *c == ' ' || *c == '\t')
+                    .count();
+                if trailing > 0 {
+                    lsp = buf.remove_chars((end - trailing, end));
+                }
+            }
+            lsp
+        })
+    }
+
+    /// Char index of the start of each grapheme cluster of `line`, and of its end.
+    fn grapheme_boundaries(&self, line: Index) -> Vec<Index> {
+        let (start, end) = self.line_bounds(line);
+        let text = self.rope.slice(start..end).to_string();
+        let mut boundaries = vec![start];
+        for grapheme in text.graphemes(true) {
+            boundaries.push(boundaries[boundaries.len() - 1] + grapheme.chars().count());
+        }
+        boundaries
+    }
+
+    /// Start of the grapheme cluster before `idx`, `idx - 1` at the start of a line.
+    pub fn prev_grapheme(&self, idx: Index) -> Index {
+        self.grapheme_boundaries(self.row_at(idx))
+            .into_iter()
+            .rev()
+            .find(|b| *b < idx)
+            .unwrap_or_else(|| idx.saturating_sub(1))
+    }
+
+    /// End of the grapheme cluster after `idx`, `idx + 1` at the end of a line.
+    pub fn next_grapheme(&self, idx: Index) -> Index {
+        self.grapheme_boundaries(self.row_at(idx))
+            .into_iter()
+            .find(|b| *b > idx)
+            .unwrap_or_else(|| idx.saturating_add(1))
+    }
+
+    /// Start of the word before `idx`, the whitespace before `idx` is part of the word.
+    pub fn prev_word_boundary(&self, idx: Index) -> Index {
+        let mut idx = min(idx, self.rope.len_chars());
+        let start = idx;
+        while idx > 0 && is_blank(self.rope.char(idx - 1)) {
+            idx -= 1;
+        }
+        if idx == 0 {
+            return 0;
+        }
+        let c = self.rope.char(idx - 1);
+        if is_newline(c) {
+            // only the line break
+            return if idx == start { idx - 1 } else { idx };
+        }
+        let class = char_class(c);
+        while idx > 0 {
+            let c = self.rope.char(idx - 1);
+            if is_newline(c) || char_class(c) != class {
+                break;
+            }
+            idx -= 1;
+        }
+        idx
+    }
+
+    /// End of the word after `idx`, the whitespace after `idx` is part of the word.
+    pub fn next_word_boundary(&self, idx: Index) -> Index {
+        let len = self.rope.len_chars();
+        let mut idx = min(idx, len);
+        let start = idx;
+        while idx < len && is_blank(self.rope.char(idx)) {
+            idx += 1;
+        }
+        if idx == len {
+            return len;
+        }
+        let c = self.rope.char(idx);
+        if is_newline(c) {
+            return if idx == start { idx + 1 } else { idx };
+        }
+        let class = char_class(c);
+        while idx < len {
+            let c = self.rope.char(idx);
+            if is_newline(c) || char_class(c) != class {
+                break;
+            }
+            idx += 1;
+        }
+        idx
+    }
+
+    pub fn cursor(&self) -> Cursor {
+        self.cursor.clone()
+    }
+
+    pub fn text(&self) -> String {
+        self.rope.chars().collect()
+    }
+
+    pub fn text_slice<R: RangeBounds<usize>>(&self, range: R) -> anyhow::Result<String> {
+        let start = match range.start_bound() {
+            Bound::Included(n) => Some(*n),
+            Bound::Excluded(n) => Some(*n + 1),
+            Bound::Unbounded => None,
+        };
+
+        let end = match range.end_bound() {
+            Bound::Included(n) => Some(*n + 1),
+            Bound::Excluded(n) => Some(*n),
+            Bound::Unbounded => None,
+        };
+
+        if let Some(end) = end {
+            if end > self.rope.len_chars() {
+                anyhow::bail!("rope slice overflow");
             }
         }
 
@@ -516,9 +2058,17 @@ impl Buffer {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::io::Cursor;
 
-    use crate::buffer::{Action, Buffer, Movement};
+    use lsp_types::{CompletionItem, DiagnosticSeverity, NumberOrString, Position, Range};
+
+    use crate::buffer::{
+        Action, Buffer, Diagnostic, Direction, Gravity, Movement, MAX_PENDING_CHANGES,
+    };
+    use crate::config::Pair;
+    use crate::lsp::{CompletionData, LspCompletion, TextEdit};
+    use crate::LspLang;
 
     #[test]
     fn selection() {
@@ -530,6 +2080,17 @@ mod tests {
         assert_eq!(buf.text(), "asst")
     }
 
+    #[test]
+    fn move_at_boundary() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("ab"));
+        assert!(!buf.move_cursor(Movement::Left, false));
+        assert!(!buf.move_cursor(Movement::Up, false));
+        assert!(buf.move_cursor(Movement::Right, false));
+        assert!(buf.move_cursor(Movement::Right, false));
+        assert!(!buf.move_cursor(Movement::Right, false));
+        assert!(!buf.move_cursor(Movement::Down, false));
+    }
+
     #[test]
     fn edit() {
         let mut buf = Buffer::from_reader(1, Cursor::new("test"));
@@ -541,6 +2102,130 @@ mod tests {
         assert_eq!(2, buf.rope().len_lines())
     }
 
+    #[test]
+    fn undo_redo() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("test"));
+        buf.do_action(Action::Insert("a".into()));
+        buf.do_action(Action::Insert("b".into()));
+        buf.do_action(Action::Insert(" ".into()));
+        buf.do_action(Action::Insert("c".into()));
+        assert_eq!(buf.text(), "ab ctest");
+
+        buf.do_action(Action::Undo);
+        assert_eq!(buf.text(), "ab test");
+        buf.do_action(Action::Undo);
+        assert_eq!(buf.text(), "abtest");
+        buf.do_action(Action::Undo);
+        assert_eq!(buf.text(), "test");
+        assert_eq!(buf.cursor().head, 0);
+        assert!(buf.do_action(Action::Undo).is_none());
+
+        buf.do_action(Action::Redo);
+        assert_eq!(buf.text(), "abtest");
+        assert_eq!(buf.cursor().head, 2);
+
+        buf.move_cursor(Movement::Right, false);
+        buf.do_action(Action::Backspace);
+        assert_eq!(buf.text(), "abest");
+        assert!(buf.do_action(Action::Redo).is_none());
+        buf.do_action(Action::Undo);
+        assert_eq!(buf.text(), "abtest");
+        assert_eq!(buf.cursor().head, 3);
+    }
+
+    #[test]
+    fn undo_replace_selection() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("hello"));
+        buf.move_cursor(Movement::Right, true);
+        buf.move_cursor(Movement::Right, true);
+        buf.do_action(Action::Insert("j".into()));
+        assert_eq!(buf.text(), "jllo");
+        buf.do_action(Action::Undo);
+        assert_eq!(buf.text(), "hello");
+        assert_eq!(buf.cursor().min(), 0);
+        assert_eq!(buf.cursor().max(), 2);
+    }
+
+    #[test]
+    fn delete_word() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("let a = foo.bar_2;"));
+        buf.move_cursor(Movement::Index(17), false);
+        buf.do_action(Action::DeleteWordBackward);
+        assert_eq!(buf.text(), "let a = foo.;");
+        buf.do_action(Action::DeleteWordBackward);
+        assert_eq!(buf.text(), "let a = foo;");
+        buf.do_action(Action::DeleteWordBackward);
+        assert_eq!(buf.text(), "let a = ;");
+        buf.do_action(Action::DeleteWordBackward);
+        assert_eq!(buf.text(), "let a ;");
+        buf.do_action(Action::Undo);
+        assert_eq!(buf.text(), "let a = ;");
+
+        buf.move_cursor(Movement::Index(0), false);
+        buf.do_action(Action::DeleteWordForward);
+        assert_eq!(buf.text(), " a = ;");
+        buf.do_action(Action::DeleteWordForward);
+        assert_eq!(buf.text(), " = ;");
+    }
+
+    #[test]
+    fn grapheme_clusters() {
+        // a flag is two regional indicators, the accent is a combining mark
+        let mut buf = Buffer::from_reader(1, Cursor::new("a\u{1f1eb}\u{1f1f7}e\u{301}b\nc"));
+        let mut heads = vec![];
+        for _ in 0..5 {
+            buf.move_cursor(Movement::Right, false);
+            heads.push(buf.cursor().head);
+        }
+        assert_eq!(heads, vec![1, 3, 5, 6, 7]);
+        heads.clear();
+        for _ in 0..5 {
+            buf.move_cursor(Movement::Left, false);
+            heads.push(buf.cursor().head);
+        }
+        assert_eq!(heads, vec![6, 5, 3, 1, 0]);
+
+        buf.move_cursor(Movement::Index(5), false);
+        buf.do_action(Action::Backspace);
+        assert_eq!(buf.text(), "a\u{1f1eb}\u{1f1f7}b\nc");
+        buf.move_cursor(Movement::Index(1), false);
+        buf.do_action(Action::Delete);
+        assert_eq!(buf.text(), "ab\nc");
+    }
+
+    #[test]
+    fn delete_to_line_end() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("abc\r\ndef"));
+        buf.move_cursor(Movement::Index(1), false);
+        buf.do_action(Action::DeleteToLineEnd);
+        assert_eq!(buf.text(), "a\r\ndef");
+        assert!(buf.do_action(Action::DeleteToLineEnd).is_none());
+        assert_eq!(buf.text(), "a\r\ndef");
+    }
+
+    #[test]
+    fn conversions() {
+        let buf = Buffer::from_reader(1, Cursor::new("aé\r\nb\n"));
+        assert_eq!(buf.index_to_position(0), Some((0, 0)));
+        assert_eq!(buf.index_to_position(2), Some((0, 2)));
+        assert_eq!(buf.index_to_position(4), Some((1, 0)));
+        assert_eq!(buf.index_to_position(6), Some((2, 0)));
+        assert_eq!(buf.index_to_position(7), None);
+
+        assert_eq!(buf.position_to_index((0, 2)), Some(2));
+        assert_eq!(buf.position_to_index((0, 3)), None);
+        assert_eq!(buf.position_to_index((1, 1)), Some(5));
+        assert_eq!(buf.position_to_index((2, 0)), Some(6));
+        assert_eq!(buf.position_to_index((3, 0)), None);
+
+        assert_eq!(buf.index_to_byte(2), Some(3));
+        assert_eq!(buf.index_to_byte(6), Some(7));
+        assert_eq!(buf.index_to_byte(7), None);
+        assert_eq!(buf.byte_to_index(3), Some(2));
+        assert_eq!(buf.byte_to_index(2), Some(1));
+        assert_eq!(buf.byte_to_index(8), None);
+    }
+
     #[test]
     fn bounds_3() {
         let input = "{\na}";
@@ -650,5 +2335,773 @@ xyzefv
         b.move_cursor(Movement::Right, false);
         b.move_cursor(Movement::Right, false);
         assert_eq!(b.cursor().head, 15);
+        b.move_cursor(Movement::Down, false);
+        assert_eq!(b.cursor().head, 15);
+    }
+
+    #[test]
+    fn tabs_keep_the_visual_column() {
+        let mut b = Buffer::from_reader(1, Cursor::new("\tab\n    cd\n  \tx\nyz"));
+        assert_eq!(b.visual_col_at(1, TAB_WIDTH), 4);
+        assert_eq!(b.visual_col_at(2, TAB_WIDTH), 5);
+        // a tab after two spaces ends on the same stop
+        assert_eq!(b.visual_col_at(14, TAB_WIDTH), 4);
+
+        b.move_cursor(Movement::Index(2), false);
+        b.move_cursor(Movement::Down, false);
+        // `d`, 5 columns in, not the 3rd char
+        assert_eq!((b.row(), b.col()), (1, 5));
+        b.move_cursor(Movement::Left, false);
+        b.move_cursor(Movement::Down, false);
+        // `x` is at column 4
+        assert_eq!((b.row(), b.col()), (2, 3));
+        b.move_cursor(Movement::Up, false);
+        assert_eq!((b.row(), b.col()), (1, 4));
+        b.move_cursor(Movement::Up, false);
+        assert_eq!((b.row(), b.col()), (0, 1));
+
+        // a column inside a tab stays before it
+        b.move_cursor(Movement::Index(7), false);
+        b.move_cursor(Movement::Down, false);
+        assert_eq!((b.row(), b.col()), (2, 2));
+        b.move_cursor(Movement::Lines(1), false);
+        assert_eq!((b.row(), b.col()), (3, 2));
+    }
+
+    #[test]
+    fn matching_bracket() {
+        let matching = |b: &Buffer, idx| b.matching_bracket(idx, (0, usize::MAX), |_| false);
+        let b = Buffer::from_reader(1, Cursor::new("f(a[b{c}], (d))"));
+        assert_eq!(matching(&b, 1), Some(14));
+        assert_eq!(matching(&b, 14), Some(1));
+        assert_eq!(matching(&b, 5), Some(7));
+        assert_eq!(matching(&b, 8), Some(3));
+        assert_eq!(matching(&b, 11), Some(13));
+        assert_eq!(matching(&b, 0), None);
+        assert_eq!(matching(&b, 15), None);
+
+        let b = Buffer::from_reader(1, Cursor::new("((a) [b) {"));
+        // never closed
+        assert_eq!(matching(&b, 0), None);
+        assert_eq!(matching(&b, 9), None);
+        // closed by the wrong kind
+        assert_eq!(matching(&b, 5), None);
+        assert_eq!(matching(&b, 7), None);
+        assert_eq!(matching(&b, 1), Some(3));
+
+        // limited to the bounds, `ignored` brackets do not count
+        let b = Buffer::from_reader(1, Cursor::new("(a \")\" [b])"));
+        assert_eq!(b.matching_bracket(0, (0, 11), |_| false), Some(4));
+        assert_eq!(b.matching_bracket(0, (0, 11), |i| i == 4), Some(10));
+        assert_eq!(b.matching_bracket(0, (0, 10), |i| i == 4), None);
+        assert_eq!(b.matching_bracket(10, (1, 11), |i| i == 4), None);
+        assert_eq!(b.matching_bracket(4, (0, 11), |i| i == 4), None);
+    }
+
+    #[test]
+    fn indent_depth() {
+        let text = "a {\n    b {\n\t\tc\n\n    }\n\n}\n  d";
+        let b = Buffer::from_reader(1, Cursor::new(text));
+        let depths = (0..b.rope().len_lines())
+            .map(|line| b.indent_depth(line))
+            .collect::<Vec<_>>();
+        // tabs are expanded, blank lines follow the block they are in
+        assert_eq!(depths, vec![0, 1, 2, 2, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn indent_settings() {
+        let mut b = Buffer::from_reader(1, Cursor::new("a {  \n\tb\t\nc"));
+        b.indent.indent_width = 2;
+        assert_eq!(b.newline_indent(3, &LspLang::Rust), "  ");
+        b.move_cursor(Movement::Index(1), false);
+        assert_eq!(b.indent_unit(), " ");
+        b.indent.use_spaces = false;
+        assert_eq!(b.newline_indent(3, &LspLang::Rust), "\t");
+        assert_eq!(b.indent_unit(), "\t");
+
+        b.trim_trailing_whitespace();
+        assert_eq!(b.text(), "a {\n\tb\nc");
+        // undone at once
+        b.do_action(Action::Undo);
+        assert_eq!(b.text(), "a {  \n\tb\t\nc");
+    }
+
+    #[test]
+    fn truncate_diagnostics() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("abc"));
+        let severities = [
+            DiagnosticSeverity::HINT,
+            DiagnosticSeverity::ERROR,
+            DiagnosticSeverity::WARNING,
+            DiagnosticSeverity::ERROR,
+        ];
+        for (n, severity) in severities.iter().enumerate() {
+            buf.diagnostics.0.push(Diagnostic {
+                bounds: (0, 1),
+                severity: *severity,
+                message: n.to_string(),
+                original: Default::default(),
+            });
+        }
+        buf.truncate_diagnostics(10);
+        assert_eq!(buf.diagnostics_hidden, 0);
+        assert_eq!(buf.diagnostics.0.len(), 4);
+
+        buf.truncate_diagnostics(3);
+        assert_eq!(buf.diagnostics_hidden, 1);
+        let messages = buf.diagnostics.0.iter().map(|d| d.message.as_str());
+        assert_eq!(messages.collect::<Vec<_>>(), vec!["1", "3", "2"]);
+    }
+
+    #[test]
+    fn diagnostic_colors() {
+        let diagnostic = |severity| Diagnostic {
+            bounds: (0, 1),
+            severity,
+            message: String::new(),
+            original: Default::default(),
+        };
+        let colors = [
+            DiagnosticSeverity::ERROR,
+            DiagnosticSeverity::WARNING,
+            DiagnosticSeverity::INFORMATION,
+            DiagnosticSeverity::HINT,
+        ]
+        .iter()
+        .map(|s| diagnostic(*s).color().as_rgba_u32())
+        .collect::<HashSet<_>>();
+        assert_eq!(colors.len(), 4);
+
+        // the message of the error, in its color
+        let mut buf = Buffer::from_reader(1, Cursor::new("abc"));
+        buf.diagnostics.0.push(diagnostic(DiagnosticSeverity::HINT));
+        buf.diagnostics.0.push(Diagnostic {
+            message: "error".into(),
+            ..diagnostic(DiagnosticSeverity::ERROR)
+        });
+        let texts = buf.virtual_texts();
+        assert_eq!(texts.len(), 1);
+        assert_eq!(texts[0].text, " error ");
+        let error = diagnostic(DiagnosticSeverity::ERROR).color();
+        assert_eq!(texts[0].style.foreground, Some(error));
+    }
+
+    #[test]
+    fn diagnostic_to_lsp() {
+        let original = lsp_types::Diagnostic {
+            range: Range::new(Position::new(0, 1), Position::new(0, 2)),
+            severity: Some(DiagnosticSeverity::WARNING),
+            code: Some(NumberOrString::String("E0425".into())),
+            source: Some("rustc".into()),
+            message: "unused".into(),
+            data: Some(serde_json::json!({ "fix": 1 })),
+            ..Default::default()
+        };
+        let mut buf = Buffer::from_reader(1, Cursor::new("abc"));
+        buf.diagnostics.0.push(Diagnostic {
+            bounds: (1, 2),
+            severity: DiagnosticSeverity::WARNING,
+            message: "unused".into(),
+            original: original.clone(),
+        });
+        buf.insert(0, "\n");
+
+        // the server gets back its code and data, at the moved range
+        let sent = buf.diagnostics.0[0].to_lsp(&buf);
+        assert_eq!(
+            sent.range,
+            Range::new(Position::new(1, 1), Position::new(1, 2))
+        );
+        assert_eq!(
+            sent,
+            lsp_types::Diagnostic {
+                range: sent.range,
+                ..original
+            }
+        );
+    }
+
+    #[test]
+    fn insert_keeping_cursor() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("abcd"));
+        buf.move_cursor(Movement::Index(2), false);
+        buf.insert_keeping_cursor(2, "xy", Gravity::Left);
+        assert_eq!(buf.text(), "abxycd");
+        assert_eq!(buf.cursor().head, 2);
+        buf.insert_keeping_cursor(2, "z", Gravity::Right);
+        assert_eq!(buf.text(), "abzxycd");
+        assert_eq!(buf.cursor().head, 3);
+
+        // before and after the cursor, whatever the gravity
+        buf.insert_keeping_cursor(0, "_", Gravity::Left);
+        assert_eq!(buf.cursor().head, 4);
+        buf.insert_keeping_cursor(6, "_", Gravity::Right);
+        assert_eq!(buf.cursor().head, 4);
+
+        // a selection starting at the insertion point
+        buf.move_cursor(Movement::Index(6), true);
+        buf.insert_keeping_cursor(4, "-", Gravity::Left);
+        assert_eq!(buf.cursor().tail, 4);
+        assert_eq!(buf.cursor().head, 7);
+        buf.insert_keeping_cursor(4, "+", Gravity::Right);
+        assert_eq!(buf.cursor().tail, 5);
+        assert_eq!(buf.cursor().head, 8);
+        assert_eq!(buf.text_slice(5..8).unwrap(), "-xy");
+    }
+
+    #[test]
+    fn remove_keeping_cursor() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("abcdef"));
+        buf.move_cursor(Movement::Index(4), false);
+        assert!(buf.remove_keeping_cursor(0, 2).is_some());
+        assert_eq!(buf.text(), "cdef");
+        assert_eq!(buf.cursor().head, 2);
+        // after the cursor
+        buf.remove_keeping_cursor(3, 4);
+        assert_eq!(buf.text(), "cde");
+        assert_eq!(buf.cursor().head, 2);
+        // around the cursor
+        buf.remove_keeping_cursor(1, 3);
+        assert_eq!(buf.text(), "c");
+        assert_eq!(buf.cursor().head, 1);
+        assert!(buf.remove_keeping_cursor(1, 1).is_none());
+
+        // one undo step per removal, the changes go to the language server
+        assert_eq!(buf.take_changes().map(|c| c.len()), Some(3));
+        buf.undo();
+        assert_eq!(buf.text(), "cde");
+        buf.undo();
+        assert_eq!(buf.text(), "cdef");
+    }
+
+    #[test]
+    fn move_lines() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("a\nbc\nd"));
+        assert!(buf.move_lines((0, 0), Direction::Up).is_none());
+        assert!(buf.move_lines((2, 2), Direction::Down).is_none());
+
+        buf.move_cursor(Movement::Index(3), false);
+        buf.do_action(Action::MoveLines(Direction::Up));
+        assert_eq!(buf.text(), "bc\na\nd");
+        assert_eq!(buf.cursor().head, 1);
+
+        // the last line has no trailing newline
+        buf.do_action(Action::MoveLines(Direction::Down));
+        buf.do_action(Action::MoveLines(Direction::Down));
+        assert_eq!(buf.text(), "a\nd\nbc");
+        assert_eq!(buf.cursor().head, 5);
+
+        // a selection of two lines
+        buf.move_cursor(Movement::Index(0), false);
+        buf.move_cursor(Movement::Index(3), true);
+        buf.do_action(Action::MoveLines(Direction::Down));
+        assert_eq!(buf.text(), "bc\na\nd");
+        assert_eq!((buf.cursor().tail, buf.cursor().head), (3, 6));
+
+        buf.do_action(Action::Undo);
+        assert_eq!(buf.text(), "a\nd\nbc");
+    }
+
+    #[test]
+    fn duplicate_lines() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("a\nbc\nd"));
+        buf.move_cursor(Movement::Index(3), false);
+        buf.duplicate_lines(1, 1);
+        assert_eq!(buf.text(), "a\nbc\nbc\nd");
+        assert_eq!(buf.cursor().head, 6);
+
+        // last line without a trailing newline
+        buf.move_cursor(Movement::Index(9), false);
+        buf.do_action(Action::DuplicateLines);
+        assert_eq!(buf.text(), "a\nbc\nbc\nd\nd");
+        assert_eq!(buf.cursor().head, 11);
+
+        buf.move_cursor(Movement::Index(0), false);
+        buf.move_cursor(Movement::Index(2), true);
+        buf.do_action(Action::DuplicateLines);
+        assert_eq!(buf.text(), "a\na\nbc\nbc\nd\nd");
+        buf.do_action(Action::Undo);
+        assert_eq!(buf.text(), "a\nbc\nbc\nd\nd");
+    }
+
+    #[test]
+    fn pending_changes() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("ab\ncd"));
+        buf.insert(4, "x");
+        buf.remove_chars((1, 4));
+        let changes = buf.take_changes().unwrap();
+        let ranges = changes
+            .iter()
+            .map(|c| {
+                let range = c.range.unwrap();
+                (
+                    (range.start.line, range.start.character),
+                    (range.end.line, range.end.character),
+                    c.range_length,
+                    c.text.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![
+                ((1, 1), (1, 1), Some(0), "x"),
+                ((0, 1), (1, 1), Some(3), ""),
+            ]
+        );
+        assert_eq!(buf.take_changes(), Some(vec![]));
+
+        // columns and lengths count UTF-16 code units, 🦀 is a surrogate pair
+        let mut buf = Buffer::from_reader(1, Cursor::new("a🦀b\n🦀"));
+        buf.insert(3, "x");
+        buf.remove_chars((1, 2));
+        buf.remove_chars((4, 5));
+        let changes = buf.take_changes().unwrap();
+        let ranges = changes
+            .iter()
+            .map(|c| {
+                let range = c.range.unwrap();
+                (
+                    (range.start.line, range.start.character),
+                    (range.end.line, range.end.character),
+                    c.range_length,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![
+                ((0, 3), (0, 3), Some(0)),
+                ((0, 1), (0, 3), Some(2)),
+                ((1, 0), (1, 2), Some(2))
+            ]
+        );
+
+        for _ in 0..=MAX_PENDING_CHANGES {
+            buf.insert(0, "a");
+        }
+        assert_eq!(buf.take_changes(), None);
+        assert_eq!(buf.take_changes(), Some(vec![]));
+    }
+
+    #[test]
+    fn apply_edits() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("let a = 1;\nf(a, a);"));
+        let edit = |line, start, end| TextEdit {
+            range: Range::new(Position::new(line, start), Position::new(line, end)),
+            new_text: "abc".to_string(),
+        };
+        // in any order, all relative to the original text
+        buf.apply_edits(&[edit(1, 2, 3), edit(0, 4, 5), edit(1, 5, 6)]);
+        assert_eq!(buf.text(), "let abc = 1;\nf(abc, abc);");
+        buf.undo();
+        assert_eq!(buf.text(), "let a = 1;\nf(a, a);");
+    }
+
+    #[test]
+    fn snippet_stops() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("use a;\nx"));
+        let edit = |line, start, end, text: &str| TextEdit {
+            range: Range::new(Position::new(line, start), Position::new(line, end)),
+            new_text: text.to_string(),
+        };
+        // an import added above the completion
+        let edits = [edit(1, 0, 1, "f(, b)"), edit(0, 0, 0, "use b;\n")];
+        let at = buf.edited_start(&edits, 0);
+        buf.apply_edits(&edits);
+        assert_eq!(buf.text(), "use b;\nuse a;\nf(, b)");
+        assert_eq!(at, 14);
+
+        buf.start_snippet(at, &[(2, 2), (4, 5), (6, 6)]);
+        assert_eq!((buf.cursor().tail, buf.cursor().head), (16, 16));
+        // typing moves the stops after it
+        buf.do_action(Action::Insert("aa".into()));
+        assert!(buf.next_stop());
+        assert_eq!((buf.cursor().tail, buf.cursor().head), (20, 21));
+        buf.do_action(Action::Insert("c".into()));
+        assert!(buf.next_stop());
+        assert_eq!(buf.cursor().head, 22);
+        assert_eq!(buf.text(), "use b;\nuse a;\nf(aa, c)");
+        assert!(!buf.next_stop());
+
+        buf.start_snippet(0, &[(0, 0), (1, 1)]);
+        assert!(buf.end_snippet());
+        assert!(!buf.next_stop());
+    }
+
+    #[test]
+    fn save_point() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("ab"));
+        assert!(!buf.is_modified());
+        buf.insert(2, "c");
+        assert!(buf.is_modified());
+        buf.mark_saved();
+        assert!(!buf.is_modified());
+        buf.undo();
+        assert!(buf.is_modified());
+        buf.redo();
+        assert!(!buf.is_modified());
+        // the saved text can't be reached anymore
+        buf.undo();
+        buf.insert(0, "d");
+        buf.undo();
+        assert!(buf.is_modified());
+    }
+
+    #[test]
+    fn add_cursors() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("abcd\nab\nabcd"));
+        buf.move_cursor(Movement::Index(3), false);
+        assert!(!buf.add_cursor_on_adjacent_line(Direction::Up));
+        assert!(buf.add_cursor_on_adjacent_line(Direction::Down));
+        // the column of the primary cursor, past the end of the short line
+        assert!(buf.add_cursor_on_adjacent_line(Direction::Down));
+        assert!(!buf.add_cursor_on_adjacent_line(Direction::Down));
+        assert_eq!(buf.secondary_cursors().len(), 2);
+
+        buf.do_action(Action::Insert("x".into()));
+        assert_eq!(buf.text(), "abcxd\nabx\nabcxd");
+        assert_eq!(buf.cursor().head, 4);
+
+        // a click adds the primary cursor
+        assert!(buf.add_selection((0, 0), true));
+        assert!(!buf.add_selection((0, 0), true));
+        assert_eq!(buf.cursor().head, 0);
+        assert_eq!(buf.secondary_cursors().len(), 3);
+        buf.do_action(Action::Insert("y".into()));
+        assert_eq!(buf.text(), "yabcxyd\nabxy\nabcxyd");
+
+        // a selection can touch another one, not overlap it
+        let mut buf = Buffer::from_reader(1, Cursor::new("foofoo"));
+        buf.set_selections(vec![(0, 3)]);
+        assert!(!buf.add_selection((2, 5), false));
+        assert!(!buf.add_selection((3, 3), false));
+        assert!(buf.add_selection((3, 6), false));
+        buf.do_action(Action::Insert("x".into()));
+        assert_eq!(buf.text(), "xx");
+    }
+
+    #[test]
+    fn reload() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("one\ntwo\nthree"));
+        buf.set_selections(vec![(12, 12)]);
+        assert!(buf.reload("one\ntwo\nthree").is_none());
+        buf.reload("one\n2\nthree").unwrap();
+        assert_eq!(buf.text(), "one\n2\nthree");
+        // same char of the last line
+        assert_eq!(buf.cursor().head, 10);
+        assert!(!buf.is_modified());
+        buf.undo();
+        assert_eq!(buf.text(), "one\ntwo\nthree");
+    }
+
+    #[test]
+    fn word_bounds_at() {
+        let buf = Buffer::from_reader(1, Cursor::new("let a_b = c(d);  \n\nx"));
+        assert_eq!(buf.word_bounds_at(0), (0, 3));
+        assert_eq!(buf.word_bounds_at(5), (4, 7));
+        assert_eq!(buf.word_bounds_at(3), (3, 4));
+        assert_eq!(buf.word_bounds_at(13), (13, 15));
+        // at the end of the line, the blanks before it
+        assert_eq!(buf.word_bounds_at(17), (15, 17));
+        assert_eq!(buf.word_bounds_at(18), (18, 18));
+        assert_eq!(buf.word_bounds_at(20), (19, 20));
+    }
+
+    #[test]
+    fn paste() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("fn a() {\n    \n}"));
+        buf.move_cursor(Movement::Index(13), false);
+        let text = "if b {\n            c();\n\n        }";
+        assert_eq!(buf.reindent_paste(text), "if b {\n        c();\n\n    }");
+        buf.do_action(Action::Paste("if b {\n\tc();\n}".into()));
+        assert_eq!(buf.text(), "fn a() {\n    if b {\n    \tc();\n    }\n}");
+        buf.do_action(Action::Undo);
+
+        buf.pairs = vec![Pair::new("(", ")")];
+        buf.do_action(Action::PasteRaw("(".into()));
+        assert_eq!(buf.text(), "fn a() {\n    (\n}");
+        buf.do_action(Action::PasteRaw("x\n  y".into()));
+        assert_eq!(buf.text(), "fn a() {\n    (x\n  y\n}");
+    }
+
+    #[test]
+    fn completion_window() {
+        let mut buf = Buffer::from_reader(1, Cursor::new(""));
+        buf.completions = (0..10)
+            .map(|n| LspCompletion {
+                original_item: Default::default(),
+                label: n.to_string(),
+                data: CompletionData::Simple(n.to_string()),
+                commit_characters: vec![],
+                stops: vec![],
+                kind: None,
+            })
+            .collect();
+        assert_eq!(buf.completion_window(4), (0, 4));
+        buf.select_completion(5);
+        assert_eq!(buf.completion_window(4), (3, 7));
+        buf.select_completion(4);
+        assert_eq!(buf.completion_window(4), (6, 10));
+        assert_eq!(buf.completion_window(20), (0, 10));
+        // wraps to the top
+        buf.select_completion(1);
+        assert_eq!(buf.completion_selected, 0);
+        assert_eq!(buf.completion_window(4), (0, 4));
+    }
+
+    #[test]
+    fn fuzzy_completions() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("let a = nw"));
+        buf.move_cursor(Movement::Index(10), false);
+        let completion = |label: &str, sort_text: &str| LspCompletion {
+            original_item: CompletionItem {
+                sort_text: Some(sort_text.to_string()),
+                ..Default::default()
+            },
+            label: label.to_string(),
+            data: CompletionData::Simple(label.to_string()),
+            commit_characters: vec![],
+            stops: vec![],
+            kind: None,
+        };
+        let completions = || {
+            vec![
+                completion("network", "1"),
+                completion("other", "0"),
+                completion("new", "3"),
+                completion("now", "2"),
+            ]
+        };
+        buf.completions = completions();
+        let labels = |buf: &Buffer| {
+            buf.sorted_completions()
+                .unwrap()
+                .iter()
+                .map(|c| c.label.clone())
+                .collect::<Vec<_>>()
+        };
+        // `new` and `now` tie, the server order decides
+        assert_eq!(labels(&buf), vec!["now", "new", "network"]);
+        buf.select_completion(-1);
+        assert_eq!(buf.selected_completion().unwrap().unwrap().label, "network");
+
+        // typing shrinks the list under the selection
+        buf.insert(10, "o");
+        assert_eq!(labels(&buf), vec!["network"]);
+        assert_eq!(buf.completion_selected, 0);
+        assert_eq!(buf.selected_completion().unwrap().unwrap().label, "network");
+
+        // nothing typed yet
+        buf.move_cursor(Movement::Index(8), false);
+        buf.completions = completions();
+        assert_eq!(labels(&buf), vec!["other", "network", "now", "new"]);
+    }
+
+    #[test]
+    fn pairs() {
+        let mut buf = Buffer::from_reader(1, Cursor::new(""));
+        buf.pairs = vec![Pair::new("<", ">"), Pair::new("'", "'")];
+        buf.do_action(Action::Insert("<".into()));
+        assert_eq!(buf.text(), "<>");
+        buf.do_action(Action::Insert("T".into()));
+        buf.do_action(Action::Insert(">".into()));
+        assert_eq!(buf.text(), "<T>");
+        assert_eq!(buf.cursor().head, 3);
+        // not part of the set
+        buf.do_action(Action::Insert("(".into()));
+        assert_eq!(buf.text(), "<T>(");
+
+        // an apostrophe is not closed
+        let mut buf = Buffer::from_reader(1, Cursor::new("don"));
+        buf.pairs = vec![Pair::new("'", "'")];
+        buf.move_cursor(Movement::Index(3), false);
+        buf.do_action(Action::Insert("'".into()));
+        assert_eq!(buf.text(), "don'");
+
+        // the selection is wrapped and stays selected
+        let mut buf = Buffer::from_reader(1, Cursor::new("a b"));
+        buf.pairs = vec![Pair::new("(", ")")];
+        buf.move_cursor(Movement::Index(2), false);
+        buf.move_cursor(Movement::Index(3), true);
+        buf.do_action(Action::Insert("(".into()));
+        assert_eq!(buf.text(), "a (b)");
+        assert_eq!((buf.cursor().tail, buf.cursor().head), (3, 4));
+        buf.do_action(Action::Undo);
+        assert_eq!(buf.text(), "a b");
+    }
+
+    #[test]
+    fn triple_quotes() {
+        let mut buf = Buffer::from_reader(1, Cursor::new(""));
+        buf.pairs = vec![Pair::new("\"", "\""), Pair::new("\"\"\"", "\"\"\"")];
+        for _ in 0..3 {
+            buf.do_action(Action::Insert("\"".into()));
+        }
+        assert_eq!(buf.text(), "\"\"\"\"\"\"");
+        assert_eq!(buf.cursor().head, 3);
+    }
+
+    #[test]
+    fn delete_line() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("a\n  b\n  c\nd"));
+        buf.move_cursor(Movement::Index(4), false);
+        buf.do_action(Action::DeleteLine);
+        assert_eq!(buf.text(), "a\n  c\nd");
+        assert_eq!(buf.cursor().head, 4);
+
+        buf.move_cursor(Movement::Index(7), false);
+        buf.do_action(Action::DeleteLine);
+        assert_eq!(buf.text(), "a\n  c");
+        assert_eq!(buf.cursor().head, 4);
+        buf.do_action(Action::Undo);
+        assert_eq!(buf.text(), "a\n  c\nd");
+
+        let mut buf = Buffer::from_reader(1, Cursor::new("only"));
+        buf.do_action(Action::DeleteLine);
+        assert_eq!(buf.text(), "");
+        assert_eq!(buf.cursor().head, 0);
+    }
+
+    #[test]
+    fn newline_indent() {
+        let buf = Buffer::from_reader(1, Cursor::new("fn a() {\n\tb\n    c"));
+        assert_eq!(buf.newline_indent(8, &LspLang::Rust), "    ");
+        assert_eq!(buf.newline_indent(7, &LspLang::Rust), "");
+        assert_eq!(buf.newline_indent(11, &LspLang::Rust), "\t");
+        assert_eq!(buf.newline_indent(14, &LspLang::Rust), "  ");
+
+        let text = "if x:\n    y = 1\n    return y\n    \n";
+        let buf = Buffer::from_reader(1, Cursor::new(text));
+        assert_eq!(buf.newline_indent(5, &LspLang::Python), "    ");
+        assert_eq!(buf.newline_indent(15, &LspLang::Python), "    ");
+        assert_eq!(buf.newline_indent(28, &LspLang::Python), "");
+        // a blank line keeps its indentation, no more
+        assert_eq!(buf.newline_indent(33, &LspLang::Python), "    ");
+        assert_eq!(buf.newline_indent(5, &LspLang::Rust), "");
+    }
+
+    #[test]
+    fn leading_whitespace() {
+        let buf = Buffer::from_reader(1, Cursor::new("a\n\t\tb\n    c {\n  \t\n"));
+        assert_eq!(buf.leading_whitespace(0), "");
+        assert_eq!(buf.leading_whitespace(1), "\t\t");
+        assert_eq!(buf.leading_whitespace(2), "    ");
+        assert_eq!(buf.leading_whitespace(3), "  \t");
+        assert_eq!(buf.leading_whitespace(4), "");
+        assert_eq!(buf.leading_whitespace(10), "");
+    }
+
+    #[test]
+    fn mixed_indentation() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("a\n\tb\n\tc\n    d\n \te"));
+        let indentation = buf.check_indentation();
+        assert_eq!(indentation.tabs, 2);
+        assert_eq!(indentation.spaces, 1);
+        assert_eq!(indentation.inconsistent_lines, vec![3, 4]);
+        assert!(indentation.use_tabs());
+
+        buf.do_action(Action::Retab);
+        assert_eq!(buf.text(), "a\n\tb\n\tc\n\td\n\te");
+        assert!(!buf.check_indentation().is_mixed());
+        buf.do_action(Action::Undo);
+        assert_eq!(buf.text(), "a\n\tb\n\tc\n    d\n \te");
+    }
+
+    #[test]
+    fn indentation_follows_edits() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("a\n\tb\n\tc\n    d\n \te"));
+        buf.indentation = Some(buf.check_indentation());
+
+        // the spaces of `d` become a tab
+        let start = buf.line_bounds(3).0;
+        buf.remove_keeping_cursor(start, start + 4);
+        buf.insert_raw(start, "\t");
+        let indentation = buf.indentation.clone().unwrap();
+        assert_eq!(indentation.inconsistent_lines, vec![4]);
+        assert_eq!((indentation.tabs, indentation.spaces), (3, 0));
+
+        // lines after an inserted line move down
+        buf.insert_raw(0, "\tz\n");
+        assert_eq!(
+            buf.indentation.as_ref().unwrap().inconsistent_lines,
+            vec![5]
+        );
+        assert_eq!(buf.indentation, Some(buf.check_indentation()));
+
+        // lines after a removed one move up
+        let end = buf.line_bounds(1).0;
+        buf.remove_keeping_cursor(0, end);
+        assert_eq!(buf.indentation, Some(buf.check_indentation()));
+        assert_eq!(
+            buf.indentation.as_ref().unwrap().inconsistent_lines,
+            vec![4]
+        );
+    }
+
+    #[test]
+    fn home_end() {
+        let mut b = Buffer::from_reader(1, Cursor::new("a\n  \tbc d\n"));
+        b.move_cursor(Movement::Index(8), false);
+        b.move_cursor(Movement::LineStart, false);
+        assert_eq!(b.cursor().head, 5);
+        b.move_cursor(Movement::LineStart, false);
+        assert_eq!(b.cursor().head, 2);
+        b.move_cursor(Movement::LineStart, false);
+        assert_eq!(b.cursor().head, 5);
+        b.move_cursor(Movement::LineEnd, true);
+        assert_eq!(b.cursor().head, 9);
+        assert_eq!(b.cursor().tail, 5);
+
+        b.move_cursor(Movement::Index(10), false);
+        b.move_cursor(Movement::LineStart, false);
+        assert_eq!(b.cursor().head, 10);
+        b.move_cursor(Movement::LineEnd, false);
+        assert_eq!(b.cursor().head, 10);
+    }
+
+    #[test]
+    fn move_lines() {
+        let mut b = Buffer::from_reader(1, Cursor::new("abc\nd\nefgh\nij"));
+        b.move_cursor(Movement::Index(2), false);
+        b.move_cursor(Movement::Lines(2), false);
+        assert_eq!(b.cursor().head, 8);
+        b.move_cursor(Movement::Lines(10), false);
+        assert_eq!(b.cursor().head, 13);
+        b.move_cursor(Movement::Lines(-2), false);
+        assert_eq!(b.cursor().head, 5);
+        b.move_cursor(Movement::Lines(-10), true);
+        assert_eq!(b.cursor().head, 1);
+        assert_eq!(b.cursor().tail, 5);
+    }
+
+    #[test]
+    fn movement_last_line() {
+        let mut b = Buffer::from_reader(1, Cursor::new("abc\nde"));
+        b.move_cursor(Movement::Index(3), false);
+        b.move_cursor(Movement::Down, false);
+        assert_eq!(b.cursor().head, 6);
+        b.move_cursor(Movement::Down, false);
+        assert_eq!(b.cursor().head, 6);
+
+        let mut b = Buffer::from_reader(1, Cursor::new("abc\nde\n"));
+        assert_eq!(b.last_line(), 2);
+        b.move_cursor(Movement::Index(2), false);
+        b.move_cursor(Movement::Down, false);
+        assert_eq!(b.cursor().head, 6);
+        b.move_cursor(Movement::Down, false);
+        assert_eq!(b.cursor().head, 7);
+        b.move_cursor(Movement::Down, false);
+        assert_eq!(b.cursor().head, 7);
+        b.move_cursor(Movement::Up, false);
+        assert_eq!(b.cursor().head, 4);
+
+        let mut b = Buffer::from_reader(1, Cursor::new("ab\r\ncd\r\n"));
+        b.move_cursor(Movement::Index(2), false);
+        b.move_cursor(Movement::Down, false);
+        assert_eq!(b.cursor().head, 6);
+        b.move_cursor(Movement::Down, false);
+        assert_eq!(b.cursor().head, 8);
     }
 }
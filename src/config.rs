@@ -1,11 +1,70 @@
+use std::collections::BTreeMap;
+
+use crate::status::error;
 use crate::LspLang;
+use druid::{Code, KeyEvent, Modifiers};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize)]
 pub struct Config {
+    /// name of a theme of `THEMES_DIR`, cycled by the `next_theme` command
+    #[serde(default = "default_theme")]
+    pub theme: String,
+    /// reopen the files of the last session, with their cursors, on a launch without a file
+    #[serde(default)]
+    pub restore_session: bool,
     pub lsp: LspConfig,
     pub render: RenderConfig,
+    #[serde(default)]
+    pub scroll: ScrollConfig,
+    #[serde(default)]
+    pub edit: EditConfig,
+    /// indentation of the languages without their own
+    #[serde(default)]
+    pub indent: IndentConfig,
+    #[serde(default)]
+    pub tree: TreeConfig,
+    /// chords of the commands, by command name
+    #[serde(default)]
+    pub keymap: KeymapConfig,
     pub extensions: Vec<Extension>,
+    /// brackets and quotes closed when typed, per language
+    #[serde(default = "default_pairs")]
+    pub pairs: Vec<LangPairs>,
+    /// indentation per language, over `indent`
+    #[serde(default = "default_languages")]
+    pub languages: Vec<LangIndent>,
+}
+
+impl Config {
+    /// Pairs of `lang`, the plain text ones for a language without its own set.
+    pub fn pairs_for(&self, lang: &LspLang) -> Vec<Pair> {
+        self.pairs
+            .iter()
+            .find(|p| &p.lang == lang)
+            .or_else(|| self.pairs.iter().find(|p| p.lang == LspLang::PlainText))
+            .map(|p| p.pairs.clone())
+            .unwrap_or_default()
+    }
+
+    /// Indentation of `lang`, its own settings over the default ones.
+    pub fn indent_for(&self, lang: &LspLang) -> IndentConfig {
+        let mut indent = self.indent.clone();
+        if let Some(own) = self.languages.iter().find(|l| &l.lang == lang) {
+            if let Some(width) = own.indent_width {
+                indent.indent_width = width;
+            }
+            if let Some(use_spaces) = own.use_spaces {
+                indent.use_spaces = use_spaces;
+            }
+            if let Some(trim) = own.trim_trailing_whitespace {
+                indent.trim_trailing_whitespace = trim;
+            }
+        }
+        // tab stops are computed with it
+        indent.indent_width = indent.indent_width.max(1);
+        indent
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -34,27 +93,508 @@ impl Default for Config {
             lang: LspLang::Json,
         });
         Self {
+            theme: default_theme(),
+            restore_session: false,
             lsp: LspConfig::default(),
             render: RenderConfig::default(),
+            scroll: ScrollConfig::default(),
+            edit: EditConfig::default(),
+            indent: IndentConfig::default(),
+            tree: TreeConfig::default(),
+            keymap: KeymapConfig::default(),
             extensions,
+            pairs: default_pairs(),
+            languages: default_languages(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Pair {
+    pub open: String,
+    pub close: String,
+}
+
+impl Pair {
+    pub fn new(open: &str, close: &str) -> Self {
+        Self {
+            open: open.to_string(),
+            close: close.to_string(),
         }
     }
 }
 
 #[derive(Deserialize, Serialize)]
+pub struct LangPairs {
+    pub lang: LspLang,
+    pub pairs: Vec<Pair>,
+}
+
+fn default_pairs() -> Vec<LangPairs> {
+    let brackets = || {
+        vec![
+            Pair::new("(", ")"),
+            Pair::new("[", "]"),
+            Pair::new("{", "}"),
+        ]
+    };
+    let with = |extra: &[(&str, &str)]| {
+        let mut pairs = brackets();
+        pairs.extend(extra.iter().map(|(open, close)| Pair::new(open, close)));
+        pairs
+    };
+    vec![
+        LangPairs {
+            lang: LspLang::PlainText,
+            pairs: with(&[("\"", "\""), ("'", "'")]),
+        },
+        // `'` starts lifetimes
+        LangPairs {
+            lang: LspLang::Rust,
+            pairs: with(&[("\"", "\"")]),
+        },
+        LangPairs {
+            lang: LspLang::Json,
+            pairs: with(&[("\"", "\"")]),
+        },
+        LangPairs {
+            lang: LspLang::Python,
+            pairs: with(&[
+                ("\"", "\""),
+                ("'", "'"),
+                ("\"\"\"", "\"\"\""),
+                ("'''", "'''"),
+            ]),
+        },
+    ]
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
 pub struct RenderConfig {
     pub text_scale: f64,
+    pub completion_max_width: f64,
+    /// completions visible at once in the popup
+    pub completion_rows: usize,
+    pub icons: bool,
+    /// warn when a file mixes tabs and spaces in its indentation
+    pub indent_warning: bool,
+    /// dim the selection and hide the caret when the editor does not have the focus
+    pub dim_unfocused: bool,
+    /// columns marked by a vertical line
+    pub rulers: Vec<usize>,
+    /// header lines of the definitions around the top of the view pinned over the text,
+    /// 0 to disable
+    pub sticky_lines: usize,
+    /// width of the caret in pixels, its color is the `ui.cursor` theme scope
+    pub cursor_width: f64,
+    /// dots for the spaces and arrows for the tabs, in the `ui.whitespace` color
+    pub show_whitespace: bool,
+    /// a vertical line at each indentation level, in the `ui.indent-guide` color
+    pub indent_guides: bool,
 }
 
 impl Default for RenderConfig {
     fn default() -> Self {
-        Self { text_scale: 1.0 }
+        Self {
+            text_scale: 1.0,
+            completion_max_width: 400.0,
+            completion_rows: 8,
+            icons: true,
+            indent_warning: true,
+            dim_unfocused: true,
+            rulers: vec![],
+            sticky_lines: 3,
+            cursor_width: 1.0,
+            show_whitespace: false,
+            indent_guides: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct ScrollConfig {
+    /// natural scrolling, the content follows the fingers
+    pub invert: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct EditConfig {
+    /// `paste` fits the indentation of pasted lines to the cursor line (the default),
+    /// `paste_raw` always pastes the text as it is
+    pub paste_reindent: bool,
+    /// keys accepting the selected completion, without `enter` it always breaks the line
+    pub completion_accept: Vec<AcceptKey>,
+    /// files of more bytes are opened without syntax highlighting and language server
+    pub large_file_size: usize,
+}
+
+impl Default for EditConfig {
+    fn default() -> Self {
+        Self {
+            paste_reindent: true,
+            completion_accept: vec![AcceptKey::Enter, AcceptKey::Tab],
+            large_file_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct IndentConfig {
+    /// columns of an indentation level, a tab is as wide
+    pub indent_width: usize,
+    /// `Tab` and new indentation levels insert spaces, a tab otherwise
+    pub use_spaces: bool,
+    /// spaces and tabs at the end of the lines are removed when saving
+    pub trim_trailing_whitespace: bool,
+}
+
+impl Default for IndentConfig {
+    fn default() -> Self {
+        Self {
+            indent_width: 4,
+            use_spaces: true,
+            trim_trailing_whitespace: false,
+        }
+    }
+}
+
+/// Settings of a language, the missing ones come from `Config::indent`.
+#[derive(Deserialize, Serialize)]
+pub struct LangIndent {
+    pub lang: LspLang,
+    pub indent_width: Option<usize>,
+    pub use_spaces: Option<bool>,
+    pub trim_trailing_whitespace: Option<bool>,
+}
+
+impl LangIndent {
+    fn spaces(lang: LspLang, width: usize) -> Self {
+        Self {
+            lang,
+            indent_width: Some(width),
+            use_spaces: Some(true),
+            trim_trailing_whitespace: None,
+        }
+    }
+}
+
+fn default_languages() -> Vec<LangIndent> {
+    vec![
+        LangIndent::spaces(LspLang::Rust, 4),
+        LangIndent::spaces(LspLang::Python, 4),
+        LangIndent::spaces(LspLang::Json, 2),
+    ]
+}
+
+fn default_theme() -> String {
+    "gruvbox".into()
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct TreeConfig {
+    /// files and folders whose name starts with a `.`, toggled with `.` in the tree
+    pub show_hidden: bool,
+    /// names never listed, `*` matches any chars and `?` one
+    pub hidden: Vec<String>,
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        Self {
+            show_hidden: false,
+            hidden: vec!["target".into(), "node_modules".into()],
+        }
+    }
+}
+
+/// Keys pressed together, written like `ctrl+shift+f`. The key is the physical one, named
+/// after the US layout: letters, digits, `f1`..`f12`, `space`, `tab`, `up`, `.`, etc.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chord {
+    pub mods: Modifiers,
+    pub code: Code,
+}
+
+impl Chord {
+    pub fn parse(chord: &str) -> Result<Chord, String> {
+        let mut mods = Modifiers::empty();
+        let mut parts: Vec<&str> = chord.split('+').map(str::trim).collect();
+        let key = parts.pop().filter(|k| !k.is_empty());
+        for part in parts {
+            mods |= match part.to_lowercase().as_str() {
+                "ctrl" | "control" => Modifiers::CONTROL,
+                "shift" => Modifiers::SHIFT,
+                "alt" => Modifiers::ALT,
+                "meta" | "cmd" | "super" => Modifiers::META,
+                _ => return Err(format!("unknown modifier `{}` in `{}`", part, chord)),
+            };
+        }
+        let key = key.ok_or_else(|| format!("no key in `{}`", chord))?;
+        let code = key_code(&key.to_lowercase())
+            .ok_or_else(|| format!("unknown key `{}` in `{}`", key, chord))?;
+        Ok(Chord { mods, code })
+    }
+
+    /// Whether `key` is this chord, with exactly its modifiers.
+    pub fn matches(&self, key: &KeyEvent) -> bool {
+        let mods = Modifiers::CONTROL | Modifiers::SHIFT | Modifiers::ALT | Modifiers::META;
+        key.code == self.code && key.mods & mods == self.mods
+    }
+}
+
+const LETTERS: [Code; 26] = [
+    Code::KeyA,
+    Code::KeyB,
+    Code::KeyC,
+    Code::KeyD,
+    Code::KeyE,
+    Code::KeyF,
+    Code::KeyG,
+    Code::KeyH,
+    Code::KeyI,
+    Code::KeyJ,
+    Code::KeyK,
+    Code::KeyL,
+    Code::KeyM,
+    Code::KeyN,
+    Code::KeyO,
+    Code::KeyP,
+    Code::KeyQ,
+    Code::KeyR,
+    Code::KeyS,
+    Code::KeyT,
+    Code::KeyU,
+    Code::KeyV,
+    Code::KeyW,
+    Code::KeyX,
+    Code::KeyY,
+    Code::KeyZ,
+];
+
+const DIGITS: [Code; 10] = [
+    Code::Digit0,
+    Code::Digit1,
+    Code::Digit2,
+    Code::Digit3,
+    Code::Digit4,
+    Code::Digit5,
+    Code::Digit6,
+    Code::Digit7,
+    Code::Digit8,
+    Code::Digit9,
+];
+
+const FUNCTIONS: [Code; 12] = [
+    Code::F1,
+    Code::F2,
+    Code::F3,
+    Code::F4,
+    Code::F5,
+    Code::F6,
+    Code::F7,
+    Code::F8,
+    Code::F9,
+    Code::F10,
+    Code::F11,
+    Code::F12,
+];
+
+fn key_code(key: &str) -> Option<Code> {
+    let mut chars = key.chars();
+    let code = match (chars.next(), chars.next()) {
+        (Some(c @ 'a'..='z'), None) => LETTERS[c as usize - 'a' as usize],
+        (Some(c @ '0'..='9'), None) => DIGITS[c as usize - '0' as usize],
+        (Some('f'), Some('0'..='9')) => {
+            let n: usize = key[1..].parse().ok()?;
+            *FUNCTIONS.get(n.checked_sub(1)?)?
+        }
+        _ => match key {
+            "space" => Code::Space,
+            "tab" => Code::Tab,
+            "enter" => Code::Enter,
+            "escape" | "esc" => Code::Escape,
+            "backspace" => Code::Backspace,
+            "delete" | "del" => Code::Delete,
+            "insert" => Code::Insert,
+            "up" => Code::ArrowUp,
+            "down" => Code::ArrowDown,
+            "left" => Code::ArrowLeft,
+            "right" => Code::ArrowRight,
+            "home" => Code::Home,
+            "end" => Code::End,
+            "pageup" => Code::PageUp,
+            "pagedown" => Code::PageDown,
+            "." => Code::Period,
+            "," => Code::Comma,
+            ";" => Code::Semicolon,
+            "/" => Code::Slash,
+            "-" => Code::Minus,
+            "=" => Code::Equal,
+            "`" => Code::Backquote,
+            "[" => Code::BracketLeft,
+            "]" => Code::BracketRight,
+            _ => return None,
+        },
+    };
+    Some(code)
+}
+
+/// Chords of the commands of the editor. Those set in the config replace the default
+/// ones, a chord taken from another command unbinds it and an empty chord unbinds one.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "BTreeMap<String, String>", into = "BTreeMap<String, String>")]
+pub struct KeymapConfig {
+    bindings: BTreeMap<String, String>,
+    // parsed, the ones which failed are left out
+    chords: Vec<(Chord, String)>,
+}
+
+const DEFAULT_KEYMAP: &[(&str, &str)] = &[
+    ("completion", "ctrl+space"),
+    ("resolve_completion", "f1"),
+    ("next_symbol", "ctrl+down"),
+    ("previous_symbol", "ctrl+up"),
+    ("move_lines_down", "alt+down"),
+    ("move_lines_up", "alt+up"),
+    ("add_cursor_below", "ctrl+alt+down"),
+    ("add_cursor_above", "ctrl+alt+up"),
+    ("delete_word_backward", "ctrl+backspace"),
+    ("delete_word_forward", "ctrl+delete"),
+    ("delete_to_line_end", "ctrl+shift+end"),
+    ("next_buffer", "ctrl+tab"),
+    ("previous_buffer", "ctrl+shift+tab"),
+    ("code_actions", "ctrl+."),
+    ("rename", "f2"),
+    ("search", "ctrl+f"),
+    ("close", "ctrl+w"),
+    ("select_all_occurrences", "ctrl+shift+l"),
+    ("restart_language_server", "ctrl+alt+r"),
+    ("reveal_in_tree", "ctrl+shift+r"),
+    ("log", "ctrl+shift+u"),
+    ("retab", "ctrl+alt+i"),
+    ("select_next_occurrence", "ctrl+d"),
+    ("duplicate_lines", "ctrl+shift+d"),
+    ("next_theme", "ctrl+alt+t"),
+    ("delete_line", "ctrl+shift+k"),
+    ("undo", "ctrl+z"),
+    ("redo", "ctrl+shift+z"),
+    ("copy_html", "ctrl+shift+c"),
+    ("paste", "ctrl+v"),
+    ("paste_raw", "ctrl+shift+v"),
+    ("export", "ctrl+e"),
+    ("new", "ctrl+n"),
+    ("open_folder", "ctrl+shift+o"),
+    ("save", "ctrl+s"),
+    ("save_as", "ctrl+shift+s"),
+];
+
+impl KeymapConfig {
+    /// Command bound to `key`.
+    pub fn command(&self, key: &KeyEvent) -> Option<&str> {
+        self.chords
+            .iter()
+            .find(|(chord, _)| chord.matches(key))
+            .map(|(_, command)| command.as_str())
+    }
+
+    /// Chord bound to `command`, as written in the config.
+    pub fn chord(&self, command: &str) -> Option<&str> {
+        self.bindings
+            .get(command)
+            .map(String::as_str)
+            .filter(|c| !c.is_empty())
+    }
+}
+
+impl Default for KeymapConfig {
+    fn default() -> Self {
+        Self::from(BTreeMap::new())
+    }
+}
+
+impl From<BTreeMap<String, String>> for KeymapConfig {
+    fn from(config: BTreeMap<String, String>) -> Self {
+        let mut bindings: BTreeMap<String, String> = DEFAULT_KEYMAP
+            .iter()
+            .map(|(command, chord)| (command.to_string(), chord.to_string()))
+            .collect();
+        for (command, chord) in &config {
+            let parsed = Chord::parse(chord).ok();
+            bindings.retain(|other, other_chord| {
+                other == command
+                    || config.contains_key(other)
+                    || parsed.is_none()
+                    || Chord::parse(other_chord).ok() != parsed
+            });
+        }
+        bindings.extend(config);
+
+        let mut chords = vec![];
+        for (command, chord) in &bindings {
+            match Chord::parse(chord) {
+                Ok(parsed) => chords.push((parsed, command.clone())),
+                Err(e) if !chord.is_empty() => error(format!("keymap: {}", e)),
+                Err(_) => {}
+            }
+        }
+        Self { bindings, chords }
     }
 }
 
+impl From<KeymapConfig> for BTreeMap<String, String> {
+    fn from(keymap: KeymapConfig) -> Self {
+        keymap.bindings
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AcceptKey {
+    Enter,
+    Tab,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct LspConfig {
     pub servers: Vec<LspServer>,
+    /// diagnostics kept per buffer, the most severe first
+    #[serde(default = "default_max_diagnostics")]
+    pub max_diagnostics: usize,
+    /// completions kept per request, in the order ranked by the server
+    #[serde(default = "default_max_completions")]
+    pub max_completions: usize,
+    #[serde(default)]
+    pub trace: LspTrace,
+}
+
+/// What is printed of the exchanges with the servers, also sent as the server trace level.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LspTrace {
+    Off,
+    /// only the methods
+    Messages,
+    /// the raw json
+    Verbose,
+}
+
+impl Default for LspTrace {
+    fn default() -> Self {
+        LspTrace::Off
+    }
+}
+
+fn default_max_diagnostics() -> usize {
+    500
+}
+
+fn default_max_completions() -> usize {
+    200
 }
 
 #[derive(Deserialize, Serialize)]
@@ -79,6 +619,90 @@ impl Default for LspConfig {
             lang: LspLang::Python,
             command: vec!["pylsp".into()],
         });
-        Self { servers }
+        Self {
+            servers,
+            max_diagnostics: default_max_diagnostics(),
+            max_completions: default_max_completions(),
+            trace: LspTrace::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use druid::{Code, KbKey, KeyEvent, Modifiers};
+
+    use crate::config::{Chord, Config, IndentConfig, KeymapConfig};
+    use crate::LspLang;
+
+    fn key(mods: Modifiers, code: Code) -> KeyEvent {
+        let mut event = KeyEvent::for_test(mods, KbKey::Unidentified);
+        event.code = code;
+        event
+    }
+
+    #[test]
+    fn chords() {
+        let chord = Chord::parse("ctrl+Shift+f").unwrap();
+        assert_eq!(chord.mods, Modifiers::CONTROL | Modifiers::SHIFT);
+        assert_eq!(chord.code, Code::KeyF);
+        assert_eq!(Chord::parse("f12").unwrap().code, Code::F12);
+        assert_eq!(Chord::parse("alt + up").unwrap().code, Code::ArrowUp);
+        assert_eq!(Chord::parse("ctrl+.").unwrap().code, Code::Period);
+        assert!(Chord::parse("ctrl+").is_err());
+        assert!(Chord::parse("hyper+a").is_err());
+        assert!(Chord::parse("f13").is_err());
+
+        // the modifiers must be the same
+        let ctrl_f = key(Modifiers::CONTROL, Code::KeyF);
+        assert!(Chord::parse("ctrl+f").unwrap().matches(&ctrl_f));
+        assert!(!chord.matches(&ctrl_f));
+        assert!(chord.matches(&key(
+            Modifiers::CONTROL | Modifiers::SHIFT | Modifiers::CAPS_LOCK,
+            Code::KeyF
+        )));
+    }
+
+    #[test]
+    fn remapped() {
+        let ctrl_n = key(Modifiers::CONTROL, Code::KeyN);
+        let ctrl_space = key(Modifiers::CONTROL, Code::Space);
+        let keymap = KeymapConfig::default();
+        assert_eq!(keymap.command(&ctrl_n), Some("new"));
+        assert_eq!(keymap.command(&ctrl_space), Some("completion"));
+
+        let config = toml::from_str::<BTreeMap<String, String>>(
+            "completion = \"ctrl+n\"\nundo = \"\"\nsave = \"ctrl+nope\"",
+        )
+        .unwrap();
+        let keymap = KeymapConfig::from(config);
+        assert_eq!(keymap.command(&ctrl_n), Some("completion"));
+        // the chord was taken from `new`
+        assert_eq!(keymap.chord("new"), None);
+        assert_eq!(keymap.command(&ctrl_space), None);
+        assert_eq!(keymap.chord("undo"), None);
+        assert_eq!(keymap.command(&key(Modifiers::CONTROL, Code::KeyZ)), None);
+        assert_eq!(keymap.chord("redo"), Some("ctrl+shift+z"));
+    }
+
+    #[test]
+    fn indent_per_language() {
+        let mut config = Config::default();
+        assert_eq!(config.indent_for(&LspLang::Json).indent_width, 2);
+        assert_eq!(
+            config.indent_for(&LspLang::PlainText),
+            IndentConfig::default()
+        );
+
+        config.indent.trim_trailing_whitespace = true;
+        config.languages = vec![toml::from_str("lang = \"Python\"\nuse_spaces = false").unwrap()];
+        let python = config.indent_for(&LspLang::Python);
+        assert!(!python.use_spaces);
+        // the other settings are the default ones
+        assert_eq!(python.indent_width, 4);
+        assert!(python.trim_trailing_whitespace);
+        assert!(config.indent_for(&LspLang::Json).use_spaces);
     }
 }
@@ -1,6 +1,8 @@
+use std::collections::HashMap;
+
 use crate::editor::{DEFAULT_FOREGROUND_COLOR, DEFAULT_TEXT_FONT, DEFAULT_TEXT_SIZE};
+use crate::lock;
 use crate::theme::Style;
-use crate::{lock, THEME};
 use druid::piet::{Text, TextAttribute, TextLayout, TextLayoutBuilder};
 use druid::{
     Affine, Color, Env, FontFamily, FontStyle, FontWeight, PaintCtx, Point, RenderContext, Vec2,
@@ -18,6 +20,7 @@ pub trait Drawable {
     fn height(&self) -> f64;
 }
 
+#[derive(Clone)]
 pub struct DrawableText {
     pub background_color: Option<Color>,
     pub text_layout: ITextLayout,
@@ -62,7 +65,7 @@ pub fn drawable_text(ctx: &mut PaintCtx, _env: &Env, text: &str, style: &Style)
             style
                 .foreground
                 .clone()
-                .or_else(|| THEME.scope("ui.text").foreground)
+                .or_else(|| lock!(theme).scope("ui.text").foreground)
                 .unwrap_or(DEFAULT_FOREGROUND_COLOR)
                 .clone(),
         )
@@ -109,3 +112,112 @@ pub fn drawable_text(ctx: &mut PaintCtx, _env: &Env, text: &str, style: &Style)
         wave_text_layout,
     }
 }
+
+/// Layouts of the last paint, reused by the next one when the same text is drawn with
+/// the same style. The key is built from the text and the style, not from the context.
+pub struct LayoutCache<T = DrawableText> {
+    // with whether it was used since the last call to `next_paint`
+    layouts: HashMap<String, (T, bool)>,
+}
+
+impl<T> Default for LayoutCache<T> {
+    fn default() -> Self {
+        Self {
+            layouts: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Clone> LayoutCache<T> {
+    /// Drop the layouts not used since the last call, to call once per paint.
+    pub fn next_paint(&mut self) {
+        self.layouts.retain(|_, (_, used)| std::mem::take(used));
+    }
+
+    pub fn get_or_insert_with<F: FnOnce() -> T>(&mut self, key: String, f: F) -> T {
+        let (layout, used) = self.layouts.entry(key).or_insert_with(|| (f(), false));
+        *used = true;
+        layout.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.layouts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layouts.is_empty()
+    }
+}
+
+/// Same as `drawable_text`, reusing the layout of the last paint if there is one.
+pub fn cached_drawable_text(
+    cache: &mut LayoutCache,
+    ctx: &mut PaintCtx,
+    env: &Env,
+    text: &str,
+    style: &Style,
+) -> DrawableText {
+    // what `drawable_text` reads besides the style
+    let scale = lock!(conf).render.text_scale;
+    let foreground = lock!(theme).scope("ui.text").foreground;
+    let key = format!("{}\0{:?}\0{:?}\0{}", scale, foreground, style, text);
+    cache.get_or_insert_with(key, || drawable_text(ctx, env, text, style))
+}
+
+/// Same as `drawable_text` but the text is cut with an ellipsis to fit in `max_width`.
+pub fn drawable_text_ellipsized(
+    ctx: &mut PaintCtx,
+    env: &Env,
+    text: &str,
+    style: &Style,
+    max_width: f64,
+) -> DrawableText {
+    let draw_text = drawable_text(ctx, env, text, style);
+    if draw_text.width() <= max_width {
+        return draw_text;
+    }
+
+    let chars = text.chars().collect::<Vec<_>>();
+    let cut = |len: usize| -> String { chars[..len].iter().chain(['…'].iter()).collect() };
+
+    // longest prefix which fits
+    let (mut low, mut high) = (0, chars.len());
+    while low < high {
+        let mid = (low + high + 1) / 2;
+        if drawable_text(ctx, env, &cut(mid), style).width() <= max_width {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    drawable_text(ctx, env, &cut(low), style)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::draw::LayoutCache;
+
+    #[test]
+    fn layouts_of_the_last_paint() {
+        let mut cache = LayoutCache::default();
+        let mut built = 0;
+        let mut layout = |cache: &mut LayoutCache<String>, text: &str| {
+            cache.get_or_insert_with(text.to_string(), || {
+                built += 1;
+                text.to_uppercase()
+            })
+        };
+        assert_eq!(layout(&mut cache, "a"), "A");
+        assert_eq!(layout(&mut cache, "b"), "B");
+        assert_eq!(layout(&mut cache, "a"), "A");
+
+        cache.next_paint();
+        assert_eq!(layout(&mut cache, "a"), "A");
+        assert_eq!(built, 2);
+        // `b` was not drawn by the last paint
+        cache.next_paint();
+        assert_eq!(cache.len(), 1);
+        cache.next_paint();
+        assert!(cache.is_empty());
+    }
+}
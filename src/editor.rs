@@ -1,21 +1,40 @@
 use std::cmp::{max, min};
 use std::collections::HashMap;
-use std::time::Duration;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use druid::kurbo::Line;
+use druid::kurbo::{BezPath, Circle, Line};
 use druid::piet::*;
 use druid::*;
 use itertools::Itertools;
+use lsp_types::request::{Completion, Request, ResolveCompletionItem};
+use lsp_types::{CodeAction, Position, Range};
 use ropey::RopeSlice;
 
-use crate::buffer::{Action, Bounds, Handle, Index, IntoWithBuffer, Movement};
-use crate::draw::{drawable_text, Drawable, DrawableText};
-use crate::highlight::TreeSitterHighlight;
-use crate::lsp::{lsp_send, lsp_try_recv, CompletionData, LspInput, LspOutput};
-use crate::style_layer::{style_for_range, DiagStyleLayer, Span, StyleLayer};
-use crate::theme::Style;
-use crate::{curr_buf, lock, AppState, BufferSource, Ignore, Path, THEME};
+use crate::buffer::{Action, Bounds, Buffer, Direction, Handle, Index, IntoWithBuffer, Movement};
+use crate::config::AcceptKey;
+use crate::draw::{
+    cached_drawable_text, drawable_text, drawable_text_ellipsized, Drawable, DrawableText,
+    LayoutCache,
+};
+use crate::export::{export, ExportFormat};
+use crate::highlight::{symbol_after, TreeSitterHighlight};
+use crate::lsp::{
+    lsp_restart, lsp_send, lsp_supports, lsp_trigger_characters, lsp_try_recv, CompletionData,
+    LspCompletion, LspFeature, LspInput, LspOutput,
+};
+use crate::metrics::{caret_at, editor_font, expand_tabs, Advance, PaintedLine};
+use crate::search::{Search, SearchStyleLayer};
+use crate::status::{error, level_style, log, notify, Level};
+use crate::style_layer::{
+    style_for_range, BracketStyleLayer, DiagStyleLayer, IndentStyleLayer, Span, StyleLayer,
+};
+use crate::tabs::{window_title, BUFFER_CHANGED};
+use crate::theme::{theme_path, Style, Theme, THEMES_DIR, THEME_CHANGED};
+use crate::tree::{ITEMS_CHANGED, REVEAL_CURRENT, REVEAL_CURSOR, ROOT_CHANGED};
+use crate::watch::FsWatcher;
+use crate::{curr_buf, lock, set_root, AppState, BufferData, BufferSource, Ignore, Path, FS};
 
 pub const LINE_SPACING: f64 = 4.0;
 pub const SCROLL_GAP: usize = 4;
@@ -23,282 +42,1708 @@ pub const HALF_LINE_SPACING: f64 = LINE_SPACING / 2.0;
 pub const DEFAULT_BACKGROUND_COLOR: Color = Color::rgb8(0x2f, 0x2f, 0x2f);
 pub const DEFAULT_FOREGROUND_COLOR: Color = Color::rgb8(0xcc, 0xcc, 0xcc);
 pub const DEFAULT_TEXT_SIZE: f64 = 18.0;
+pub const SCROLLBAR_WIDTH: f64 = 8.0;
+pub const SCROLLBAR_MIN_THUMB: f64 = 20.0;
+/// Lines of the documentation of a completion shown next to the popup.
+pub const DOC_LINES: usize = 12;
+pub const DEFAULT_CURSORLINE_COLOR: Color = Color::rgba8(0xff, 0xff, 0xff, 0x0a);
+pub const DEFAULT_SELECTION_COLOR: Color = Color::rgba8(0x60, 0x90, 0xe0, 0x60);
+pub const DEFAULT_WHITESPACE_COLOR: Color = Color::rgba8(0x80, 0x80, 0x80, 0x60);
+pub const DEFAULT_SCROLLBAR_COLOR: Color = Color::rgba8(0x80, 0x80, 0x80, 0x80);
+pub const DEFAULT_INDENT_GUIDE_COLOR: Color = Color::rgba8(0x80, 0x80, 0x80, 0x30);
+pub const EXPORT_FILE: Selector<FileInfo> = Selector::new("ste.export-file");
+pub const SAVE_AS_FILE: Selector<FileInfo> = Selector::new("ste.save-as-file");
+pub const OPEN_FOLDER: Selector<FileInfo> = Selector::new("ste.open-folder");
+/// Time without edits after which the outline is requested again.
+pub const SYMBOLS_DELAY: Duration = Duration::from_millis(750);
+/// Time without typing after a trigger character before completion is requested.
+pub const TRIGGER_DELAY: Duration = Duration::from_millis(150);
+/// Lines highlighted above and below the viewport, scrolling within them highlights nothing.
+pub const HIGHLIGHT_MARGIN: usize = 100;
+/// Entries of the log scrolled by `PageUp` and `PageDown`.
+const LOG_PAGE: usize = 10;
 lazy_static::lazy_static! {
     pub static ref DEFAULT_TEXT_FONT: String = String::from("Fira Code");
 }
 
+/// Text typed with `key`, already composed by the system for dead keys.
+/// `None` for shortcuts and control characters, AltGr (Ctrl+Alt) still types.
+pub fn typed_text(key: &KeyEvent) -> Option<String> {
+    if key.mods.ctrl() && !key.mods.alt() {
+        return None;
+    }
+    match &key.key {
+        KbKey::Character(text) => {
+            let text: String = text.chars().filter(|c| !c.is_control()).collect();
+            if text.is_empty() {
+                None
+            } else {
+                Some(text)
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Code actions offered by the server, the selected one is applied with Enter.
+struct CodeActionMenu {
+    actions: Vec<CodeAction>,
+    selected: usize,
+}
+
+/// Prompt for the new name of the symbol at `at`.
+struct Rename {
+    at: Index,
+    name: String,
+}
+
+/// Side effects of an event which need the druid context to be applied.
+#[derive(Default)]
+pub struct EventOutcome {
+    pub repaint: bool,
+    pub commands: Vec<Command>,
+}
+
 pub struct TextEditor {
     last_buffer_id: Option<u32>,
-    char_points: Vec<(Point, Index)>,
+    painted_lines: Vec<PaintedLine>,
+    // where the text is painted, right of the line numbers
+    text_area: Rect,
     highlight: Option<TreeSitterHighlight>,
     highlight_spans: Vec<Span>,
+    // lines covered by `highlight_spans`
+    highlight_lines: (usize, usize),
+    // text layouts of the last paint
+    layouts: LayoutCache,
     scroll_line: usize,
+    scroll_x: f64,
+    // fraction of line not scrolled yet by the wheel
+    scroll_remainder: f64,
+    line_height: f64,
     last_line_painted: usize,
+    search: Option<Search>,
+    rename: Option<Rename>,
+    code_actions: Option<CodeActionMenu>,
+    focused: bool,
+    // thumb of the scrollbar as last painted
+    scrollbar: Option<Rect>,
+    // distance from the top of the thumb to where it was grabbed
+    scrollbar_grab: Option<f64>,
+    advance: Option<Advance>,
+    // pinned header lines as last painted, with the start of their definition
+    sticky: Vec<(Rect, Index)>,
+    // last edit not followed by a request of the outline
+    edited: Option<Instant>,
+    // last trigger character typed, with its buffer, not followed by a request of completion
+    triggered: Option<(Instant, u32, String)>,
+    trigger_timer: Option<TimerToken>,
+    watcher: Option<FsWatcher>,
+    theme_watcher: Option<FsWatcher>,
+    // why the theme file could not be loaded, shown until it is fixed
+    theme_error: Option<String>,
+    // buffers with unsaved changes whose file changed on disk, the first one is prompted
+    changed_on_disk: Vec<u32>,
+    // entries of the log overlay scrolled past from the newest, `None` when it is hidden
+    log: Option<usize>,
+    // of the window, as last set
+    title: String,
 }
 
 impl TextEditor {
     fn do_action(&mut self, action: Action, _data: &mut AppState) -> anyhow::Result<bool> {
-        let (action, id) = {
+        let (action, moved, id) = {
             let mut buffers = lock!(mut buffers);
             let buf = buffers.get_mut_curr()?;
-            (buf.buffer.do_action(action), buffers.curr()?)
+            if buf.read_only {
+                return Ok(false);
+            }
+            let before = buf.buffer.cursor();
+            let action = buf.buffer.do_action(action);
+            let after = buf.buffer.cursor();
+            // typing over a closing char only moves the cursor
+            let moved = before.head != after.head || before.tail != after.tail;
+            (action, moved, buffers.curr()?)
         };
         if let Some(action) = action {
             lsp_send(id, action).ignore();
+            self.edited = Some(Instant::now());
             Ok(true)
         } else {
-            Ok(false)
+            Ok(moved)
         }
     }
 
+    fn export(&mut self, format: ExportFormat) -> anyhow::Result<String> {
+        let (bounds, lines) = {
+            let buffers = lock!(buffers);
+            let buf = &buffers.get_curr()?.buffer;
+            let cursor = buf.cursor();
+            let bounds = if cursor.same() {
+                (0, buf.rope().len_chars())
+            } else {
+                (cursor.min(), cursor.max())
+            };
+            (bounds, (buf.row_at(bounds.0), buf.row_at(bounds.1)))
+        };
+        // the highlight can be limited to the viewport
+        self.ensure_highlight(lines.0, lines.1).ignore();
+        let buffers = lock!(buffers);
+        let buf = buffers.get_curr()?;
+        export(&buf.buffer, &self.highlight_spans, bounds, format)
+    }
+
+    /// Lines that fit in the viewport, as many as were last painted.
+    fn visible_lines(&self) -> usize {
+        max(self.last_line_painted.saturating_sub(self.scroll_line), 1)
+    }
+
+    /// Highest `scroll_line` of a buffer of `len_lines`, the last page still fills the viewport.
+    fn max_scroll_line(&self, len_lines: usize) -> usize {
+        len_lines.saturating_sub(self.visible_lines())
+    }
+
     fn fix_scroll(&mut self) -> anyhow::Result<()> {
         let buffers = lock!(buffers);
         let buf = buffers.get(buffers.curr()?)?;
         let cursor_row = buf.buffer.row();
-        if buf.buffer.rope().len_lines() <= SCROLL_GAP * 2 {
+        let len_lines = buf.buffer.rope().len_lines();
+        if len_lines <= SCROLL_GAP * 2 {
             self.scroll_line = 0;
         } else if cursor_row.saturating_sub(SCROLL_GAP) < self.scroll_line {
             self.scroll_line = cursor_row.saturating_sub(SCROLL_GAP)
         } else if cursor_row.saturating_add(SCROLL_GAP) > self.last_line_painted {
             self.scroll_line = cursor_row
                 .saturating_add(SCROLL_GAP)
-                .saturating_sub(self.last_line_painted.saturating_sub(self.scroll_line))
+                .saturating_sub(self.visible_lines())
         }
+        self.scroll_line = min(self.scroll_line, self.max_scroll_line(len_lines));
         Ok(())
     }
 
-    fn recv_lsp_event(&mut self, ctx: &mut EventCtx) -> anyhow::Result<()> {
+    fn recv_lsp_event(&mut self) -> anyhow::Result<EventOutcome> {
         let id = curr_buf!(id);
         let evt = lsp_try_recv(id)?;
+        self.apply_lsp_output(evt)
+    }
+
+    /// Follow an answer of the language server of the current buffer.
+    pub fn apply_lsp_output(&mut self, evt: LspOutput) -> anyhow::Result<EventOutcome> {
+        let mut commands = vec![];
 
         match evt {
-            LspOutput::Completion(completions) => {
+            LspOutput::Completion(completions, hidden) => {
                 let mut buffers = lock!(mut buffers);
                 let buf = buffers.get_mut_curr()?;
                 buf.buffer.completions = completions;
-                ctx.request_paint();
+                buf.buffer.completion_selected = 0;
+                buf.buffer.completions_hidden = hidden;
             }
-            LspOutput::CompletionResolve(c) => {
-                match c.data {
-                    CompletionData::Simple(text) => {
-                        let mut buffers = lock!(mut buffers);
-                        let buf = buffers.get_mut_curr()?;
-                        buf.buffer.insert(buf.buffer.cursor().head, &text);
-                    }
-                    CompletionData::Edits(edits) => {
-                        let mut buffers = lock!(mut buffers);
-                        let buf = buffers.get_mut_curr()?;
-                        edits
-                            .iter()
-                            .sorted_by_key(|e| {
-                                let bounds: Bounds = (&e.range).into_with_buf(&buf.buffer);
-                                bounds.0
-                            })
-                            .rev()
-                            .for_each(|e| {
-                                buf.buffer.remove_chars(&e.range);
-                                buf.buffer.insert(&e.range.start, &e.new_text);
-                            });
-                    }
+            LspOutput::CompletionResolve(c) => self.apply_completion(c)?,
+            // the buffer may not be the current one anymore
+            LspOutput::Failed(_, uri) if curr_buf!(uri).as_ref() != Some(&uri) => {}
+            LspOutput::Failed(method, _) => match method.as_str() {
+                // applied as it is
+                ResolveCompletionItem::METHOD => {
+                    self.accept_completion()?;
+                }
+                // the list is for the text before
+                Completion::METHOD => {
+                    lock!(mut buffers).get_mut_curr()?.buffer.completions = vec![]
+                }
+                _ => {}
+            },
+            LspOutput::Diagnostics => {}
+            LspOutput::InlayHints => {}
+            LspOutput::CodeActions(actions) => {
+                self.code_actions = if actions.is_empty() {
+                    None
+                } else {
+                    Some(CodeActionMenu {
+                        actions,
+                        selected: 0,
+                    })
                 };
+            }
+            LspOutput::SignatureHelp(signature) => {
+                lock!(mut buffers).get_mut_curr()?.buffer.signature = signature;
+            }
+            LspOutput::WorkspaceEdit(edit) => {
+                lock!(mut buffers).apply_workspace_edit(edit)?;
+                self.edited = Some(Instant::now());
                 self.calculate_highlight().ignore();
-                ctx.request_paint();
             }
-            LspOutput::Diagnostics => {
-                ctx.request_paint();
+            LspOutput::DocumentSymbols(uri, symbols) => {
+                if let Some(buf) = lock!(mut buffers).get_by_uri_mut(uri) {
+                    buf.buffer.symbols = symbols;
+                }
+                commands.push(ITEMS_CHANGED.into());
             }
-            LspOutput::InlayHints => {
-                ctx.request_paint();
+        }
+        Ok(EventOutcome {
+            repaint: true,
+            commands,
+        })
+    }
+
+    /// Request completion for the last trigger character once the typing paused for
+    /// `TRIGGER_DELAY`, returns how long to wait otherwise.
+    fn request_triggered_completion(&mut self) -> anyhow::Result<Option<Duration>> {
+        let elapsed = match &self.triggered {
+            Some((at, _, _)) => at.elapsed(),
+            None => return Ok(None),
+        };
+        if elapsed < TRIGGER_DELAY {
+            return Ok(Some(TRIGGER_DELAY - elapsed));
+        }
+        let (_, buffer_id, trigger) = self.triggered.take().unwrap();
+        let (row, col, before) = {
+            let buffers = lock!(buffers);
+            let buf = buffers.get_curr()?;
+            if buf.id != buffer_id {
+                return Ok(None);
             }
+            let head = buf.buffer.cursor().head;
+            let before = head.checked_sub(1).map(|i| buf.buffer.rope().char(i));
+            (buf.buffer.row(), buf.buffer.col(), before)
+        };
+        // typed on after the trigger, the completion is asked for the word instead
+        let trigger_character = before
+            .map(String::from)
+            .filter(|before| trigger.ends_with(before.as_str()));
+        lsp_send(
+            buffer_id,
+            LspInput::RequestCompletion {
+                buffer_id,
+                row: row as u32,
+                col: col as u32,
+                trigger_character,
+            },
+        )?;
+        Ok(None)
+    }
+
+    /// Request the outline again once the edits settled for `SYMBOLS_DELAY`.
+    fn refresh_symbols(&mut self) -> anyhow::Result<()> {
+        match self.edited {
+            Some(at) if at.elapsed() >= SYMBOLS_DELAY => self.edited = None,
+            _ => return Ok(()),
+        }
+        let buffer_id = curr_buf!(id);
+        lsp_send(buffer_id, LspInput::RequestDocumentSymbols { buffer_id })
+    }
+
+    fn apply_completion(&mut self, c: LspCompletion) -> anyhow::Result<()> {
+        {
+            let mut buffers = lock!(mut buffers);
+            let buf = buffers.get_mut_curr()?;
+            let at = match c.data {
+                CompletionData::Simple(text) => {
+                    let at = buf.buffer.cursor().head;
+                    buf.buffer.insert(at, &text);
+                    at
+                }
+                CompletionData::Edits(edits) => {
+                    let at = buf.buffer.edited_start(&edits, 0);
+                    buf.buffer.apply_edits(&edits);
+                    at
+                }
+            };
+            buf.buffer.start_snippet(at, &c.stops);
+            buf.buffer.completions = vec![];
         }
+        self.edited = Some(Instant::now());
+        self.calculate_highlight().ignore();
         Ok(())
     }
 
-    fn process(
-        &mut self,
-        ctx: &mut EventCtx,
-        event: &Event,
-        data: &mut AppState,
-    ) -> anyhow::Result<()> {
-        let id = curr_buf!(id);
-        let old = self.last_buffer_id.replace(id);
-        if let Some(old) = old {
-            if id != old {
-                self.highlight = TreeSitterHighlight::new(curr_buf!(lang));
-                self.calculate_highlight().ignore();
-                ctx.request_paint();
+    /// Apply the selected completion as it is, without resolving it first.
+    fn accept_completion(&mut self) -> anyhow::Result<bool> {
+        let c = {
+            let buffers = lock!(buffers);
+            buffers.get_curr()?.buffer.selected_completion()?.cloned()
+        };
+        match c {
+            Some(c) => {
+                self.apply_completion(c)?;
+                Ok(true)
             }
-        } else {
-            self.highlight = TreeSitterHighlight::new(curr_buf!(lang));
+            None => Ok(false),
+        }
+    }
+
+    /// Whether typing `text` accepts the selected completion before being typed.
+    fn commits_completion(&self, text: &str) -> anyhow::Result<bool> {
+        let buffers = lock!(buffers);
+        let c = buffers.get_curr()?.buffer.selected_completion()?;
+        Ok(c.map_or(false, |c| c.commit_characters.iter().any(|s| s == text)))
+    }
+
+    /// Keys of the log overlay, the arrows and the pages scroll it, `Escape` hides it.
+    fn log_key(&mut self, key: &KeyEvent) -> anyhow::Result<Option<EventOutcome>> {
+        let scrolled = match self.log.as_mut() {
+            Some(scrolled) => scrolled,
+            None => return Ok(None),
+        };
+        let last = log().len().saturating_sub(1);
+        match &key.code {
+            Code::Escape => self.log = None,
+            Code::ArrowUp => *scrolled = min(*scrolled + 1, last),
+            Code::ArrowDown => *scrolled = scrolled.saturating_sub(1),
+            Code::PageUp => *scrolled = min(*scrolled + LOG_PAGE, last),
+            Code::PageDown => *scrolled = scrolled.saturating_sub(LOG_PAGE),
+            _ => return Ok(None),
+        }
+        Ok(Some(EventOutcome {
+            repaint: true,
+            commands: vec![],
+        }))
+    }
+
+    /// Keys typed while the search box is open, `None` if the key is not for the search.
+    fn search_key(&mut self, key: &KeyEvent) -> anyhow::Result<Option<EventOutcome>> {
+        let search = match self.search.as_mut() {
+            Some(search) => search,
+            None => return Ok(None),
+        };
+        let mut buffers = lock!(mut buffers);
+        let buf = &mut buffers.get_mut_curr()?.buffer;
+        let cursor = buf.cursor();
+
+        let target = match &key.code {
+            Code::Escape => {
+                self.search = None;
+                None
+            }
+            Code::KeyF if key.mods.ctrl() => {
+                self.search = None;
+                None
+            }
+            Code::Enter if key.mods.alt() => {
+                let (query, ignore_case, scope) =
+                    (search.query.clone(), search.ignore_case, search.scope);
+                self.search = None;
+                buf.select_all_occurrences(&query, ignore_case, scope);
+                None
+            }
+            Code::Enter if key.mods.shift() => search.prev(cursor.min()),
+            Code::Enter => search.next(cursor.max()),
+            Code::KeyC if key.mods.alt() => {
+                search.ignore_case = !search.ignore_case;
+                search.update(buf);
+                search.next(cursor.min())
+            }
+            Code::Backspace => {
+                search.query.pop();
+                search.update(buf);
+                search.next(cursor.min())
+            }
+            _ => match typed_text(key) {
+                Some(text) => {
+                    search.query.push_str(&text);
+                    search.update(buf);
+                    search.next(cursor.min())
+                }
+                _ => return Ok(None),
+            },
+        };
+
+        drop(buffers);
+        match target {
+            Some(bounds) => self.reveal(bounds, true)?,
+            None => self.fix_scroll()?,
+        }
+
+        Ok(Some(EventOutcome {
+            repaint: true,
+            commands: vec![],
+        }))
+    }
+
+    /// Definitions whose header line scrolled above the view while the top of the view is
+    /// in them, the outermost first, at most `render.sticky_lines`.
+    pub fn sticky_headers(&self) -> anyhow::Result<Vec<Bounds>> {
+        let buffers = lock!(buffers);
+        Ok(self.headers(&buffers.get_curr()?.buffer))
+    }
+
+    fn headers(&self, buffer: &Buffer) -> Vec<Bounds> {
+        let max = lock!(conf).render.sticky_lines;
+        let highlight = match &self.highlight {
+            Some(highlight) if max > 0 => highlight,
+            _ => return vec![],
+        };
+        let top = buffer.line_bounds(self.scroll_line).0;
+        highlight
+            .enclosing(buffer.rope(), top)
+            .into_iter()
+            .filter(|(start, _)| buffer.row_at(*start) < self.scroll_line)
+            .take(max)
+            .collect()
+    }
+
+    /// Follow the changes made on disk under `root`, e.g. by another program,
+    /// and to the theme files. The themes are optional, failing to watch them is only logged.
+    pub fn watch(&mut self, root: &std::path::Path) -> notify::Result<()> {
+        self.watcher = Some(FsWatcher::new(root)?);
+        if self.theme_watcher.is_none() {
+            match FsWatcher::new(&THEMES_DIR) {
+                Ok(watcher) => self.theme_watcher = Some(watcher),
+                Err(e) => error(format!("cannot watch {}: {}", THEMES_DIR.display(), e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the theme again if its file is in `paths`, the widgets repaint with it.
+    pub fn themes_changed(&mut self, paths: &[PathBuf]) -> EventOutcome {
+        let path = theme_path(&lock!(conf).theme);
+        if !paths.contains(&path) {
+            return EventOutcome::default();
+        }
+        self.theme_error = Theme::reload(&path).err();
+        // the spans keep the styles of the old theme
+        self.calculate_highlight().ignore();
+        EventOutcome {
+            repaint: true,
+            commands: vec![THEME_CHANGED.into()],
+        }
+    }
+
+    /// Use the theme after the current one in `THEMES_DIR`.
+    fn next_theme(&mut self) -> EventOutcome {
+        let names = Theme::names();
+        let name = {
+            let mut config = lock!(mut conf);
+            let next = names
+                .iter()
+                .position(|n| *n == config.theme)
+                .map_or(0, |i| (i + 1) % names.len().max(1));
+            match names.get(next) {
+                Some(name) => config.theme = name.clone(),
+                None => return EventOutcome::default(),
+            }
+            config.theme.clone()
+        };
+        self.theme_error = Theme::select(&name).err();
+        self.calculate_highlight().ignore();
+        EventOutcome {
+            repaint: true,
+            commands: vec![THEME_CHANGED.into()],
+        }
+    }
+
+    /// The files at `paths` changed on disk, the trees are refreshed and the buffers
+    /// reloaded, the user is asked first when a buffer has unsaved changes. A file with
+    /// the text last loaded or saved, e.g. written by a save, is left alone.
+    pub fn files_changed(&mut self, paths: &[PathBuf]) -> anyhow::Result<EventOutcome> {
+        let mut current_reloaded = false;
+        {
+            let mut buffers = lock!(mut buffers);
+            let changed: Vec<u32> = buffers
+                .buffers
+                .values()
+                .filter(|b| {
+                    b.source
+                        .path()
+                        .map_or(false, |p| paths.iter().any(|c| c.as_path() == p.as_ref()))
+                })
+                .map(|b| b.id)
+                .collect();
+            for id in changed {
+                let buf = buffers.get(id)?;
+                let path = buf.source.path().context("no path")?;
+                // a removed file keeps its buffer
+                let written = std::fs::read_to_string(path.as_ref())
+                    .map_or(true, |text| buf.buffer.is_saved_text(&text));
+                if written {
+                    continue;
+                }
+                if !buf.buffer.is_modified() {
+                    let reloaded = buffers.reload(id).unwrap_or(false);
+                    current_reloaded |= reloaded && buffers.current == Some(id);
+                } else if !self.changed_on_disk.contains(&id) {
+                    self.changed_on_disk.push(id);
+                }
+            }
+        }
+        if current_reloaded {
             self.calculate_highlight().ignore();
-            ctx.request_paint();
         }
+        Ok(EventOutcome {
+            repaint: true,
+            commands: vec![ITEMS_CHANGED.into()],
+        })
+    }
 
-        match event {
-            Event::Timer(_timer) => {
-                self.recv_lsp_event(ctx).err().map(|_ignore| {});
-                ctx.request_timer(Duration::from_millis(250));
+    /// Run a command of the keymap, returns whether the text changed.
+    fn run_command(
+        &mut self,
+        command: &str,
+        data: &mut AppState,
+        outcome: &mut EventOutcome,
+    ) -> anyhow::Result<bool> {
+        let dirty = match command {
+            "completion" => {
+                let id = curr_buf!(id);
+                let row = curr_buf!(row);
+                let col = curr_buf!(col);
+                lsp_send(
+                    id,
+                    LspInput::RequestCompletion {
+                        buffer_id: id,
+                        row: row as u32,
+                        col: col as u32,
+                        trigger_character: None,
+                    },
+                )
+                .ignore();
+                false
             }
-            Event::KeyDown(key) => {
-                let is_shift = key.mods.shift();
-                let dirty = match &key.code {
-                    Code::Space if key.mods.ctrl() => {
-                        let id = curr_buf!(id);
-                        let row = curr_buf!(row);
-                        let col = curr_buf!(col);
+            "resolve_completion" => {
+                let c = {
+                    let buffers = lock!(buffers);
+                    let buf = buffers.get_curr()?;
+                    buf.buffer.selected_completion()?.cloned()
+                };
+                let id = curr_buf!(id);
+                match c {
+                    // applied as it is
+                    Some(_) if !lsp_supports(id, LspFeature::CompletionResolve) => {
+                        self.accept_completion()?
+                    }
+                    Some(c) => {
                         lsp_send(
                             id,
-                            LspInput::RequestCompletion {
+                            LspInput::RequestCompletionResolve {
                                 buffer_id: id,
-                                row: row as u32,
-                                col: col as u32,
+                                item: c.original_item,
                             },
                         )
                         .ignore();
                         false
                     }
-                    Code::F1 => {
-                        let c = {
-                            let buffers = lock!(buffers);
-                            let buf = buffers.get_curr()?;
-                            buf.buffer.sorted_completions()?.first().cloned().cloned()
-                        };
-                        let id = curr_buf!(id);
-                        if let Some(c) = c {
-                            lsp_send(
-                                id,
-                                LspInput::RequestCompletionResolve {
-                                    buffer_id: id,
-                                    item: c.original_item,
-                                },
-                            )
-                            .ignore();
-                            true
-                        } else {
-                            false
-                        }
+                    None => false,
+                }
+            }
+            "next_symbol" | "previous_symbol" => {
+                let direction = if command == "next_symbol" {
+                    Direction::Down
+                } else {
+                    Direction::Up
+                };
+                let (symbols, head) = {
+                    let buffers = lock!(buffers);
+                    let buf = &buffers.get_curr()?.buffer;
+                    let symbols = match &mut self.highlight {
+                        Some(highlight) => highlight.symbols(buf.rope()),
+                        None => vec![],
+                    };
+                    (symbols, buf.cursor().head)
+                };
+                if let Some((start, _)) = symbol_after(&symbols, head, direction) {
+                    self.reveal((start, start), false)?;
+                    outcome.repaint = true;
+                }
+                false
+            }
+            "move_lines_down" => self.do_action(Action::MoveLines(Direction::Down), data)?,
+            "move_lines_up" => self.do_action(Action::MoveLines(Direction::Up), data)?,
+            "delete_word_backward" => self.do_action(Action::DeleteWordBackward, data)?,
+            "delete_word_forward" => self.do_action(Action::DeleteWordForward, data)?,
+            "delete_to_line_end" => self.do_action(Action::DeleteToLineEnd, data)?,
+            "next_buffer" | "previous_buffer" => {
+                lock!(mut buffers).cycle(command == "previous_buffer");
+                outcome.commands.push(REVEAL_CURRENT.into());
+                outcome.commands.push(ITEMS_CHANGED.into());
+                true
+            }
+            "code_actions" if !lsp_supports(curr_buf!(id), LspFeature::CodeActions) => {
+                notify("no code actions from the language server");
+                false
+            }
+            "code_actions" => {
+                self.request_code_actions().ignore();
+                false
+            }
+            "rename" if !lsp_supports(curr_buf!(id), LspFeature::Rename) => {
+                notify("the language server cannot rename");
+                false
+            }
+            "rename" => {
+                let rename = {
+                    let buffers = lock!(buffers);
+                    let buf = buffers.get_curr()?;
+                    buf.buffer
+                        .word_at(buf.buffer.cursor().head)
+                        .map(|(start, end)| Rename {
+                            at: start,
+                            name: buf.buffer.rope().slice(start..end).to_string(),
+                        })
+                };
+                if rename.is_some() {
+                    self.search = None;
+                    self.rename = rename;
+                    outcome.repaint = true;
+                }
+                false
+            }
+            "search" => {
+                let cursor = curr_buf!(cursor);
+                let mut search = Search::default();
+                if !cursor.same() {
+                    search.scope = Some((cursor.min(), cursor.max()));
+                }
+                self.rename = None;
+                self.search = Some(search);
+                outcome.repaint = true;
+                false
+            }
+            "close" => {
+                let mut buffers = lock!(mut buffers);
+                let (id, modified) = {
+                    let buf = buffers.get_curr()?;
+                    (buf.id, buf.buffer.is_modified())
+                };
+                // there is no prompt to save it first
+                if !modified {
+                    buffers.close(id)?;
+                    if buffers.current.is_none() {
+                        buffers.open_text("");
                     }
-                    Code::ArrowDown => {
-                        let mut buffers = lock!(mut buffers);
-                        buffers
-                            .get_mut_curr()?
-                            .buffer
-                            .move_cursor(Movement::Down, is_shift)
+                    outcome.repaint = true;
+                    outcome.commands.push(REVEAL_CURRENT.into());
+                }
+                false
+            }
+            "add_cursor_below" | "add_cursor_above" => {
+                let direction = if command == "add_cursor_above" {
+                    Direction::Up
+                } else {
+                    Direction::Down
+                };
+                let mut buffers = lock!(mut buffers);
+                let buffer = &mut buffers.get_mut_curr()?.buffer;
+                outcome.repaint = buffer.add_cursor_on_adjacent_line(direction);
+                false
+            }
+            "select_next_occurrence" => {
+                let mut buffers = lock!(mut buffers);
+                outcome.repaint = buffers.get_mut_curr()?.buffer.add_selection_at_next_match();
+                false
+            }
+            "select_all_occurrences" => {
+                let mut buffers = lock!(mut buffers);
+                buffers.get_mut_curr()?.buffer.select_all_current();
+                outcome.repaint = true;
+                false
+            }
+            "restart_language_server" => {
+                lsp_restart(curr_buf!(lang))?;
+                false
+            }
+            "log" => {
+                self.log = match self.log {
+                    Some(_) => None,
+                    None => Some(0),
+                };
+                outcome.repaint = true;
+                false
+            }
+            "reveal_in_tree" => {
+                outcome.commands.push(REVEAL_CURRENT.into());
+                false
+            }
+            "retab" => self.do_action(Action::Retab, data)?,
+            "duplicate_lines" => self.do_action(Action::DuplicateLines, data)?,
+            "next_theme" => {
+                let theme = self.next_theme();
+                outcome.commands.extend(theme.commands);
+                outcome.repaint = true;
+                false
+            }
+            "delete_line" => self.do_action(Action::DeleteLine, data)?,
+            "undo" => self.do_action(Action::Undo, data)?,
+            "redo" => self.do_action(Action::Redo, data)?,
+            "copy_html" => {
+                let html = self.export(ExportFormat::Html)?;
+                Application::global().clipboard().put_string(html);
+                false
+            }
+            "paste" | "paste_raw" => match Application::global().clipboard().get_string() {
+                Some(text) => {
+                    let reindent = command == "paste" && lock!(conf).edit.paste_reindent;
+                    let action = if reindent {
+                        Action::Paste(text)
+                    } else {
+                        Action::PasteRaw(text)
+                    };
+                    self.do_action(action, data)?
+                }
+                None => false,
+            },
+            "export" => {
+                let options = FileDialogOptions::new()
+                    .allowed_types(vec![
+                        FileSpec::HTML,
+                        FileSpec::new("Rich Text", &["rtf"]),
+                        FileSpec::TEXT,
+                    ])
+                    .default_type(FileSpec::HTML)
+                    .accept_command(EXPORT_FILE);
+                outcome
+                    .commands
+                    .push(commands::SHOW_SAVE_PANEL.with(options));
+                false
+            }
+            "new" => {
+                lock!(mut buffers).new_scratch();
+                true
+            }
+            "open_folder" => {
+                let options = FileDialogOptions::new()
+                    .title("Open Folder")
+                    .select_directories()
+                    .accept_command(OPEN_FOLDER);
+                outcome
+                    .commands
+                    .push(commands::SHOW_OPEN_PANEL.with(options));
+                false
+            }
+            // a buffer without a file is saved as one
+            "save_as" | "save" if command == "save_as" || curr_buf!(uri).is_none() => {
+                let options = FileDialogOptions::new()
+                    .title("Save As")
+                    .accept_command(SAVE_AS_FILE);
+                outcome
+                    .commands
+                    .push(commands::SHOW_SAVE_PANEL.with(options));
+                false
+            }
+            "save" => {
+                let uri = curr_buf!(uri);
+                let mut dirty = false;
+
+                if let Some(uri) = uri {
+                    let trim = lock!(buffers)
+                        .get_curr()?
+                        .buffer
+                        .indent
+                        .trim_trailing_whitespace;
+                    if trim {
+                        dirty = self.do_action(Action::TrimTrailingWhitespace, data)?;
                     }
-                    Code::ArrowLeft => {
+                    let id = curr_buf!(id);
+                    let saved = {
                         let mut buffers = lock!(mut buffers);
-                        buffers
-                            .get_mut_curr()?
-                            .buffer
-                            .move_cursor(Movement::Left, is_shift)
+                        // get buffer rope
+                        let buf = buffers.get_mut_curr()?;
+                        let rope = buf.buffer.rope();
+                        // if buffer source is a file
+                        if let BufferSource::File { path } = &buf.source {
+                            rope.write_to(path.writer())?;
+                            // the undo history is kept
+                            buf.buffer.mark_saved();
+                            notify(format!("Saved {}", path.name()));
+                            Some(rope.clone())
+                        } else {
+                            None
+                        }
+                    };
+                    if let Some(content) = saved {
+                        lsp_send(id, LspInput::SavedFile { uri, content }).ignore();
                     }
-                    Code::ArrowRight => {
-                        let mut buffers = lock!(mut buffers);
-                        buffers
-                            .get_mut_curr()?
-                            .buffer
-                            .move_cursor(Movement::Right, is_shift)
+                }
+
+                dirty
+            }
+            _ => {
+                error(format!("keymap: unknown command `{}`", command));
+                false
+            }
+        };
+        Ok(dirty)
+    }
+
+    /// Keys answering whether a file changed on disk is reloaded over the unsaved changes,
+    /// `None` if there is no question or the key is not an answer. Losing the changes takes
+    /// a chord, Ctrl+R, which cannot be typed by accident.
+    fn changed_on_disk_key(&mut self, key: &KeyEvent) -> anyhow::Result<Option<EventOutcome>> {
+        let id = match self.changed_on_disk.first() {
+            Some(id) => *id,
+            None => return Ok(None),
+        };
+        let reload = match &key.code {
+            Code::KeyR if key.mods.ctrl() && !key.mods.alt() && !key.mods.shift() => true,
+            Code::Escape => false,
+            _ => return Ok(None),
+        };
+        self.changed_on_disk.remove(0);
+        if reload {
+            let reloaded = {
+                let mut buffers = lock!(mut buffers);
+                // the changes can still be undone
+                if let Ok(buf) = buffers.get_mut(id) {
+                    buf.buffer.mark_saved();
+                }
+                buffers.reload(id).unwrap_or(false) && buffers.current == Some(id)
+            };
+            if reloaded {
+                self.calculate_highlight().ignore();
+            }
+        }
+        Ok(Some(EventOutcome {
+            repaint: true,
+            commands: vec![],
+        }))
+    }
+
+    /// Read the current file again if it has no unsaved changes.
+    fn reload(&mut self) -> anyhow::Result<bool> {
+        let mut buffers = lock!(mut buffers);
+        let id = buffers.curr()?;
+        buffers.reload(id)
+    }
+
+    /// Keys typed while the code action menu is open, any other key closes it and
+    /// is handled as usual.
+    fn code_action_key(&mut self, key: &KeyEvent) -> anyhow::Result<Option<EventOutcome>> {
+        let menu = match self.code_actions.as_mut() {
+            Some(menu) => menu,
+            None => return Ok(None),
+        };
+        let len = menu.actions.len();
+        match &key.code {
+            Code::ArrowUp => menu.selected = (menu.selected + len - 1) % len,
+            Code::ArrowDown => menu.selected = (menu.selected + 1) % len,
+            Code::Enter => {
+                let action = menu.actions.swap_remove(menu.selected);
+                self.code_actions = None;
+                self.apply_code_action(action)?;
+            }
+            Code::Escape => self.code_actions = None,
+            _ => {
+                self.code_actions = None;
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(EventOutcome {
+            repaint: true,
+            commands: vec![],
+        }))
+    }
+
+    /// Apply the edit of `action`, resolved first when the server sent it without it.
+    fn apply_code_action(&mut self, action: CodeAction) -> anyhow::Result<()> {
+        match action.edit {
+            Some(edit) => {
+                lock!(mut buffers).apply_workspace_edit(edit)?;
+                self.calculate_highlight().ignore();
+            }
+            None if !lsp_supports(curr_buf!(id), LspFeature::CodeActionResolve) => {
+                notify(format!("no edit for `{}`", action.title));
+            }
+            None => {
+                let id = curr_buf!(id);
+                lsp_send(
+                    id,
+                    LspInput::RequestCodeActionResolve {
+                        buffer_id: id,
+                        action,
+                    },
+                )
+                .ignore();
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask for the code actions of the selection, or of the cursor line, when it has
+    /// diagnostics.
+    fn request_code_actions(&self) -> anyhow::Result<()> {
+        let range: Range = {
+            let buffers = lock!(buffers);
+            let buffer = &buffers.get_curr()?.buffer;
+            let cursor = buffer.cursor();
+            let bounds = if cursor.same() {
+                buffer.line_bounds(buffer.row())
+            } else {
+                (cursor.min(), cursor.max())
+            };
+            let has_diagnostics = buffer
+                .diagnostics
+                .0
+                .iter()
+                .any(|d| d.bounds.0 <= bounds.1 && bounds.0 <= d.bounds.1);
+            if !has_diagnostics {
+                return Ok(());
+            }
+            (&bounds).into_with_buf(buffer)
+        };
+        let id = curr_buf!(id);
+        lsp_send(
+            id,
+            LspInput::RequestCodeActions {
+                buffer_id: id,
+                range,
+            },
+        )
+    }
+
+    /// Ask for the signature of the call around the cursor.
+    fn request_signature_help(&self) -> anyhow::Result<()> {
+        let id = curr_buf!(id);
+        let row = curr_buf!(row);
+        let col = curr_buf!(col);
+        lsp_send(
+            id,
+            LspInput::RequestSignatureHelp {
+                buffer_id: id,
+                row: row as u32,
+                col: col as u32,
+            },
+        )
+    }
+
+    /// Keys typed while the rename prompt is open, `None` if the key is not for the prompt.
+    fn rename_key(&mut self, key: &KeyEvent) -> anyhow::Result<Option<EventOutcome>> {
+        let rename = match self.rename.as_mut() {
+            Some(rename) => rename,
+            None => return Ok(None),
+        };
+        match &key.code {
+            Code::Escape => self.rename = None,
+            Code::Enter => {
+                let (id, position) = {
+                    let buffers = lock!(buffers);
+                    let buf = buffers.get_curr()?;
+                    let position: Position = (&rename.at).into_with_buf(&buf.buffer);
+                    (buf.id, position)
+                };
+                let new_name = std::mem::take(&mut rename.name);
+                self.rename = None;
+                if !new_name.is_empty() {
+                    lsp_send(
+                        id,
+                        LspInput::RequestRename {
+                            buffer_id: id,
+                            row: position.line,
+                            col: position.character,
+                            new_name,
+                        },
+                    )
+                    .ignore();
+                }
+            }
+            Code::Backspace => {
+                rename.name.pop();
+            }
+            _ => match typed_text(key) {
+                Some(text) => rename.name.push_str(&text),
+                None => return Ok(None),
+            },
+        }
+
+        Ok(Some(EventOutcome {
+            repaint: true,
+            commands: vec![],
+        }))
+    }
+
+    /// Handle a key press without any druid context, the returned outcome tells
+    /// the caller what to repaint / submit.
+    pub fn key_down(
+        &mut self,
+        key: &KeyEvent,
+        data: &mut AppState,
+    ) -> anyhow::Result<EventOutcome> {
+        // keys going to an input method, the composed text comes as a `Character` key
+        if key.is_composing || matches!(key.key, KbKey::Dead | KbKey::Process) {
+            return Ok(EventOutcome::default());
+        }
+        if let Some(outcome) = self.changed_on_disk_key(key)? {
+            return Ok(outcome);
+        }
+        if let Some(outcome) = self.log_key(key)? {
+            return Ok(outcome);
+        }
+        if let Some(outcome) = self.search_key(key)? {
+            return Ok(outcome);
+        }
+        if let Some(outcome) = self.rename_key(key)? {
+            return Ok(outcome);
+        }
+        if let Some(outcome) = self.code_action_key(key)? {
+            return Ok(outcome);
+        }
+
+        let mut outcome = EventOutcome::default();
+        let is_shift = key.mods.shift();
+        let command = lock!(conf).keymap.command(key).map(String::from);
+        let dirty = match &key.code {
+            _ if command.is_some() => {
+                self.run_command(command.as_deref().unwrap_or_default(), data, &mut outcome)?
+            }
+            Code::ArrowDown | Code::ArrowUp if !is_shift && curr_buf!(has_completions) => {
+                let delta = if key.code == Code::ArrowDown { 1 } else { -1 };
+                let mut buffers = lock!(mut buffers);
+                outcome.repaint = buffers.get_mut_curr()?.buffer.select_completion(delta);
+                false
+            }
+            Code::ArrowDown => {
+                let mut buffers = lock!(mut buffers);
+                outcome.repaint = buffers
+                    .get_mut_curr()?
+                    .buffer
+                    .move_cursor(Movement::Down, is_shift);
+                false
+            }
+            Code::ArrowLeft => {
+                let mut buffers = lock!(mut buffers);
+                outcome.repaint = buffers
+                    .get_mut_curr()?
+                    .buffer
+                    .move_cursor(Movement::Left, is_shift);
+                false
+            }
+            Code::ArrowRight => {
+                let mut buffers = lock!(mut buffers);
+                outcome.repaint = buffers
+                    .get_mut_curr()?
+                    .buffer
+                    .move_cursor(Movement::Right, is_shift);
+                false
+            }
+            Code::ArrowUp => {
+                let mut buffers = lock!(mut buffers);
+                outcome.repaint = buffers
+                    .get_mut_curr()?
+                    .buffer
+                    .move_cursor(Movement::Up, is_shift);
+                false
+            }
+            Code::PageUp | Code::PageDown => {
+                let page = self.visible_lines();
+                let lines = if key.code == Code::PageUp {
+                    -(page as isize)
+                } else {
+                    page as isize
+                };
+                {
+                    let mut buffers = lock!(mut buffers);
+                    buffers
+                        .get_mut_curr()?
+                        .buffer
+                        .move_cursor(Movement::Lines(lines), is_shift);
+                }
+                self.scroll(lines)?;
+                outcome.repaint = true;
+                false
+            }
+            Code::Home => {
+                let mut buffers = lock!(mut buffers);
+                outcome.repaint = buffers
+                    .get_mut_curr()?
+                    .buffer
+                    .move_cursor(Movement::LineStart, is_shift);
+                false
+            }
+            Code::End if !key.mods.ctrl() => {
+                let mut buffers = lock!(mut buffers);
+                outcome.repaint = buffers
+                    .get_mut_curr()?
+                    .buffer
+                    .move_cursor(Movement::LineEnd, is_shift);
+                false
+            }
+            Code::Backspace => self.do_action(Action::Backspace, data)?,
+            Code::Delete => self.do_action(Action::Delete, data)?,
+            Code::Enter | Code::Tab
+                if curr_buf!(has_completions)
+                    && lock!(conf)
+                        .edit
+                        .completion_accept
+                        .contains(&match key.code {
+                            Code::Enter => AcceptKey::Enter,
+                            _ => AcceptKey::Tab,
+                        }) =>
+            {
+                self.accept_completion()?
+            }
+            Code::Enter => {
+                let text = {
+                    let buffers = lock!(buffers);
+                    let buf = buffers.get_curr()?;
+                    let indent = buf
+                        .buffer
+                        .newline_indent(buf.buffer.cursor().min(), &buf.lsp_lang);
+                    format!("\n{}", indent)
+                };
+                self.do_action(Action::Insert(text), data)?
+            }
+            Code::Escape => {
+                let mut buffers = lock!(mut buffers);
+                let buffer = &mut buffers.get_mut_curr()?.buffer;
+                let had_signature = buffer.signature.take().is_some();
+                let had_snippet = buffer.end_snippet();
+                let had_completions = !std::mem::take(&mut buffer.completions).is_empty();
+                self.triggered = None;
+                outcome.repaint =
+                    buffer.collapse_cursors() || had_signature || had_snippet || had_completions;
+                false
+            }
+            Code::Tab if lock!(mut buffers).get_mut_curr()?.buffer.next_stop() => true,
+            Code::Tab => {
+                let unit = lock!(buffers).get_curr()?.buffer.indent_unit();
+                self.do_action(Action::Insert(unit), data)?
+            }
+            _ => match typed_text(key) {
+                Some(text) => {
+                    let accepted = self.commits_completion(&text)? && self.accept_completion()?;
+                    let dirty = self.do_action(Action::Insert(text.clone()), data)? || accepted;
+                    match text.as_str() {
+                        "(" | "," => self.request_signature_help().ignore(),
+                        ")" => lock!(mut buffers).get_mut_curr()?.buffer.signature = None,
+                        _ => {}
                     }
-                    Code::ArrowUp => {
-                        let mut buffers = lock!(mut buffers);
-                        buffers
-                            .get_mut_curr()?
-                            .buffer
-                            .move_cursor(Movement::Up, is_shift)
+                    let id = curr_buf!(id);
+                    let trigger = lsp_trigger_characters(id)
+                        .into_iter()
+                        .find(|t| text.ends_with(t.as_str()));
+                    if let (Some(trigger), true) = (trigger, dirty) {
+                        self.triggered = Some((Instant::now(), id, trigger));
                     }
-                    Code::Backspace => self.do_action(Action::Backspace, data)?,
-                    Code::Delete => self.do_action(Action::Delete, data)?,
-                    Code::Enter => self.do_action(Action::Insert("\n".into()), data)?,
-                    Code::KeyS if key.mods.ctrl() => {
-                        let uri = curr_buf!(uri);
-
-                        if let Some(uri) = uri {
-                            let id = curr_buf!(id);
-                            let buffers = lock!(buffers);
-                            // get buffer rope
-                            let buf = buffers.get_curr()?;
-                            let rope = buf.buffer.rope();
-                            // if buffer source is a file
-                            if let BufferSource::File { path } = &buf.source {
-                                rope.write_to(path.writer())?;
-                                lsp_send(
-                                    id,
-                                    LspInput::SavedFile {
-                                        uri,
-                                        content: buf.buffer.text(),
-                                    },
-                                )
-                                .ignore();
-                            }
-                        }
+                    dirty
+                }
+                None => false,
+            },
+        };
+        if dirty {
+            self.calculate_highlight().ignore();
+            if let Some(search) = &mut self.search {
+                let buffers = lock!(buffers);
+                search.update(&buffers.get_curr()?.buffer);
+            }
+            outcome.repaint = true;
+        }
+        if outcome.repaint {
+            self.fix_scroll()?;
+        }
+        Ok(outcome)
+    }
 
-                        false
-                    }
-                    _ => {
-                        let code = key.key.legacy_charcode();
-                        if code == 0 {
-                            false
-                        } else {
-                            let char = char::from_u32(code);
-                            if let Some(char) = char {
-                                self.do_action(Action::Insert(String::from(char)), data)?
-                            } else {
-                                false
-                            }
-                        }
+    fn process(
+        &mut self,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut AppState,
+    ) -> anyhow::Result<()> {
+        let mut repaint = self.sync_buffer()?;
+
+        match event {
+            Event::Command(cmd) if cmd.is(EXPORT_FILE) => {
+                let path = cmd.get_unchecked(EXPORT_FILE).path();
+                let format =
+                    ExportFormat::from_extension(path.extension().and_then(|e| e.to_str()));
+                std::fs::write(path, self.export(format)?)?;
+            }
+            Event::Command(cmd) if cmd.is(SAVE_AS_FILE) => {
+                let path = cmd.get_unchecked(SAVE_AS_FILE).path();
+                self.save_as(path)?;
+                repaint = true;
+            }
+            Event::Command(cmd) if cmd.is(OPEN_FOLDER) => {
+                let path = cmd.get_unchecked(OPEN_FOLDER).path();
+                set_root(FS.path(path.to_string_lossy()));
+                if let Err(e) = self.watch(path) {
+                    error(format!("cannot watch {}: {}", path.display(), e));
+                }
+                ctx.submit_command(ROOT_CHANGED);
+                ctx.submit_command(REVEAL_CURRENT);
+            }
+            Event::Command(cmd) if cmd.is(BUFFER_CHANGED) => repaint = true,
+            Event::Command(cmd) if cmd.is(REVEAL_CURSOR) => {
+                let head = curr_buf!(cursor).head;
+                self.reveal((head, head), false)?;
+                repaint = true;
+            }
+            Event::Timer(token) if Some(*token) == self.trigger_timer => {
+                self.trigger_timer = None;
+                if let Some(wait) = self.request_triggered_completion()? {
+                    self.trigger_timer = Some(ctx.request_timer(wait));
+                }
+            }
+            Event::Timer(_timer) => {
+                let outcome = self.recv_lsp_event().unwrap_or_default();
+                for cmd in outcome.commands {
+                    ctx.submit_command(cmd);
+                }
+                repaint |= outcome.repaint;
+                self.refresh_symbols().ignore();
+                let changed = self
+                    .watcher
+                    .as_ref()
+                    .map(|w| w.changed())
+                    .unwrap_or_default();
+                if !changed.is_empty() {
+                    let outcome = self.files_changed(&changed)?;
+                    for cmd in outcome.commands {
+                        ctx.submit_command(cmd);
                     }
-                };
-                if dirty {
-                    self.calculate_highlight().ignore();
+                    repaint |= outcome.repaint;
+                }
+                let themes = self
+                    .theme_watcher
+                    .as_ref()
+                    .map(|w| w.changed())
+                    .unwrap_or_default();
+                let outcome = self.themes_changed(&themes);
+                for cmd in outcome.commands {
+                    ctx.submit_command(cmd);
+                }
+                repaint |= outcome.repaint;
+                ctx.request_timer(Duration::from_millis(250));
+            }
+            Event::KeyDown(key) => {
+                let outcome = self.key_down(key, data)?;
+                for cmd in outcome.commands {
+                    ctx.submit_command(cmd);
+                }
+                repaint |= outcome.repaint;
+                // one timer at a time, it waits again while the typing goes on
+                if self.triggered.is_some() && self.trigger_timer.is_none() {
+                    self.trigger_timer = Some(ctx.request_timer(TRIGGER_DELAY));
                 }
-                self.fix_scroll()?;
-                ctx.request_paint();
             }
             Event::Wheel(e) => {
-                if e.wheel_delta.y < 0.0 {
-                    self.scroll(-3)?;
-                    ctx.request_paint();
+                let delta = if e.mods.shift() {
+                    Vec2::new(e.wheel_delta.y, e.wheel_delta.x)
+                } else {
+                    e.wheel_delta
+                };
+                repaint |= self.wheel(delta)?;
+            }
+            Event::MouseDown(e)
+                if e.button.is_left()
+                    && e.pos.x >= ctx.size().width - SCROLLBAR_WIDTH
+                    && self.scrollbar.is_some() =>
+            {
+                // without a scrollbar the click goes to the text under it
+                if let Some(thumb) = self.scrollbar {
+                    // clicking the track centers the thumb under the mouse
+                    let grab = if thumb.y0 <= e.pos.y && e.pos.y <= thumb.y1 {
+                        e.pos.y - thumb.y0
+                    } else {
+                        thumb.height() / 2.0
+                    };
+                    self.scrollbar_grab = Some(grab);
+                    ctx.set_active(true);
+                    repaint |= self.drag_scrollbar(e.pos.y - grab, ctx.size().height)?;
+                }
+            }
+            Event::MouseMove(e) => {
+                if let Some(grab) = self.scrollbar_grab {
+                    repaint |= self.drag_scrollbar(e.pos.y - grab, ctx.size().height)?;
                 }
-                if e.wheel_delta.y > 0.0 {
-                    self.scroll(3)?;
-                    ctx.request_paint();
+            }
+            Event::MouseUp(_) => {
+                if self.scrollbar_grab.take().is_some() {
+                    ctx.set_active(false);
+                }
+            }
+            Event::MouseDown(e)
+                if e.button.is_left()
+                    && self.sticky.iter().any(|(area, _)| area.contains(e.pos)) =>
+            {
+                let start = self
+                    .sticky
+                    .iter()
+                    .find(|(area, _)| area.contains(e.pos))
+                    .map(|(_, start)| *start);
+                if let Some(start) = start {
+                    // back to the header line in the text
+                    self.reveal((start, start), false)?;
+                    repaint = true;
                 }
+                ctx.request_focus();
             }
             Event::MouseDown(e) => {
                 if e.button.is_left() {
-                    let found = self
-                        .char_points
-                        .iter()
-                        .sorted_by_key(|(p, _)| p.distance(e.pos.clone()) as i64)
-                        .next()
-                        .map(|(_, idx)| idx.clone());
-                    if let Some(idx) = found {
-                        {
+                    if let Some(idx) = caret_at(&self.painted_lines, e.pos) {
+                        let moved = {
                             let mut buffers = lock!(mut buffers);
-                            buffers
-                                .get_mut_curr()?
-                                .buffer
-                                .move_cursor(Movement::Index(idx), e.mods.shift());
+                            let buffer = &mut buffers.get_mut_curr()?.buffer;
+                            // double click selects a word, triple click a line,
+                            // alt+click adds a caret
+                            let bounds = match e.count {
+                                2 => Some(buffer.word_bounds_at(idx)),
+                                3 => Some(buffer.line_bounds(buffer.row_at(idx))),
+                                _ => None,
+                            };
+                            match bounds {
+                                Some(bounds) => {
+                                    buffer.set_selections(vec![bounds]);
+                                    true
+                                }
+                                None if e.mods.alt() => buffer.add_selection((idx, idx), true),
+                                None => buffer.move_cursor(Movement::Index(idx), e.mods.shift()),
+                            }
+                        };
+                        if moved {
+                            self.fix_scroll()?;
+                            repaint = true;
                         }
-                        self.fix_scroll()?;
-                        ctx.request_paint()
                     }
                 }
                 ctx.request_focus();
             }
             _ => {}
         }
+
+        if repaint {
+            ctx.request_paint();
+        }
+        Ok(())
+    }
+
+    /// Show the name of the current buffer and its modified state in the window title.
+    fn refresh_title(&mut self, ctx: &mut EventCtx) {
+        let title = window_title();
+        if title != self.title {
+            ctx.window().set_title(&title);
+            self.title = title;
+        }
+    }
+
+    /// Header lines of the definitions the top of the view is in, pinned over the text.
+    fn paint_sticky(
+        &mut self,
+        ctx: &mut PaintCtx,
+        env: &Env,
+        buf: &BufferData,
+        linenr_max_width: f64,
+        width: f64,
+    ) -> anyhow::Result<()> {
+        self.sticky = vec![];
+        let bg = lock!(theme)
+            .scope("ui.background")
+            .background
+            .unwrap_or(DEFAULT_BACKGROUND_COLOR);
+        let sticky_bg = lock!(theme)
+            .scope("ui.sticky")
+            .background
+            .unwrap_or(DEFAULT_CURSORLINE_COLOR);
+        for (n, (start, _)) in self.headers(&buf.buffer).into_iter().enumerate() {
+            let row = buf.buffer.row_at(start);
+            let top = n as f64 * self.line_height;
+            let area = Rect::new(0.0, top, width, top + self.line_height);
+            // over the text scrolled under it
+            ctx.fill(area, &bg);
+            ctx.fill(area, &sticky_bg);
+
+            let linenr = cached_drawable_text(
+                &mut self.layouts,
+                ctx,
+                env,
+                &format!("{}", row + 1),
+                &lock!(theme).scope("ui.linenr"),
+            );
+            linenr.draw(
+                ctx,
+                linenr_max_width - linenr.width() - LINE_SPACING * 2.0,
+                top + HALF_LINE_SPACING,
+            );
+
+            let bounds = buf.buffer.line_bounds(row);
+            // a header far above the viewport is out of the highlighted lines
+            let (first, last) = self.highlight_lines;
+            let header_spans;
+            let highlighted = match self.highlight.as_mut() {
+                Some(highlight) if !(first..=last).contains(&row) => {
+                    header_spans = highlight.spans(buf, bounds.0, bounds.1)?;
+                    header_spans.as_slice()
+                }
+                _ => self.highlight_spans.as_slice(),
+            };
+            let spans = style_for_range(&[highlighted], bounds.0, bounds.1, vec![])?;
+            ctx.save().unwrap();
+            ctx.clip(Rect::new(
+                linenr_max_width,
+                top,
+                width,
+                top + self.line_height,
+            ));
+            let mut x = linenr_max_width + LINE_SPACING * 2.0 - self.scroll_x;
+            let mut col = 0;
+            for span in spans {
+                let text = buf.buffer.text_slice(span.start..span.end)?;
+                let (text, _) = expand_tabs(&text, col, buf.buffer.indent.indent_width);
+                col += text.chars().count();
+                let draw_text =
+                    cached_drawable_text(&mut self.layouts, ctx, env, &text, &span.style);
+                draw_text.draw(ctx, x, top + HALF_LINE_SPACING);
+                x += draw_text.text_layout.trailing_whitespace_width();
+            }
+            ctx.restore().unwrap();
+            self.sticky.push((area, start));
+        }
         Ok(())
     }
 
+    /// Log over the bottom of the text, the newest entry last.
+    fn paint_log(&self, ctx: &mut PaintCtx, env: &Env, scrolled: usize) {
+        let area = self.text_area;
+        let top = area.y0 + area.height() * 0.6;
+        let rect = Rect::new(area.x0, top, area.x1, area.y1);
+        ctx.fill(
+            rect,
+            &lock!(theme)
+                .scope("ui.popup")
+                .background
+                .unwrap_or(DEFAULT_BACKGROUND_COLOR),
+        );
+        let entries = log();
+        if entries.is_empty() {
+            let draw_text = drawable_text(ctx, env, "No messages", &level_style(Level::Info));
+            draw_text.draw(ctx, rect.x0 + LINE_SPACING * 2.0, top + HALF_LINE_SPACING);
+            return;
+        }
+        let mut y = rect.y1 - HALF_LINE_SPACING;
+        for entry in entries.iter().rev().skip(scrolled) {
+            let mut text = entry.message.replace('\n', " ");
+            if entry.count > 1 {
+                text.push_str(&format!(" (×{})", entry.count));
+            }
+            let draw_text = drawable_text(ctx, env, &text, &level_style(entry.level));
+            y -= draw_text.height();
+            if y < top {
+                break;
+            }
+            draw_text.draw(ctx, rect.x0 + LINE_SPACING * 2.0, y);
+        }
+    }
+
+    /// Menu of the code actions under the cursor line.
+    fn paint_code_actions(&self, ctx: &mut PaintCtx, env: &Env, (x, y): (f64, f64)) {
+        let menu = match &self.code_actions {
+            Some(menu) => menu,
+            None => return,
+        };
+        let max_width = lock!(conf).render.completion_max_width;
+        let style = lock!(theme).scope("ui.text");
+        let rows = menu
+            .actions
+            .iter()
+            .map(|action| drawable_text_ellipsized(ctx, env, &action.title, &style, max_width))
+            .collect_vec();
+        let width = rows.iter().map(|r| r.width()).fold(0.0, f64::max);
+        let height: f64 = rows.iter().map(|r| r.height()).sum();
+
+        ctx.fill(
+            Rect::new(x, y, x + width, y + height),
+            &lock!(theme)
+                .scope("ui.popup")
+                .background
+                .unwrap_or(DEFAULT_BACKGROUND_COLOR),
+        );
+        let mut row_y = y;
+        for (n, row) in rows.iter().enumerate() {
+            if n == menu.selected {
+                let selected_bg = lock!(theme)
+                    .scope("ui.menu.selected")
+                    .background
+                    .unwrap_or(DEFAULT_FOREGROUND_COLOR);
+                ctx.fill(
+                    Rect::new(x, row_y, x + width, row_y + row.height()),
+                    &selected_bg.with_alpha(0.3),
+                );
+            }
+            row.draw(ctx, x, row_y);
+            row_y += row.height();
+        }
+    }
+
+    /// Signature of the call above the cursor line, with the active parameter emphasized.
+    fn paint_signature(&self, ctx: &mut PaintCtx, env: &Env, buf: &BufferData, (x, y): (f64, f64)) {
+        let signature = match &buf.buffer.signature {
+            Some(signature) => signature,
+            None => return,
+        };
+        let style = lock!(theme).scope("ui.text");
+        let mut active_style = lock!(theme).scope("ui.text.focus");
+        active_style.bold = Some(true);
+        active_style.underline = Some(true);
+
+        let label = &signature.label;
+        // the offsets come from the server, nothing is highlighted when they do not fit
+        let (start, end) = signature
+            .active
+            .filter(|(start, end)| {
+                start <= end && label.is_char_boundary(*start) && label.is_char_boundary(*end)
+            })
+            .unwrap_or((0, 0));
+        let texts = [
+            (&label[..start], &style),
+            (&label[start..end], &active_style),
+            (&label[end..], &style),
+        ]
+        .iter()
+        .filter(|(text, _)| !text.is_empty())
+        .map(|(text, style)| drawable_text(ctx, env, text, style))
+        .collect_vec();
+        let width: f64 = texts
+            .iter()
+            .map(|t| t.text_layout.trailing_whitespace_width())
+            .sum();
+        let height = texts.iter().map(|t| t.height()).fold(0.0, f64::max);
+
+        // below the cursor line when there is no room above
+        let top = y - self.line_height - height;
+        let top = if top < 0.0 { y } else { top };
+        ctx.fill(
+            Rect::new(x, top, x + width, top + height),
+            &lock!(theme)
+                .scope("ui.popup")
+                .background
+                .unwrap_or(DEFAULT_BACKGROUND_COLOR),
+        );
+        let mut text_x = x;
+        for text in texts {
+            text.draw(ctx, text_x, top);
+            text_x += text.text_layout.trailing_whitespace_width();
+        }
+    }
+
+    fn paint_completions(
+        &self,
+        ctx: &mut PaintCtx,
+        env: &Env,
+        buf: &BufferData,
+        (x, y): (f64, f64),
+    ) {
+        let completions = buf.buffer.sorted_completions().unwrap_or_else(|_| vec![]);
+        let selected = match completions.get(buf.buffer.completion_selected) {
+            Some(selected) => selected,
+            None => return,
+        };
+
+        let (max_width, max_rows) = {
+            let config = lock!(conf);
+            (
+                config.render.completion_max_width,
+                config.render.completion_rows,
+            )
+        };
+        let label_style = lock!(theme).scope("ui.text");
+        let detail_style = lock!(theme).scope("ui.text.detail");
+
+        let (start, end) = buf.buffer.completion_window(max_rows);
+        let mut rows = vec![];
+        if start > 0 {
+            let text = format!("▲ {} more", start);
+            rows.push((None, drawable_text(ctx, env, &text, &detail_style), None));
+        }
+        let first_item = rows.len();
+        rows.extend(completions[start..end].iter().map(|c| {
+            let kind_style = lock!(theme).scope(&format!("ui.completion.{}", c.kind_scope()));
+            let kind = drawable_text(ctx, env, c.kind_letter(), &kind_style);
+            let label = drawable_text_ellipsized(ctx, env, &c.label, &label_style, max_width);
+            let detail = c.original_item.detail.as_ref().map(|detail| {
+                drawable_text_ellipsized(ctx, env, detail, &detail_style, max_width / 2.0)
+            });
+            (Some(kind), label, detail)
+        }));
+        let more = completions.len() - end + buf.buffer.completions_hidden;
+        if more > 0 {
+            let text = format!("▼ {} more", more);
+            rows.push((None, drawable_text(ctx, env, &text, &detail_style), None));
+        }
+
+        // the kinds in a column of their own, before the labels
+        let kind_width = rows
+            .iter()
+            .flat_map(|(k, _, _)| k.as_ref().map(|k| k.width() + LINE_SPACING * 2.0))
+            .fold(0.0, f64::max);
+        let label_width = rows.iter().map(|(_, l, _)| l.width()).fold(0.0, f64::max);
+        let detail_width = rows
+            .iter()
+            .flat_map(|(_, _, d)| d.as_ref().map(|d| d.width()))
+            .fold(0.0, f64::max);
+        let width = if detail_width > 0.0 {
+            kind_width + label_width + LINE_SPACING * 4.0 + detail_width
+        } else {
+            kind_width + label_width
+        };
+        let height: f64 = rows.iter().map(|(_, l, _)| l.height()).sum();
+
+        let bg = lock!(theme)
+            .scope("ui.popup")
+            .background
+            .unwrap_or(DEFAULT_BACKGROUND_COLOR);
+        ctx.fill(Rect::new(x, y, x + width, y + height), &bg);
+
+        let selected_row = first_item + buf.buffer.completion_selected - start;
+        let mut row_y = y;
+        for (n, (kind, label, detail)) in rows.iter().enumerate() {
+            if n == selected_row {
+                let selected_bg = lock!(theme)
+                    .scope("ui.menu.selected")
+                    .background
+                    .unwrap_or(DEFAULT_FOREGROUND_COLOR);
+                ctx.fill(
+                    Rect::new(x, row_y, x + width, row_y + label.height()),
+                    &selected_bg.with_alpha(0.3),
+                );
+            }
+            if let Some(kind) = kind {
+                kind.draw(ctx, x + LINE_SPACING, row_y);
+            }
+            label.draw(ctx, x + kind_width, row_y);
+            if let Some(detail) = detail {
+                detail.draw(ctx, x + width - detail.width(), row_y);
+            }
+            row_y += label.height();
+        }
+
+        // full label, detail and documentation of the selected item next to the popup
+        let mut doc = selected.label.clone();
+        if let Some(detail) = &selected.original_item.detail {
+            doc.push('\n');
+            doc.push_str(detail);
+        }
+        let documentation = selected.documentation();
+        if let Some(documentation) = documentation {
+            doc.push_str("\n\n");
+            doc.push_str(&documentation.lines().take(DOC_LINES).join("\n"));
+        }
+        let truncated = drawable_text(ctx, env, &selected.label, &label_style).width() > max_width;
+        if truncated || selected.original_item.detail.is_some() || documentation.is_some() {
+            let doc = drawable_text(ctx, env, &doc, &label_style);
+            let doc_x = x + width + LINE_SPACING;
+            ctx.fill(
+                Rect::new(doc_x, y, doc_x + doc.width(), y + doc.height()),
+                &bg,
+            );
+            doc.draw(ctx, doc_x, y);
+        }
+    }
+
     fn _paint(&mut self, ctx: &mut PaintCtx, env: &Env) -> anyhow::Result<()> {
         let rect = ctx.size().to_rect();
-        let bg = THEME
+        let bg = lock!(theme)
             .scope("ui.background")
             .background
             .unwrap_or(DEFAULT_BACKGROUND_COLOR);
         ctx.fill(rect, &bg);
 
+        // the current buffer can be changed by the other widgets
+        self.sync_buffer().ignore();
+        // scrolled since the last paint, or out of the highlighted lines
+        let visible = (rect.height() / self.line_height).ceil() as usize;
+        self.ensure_highlight(self.scroll_line, self.scroll_line + visible)
+            .ignore();
+        self.layouts.next_paint();
+
         let buffers = lock!(buffers);
         let buf = buffers.get(buffers.curr()?)?;
 
@@ -310,16 +1755,19 @@ impl TextEditor {
         let rope = buf.buffer.rope();
 
         let cursor_row = buf.buffer.row();
+        // the buffer may have shrunk or been replaced, at least its last line is painted
+        self.scroll_line = min(self.scroll_line, buf.buffer.last_line());
 
         let mut line_numbers_texts = Vec::new();
         self.last_line_painted = 0;
         for n in self.scroll_line..rope.len_lines() {
             let style = if n == cursor_row {
-                THEME.scope("ui.linenr.selected")
+                lock!(theme).scope("ui.linenr.selected")
             } else {
-                THEME.scope("ui.linenr")
+                lock!(theme).scope("ui.linenr")
             };
-            let draw_text = drawable_text(ctx, env, &format!("{}", n + 1), &style);
+            let text = format!("{}", n + 1);
+            let draw_text = cached_drawable_text(&mut self.layouts, ctx, env, &text, &style);
             line_numbers_texts.push(draw_text);
         }
 
@@ -336,25 +1784,102 @@ impl TextEditor {
                     Point::new(linenr_max_width, 0.0),
                     Point::new(linenr_max_width, rect.height()),
                 ),
-                &THEME
+                &lock!(theme)
                     .scope("ui.popup")
                     .background
                     .unwrap_or(DEFAULT_BACKGROUND_COLOR),
                 1.0,
             );
 
+            self.text_area = Rect::new(linenr_max_width, 0.0, rect.width(), rect.height());
+            // text scrolled horizontally must not cover the line numbers
+            ctx.save().unwrap();
+            ctx.clip(Rect::new(
+                linenr_max_width,
+                0.0,
+                rect.width(),
+                rect.height(),
+            ));
+            let mut line_numbers = Vec::new();
+
+            let (font, size) = editor_font();
+            let mut advance = match self.advance.take() {
+                Some(advance) if advance.measures(&font, size) => advance,
+                _ => Advance::new(ctx.text(), font, size),
+            };
+            let text_x = linenr_max_width + LINE_SPACING * 2.0 - self.scroll_x;
+            let (rulers, indent_guides) = {
+                let config = lock!(conf);
+                (config.render.rulers.clone(), config.render.indent_guides)
+            };
+            if !rulers.is_empty() {
+                let color = lock!(theme)
+                    .scope("ui.ruler")
+                    .background
+                    .unwrap_or(DEFAULT_SCROLLBAR_COLOR);
+                for col in rulers {
+                    let x = text_x + advance.ruler_x(col);
+                    ctx.stroke(
+                        Line::new(Point::new(x, 0.0), Point::new(x, rect.height())),
+                        &color,
+                        1.0,
+                    );
+                }
+            }
+            // tabs are expanded to spaces, a level is as wide as its spaces
+            let indent_width = buf.buffer.indent.indent_width;
+            let level_width = advance.char_width(ctx.text(), ' ') * indent_width as f64;
+            let guide_color = {
+                let theme = lock!(theme);
+                let style = theme.scope("ui.indent-guide");
+                style
+                    .foreground
+                    .or(style.background)
+                    .unwrap_or(DEFAULT_INDENT_GUIDE_COLOR)
+            };
+            self.advance = Some(advance);
+
             let mut cursor_point = None;
 
-            let cursor = buf.buffer.cursor().head;
-            self.char_points = vec![];
+            let active = self.focused || !lock!(conf).render.dim_unfocused;
+            let selection_color = lock!(theme)
+                .scope("ui.selection")
+                .background
+                .unwrap_or(DEFAULT_SELECTION_COLOR);
+            let cursor_style = lock!(theme).scope("ui.cursor");
+            // the text color shows on the background of any theme
+            let cursor_color = cursor_style
+                .foreground
+                .or(cursor_style.background)
+                .or_else(|| lock!(theme).scope("ui.text").foreground)
+                .unwrap_or(DEFAULT_FOREGROUND_COLOR);
+            let (cursor_width, show_whitespace) = {
+                let config = lock!(conf);
+                (config.render.cursor_width, config.render.show_whitespace)
+            };
+            let whitespace_color = lock!(theme)
+                .scope("ui.whitespace")
+                .foreground
+                .unwrap_or(DEFAULT_WHITESPACE_COLOR);
+            let selection_color = if active {
+                selection_color
+            } else {
+                let (_, _, _, alpha) = selection_color.as_rgba();
+                selection_color.with_alpha(alpha * 0.4)
+            };
+
+            // the primary cursor first
+            let cursors = std::iter::once(buf.buffer.cursor())
+                .chain(buf.buffer.secondary_cursors().iter().cloned())
+                .collect_vec();
+            self.painted_lines = vec![];
             let mut y = HALF_LINE_SPACING;
 
             self.last_line_painted = 0;
 
-            let mut spans_layers = vec![];
-            spans_layers.push(self.highlight_spans.as_slice());
-            let diags_layer = DiagStyleLayer().spans(buf, 0, rope.len_chars())?;
-            spans_layers.push(&diags_layer);
+            let overlays = self.overlay_layers(buf)?;
+            let mut spans_layers = vec![self.highlight_spans.as_slice()];
+            spans_layers.extend(overlays.iter().map(Vec::as_slice));
 
             for (line_number_text, line) in
                 line_numbers_texts.iter().zip((0..).skip(self.scroll_line))
@@ -365,7 +1890,13 @@ impl TextEditor {
                 for v in &virtual_texts {
                     if let Handle::Char(idx) = v.handle {
                         if idx >= bounds.0 && idx < bounds.1 {
-                            let draw_text = drawable_text(ctx, env, &v.text, &v.style);
+                            let draw_text = cached_drawable_text(
+                                &mut self.layouts,
+                                ctx,
+                                env,
+                                &v.text,
+                                &v.style,
+                            );
                             hints.insert(idx, draw_text);
                         }
                     }
@@ -377,24 +1908,41 @@ impl TextEditor {
                     bounds.1,
                     hints.keys().copied().collect(),
                 )?;
+                // an empty line has no span, one without text holds its caret
+                if spans.is_empty() {
+                    spans.push(Span {
+                        start: bounds.0,
+                        end: bounds.0,
+                        style: Style::default(),
+                    });
+                }
 
+                // tabs are laid out as spaces, the chars are found with their offsets
+                let mut col = 0;
                 let mut draw_texts = spans
                     .iter()
                     .flat_map(|s| -> anyhow::Result<_> {
-                        Ok(drawable_text(
-                            ctx,
-                            env,
-                            &buf.buffer.text_slice(s.start..s.end)?,
-                            &s.style,
-                        ))
+                        let text = buf.buffer.text_slice(s.start..s.end)?;
+                        let (text, offsets) =
+                            expand_tabs(&text, col, buf.buffer.indent.indent_width);
+                        col += text.chars().count();
+                        let draw_text =
+                            cached_drawable_text(&mut self.layouts, ctx, env, &text, &s.style);
+                        Ok((draw_text, offsets))
                     })
                     .collect::<Vec<_>>();
 
                 for v in &virtual_texts {
                     if let Handle::LineEnd(line_idx) = v.handle {
                         if line_idx == line {
-                            let draw_text = drawable_text(ctx, env, &v.text, &v.style);
-                            draw_texts.push(draw_text);
+                            let draw_text = cached_drawable_text(
+                                &mut self.layouts,
+                                ctx,
+                                env,
+                                &v.text,
+                                &v.style,
+                            );
+                            draw_texts.push((draw_text, vec![0]));
                             spans.push(Span {
                                 start: bounds.1,
                                 end: bounds.1,
@@ -406,15 +1954,44 @@ impl TextEditor {
 
                 let max_height = draw_texts
                     .iter()
-                    .map(|l| l.height())
+                    .map(|(l, _)| l.height())
                     .max_by(|a, b| a.partial_cmp(b).unwrap())
                     .unwrap_or(line_number_text.height());
 
-                line_number_text.draw(
-                    ctx,
-                    linenr_max_width - line_number_text.width() - LINE_SPACING * 2.0,
-                    y,
-                );
+                line_numbers.push((line_number_text, y));
+                if line == self.scroll_line {
+                    self.line_height = max_height + LINE_SPACING;
+                }
+
+                if line == cursor_row {
+                    // under the selection, which is painted with the spans
+                    ctx.fill(
+                        Rect::new(
+                            linenr_max_width,
+                            y,
+                            rect.width(),
+                            y + max_height + LINE_SPACING,
+                        ),
+                        &lock!(theme)
+                            .scope("ui.cursorline")
+                            .background
+                            .unwrap_or(DEFAULT_CURSORLINE_COLOR),
+                    );
+                }
+
+                if indent_guides {
+                    for level in 0..buf.buffer.indent_depth(line) {
+                        let x = text_x + level as f64 * level_width;
+                        ctx.stroke(
+                            Line::new(
+                                Point::new(x, y),
+                                Point::new(x, y + max_height + LINE_SPACING),
+                            ),
+                            &guide_color,
+                            1.0,
+                        );
+                    }
+                }
 
                 let mut spans_with_texts = spans.into_iter().zip(draw_texts).collect_vec();
 
@@ -426,7 +2003,7 @@ impl TextEditor {
                             end: idx,
                             style: Style::default(),
                         },
-                        text,
+                        (text, vec![0]),
                     );
                     if let Some(pos) = pos {
                         spans_with_texts.insert(pos, data);
@@ -435,60 +2012,86 @@ impl TextEditor {
                     }
                 }
 
-                let mut x = linenr_max_width + LINE_SPACING * 2.0;
-                for (span, draw_text) in spans_with_texts {
+                let mut x = text_x;
+                let mut carets = vec![];
+                for (span, (draw_text, offsets)) in spans_with_texts {
                     let slice = rope.slice(span.start..span.end);
-                    for idx in span.start..span.end {
-                        if idx - span.start + 1 < slice.len_chars() {
-                            let byte_start = slice.char_to_byte(idx - span.start);
-                            let byte_end = slice.char_to_byte(idx - span.start + 1);
-                            let rects = draw_text.text_layout.rects_for_range(byte_start..byte_end);
-                            for r in rects {
-                                let point = Point::new(r.x0 + x, y + (r.y0 + r.y1) / 2.0);
-                                self.char_points.push((point, idx))
-                            }
-                        }
+                    for (i, offset) in offsets.iter().enumerate() {
+                        let hit = draw_text.text_layout.hit_test_text_position(*offset);
+                        carets.push((x + hit.point.x, span.start + i));
                     }
 
-                    let sel_min =
-                        max(span.start, buf.buffer.cursor().min()).saturating_sub(span.start);
-                    let sel_max =
-                        min(span.end, buf.buffer.cursor().max()).saturating_sub(span.start);
-
-                    if sel_min < sel_max {
-                        let rects = draw_text.text_layout.rects_for_range(sel_min..sel_max);
-                        ctx.with_save(|ctx| {
-                            ctx.transform(Affine::translate(Vec2::new(x, y)));
-                            for mut r in rects {
-                                r.y1 += LINE_SPACING;
-                                ctx.fill(
-                                    r,
-                                    &THEME
-                                        .scope("ui.selection")
-                                        .background
-                                        .unwrap_or(DEFAULT_BACKGROUND_COLOR),
-                                )
-                            }
-                        });
+                    for sel in &cursors {
+                        let sel_min = max(span.start, sel.min()).saturating_sub(span.start);
+                        let sel_max = min(span.end, sel.max()).saturating_sub(span.start);
+
+                        if sel_min < sel_max {
+                            let rects = draw_text
+                                .text_layout
+                                .rects_for_range(offsets[sel_min]..offsets[sel_max]);
+                            ctx.with_save(|ctx| {
+                                ctx.transform(Affine::translate(Vec2::new(x, y)));
+                                for mut r in rects {
+                                    r.y1 += LINE_SPACING;
+                                    ctx.fill(r, &selection_color)
+                                }
+                            });
+                        }
                     }
 
                     draw_text.draw(ctx, x, y);
 
-                    if span.start <= cursor && cursor <= span.end {
-                        let char_idx = cursor - span.start;
-                        let byte_idx = slice.char_to_byte(char_idx);
-                        let hit = draw_text.text_layout.hit_test_text_position(byte_idx);
-                        let curr_x = x + hit.point.x;
-                        let line = Line::new(
-                            Point::new(curr_x, y),
-                            Point::new(curr_x, y + max_height + LINE_SPACING),
-                        );
-                        cursor_point = Some((curr_x, y + max_height + LINE_SPACING));
-                        ctx.stroke(line, &Color::RED, 1.0);
+                    if show_whitespace {
+                        let layout = &draw_text.text_layout;
+                        let mid_y = y + max_height / 2.0;
+                        for (i, c) in slice.chars().enumerate() {
+                            if c != ' ' && c != '\t' {
+                                continue;
+                            }
+                            let x0 = x + layout.hit_test_text_position(offsets[i]).point.x;
+                            let x1 = x + layout.hit_test_text_position(offsets[i + 1]).point.x;
+                            if c == ' ' {
+                                let dot = Circle::new(Point::new((x0 + x1) / 2.0, mid_y), 1.5);
+                                ctx.fill(dot, &whitespace_color);
+                            } else {
+                                let (start, end) = (x0 + 2.0, x1 - 2.0);
+                                let mut arrow = BezPath::new();
+                                arrow.move_to((start, mid_y));
+                                arrow.line_to((end, mid_y));
+                                arrow.move_to((end - 4.0, mid_y - 4.0));
+                                arrow.line_to((end, mid_y));
+                                arrow.line_to((end - 4.0, mid_y + 4.0));
+                                ctx.stroke(arrow, &whitespace_color, 1.0);
+                            }
+                        }
+                    }
+
+                    for (n, cursor) in cursors.iter().map(|c| c.head).enumerate() {
+                        if span.start <= cursor && cursor <= span.end {
+                            let char_idx = cursor - span.start;
+                            let byte_idx = offsets[char_idx];
+                            let hit = draw_text.text_layout.hit_test_text_position(byte_idx);
+                            let curr_x = x + hit.point.x;
+                            let line = Line::new(
+                                Point::new(curr_x, y),
+                                Point::new(curr_x, y + max_height + LINE_SPACING),
+                            );
+                            if n == 0 {
+                                cursor_point = Some((curr_x, y + max_height + LINE_SPACING));
+                            }
+                            if active {
+                                ctx.stroke(line, &cursor_color, cursor_width);
+                            }
+                        }
                     }
 
                     x += draw_text.text_layout.trailing_whitespace_width();
                 }
+                self.painted_lines.push(PaintedLine {
+                    top: y,
+                    bottom: y + max_height + LINE_SPACING,
+                    carets,
+                });
 
                 y += max_height + LINE_SPACING;
 
@@ -506,33 +2109,169 @@ impl TextEditor {
                     + line_numbers_texts.len();
             }
 
+            ctx.restore().unwrap();
+
+            for (line_number_text, y) in line_numbers {
+                line_number_text.draw(
+                    ctx,
+                    linenr_max_width - line_number_text.width() - LINE_SPACING * 2.0,
+                    y,
+                );
+            }
+
+            self.paint_sticky(ctx, env, buf, linenr_max_width, rect.width())?;
+
             let cursor_point = cursor_point.unwrap_or((0.0, 0.0));
+            self.paint_completions(ctx, env, buf, cursor_point);
+            self.paint_signature(ctx, env, buf, cursor_point);
+            self.paint_code_actions(ctx, env, cursor_point);
+        }
 
-            let text = buf
-                .buffer
-                .sorted_completions()
-                .unwrap_or_else(|_| vec![])
-                .iter()
-                .take(8)
-                .map(|c| &c.label)
-                .join("\n");
+        let len_lines = buf.buffer.rope().len_lines();
+        self.scrollbar = if self.max_scroll_line(len_lines) > 0 {
+            let (top, bottom) = self.scrollbar_thumb(rect.height(), len_lines);
+            let thumb = Rect::new(rect.width() - SCROLLBAR_WIDTH, top, rect.width(), bottom);
+            ctx.fill(
+                thumb,
+                &lock!(theme)
+                    .scope("ui.scrollbar")
+                    .background
+                    .unwrap_or(DEFAULT_SCROLLBAR_COLOR),
+            );
+            Some(thumb)
+        } else {
+            None
+        };
+
+        let mut notices = vec![];
+        if let Some(indentation) = &buf.buffer.indentation {
+            if indentation.is_mixed() {
+                let retab = match lock!(conf).keymap.chord("retab") {
+                    Some(chord) => format!(", {} to retab", chord),
+                    None => String::new(),
+                };
+                notices.push(format!(
+                    " Mixed indentation on {} lines{} ",
+                    indentation.inconsistent_lines.len(),
+                    retab
+                ));
+            }
+        }
+        if buf.buffer.diagnostics_hidden > 0 {
+            notices.push(format!(
+                " +{} more diagnostics ",
+                buf.buffer.diagnostics_hidden
+            ));
+        }
+        let mut y = rect.height();
+        for text in notices {
+            let draw_text = drawable_text(ctx, env, &text, &lock!(theme).scope("ui.text"));
+            let x = rect.width() - draw_text.width() - LINE_SPACING * 4.0;
+            let top = y - draw_text.height() - LINE_SPACING;
+            ctx.fill(
+                Rect::new(x, top, x + draw_text.width(), y),
+                &lock!(theme)
+                    .scope("ui.popup")
+                    .background
+                    .unwrap_or(DEFAULT_BACKGROUND_COLOR),
+            );
+            draw_text.draw(ctx, x, top + HALF_LINE_SPACING);
+            y = top;
+        }
+
+        if let Some(search) = &self.search {
+            let cursor = buf.buffer.cursor();
+            let text = format!(
+                " Find: {}  {}/{}{} ",
+                search.query,
+                search.position(cursor.min()).unwrap_or(0),
+                search.matches.len(),
+                if search.ignore_case { "  [aA]" } else { "" }
+            );
+            let draw_text = drawable_text(ctx, env, &text, &lock!(theme).scope("ui.text"));
+            let x = rect.width() - draw_text.width() - LINE_SPACING * 4.0;
+            ctx.fill(
+                Rect::new(
+                    x,
+                    0.0,
+                    x + draw_text.width(),
+                    draw_text.height() + LINE_SPACING,
+                ),
+                &lock!(theme)
+                    .scope("ui.popup")
+                    .background
+                    .unwrap_or(DEFAULT_BACKGROUND_COLOR),
+            );
+            draw_text.draw(ctx, x, HALF_LINE_SPACING);
+        }
+
+        if let Some(scrolled) = self.log {
+            self.paint_log(ctx, env, scrolled);
+        }
 
-            let draw_text = drawable_text(ctx, env, &text, &THEME.scope("ui.text"));
+        if let Some(error) = &self.theme_error {
+            let text = format!(" {} ", error);
+            let draw_text = drawable_text(ctx, env, &text, &lock!(theme).scope("ui.text"));
+            let x = rect.width() - draw_text.width() - LINE_SPACING * 4.0;
+            let y = rect.height() - draw_text.height() - LINE_SPACING;
+            ctx.fill(
+                Rect::new(
+                    x,
+                    y,
+                    x + draw_text.width(),
+                    y + draw_text.height() + LINE_SPACING,
+                ),
+                &lock!(theme)
+                    .scope("ui.popup")
+                    .background
+                    .unwrap_or(DEFAULT_BACKGROUND_COLOR),
+            );
+            draw_text.draw(ctx, x, y + HALF_LINE_SPACING);
+        }
 
-            let rect = Rect::new(
-                cursor_point.0,
-                cursor_point.1,
-                cursor_point.0 + draw_text.width(),
-                cursor_point.1 + draw_text.height(),
+        let changed = self.changed_on_disk.first().and_then(|id| {
+            let buffers = lock!(buffers);
+            Some(buffers.get(*id).ok()?.source.path()?.file_name())
+        });
+        if let Some(name) = changed {
+            let text = format!(
+                " {} changed on disk: Ctrl+R reloads it and loses the changes, Escape keeps them ",
+                name
+            );
+            let draw_text = drawable_text(ctx, env, &text, &lock!(theme).scope("ui.text"));
+            let x = rect.width() - draw_text.width() - LINE_SPACING * 4.0;
+            ctx.fill(
+                Rect::new(
+                    x,
+                    0.0,
+                    x + draw_text.width(),
+                    draw_text.height() + LINE_SPACING,
+                ),
+                &lock!(theme)
+                    .scope("ui.popup")
+                    .background
+                    .unwrap_or(DEFAULT_BACKGROUND_COLOR),
             );
+            draw_text.draw(ctx, x, HALF_LINE_SPACING);
+        }
+
+        if let Some(rename) = &self.rename {
+            let text = format!(" Rename: {} ", rename.name);
+            let draw_text = drawable_text(ctx, env, &text, &lock!(theme).scope("ui.text"));
+            let x = rect.width() - draw_text.width() - LINE_SPACING * 4.0;
             ctx.fill(
-                rect,
-                &THEME
+                Rect::new(
+                    x,
+                    0.0,
+                    x + draw_text.width(),
+                    draw_text.height() + LINE_SPACING,
+                ),
+                &lock!(theme)
                     .scope("ui.popup")
                     .background
                     .unwrap_or(DEFAULT_BACKGROUND_COLOR),
             );
-            draw_text.draw(ctx, cursor_point.0, cursor_point.1);
+            draw_text.draw(ctx, x, HALF_LINE_SPACING);
         }
         ctx.restore().unwrap();
         Ok(())
@@ -543,28 +2282,276 @@ impl TextEditor {
     pub fn new() -> Self {
         Self {
             last_buffer_id: None,
-            char_points: vec![],
+            painted_lines: vec![],
+            text_area: Rect::ZERO,
             highlight: None,
             highlight_spans: vec![],
+            highlight_lines: (0, 0),
+            layouts: LayoutCache::default(),
             scroll_line: 0,
+            scroll_x: 0.0,
+            scroll_remainder: 0.0,
+            line_height: DEFAULT_TEXT_SIZE + LINE_SPACING,
             last_line_painted: 0,
+            search: None,
+            rename: None,
+            code_actions: None,
+            focused: false,
+            scrollbar: None,
+            scrollbar_grab: None,
+            watcher: None,
+            theme_watcher: None,
+            theme_error: None,
+            changed_on_disk: vec![],
+            log: None,
+            advance: None,
+            sticky: vec![],
+            edited: None,
+            triggered: None,
+            trigger_timer: None,
+            title: String::new(),
         }
     }
 
+    pub fn scroll_line(&self) -> usize {
+        self.scroll_line
+    }
+
+    /// Set how many lines fit in the viewport, normally computed by `paint`.
+    pub fn set_visible_lines(&mut self, lines: usize) {
+        self.last_line_painted = self.scroll_line + lines;
+    }
+
+    /// Highlight the lines around the viewport.
     pub fn calculate_highlight(&mut self) -> anyhow::Result<()> {
+        self.highlight_lines(self.scroll_line, self.last_line_painted)
+    }
+
+    /// Highlight from `first` to `last` line, plus `HIGHLIGHT_MARGIN` lines on both sides.
+    fn highlight_lines(&mut self, first: usize, last: usize) -> anyhow::Result<()> {
         let highlight = self.highlight.as_mut().context("no highlight")?;
         let buffers = lock!(buffers);
         let buf = buffers.get_curr()?;
-        let rope = buf.buffer.rope();
-        let min = 0;
-        let max = rope.len_chars();
-        self.highlight_spans = highlight.spans(buf, min, max)?;
+        let lines = (
+            first.saturating_sub(HIGHLIGHT_MARGIN),
+            max(first, last).saturating_add(HIGHLIGHT_MARGIN),
+        );
+        let start = buf.buffer.line_bounds(lines.0).0;
+        let end = buf.buffer.line_bounds(lines.1).1;
+        self.highlight_spans = highlight.spans(buf, start, end)?;
+        self.highlight_lines = lines;
+        Ok(())
+    }
+
+    /// Highlight again unless the lines from `first` to `last` already are.
+    fn ensure_highlight(&mut self, first: usize, last: usize) -> anyhow::Result<()> {
+        let (start, end) = self.highlight_lines;
+        if start <= first && last <= end {
+            return Ok(());
+        }
+        self.highlight_lines(first, last)
+    }
+
+    /// Select `bounds`, or put the cursor at its start, and scroll it into view
+    /// `SCROLL_GAP` lines away from the edges, centered when it was out of them.
+    pub fn reveal(&mut self, bounds: Bounds, select: bool) -> anyhow::Result<()> {
+        // the scroll of a buffer made current since is restored first
+        self.sync_buffer()?;
+        let (row, len_lines) = {
+            let mut buffers = lock!(mut buffers);
+            let buffer = &mut buffers.get_mut_curr()?.buffer;
+            if select {
+                buffer.set_selections(vec![bounds]);
+            } else {
+                buffer.move_cursor(Movement::Index(bounds.0), false);
+            }
+            (buffer.row_at(bounds.0), buffer.rope().len_lines())
+        };
+
+        let visible = self.visible_lines();
+        let gap = min(SCROLL_GAP, visible / 2);
+        if row < self.scroll_line + gap || row + gap >= self.scroll_line + visible {
+            self.scroll_line = min(
+                row.saturating_sub(visible / 2),
+                self.max_scroll_line(len_lines),
+            );
+            self.last_line_painted = self.scroll_line + visible;
+        }
+
+        // horizontally, the position is only known on the lines painted
+        let x = self
+            .painted_lines
+            .iter()
+            .flat_map(|line| &line.carets)
+            .find(|(_, idx)| *idx == bounds.0)
+            .map(|(x, _)| *x);
+        if let Some(x) = x {
+            let margin = self.line_height * 2.0;
+            if x < self.text_area.x0 {
+                self.scroll_x = (self.scroll_x - (self.text_area.x0 - x) - margin).max(0.0);
+            } else if x > self.text_area.x1 {
+                self.scroll_x += x - self.text_area.x1 + margin;
+            }
+        }
+        Ok(())
+    }
+
+    /// Save the current buffer to `path`, its language can change with the name.
+    pub fn save_as(&mut self, path: &std::path::Path) -> anyhow::Result<()> {
+        {
+            let mut buffers = lock!(mut buffers);
+            let id = buffers.curr()?;
+            buffers.save_as(id, path)?;
+        }
+        // highlighted again by `sync_buffer`
+        self.last_buffer_id = None;
+        self.sync_buffer()?;
         Ok(())
     }
 
-    fn scroll(&mut self, scroll: isize) -> anyhow::Result<()> {
+    /// Follow a change of the current buffer, returns whether it changed.
+    fn sync_buffer(&mut self) -> anyhow::Result<bool> {
+        let id = curr_buf!(id);
+        let old = self.last_buffer_id.replace(id);
+        {
+            // each buffer keeps its scroll, for the tabs and the session
+            let mut buffers = lock!(mut buffers);
+            if let Some(data) = old.and_then(|old| buffers.get_mut(old).ok()) {
+                data.scroll_line = self.scroll_line;
+            }
+            if old == Some(id) {
+                return Ok(false);
+            }
+            let visible = self.visible_lines();
+            self.scroll_line = buffers.get(id)?.scroll_line;
+            self.last_line_painted = self.scroll_line + visible;
+        }
+        self.rename = None;
+        let (lang, large_file) = {
+            let buffers = lock!(buffers);
+            let buf = buffers.get_curr()?;
+            (buf.lsp_lang.clone(), buf.large_file)
+        };
+        // a large file is painted as plain text
+        self.highlight = if large_file {
+            None
+        } else {
+            TreeSitterHighlight::new(lang)
+        };
+        // those of the previous buffer, when this one has no highlight
+        self.highlight_spans.clear();
+        self.calculate_highlight().ignore();
+        Ok(true)
+    }
+
+    /// Layers drawn over the syntax highlight, in increasing priority.
+    fn overlay_layers(&self, buf: &BufferData) -> anyhow::Result<Vec<Vec<Span>>> {
+        let len = buf.buffer.rope().len_chars();
+        let search = match &self.search {
+            Some(search) => SearchStyleLayer(search).spans(buf, 0, len)?,
+            None => vec![],
+        };
+        // the brackets can be far apart, they are matched around the viewport only
+        let brackets = if buf.large_file {
+            vec![]
+        } else {
+            let first = self.scroll_line.saturating_sub(HIGHLIGHT_MARGIN);
+            let last = max(self.scroll_line, self.last_line_painted) + HIGHLIGHT_MARGIN;
+            let start = buf.buffer.line_bounds(first).0;
+            let end = buf.buffer.line_bounds(last).1;
+            BracketStyleLayer(self.highlight.as_ref()).spans(buf, start, end)?
+        };
+        // every line is checked for its indentation
+        let indents = if buf.large_file {
+            vec![]
+        } else {
+            IndentStyleLayer().spans(buf, 0, len)?
+        };
+        Ok(vec![
+            DiagStyleLayer().spans(buf, 0, len)?,
+            brackets,
+            search,
+            indents,
+        ])
+    }
+
+    /// Style of the current buffer between `min` and `max` as it is painted,
+    /// without overlapping spans.
+    pub fn spans_for_range(&mut self, min: Index, max: Index) -> anyhow::Result<Vec<Span>> {
+        self.sync_buffer()?;
+        let lines = {
+            let buffers = lock!(buffers);
+            let buf = &buffers.get_curr()?.buffer;
+            (buf.row_at(min), buf.row_at(max))
+        };
+        self.ensure_highlight(lines.0, lines.1).ignore();
+        let buffers = lock!(buffers);
+        let buf = buffers.get_curr()?;
+        let overlays = self.overlay_layers(buf)?;
+        let mut layers = vec![self.highlight_spans.as_slice()];
+        layers.extend(overlays.iter().map(Vec::as_slice));
+        style_for_range(&layers, min, max, vec![])
+    }
+
+    /// Scroll by a wheel delta in pixels, returns whether the view moved.
+    fn wheel(&mut self, delta: Vec2) -> anyhow::Result<bool> {
+        let invert = {
+            let config = lock!(conf);
+            config.scroll.invert
+        };
+        let delta = if invert { -delta } else { delta };
+
+        self.scroll_remainder += delta.y / self.line_height;
+        let lines = self.scroll_remainder.trunc();
+        self.scroll_remainder -= lines;
+        let mut changed = self.scroll(lines as isize)?;
+
+        let scroll_x = (self.scroll_x + delta.x).max(0.0);
+        changed |= scroll_x != self.scroll_x;
+        self.scroll_x = scroll_x;
+
+        Ok(changed)
+    }
+
+    /// Vertical extent of the scrollbar thumb in a track of `height`,
+    /// the thumb is a page out of `len_lines` and reaches the bottom at `max_scroll_line`.
+    fn scrollbar_thumb(&self, height: f64, len_lines: usize) -> (f64, f64) {
+        let visible = self.visible_lines() as f64;
+        let thumb = (height * visible / (len_lines as f64).max(visible))
+            .max(SCROLLBAR_MIN_THUMB)
+            .min(height);
+        let max_scroll = self.max_scroll_line(len_lines);
+        let top = if max_scroll == 0 {
+            0.0
+        } else {
+            (height - thumb) * self.scroll_line as f64 / max_scroll as f64
+        };
+        (top, top + thumb)
+    }
+
+    /// Scroll so that the thumb starts at `top`, returns whether the view moved.
+    fn drag_scrollbar(&mut self, top: f64, height: f64) -> anyhow::Result<bool> {
+        let len_lines = {
+            let buffers = lock!(buffers);
+            buffers.get_curr()?.buffer.rope().len_lines()
+        };
+        let (thumb_top, thumb_bottom) = self.scrollbar_thumb(height, len_lines);
+        let track = height - (thumb_bottom - thumb_top);
+        if track <= 0.0 {
+            return Ok(false);
+        }
+        let line = (top / track * self.max_scroll_line(len_lines) as f64)
+            .round()
+            .max(0.0) as usize;
+        self.scroll(line as isize - self.scroll_line as isize)
+    }
+
+    /// Returns whether the scroll position changed.
+    fn scroll(&mut self, scroll: isize) -> anyhow::Result<bool> {
         let buffers = lock!(buffers);
         let buf = buffers.get(buffers.curr()?)?;
+        let old = self.scroll_line;
+        let visible = self.visible_lines();
 
         if scroll < 0 {
             self.scroll_line = self.scroll_line.saturating_sub(scroll.abs() as usize)
@@ -573,25 +2560,39 @@ impl TextEditor {
             self.scroll_line = self.scroll_line.saturating_add(scroll as usize)
         }
 
-        self.scroll_line = min(self.scroll_line, buf.buffer.rope().len_lines() - 1);
-        Ok(())
+        self.scroll_line = min(
+            self.scroll_line,
+            self.max_scroll_line(buf.buffer.rope().len_lines()),
+        );
+        self.last_line_painted = self.scroll_line + visible;
+        Ok(old != self.scroll_line)
     }
 }
 
 impl Widget<AppState> for TextEditor {
     fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut AppState, _env: &Env) {
         if let Err(e) = self.process(ctx, event, data) {
-            println!("{}", e);
+            error(e.to_string());
         }
+        // the buffer may have changed before a failure too
+        self.refresh_title(ctx);
     }
 
     fn lifecycle(
         &mut self,
         ctx: &mut LifeCycleCtx,
-        _event: &LifeCycle,
+        event: &LifeCycle,
         _data: &AppState,
         _env: &Env,
     ) {
+        if let LifeCycle::FocusChanged(focused) = event {
+            self.focused = *focused;
+            // the file may have been changed by another program meanwhile
+            if *focused && self.reload().unwrap_or(false) {
+                self.calculate_highlight().ignore();
+            }
+            ctx.request_paint();
+        }
         ctx.request_timer(Duration::from_millis(100));
     }
 
@@ -610,7 +2611,7 @@ impl Widget<AppState> for TextEditor {
 
     fn paint(&mut self, ctx: &mut PaintCtx, _data: &AppState, env: &Env) {
         if let Err(e) = self._paint(ctx, env) {
-            println!("failed to paint : {}", e)
+            error(format!("failed to paint : {}", e))
         }
     }
 }
@@ -0,0 +1,216 @@
+use druid::Color;
+use std::fmt::Write;
+
+use crate::buffer::{Bounds, Buffer};
+use crate::style_layer::{style_for_range, Span};
+use crate::theme::Style;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Html,
+    Rtf,
+    PlainText,
+}
+
+impl ExportFormat {
+    pub fn from_extension(ext: Option<&str>) -> Self {
+        match ext.map(|e| e.to_lowercase()).as_deref() {
+            Some("html" | "htm") => ExportFormat::Html,
+            Some("rtf") => ExportFormat::Rtf,
+            _ => ExportFormat::PlainText,
+        }
+    }
+}
+
+/// Export the text between `bounds` using the highlight `spans` (possibly overlapping).
+pub fn export(
+    buffer: &Buffer,
+    spans: &[Span],
+    bounds: Bounds,
+    format: ExportFormat,
+) -> anyhow::Result<String> {
+    let spans = style_for_range(&[spans], bounds.0, bounds.1, vec![])?;
+    let mut parts = Vec::with_capacity(spans.len());
+    for span in spans {
+        let text = buffer.text_slice(span.start..span.end)?;
+        parts.push((text, span.style));
+    }
+    Ok(match format {
+        ExportFormat::Html => to_html(&parts),
+        ExportFormat::Rtf => to_rtf(&parts),
+        ExportFormat::PlainText => parts.into_iter().map(|(text, _)| text).collect(),
+    })
+}
+
+fn hex(color: &Color) -> String {
+    let rgba = color.as_rgba_u32();
+    format!("#{:06x}", rgba >> 8)
+}
+
+fn rgb(color: &Color) -> (u8, u8, u8) {
+    let rgba = color.as_rgba_u32();
+    ((rgba >> 24) as u8, (rgba >> 16) as u8, (rgba >> 8) as u8)
+}
+
+fn css(style: &Style) -> String {
+    let mut css = String::new();
+    if let Some(fg) = &style.foreground {
+        write!(css, "color:{};", hex(fg)).unwrap();
+    }
+    if let Some(bg) = &style.background {
+        write!(css, "background-color:{};", hex(bg)).unwrap();
+    }
+    if let Some(true) = style.bold {
+        css.push_str("font-weight:bold;");
+    }
+    if let Some(true) = style.italic {
+        css.push_str("font-style:italic;");
+    }
+    if let Some(true) = style.underline {
+        css.push_str("text-decoration:underline;");
+    }
+    css
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn to_html(parts: &[(String, Style)]) -> String {
+    let mut html = String::from("<pre style=\"font-family:monospace;\">");
+    for (text, style) in parts {
+        let css = css(style);
+        if css.is_empty() {
+            html.push_str(&escape_html(text));
+        } else {
+            write!(html, "<span style=\"{}\">{}</span>", css, escape_html(text)).unwrap();
+        }
+    }
+    html.push_str("</pre>");
+    html
+}
+
+fn escape_rtf(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '{' | '}' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("\\line\n"),
+            '\r' => {}
+            '\t' => out.push_str("\\tab "),
+            c if (c as u32) < 0x80 => out.push(c),
+            c => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    write!(out, "\\u{}?", *unit as i16).unwrap();
+                }
+            }
+        }
+    }
+    out
+}
+
+fn to_rtf(parts: &[(String, Style)]) -> String {
+    let mut colors: Vec<(u8, u8, u8)> = vec![];
+    let mut color_index = |color: &Color| {
+        let rgb = rgb(color);
+        let pos = colors.iter().position(|c| *c == rgb).unwrap_or_else(|| {
+            colors.push(rgb);
+            colors.len() - 1
+        });
+        // index 0 is the auto color
+        pos + 1
+    };
+
+    let mut body = String::new();
+    for (text, style) in parts {
+        body.push('{');
+        if let Some(fg) = &style.foreground {
+            write!(body, "\\cf{}", color_index(fg)).unwrap();
+        }
+        if let Some(bg) = &style.background {
+            write!(body, "\\highlight{}", color_index(bg)).unwrap();
+        }
+        if let Some(true) = style.bold {
+            body.push_str("\\b");
+        }
+        if let Some(true) = style.italic {
+            body.push_str("\\i");
+        }
+        if let Some(true) = style.underline {
+            body.push_str("\\ul");
+        }
+        body.push(' ');
+        body.push_str(&escape_rtf(text));
+        body.push('}');
+    }
+
+    let mut rtf = String::from("{\\rtf1\\ansi\\deff0{\\fonttbl{\\f0\\fmodern Courier New;}}");
+    rtf.push_str("{\\colortbl;");
+    for (r, g, b) in &colors {
+        write!(rtf, "\\red{}\\green{}\\blue{};", r, g, b).unwrap();
+    }
+    rtf.push_str("}\\f0 ");
+    rtf.push_str(&body);
+    rtf.push('}');
+    rtf
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use druid::Color;
+
+    use crate::buffer::Buffer;
+    use crate::export::{export, ExportFormat};
+    use crate::style_layer::Span;
+    use crate::theme::Style;
+
+    #[test]
+    fn html() {
+        let buf = Buffer::from_reader(1, Cursor::new("a < b"));
+        let mut style = Style::default();
+        style.foreground = Some(Color::rgb8(0xff, 0x00, 0x10));
+        let spans = vec![Span {
+            start: 2,
+            end: 3,
+            style,
+        }];
+        let html = export(&buf, &spans, (0, 5), ExportFormat::Html).unwrap();
+        assert_eq!(
+            html,
+            "<pre style=\"font-family:monospace;\">a <span style=\"color:#ff0010;\">&lt;</span> b</pre>"
+        );
+    }
+
+    #[test]
+    fn rtf() {
+        let buf = Buffer::from_reader(1, Cursor::new("{x}"));
+        let mut style = Style::default();
+        style.bold = Some(true);
+        style.foreground = Some(Color::rgb8(1, 2, 3));
+        let spans = vec![Span {
+            start: 1,
+            end: 2,
+            style,
+        }];
+        let rtf = export(&buf, &spans, (0, 3), ExportFormat::Rtf).unwrap();
+        assert!(rtf.contains("{\\colortbl;\\red1\\green2\\blue3;}"));
+        assert!(rtf.contains("{ \\{}{\\cf1\\b x}{ \\}}"));
+    }
+}
@@ -1,14 +1,49 @@
-use std::fs::{File as StdFile, File};
+use std::cell::RefCell;
+use std::fs::File as StdFile;
+use std::io::{Read, Write};
 use std::path::PathBuf;
 
-use crate::lock;
-use crate::lsp::LspLang;
+use crate::icons::icon_for;
+use crate::lsp::{lsp_send_to, LspInput, LspLang};
+use crate::mem_fs::MemFs;
+use crate::status::error;
 use crate::tree::{ItemStyle, ShouldRepaint, Tree};
+use crate::{lock, BufferSource, Ignore};
 use druid::{Data, KbKey};
 use lsp_types::Url;
 
+thread_local! {
+    // files of the paths used on this thread, on disk when `None`
+    static MEM_FS: RefCell<Option<MemFs>> = RefCell::new(None);
+}
+
+/// Keep the files of the paths used on this thread in `fs`, instead of on disk when `None`.
+/// Returns the previous one. For the tests, which run on their own thread.
+pub fn swap_fs(fs: Option<MemFs>) -> Option<MemFs> {
+    MEM_FS.with(|mem| mem.replace(fs))
+}
+
+fn mem_fs() -> Option<MemFs> {
+    MEM_FS.with(|mem| mem.borrow().clone())
+}
+
 #[derive(Default, Clone, Eq, PartialEq, Ord, PartialOrd)]
-pub struct LocalFs {}
+pub struct LocalFs {
+    /// removed if Delete is pressed again while it is selected
+    deleting: Option<LocalPath>,
+    renaming: Option<Renaming>,
+    /// the new path of the last renamed item
+    renamed: Option<LocalPath>,
+}
+
+/// Name of an item being edited in the tree.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
+struct Renaming {
+    path: LocalPath,
+    name: String,
+    /// a sibling already has the name
+    taken: bool,
+}
 
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct LocalPath {
@@ -22,6 +57,41 @@ impl LocalPath {
     pub fn extension(&self) -> Option<String> {
         self.inner.extension().map(|e| e.to_str().unwrap().into())
     }
+
+    pub fn is_dir(&self) -> bool {
+        match mem_fs() {
+            Some(fs) => fs.path(self.inner.to_string_lossy()).is_dir(),
+            None => self.inner.is_dir(),
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        match mem_fs() {
+            Some(fs) => fs.path(self.inner.to_string_lossy()).is_file(),
+            None => self.inner.is_file(),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        match mem_fs() {
+            Some(_) => self.is_file() || self.is_dir(),
+            None => self.inner.exists(),
+        }
+    }
+
+    /// The absolute path without links, `None` when nothing is there.
+    fn canonical(&self) -> Option<PathBuf> {
+        match mem_fs() {
+            Some(_) => Some(self.inner.clone()).filter(|_| self.exists()),
+            None => self.inner.canonicalize().ok(),
+        }
+    }
+}
+
+impl AsRef<std::path::Path> for LocalPath {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.inner
+    }
 }
 
 impl Data for LocalPath {
@@ -37,12 +107,30 @@ impl FileSystem for LocalFs {
     where
         S: Into<String>,
     {
-        LocalPath {
-            inner: PathBuf::from(path.into()).canonicalize().unwrap(),
+        if let Some(fs) = mem_fs() {
+            return LocalPath {
+                inner: fs.path(path).path().into(),
+            };
         }
+        let path = PathBuf::from(path.into());
+        // a missing file, e.g. the target of a save, is only made absolute
+        let inner = match path.canonicalize() {
+            Ok(path) => path,
+            Err(_) => std::env::current_dir().map_or(path.clone(), |dir| dir.join(&path)),
+        };
+        LocalPath { inner }
     }
 
     fn list(&self, path: Self::Path) -> Vec<Self::Path> {
+        if let Some(fs) = mem_fs() {
+            return fs
+                .list(fs.path(path.inner.to_string_lossy()))
+                .into_iter()
+                .map(|p| LocalPath {
+                    inner: p.path().into(),
+                })
+                .collect();
+        }
         if path.inner.is_dir() {
             path.inner
                 .read_dir()
@@ -58,32 +146,11 @@ impl FileSystem for LocalFs {
 }
 
 impl Path for LocalPath {
-    type Reader = File;
-    type Writer = File;
+    type Reader = Box<dyn Read>;
+    type Writer = Box<dyn Write>;
 
     fn lsp_lang(&self) -> LspLang {
-        let name = self.name();
-        let config = lock!(conf);
-        let lang = config
-            .extensions
-            .iter()
-            .find(|e| e.file_names.contains(&name))
-            .map(|e| e.lang.clone());
-
-        if let Some(lang) = lang {
-            return lang;
-        }
-
-        if let Some(ext) = self.extension() {
-            config
-                .extensions
-                .iter()
-                .find(|e| e.file_extension.contains(&ext))
-                .map(|e| e.lang.clone())
-                .unwrap_or(LspLang::PlainText)
-        } else {
-            LspLang::PlainText
-        }
+        lang_for(&self.name(), self.extension())
     }
 
     fn name(&self) -> String {
@@ -91,7 +158,11 @@ impl Path for LocalPath {
     }
 
     fn path(&self) -> String {
-        self.inner.canonicalize().unwrap().to_str().unwrap().into()
+        self.canonical()
+            .as_ref()
+            .unwrap_or(&self.inner)
+            .to_string_lossy()
+            .into()
     }
 
     fn uri(&self) -> Url {
@@ -99,10 +170,41 @@ impl Path for LocalPath {
     }
 
     fn reader(&self) -> Self::Reader {
-        StdFile::open(&self.inner).unwrap()
+        match mem_fs() {
+            Some(fs) => Box::new(fs.path(self.inner.to_string_lossy()).reader()),
+            None => Box::new(StdFile::open(&self.inner).unwrap()),
+        }
     }
     fn writer(&self) -> Self::Writer {
-        StdFile::create(&self.inner).unwrap()
+        match mem_fs() {
+            Some(fs) => Box::new(fs.path(self.inner.to_string_lossy()).writer()),
+            None => Box::new(StdFile::create(&self.inner).unwrap()),
+        }
+    }
+}
+
+/// Language of a file from its name, or else its extension, as configured.
+pub(crate) fn lang_for(name: &str, extension: Option<String>) -> LspLang {
+    let config = lock!(conf);
+    let lang = config
+        .extensions
+        .iter()
+        .find(|e| e.file_names.iter().any(|n| n == name))
+        .map(|e| e.lang.clone());
+
+    if let Some(lang) = lang {
+        return lang;
+    }
+
+    if let Some(ext) = extension {
+        config
+            .extensions
+            .iter()
+            .find(|e| e.file_extension.contains(&ext))
+            .map(|e| e.lang.clone())
+            .unwrap_or(LspLang::PlainText)
+    } else {
+        LspLang::PlainText
     }
 }
 
@@ -117,8 +219,8 @@ pub trait FileSystem {
 }
 
 pub trait Path {
-    type Reader;
-    type Writer;
+    type Reader: std::io::Read;
+    type Writer: std::io::Write;
 
     fn lsp_lang(&self) -> LspLang;
     fn name(&self) -> String;
@@ -138,8 +240,17 @@ impl Tree for LocalFs {
 
     fn children(&self, parent: &Self::Key) -> Vec<Self::Key> {
         let mut list = self.list(parent.clone());
+        {
+            let config = lock!(conf);
+            let tree = &config.tree;
+            list.retain(|k| {
+                let name = k.file_name();
+                (tree.show_hidden || !name.starts_with('.'))
+                    && !tree.hidden.iter().any(|glob| glob_match(glob, &name))
+            });
+        }
         list.sort_by_key(|k| k.file_name());
-        list.sort_by_key(|k| if k.inner.is_dir() { 1 } else { 2 });
+        list.sort_by_key(|k| if k.is_dir() { 1 } else { 2 });
         list
     }
 
@@ -147,25 +258,386 @@ impl Tree for LocalFs {
 
     fn item(&self, key: &Self::Key) -> ItemStyle {
         let level = key.inner.components().count() - self.root().inner.components().count();
-        let style_scope = if key.inner.is_dir() {
+        let style_scope = if key.is_dir() {
             "tree.dir"
         } else {
             "tree.file"
         };
+        let mut text = key.file_name();
+        if self.deleting.as_ref() == Some(key) {
+            let content = if key.is_dir() { " and its content" } else { "" };
+            text = format!("{} (Delete again to remove it{})", text, content);
+        }
+        if let Some(renaming) = self.renaming.as_ref().filter(|r| &r.path == key) {
+            let taken = if renaming.taken {
+                " (already exists)"
+            } else {
+                ""
+            };
+            text = format!("{}|{}", renaming.name, taken);
+        }
         ItemStyle {
-            text: key.file_name(),
+            text,
             style_scope: style_scope.into(),
             level,
+            icon: Some(icon_for(key.extension().as_deref(), key.is_dir())),
         }
     }
 
-    fn key_down(&mut self, selected: &Self::Key, key: &KbKey) -> ShouldRepaint {
-        if key == &KbKey::Enter && selected.inner.is_file() {
-            let mut buffers = lock!(mut buffers);
-            buffers.open_file(selected.clone()).unwrap();
-            true
+    fn expandable(&self, key: &Self::Key) -> bool {
+        key.is_dir()
+    }
+
+    fn parent(&self, key: &Self::Key) -> Option<Self::Key> {
+        if *key == self.root() {
+            return None;
+        }
+        key.inner.parent().map(|p| LocalPath { inner: p.into() })
+    }
+
+    fn current(&self) -> Option<Self::Key> {
+        let path = {
+            let buffers = lock!(buffers);
+            buffers.get_curr().ok()?.source.path()?
+        };
+        if path.inner.starts_with(&self.root().inner) {
+            Some(path)
         } else {
-            false
+            None
+        }
+    }
+
+    fn key_down(&mut self, selected: &Self::Key, key: &KbKey) -> ShouldRepaint {
+        if self.renaming.is_some() {
+            return self.rename_key(key);
         }
+        let deleting = self.deleting.take();
+        let repaint = match key {
+            KbKey::Enter if selected.is_file() => {
+                let mut buffers = lock!(mut buffers);
+                buffers.open_file(selected.clone()).unwrap();
+                true
+            }
+            KbKey::Delete if *selected == self.root() => false,
+            KbKey::Delete if deleting.as_ref() == Some(selected) => {
+                if let Err(e) = remove(selected) {
+                    error(format!("cannot remove {}: {}", selected.file_name(), e));
+                }
+                true
+            }
+            KbKey::Delete => {
+                self.deleting = Some(selected.clone());
+                true
+            }
+            KbKey::Character(c) if c == "." => {
+                let mut config = lock!(mut conf);
+                config.tree.show_hidden = !config.tree.show_hidden;
+                true
+            }
+            KbKey::Character(c) if c == "r" && *selected != self.root() => {
+                self.renaming = Some(Renaming {
+                    path: selected.clone(),
+                    name: selected.file_name(),
+                    taken: false,
+                });
+                true
+            }
+            _ => false,
+        };
+        // any other key, Escape in particular, cancels a pending removal
+        repaint || deleting.is_some()
+    }
+
+    fn selection_changed(&mut self, _selected: &Self::Key) {
+        self.deleting = None;
+    }
+
+    fn editing(&self) -> bool {
+        self.renaming.is_some()
+    }
+
+    fn take_selection(&mut self) -> Option<Self::Key> {
+        self.renamed.take()
+    }
+}
+
+impl LocalFs {
+    /// Edit the name of the item being renamed, Enter renames it and Escape cancels.
+    fn rename_key(&mut self, key: &KbKey) -> ShouldRepaint {
+        let renaming = match &mut self.renaming {
+            Some(renaming) => renaming,
+            None => return false,
+        };
+        match key {
+            KbKey::Character(c) => {
+                renaming.name.push_str(c);
+                renaming.taken = false;
+            }
+            KbKey::Backspace => {
+                renaming.name.pop();
+                renaming.taken = false;
+            }
+            KbKey::Escape => self.renaming = None,
+            KbKey::Enter => {
+                let to = match renaming.path.inner.parent() {
+                    Some(parent) => LocalPath {
+                        inner: parent.join(&renaming.name),
+                    },
+                    None => return false,
+                };
+                if renaming.name.is_empty() || renaming.name.contains(std::path::MAIN_SEPARATOR) {
+                    return false;
+                }
+                if to == renaming.path {
+                    self.renaming = None;
+                } else if to.exists() {
+                    renaming.taken = true;
+                } else {
+                    match rename(&renaming.path, &to) {
+                        Ok(()) => self.renamed = Some(to),
+                        Err(e) => error(format!(
+                            "cannot rename {}: {}",
+                            renaming.path.file_name(),
+                            e
+                        )),
+                    }
+                    self.renaming = None;
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Files marking the root of a project, where a language server is started for the files
+/// out of the workspace.
+const PROJECT_FILES: &[&str] = &[
+    "Cargo.toml",
+    "pyproject.toml",
+    "setup.py",
+    "package.json",
+    ".git",
+];
+
+/// Directory the language server of `path` runs in: the workspace root when the file is in
+/// it, else the closest directory above the file with a project file, else the workspace root.
+pub fn lsp_root(path: &LocalPath) -> LocalPath {
+    let root = lock!(global).root_path.clone();
+    let file = match path.canonical() {
+        Some(file) => file,
+        None => return root,
+    };
+    if root.canonical().map_or(true, |r| file.starts_with(r)) {
+        return root;
+    }
+    file.ancestors()
+        .skip(1)
+        .find(|dir| {
+            PROJECT_FILES.iter().any(|name| {
+                LocalPath {
+                    inner: dir.join(name),
+                }
+                .exists()
+            })
+        })
+        .map(|dir| LocalPath {
+            inner: dir.to_path_buf(),
+        })
+        .unwrap_or(root)
+}
+
+/// Rename a file or a folder, the open buffers follow and their language servers are told.
+fn rename(from: &LocalPath, to: &LocalPath) -> anyhow::Result<()> {
+    let mut buffers = lock!(mut buffers);
+    // the uris and servers of the old paths are needed after the renaming
+    let moved: Vec<(u32, Url, Url, LocalPath)> = buffers
+        .buffers
+        .values()
+        .filter_map(|b| {
+            let path = b.source.path()?;
+            let rest = path.inner.strip_prefix(&from.inner).ok()?;
+            let inner = if rest.as_os_str().is_empty() {
+                to.inner.clone()
+            } else {
+                to.inner.join(rest)
+            };
+            Some((b.id, path.uri(), b.lsp_root.clone(), LocalPath { inner }))
+        })
+        .collect();
+    match mem_fs() {
+        Some(fs) => fs.rename(&from.path(), &to.path())?,
+        None => std::fs::rename(&from.inner, &to.inner)?,
+    }
+
+    for (id, uri, old_root, path) in moved {
+        let data = buffers.get_mut(id)?;
+        let (old_lang, lang) = (data.lsp_lang.clone(), path.lsp_lang());
+        data.source = BufferSource::File { path: path.clone() };
+        data.lsp_root = data.source.lsp_root();
+        data.lsp_lang = lang.clone();
+        if !data.lsp_enabled() {
+            continue;
+        }
+        lsp_send_to(old_root, old_lang, LspInput::CloseFile { uri }).ignore();
+        lsp_send_to(
+            data.lsp_root.clone(),
+            lang,
+            data.buffer.open_input(path.uri()),
+        )
+        .ignore();
+    }
+    Ok(())
+}
+
+/// Whether `name` matches `glob`, where `*` is any chars and `?` a single one.
+fn glob_match(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // positions to retry when a `*` has to match one more char
+    let (mut g, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g, n));
+                g += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_g, star_n)) => {
+                    star = Some((star_g, star_n + 1));
+                    g = star_g + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|c| *c == '*')
+}
+
+/// Remove a file or a folder with its content, the buffers of the removed files are closed.
+fn remove(path: &LocalPath) -> anyhow::Result<()> {
+    if let Some(fs) = mem_fs() {
+        fs.remove_all(&path.path())?;
+    } else if path.inner.is_dir() {
+        std::fs::remove_dir_all(&path.inner)?;
+    } else {
+        std::fs::remove_file(&path.inner)?;
+    }
+    // the buffers stay open when the removal failed
+    let mut buffers = lock!(mut buffers);
+    let removed: Vec<u32> = buffers
+        .buffers
+        .values()
+        .filter(|b| {
+            b.source
+                .path()
+                .map_or(false, |p| p.inner.starts_with(&path.inner))
+        })
+        .map(|b| b.id)
+        .collect();
+    for id in removed {
+        buffers.close(id)?;
+    }
+    if buffers.current.is_none() {
+        buffers.open_text("");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::{glob_match, lsp_root, swap_fs, FileSystem, Path};
+    use crate::mem_fs::MemFs;
+    use crate::tree::Tree;
+    use crate::{lock, FS};
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("target", "target"));
+        assert!(!glob_match("target", "targets"));
+        assert!(glob_match("*.log", "build.log"));
+        assert!(!glob_match("*.log", "build.log.txt"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(glob_match("?.txt", "a.txt"));
+        assert!(!glob_match("?.txt", ".txt"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn lsp_root_out_of_workspace() {
+        let root = lock!(global).root_path.clone();
+        let mem = MemFs::default();
+        mem.insert("/elsewhere/project/Cargo.toml", "");
+        mem.insert("/elsewhere/project/src/main.rs", "");
+        mem.insert("/elsewhere/loose.txt", "");
+        mem.insert(&format!("{}/src/fs.rs", root.path()), "");
+        swap_fs(Some(mem));
+
+        let project = FS.path("/elsewhere/project").uri();
+        assert_eq!(
+            lsp_root(&FS.path("/elsewhere/project/src/main.rs")).uri(),
+            project
+        );
+        // without a project file above it, the workspace is used
+        assert_eq!(lsp_root(&FS.path("/elsewhere/loose.txt")).uri(), root.uri());
+        // so are the files in it
+        let file = FS.path(format!("{}/src/fs.rs", root.path()));
+        assert_eq!(lsp_root(&file).uri(), root.uri());
+        swap_fs(None);
+    }
+
+    #[test]
+    fn tree_of_files() {
+        let root = lock!(global).root_path.clone();
+        let mem = MemFs::default();
+        let file = |name: &str| format!("{}/{}", root.path(), name);
+        mem.insert(&file("b.rs"), "");
+        mem.insert(&file("a.txt"), "");
+        mem.insert(&file("src/main.rs"), "");
+        mem.insert(&file(".git/HEAD"), "");
+        swap_fs(Some(mem));
+
+        // folders first, hidden files are not shown
+        let names = |key| {
+            FS.children(key)
+                .iter()
+                .map(|c| c.name())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&root), vec!["src", "a.txt", "b.rs"]);
+        let src = FS.path(file("src"));
+        assert!(FS.expandable(&src) && !FS.expandable(&FS.path(file("b.rs"))));
+        assert_eq!(names(&src), vec!["main.rs"]);
+        let main = FS.path(file("src/main.rs"));
+        assert_eq!(FS.item(&main).level, 2);
+        assert!(FS.parent(&main) == Some(src));
+        assert!(FS.parent(&root).is_none());
+        swap_fs(None);
+    }
+
+    #[test]
+    fn missing_path() {
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let missing = FS.path(dir.join("ste_fs_missing/a.rs").to_string_lossy());
+        assert_eq!(
+            missing.path(),
+            dir.join("ste_fs_missing/a.rs").to_string_lossy()
+        );
+        assert_eq!(missing.name(), "a.rs");
+        assert!(missing.uri().as_str().ends_with("/ste_fs_missing/a.rs"));
+
+        // relative to the working directory, like the existing ones
+        let relative = FS.path("ste_fs_missing.txt");
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            relative.path(),
+            cwd.join("ste_fs_missing.txt").to_string_lossy()
+        );
     }
 }
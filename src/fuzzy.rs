@@ -0,0 +1,81 @@
+const MATCH: i64 = 16;
+const CONSECUTIVE: i64 = 8;
+const BOUNDARY: i64 = 8;
+const PREFIX: i64 = 12;
+const GAP: i64 = 2;
+
+/// Score of `query` as a subsequence of `candidate`, ignoring case, `None` if it is not one.
+/// Matches next to each other, at the start of a word or of a camelCase hump and at
+/// the start of the candidate are worth more, skipped chars cost a little.
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    let lower = |c: char| c.to_lowercase().next().unwrap_or(c);
+    let query: Vec<char> = query.chars().map(lower).collect();
+    let chars: Vec<char> = candidate.chars().collect();
+    if query.is_empty() {
+        return Some(0);
+    }
+
+    let bonus = |j: usize| {
+        let boundary = match j.checked_sub(1).map(|p| chars[p]) {
+            None => PREFIX + BOUNDARY,
+            Some(prev) if !prev.is_alphanumeric() => BOUNDARY,
+            Some(prev) if prev.is_lowercase() && chars[j].is_uppercase() => BOUNDARY,
+            _ => 0,
+        };
+        MATCH + boundary
+    };
+    let matches = |i: usize, j: usize| lower(chars[j]) == query[i];
+
+    // best score of the query up to `i` with `query[i]` matched at `j`
+    let mut best: Vec<Option<i64>> = (0..chars.len())
+        .map(|j| Some(bonus(j) - GAP * j as i64).filter(|_| matches(0, j)))
+        .collect();
+    for i in 1..query.len() {
+        let mut next = vec![None; chars.len()];
+        for j in i..chars.len() {
+            if !matches(i, j) {
+                continue;
+            }
+            next[j] = (0..j)
+                .filter_map(|k| {
+                    let gap = j - k - 1;
+                    let link = if gap == 0 {
+                        CONSECUTIVE
+                    } else {
+                        -GAP * gap as i64
+                    };
+                    best[k].map(|score| score + link)
+                })
+                .max()
+                .map(|score| score + bonus(j));
+        }
+        best = next;
+    }
+    best.into_iter().flatten().max()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fuzzy::fuzzy_score;
+
+    #[test]
+    fn subsequence() {
+        assert_eq!(fuzzy_score("", "abc"), Some(0));
+        assert!(fuzzy_score("ac", "abc").is_some());
+        assert!(fuzzy_score("ca", "abc").is_none());
+        assert!(fuzzy_score("abcd", "abc").is_none());
+        // case is ignored
+        assert!(fuzzy_score("AB", "abc").is_some());
+    }
+
+    #[test]
+    fn ranking() {
+        let score = |candidate| fuzzy_score("nw", candidate).unwrap();
+        assert!(score("new") > score("network"));
+        // contiguous, then word starts, then a prefix
+        assert!(fuzzy_score("get", "get_id") > fuzzy_score("get", "g_e_t"));
+        assert!(fuzzy_score("gi", "getId") > fuzzy_score("gi", "getid"));
+        assert!(fuzzy_score("gi", "get_id") > fuzzy_score("gi", "getid"));
+        assert!(fuzzy_score("id", "id_get") > fuzzy_score("id", "get_id"));
+    }
+}
@@ -1,9 +1,11 @@
-use crate::buffer::Index;
+use crate::buffer::{Bounds, Direction, Index};
 use crate::style_layer::{Span, StyleLayer};
 use crate::theme::Style;
-use crate::{BufferData, LspLang, THEME};
-use std::collections::HashMap;
-use tree_sitter::{Language, Parser, Query, QueryCursor};
+use crate::{lock, BufferData, LspLang};
+use ropey::Rope;
+use std::cmp::min;
+use std::ops::Range;
+use tree_sitter::{InputEdit, Language, Node, Parser, Query, QueryCursor, Tree};
 
 extern "C" {
     fn tree_sitter_json() -> Language;
@@ -32,6 +34,31 @@ fn rust_lang() -> Parser {
     parser
 }
 
+/// Bytes of `rope` from `byte` to the end of its chunk, what tree-sitter reads next.
+fn rope_chunk(rope: &Rope, byte: usize) -> &[u8] {
+    if byte >= rope.len_bytes() {
+        return &[];
+    }
+    let (chunk, start, _, _) = rope.chunk_at_byte(byte);
+    &chunk.as_bytes()[byte - start..]
+}
+
+/// Text of `node` as the chunks of `rope` it spans, for the predicates of the queries.
+fn node_text<'a>(rope: &'a Rope, node: Node) -> impl Iterator<Item = &'a [u8]> + 'a {
+    let start = rope.byte_to_char(node.start_byte());
+    let end = rope.byte_to_char(node.end_byte());
+    rope.slice(start..end).chunks().map(str::as_bytes)
+}
+
+/// First symbol starting after `idx`, or last one starting before it when going up.
+pub fn symbol_after(symbols: &[Bounds], idx: Index, direction: Direction) -> Option<Bounds> {
+    match direction {
+        Direction::Down => symbols.iter().find(|s| s.0 > idx),
+        Direction::Up => symbols.iter().rev().find(|s| s.0 < idx),
+    }
+    .copied()
+}
+
 pub trait Highlight {
     fn parse(&mut self, input: &[u8]) -> Vec<Region>;
 }
@@ -39,6 +66,10 @@ pub trait Highlight {
 pub struct TreeSitterHighlight {
     parser: Parser,
     query: Query,
+    symbol_kinds: &'static [&'static str],
+    literal_kinds: &'static [&'static str],
+    // parsed by the last highlight
+    tree: Option<Tree>,
 }
 
 #[derive(Debug, Clone)]
@@ -67,62 +98,354 @@ impl LspLang {
             _ => None,
         }
     }
+
+    /// Node kinds of the definitions jumped between by the structural navigation.
+    pub fn symbol_kinds(&self) -> &'static [&'static str] {
+        match self {
+            LspLang::Rust => &[
+                "function_item",
+                "struct_item",
+                "enum_item",
+                "union_item",
+                "trait_item",
+                "impl_item",
+                "mod_item",
+                "macro_definition",
+            ],
+            LspLang::Python => &["function_definition", "class_definition"],
+            _ => &[],
+        }
+    }
+
+    /// Node kinds of the strings and comments, the brackets in them are not matched.
+    pub fn literal_kinds(&self) -> &'static [&'static str] {
+        match self {
+            LspLang::Rust => &[
+                "string_literal",
+                "raw_string_literal",
+                "char_literal",
+                "line_comment",
+                "block_comment",
+            ],
+            LspLang::Python => &["string", "comment"],
+            LspLang::Json => &["string"],
+            _ => &[],
+        }
+    }
 }
 
 impl TreeSitterHighlight {
     pub fn new(lang: LspLang) -> Option<Self> {
         let (parser, highlight) = lang.tree_sitter_lang()?;
         let query = Query::new(parser.language().unwrap(), highlight).unwrap();
-        Some(Self { parser, query })
+        Some(Self {
+            parser,
+            query,
+            symbol_kinds: lang.symbol_kinds(),
+            literal_kinds: lang.literal_kinds(),
+            tree: None,
+        })
     }
 }
 
-impl StyleLayer for TreeSitterHighlight {
-    fn spans(
-        &mut self,
-        buffer: &BufferData,
-        _min: Index,
-        _max: Index,
-    ) -> anyhow::Result<Vec<Span>> {
-        let text = buffer.buffer.text();
-        let rope = buffer.buffer.rope();
-        let tree = self.parser.parse(&text, None).unwrap();
-        let mut cur = QueryCursor::new();
+impl TreeSitterHighlight {
+    /// Bounds of the definitions, the nested ones too, in document order.
+    pub fn symbols(&mut self, rope: &Rope) -> Vec<Bounds> {
+        let tree = self
+            .parser
+            .parse_with(&mut |byte, _| rope_chunk(rope, byte), None)
+            .unwrap();
+        let mut cursor = tree.walk();
+        let mut symbols = vec![];
+        // depth first, a node before its children
+        loop {
+            let node = cursor.node();
+            if self.symbol_kinds.contains(&node.kind()) {
+                symbols.push((
+                    rope.byte_to_char(node.start_byte()),
+                    rope.byte_to_char(node.end_byte()),
+                ));
+            }
+            if cursor.goto_first_child() {
+                continue;
+            }
+            while !cursor.goto_next_sibling() {
+                if !cursor.goto_parent() {
+                    return symbols;
+                }
+            }
+        }
+    }
 
-        let mut map = HashMap::new();
-        for name in self.query.capture_names() {
-            if let Some(index) = self.query.capture_index_for_name(name) {
-                map.insert(index, name.clone());
+    /// Definitions containing `idx` in the tree of the last highlight, the outermost first.
+    pub fn enclosing(&self, rope: &Rope, idx: Index) -> Vec<Bounds> {
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => return vec![],
+        };
+        // the tree can be older than the text
+        let to_char = |byte| rope.byte_to_char(min(byte, rope.len_bytes()));
+        let byte = rope.char_to_byte(min(idx, rope.len_chars()));
+        let mut node = tree.root_node().descendant_for_byte_range(byte, byte);
+        let mut symbols = vec![];
+        while let Some(n) = node {
+            if self.symbol_kinds.contains(&n.kind()) {
+                symbols.push((to_char(n.start_byte()), to_char(n.end_byte())));
             }
+            node = n.parent();
         }
+        symbols.reverse();
+        symbols
+    }
+
+    /// Whether `idx` is in a string or a comment in the tree of the last highlight.
+    pub fn in_literal(&self, rope: &Rope, idx: Index) -> bool {
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => return false,
+        };
+        let byte = rope.char_to_byte(min(idx, rope.len_chars()));
+        let mut node = tree.root_node().descendant_for_byte_range(byte, byte + 1);
+        while let Some(n) = node {
+            if self.literal_kinds.contains(&n.kind()) {
+                return true;
+            }
+            node = n.parent();
+        }
+        false
+    }
+
+    /// Parse `rope`, reusing the unchanged parts of the tree of the last highlight when
+    /// `edits` lead from its text to this one.
+    fn parse(&mut self, rope: &Rope, edits: Option<Vec<InputEdit>>) -> Tree {
+        let old = match (self.tree.take(), edits) {
+            (Some(mut tree), Some(edits)) => {
+                edits.iter().for_each(|edit| tree.edit(edit));
+                Some(tree)
+            }
+            _ => None,
+        };
+        self.parser
+            .parse_with(&mut |byte, _| rope_chunk(rope, byte), old.as_ref())
+            .unwrap()
+    }
 
-        let mut spans = vec![];
+    /// Captures as `(start, end, name)` of the nodes in `bounds`, sorted so that the capture
+    /// winning an overlap comes last: the narrowest node wins, ties go to the first pattern
+    /// of the query.
+    fn captures(
+        &mut self,
+        rope: &Rope,
+        edits: Option<Vec<InputEdit>>,
+        bounds: Bounds,
+    ) -> Vec<(Index, Index, String)> {
+        let tree = self.parse(rope, edits);
+        let mut cur = QueryCursor::new();
+        cur.set_byte_range(byte_range(rope, bounds));
+        let names = self.query.capture_names();
 
-        let matches = cur.matches(&self.query, tree.root_node(), text.as_bytes());
+        let mut captures = vec![];
+        let text = |node: Node| node_text(rope, node);
+        let matches = cur.matches(&self.query, tree.root_node(), text);
         for m in matches {
-            let name = map.get(&(m.pattern_index as u32));
-            if let Some(name) = name {
-                for cap in m.captures {
-                    let start_byte = cap.node.range().start_byte;
-                    let end_byte = cap.node.range().end_byte;
-
-                    let start = rope.byte_to_char(start_byte);
-                    let end = rope.byte_to_char(end_byte);
-
-                    spans.push((
-                        m.pattern_index,
-                        Span {
-                            start,
-                            end,
-                            style: THEME.scope(name),
-                        },
-                    ))
-                }
+            for cap in m.captures {
+                let start = rope.byte_to_char(cap.node.start_byte());
+                let end = rope.byte_to_char(cap.node.end_byte());
+                let name = &names[cap.index as usize];
+                captures.push((m.pattern_index, start, end, name.clone()));
             }
         }
 
-        spans.sort_unstable_by_key(|(i, _)| *i);
-        spans.reverse();
-        Ok(spans.into_iter().map(|(_, span)| span).collect())
+        captures.sort_by(
+            |(a_pattern, a_start, a_end, _), (b_pattern, b_start, b_end, _)| {
+                (b_end - b_start)
+                    .cmp(&(a_end - a_start))
+                    .then(b_pattern.cmp(a_pattern))
+            },
+        );
+        self.tree = Some(tree);
+        captures
+            .into_iter()
+            .map(|(_, start, end, name)| (start, end, name))
+            .collect()
+    }
+}
+
+/// Bytes of `bounds`, the query matches the nodes overlapping them, so a construct
+/// starting above the viewport and its margin is not highlighted as a whole.
+fn byte_range(rope: &Rope, bounds: Bounds) -> Range<usize> {
+    let to_byte = |idx| rope.char_to_byte(min(idx, rope.len_chars()));
+    to_byte(bounds.0)..to_byte(bounds.1)
+}
+
+impl StyleLayer for TreeSitterHighlight {
+    fn spans(&mut self, buffer: &BufferData, min: Index, max: Index) -> anyhow::Result<Vec<Span>> {
+        let rope = buffer.buffer.rope();
+        let edits = buffer.buffer.take_syntax_edits();
+        Ok(self
+            .captures(rope, edits, (min, max))
+            .into_iter()
+            .map(|(start, end, name)| Span {
+                start,
+                end,
+                style: lock!(theme).scope(&name),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use ropey::Rope;
+
+    use crate::buffer::{Buffer, Direction, Index};
+    use tree_sitter::{Node, QueryCursor, QueryMatch};
+
+    use crate::highlight::{node_text, symbol_after, TreeSitterHighlight};
+    use crate::LspLang;
+
+    #[test]
+    fn precedence() {
+        let mut highlight = TreeSitterHighlight::new(LspLang::Rust).unwrap();
+        let text = "m!(fn);";
+        let captures = highlight.captures(&Rope::from_str(text), None, (0, text.len()));
+        let winner = |idx: Index| {
+            captures
+                .iter()
+                .rev()
+                .find(|(start, end, _)| *start <= idx && idx < *end)
+                .map(|(_, _, name)| name.as_str())
+        };
+        assert_eq!(winner(0), Some("function.macro"));
+        // also an operator, the macro pattern comes first
+        assert_eq!(winner(1), Some("function.macro"));
+        assert_eq!(winner(3), Some("keyword"));
+    }
+
+    #[test]
+    fn in_literal() {
+        let mut highlight = TreeSitterHighlight::new(LspLang::Rust).unwrap();
+        let text = "f(\"(\", '[', x); // {\n";
+        let rope = Rope::from_str(text);
+        highlight.captures(&rope, None, (0, text.len()));
+        let literal = |c: char| highlight.in_literal(&rope, text.find(c).unwrap());
+        assert!(!literal('('));
+        assert!(literal('"'));
+        assert!(literal('['));
+        assert!(literal('{'));
+        assert!(!literal(')'));
+    }
+
+    #[test]
+    fn enclosing() {
+        let mut highlight = TreeSitterHighlight::new(LspLang::Rust).unwrap();
+        let text = "impl A {\n    fn b() {\n        c();\n    }\n}\n";
+        let rope = Rope::from_str(text);
+        let c = text.find("c()").unwrap();
+        assert!(highlight.enclosing(&rope, c).is_empty());
+
+        highlight.captures(&rope, None, (0, text.len()));
+        let starts = highlight
+            .enclosing(&rope, c)
+            .iter()
+            .map(|s| &text[s.0..s.0 + 4])
+            .collect::<Vec<_>>();
+        assert_eq!(starts, vec!["impl", "fn b"]);
+        assert!(highlight.enclosing(&rope, text.len()).is_empty());
+    }
+
+    #[test]
+    fn incremental_parse() {
+        let mut highlight = TreeSitterHighlight::new(LspLang::Rust).unwrap();
+        let mut buf = Buffer::from_reader(1, Cursor::new("fn a() {\n    let b = 1;\n}\n"));
+        highlight.captures(buf.rope(), buf.take_syntax_edits(), (0, 0));
+
+        buf.insert(9, "    // é\n");
+        buf.remove_chars((0, 3));
+        buf.insert(0, "struct C;\nfn ");
+        let all = (0, buf.rope().len_chars());
+        let incremental = highlight.captures(buf.rope(), buf.take_syntax_edits(), all);
+        let tree = highlight.tree.clone().unwrap();
+
+        let mut fresh = TreeSitterHighlight::new(LspLang::Rust).unwrap();
+        assert_eq!(incremental, fresh.captures(buf.rope(), None, all));
+        assert_eq!(
+            tree.root_node().to_sexp(),
+            fresh.tree.unwrap().root_node().to_sexp()
+        );
+    }
+
+    #[test]
+    fn bounded_captures() {
+        let mut highlight = TreeSitterHighlight::new(LspLang::Rust).unwrap();
+        let text = "fn a() {}\nfn b() {\n    1;\n    2;\n}\nfn c() {}\n";
+        let two = text.find('2').unwrap();
+        let captures = highlight.captures(&Rope::from_str(text), None, (two, two + 1));
+        let names = captures
+            .iter()
+            .map(|(start, end, _)| &text[*start..*end])
+            .collect::<Vec<_>>();
+        assert!(names.contains(&"2"));
+        // the rest of the definition around the range is not highlighted
+        assert!(!names.contains(&"1"));
+        assert!(!names.contains(&"a") && !names.contains(&"c"));
+    }
+
+    #[test]
+    fn rope_input() {
+        // many chunks, with chars of more than one byte
+        let text = "fn é() -> &str {\n    \"ü\" // ü\n}\n".repeat(2000);
+        let rope = Rope::from_str(&text);
+        assert!(rope.chunks().count() > 1);
+        let mut highlight = TreeSitterHighlight::new(LspLang::Rust).unwrap();
+        highlight.captures(&rope, None, (0, rope.len_chars()));
+        let from_rope = highlight.tree.clone().unwrap();
+        let from_text = highlight.parser.parse(&text, None).unwrap();
+        assert_eq!(
+            from_rope.root_node().to_sexp(),
+            from_text.root_node().to_sexp()
+        );
+
+        // the predicates see the same text
+        let query = &highlight.query;
+        let root = from_text.root_node();
+        let ranges = |m: QueryMatch| {
+            let nodes = m.captures.iter().map(|c| (c.index, c.node.byte_range()));
+            (m.pattern_index, nodes.collect::<Vec<_>>())
+        };
+        let text_matches: Vec<_> = QueryCursor::new()
+            .matches(query, root, text.as_bytes())
+            .map(ranges)
+            .collect();
+        let rope_matches: Vec<_> = QueryCursor::new()
+            .matches(query, root, |node: Node| node_text(&rope, node))
+            .map(ranges)
+            .collect();
+        assert_eq!(text_matches, rope_matches);
+    }
+
+    #[test]
+    fn symbols() {
+        let mut highlight = TreeSitterHighlight::new(LspLang::Rust).unwrap();
+        let text =
+            "fn a() {}\n\nstruct B;\n\nimpl B {\n    fn c() {}\n}\n\nfn d() {\n    let x = 1;\n}\n";
+        let symbols = highlight.symbols(&Rope::from_str(text));
+        let starts = symbols
+            .iter()
+            .map(|s| &text[s.0..s.0 + 4])
+            .collect::<Vec<_>>();
+        assert_eq!(starts, vec!["fn a", "stru", "impl", "fn c", "fn d"]);
+
+        let d = text.find("fn d").unwrap();
+        let x = text.find("let x").unwrap();
+        assert_eq!(symbol_after(&symbols, 0, Direction::Down), Some(symbols[1]));
+        assert_eq!(
+            symbol_after(&symbols, x, Direction::Up).map(|s| s.0),
+            Some(d)
+        );
+        assert_eq!(symbol_after(&symbols, x, Direction::Down), None);
+        assert_eq!(symbol_after(&symbols, 0, Direction::Up), None);
     }
 }
@@ -0,0 +1,69 @@
+use druid::{Color, PaintCtx, Rect, RenderContext};
+
+use crate::lock;
+
+pub const ICON_WIDTH: f64 = 14.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconShape {
+    Folder,
+    File,
+}
+
+#[derive(Debug, Clone)]
+pub struct Icon {
+    pub shape: IconShape,
+    pub color: Color,
+}
+
+fn default_color(extension: &str) -> Color {
+    match extension {
+        "rs" => Color::rgb8(0xde, 0x6c, 0x2e),
+        "py" => Color::rgb8(0x35, 0x72, 0xa5),
+        "json" => Color::rgb8(0xcb, 0xcb, 0x41),
+        "toml" => Color::rgb8(0x9c, 0x41, 0x21),
+        "md" => Color::rgb8(0x51, 0x9a, 0xba),
+        "java" => Color::rgb8(0xb0, 0x72, 0x19),
+        _ => Color::rgb8(0xa8, 0x99, 0x84),
+    }
+}
+
+/// Icon for a tree entry or a tab, themeable with `icon.dir` and `icon.<extension>`.
+pub fn icon_for(extension: Option<&str>, is_dir: bool) -> Icon {
+    if is_dir {
+        let color = lock!(theme)
+            .scope("icon.dir")
+            .foreground
+            .unwrap_or_else(|| Color::rgb8(0xd7, 0x99, 0x21));
+        return Icon {
+            shape: IconShape::Folder,
+            color,
+        };
+    }
+    let extension = extension.unwrap_or("").to_lowercase();
+    let color = lock!(theme)
+        .scope(&format!("icon.{}", extension))
+        .foreground
+        .unwrap_or_else(|| default_color(&extension));
+    Icon {
+        shape: IconShape::File,
+        color,
+    }
+}
+
+/// Draw the icon vertically centered in a row of `height` starting at `y`.
+pub fn draw_icon(ctx: &mut PaintCtx, icon: &Icon, x: f64, y: f64, height: f64) {
+    let middle = y + height / 2.0;
+    match icon.shape {
+        IconShape::Folder => {
+            let body = Rect::new(x, middle - 4.0, x + ICON_WIDTH - 4.0, middle + 5.0);
+            let tab = Rect::new(x, middle - 6.0, x + 4.0, middle - 3.0);
+            ctx.fill(tab.to_rounded_rect(1.0), &icon.color);
+            ctx.fill(body.to_rounded_rect(1.5), &icon.color);
+        }
+        IconShape::File => {
+            let body = Rect::new(x + 1.0, middle - 6.0, x + ICON_WIDTH - 5.0, middle + 6.0);
+            ctx.fill(body.to_rounded_rect(1.5), &icon.color);
+        }
+    }
+}
@@ -1,35 +1,52 @@
-use crate::fs::{FileSystem, LocalPath, Path};
+use crate::fs::{lang_for, FileSystem, LocalPath, Path};
 use druid::{Data, FontDescriptor, Key};
 use std::collections::HashMap;
+use std::io::Read;
 use std::sync::atomic::{AtomicU32, Ordering};
 
 pub mod buffer;
 pub mod config;
 pub mod draw;
 pub mod editor;
+pub mod export;
 pub mod fs;
+pub mod fuzzy;
 pub mod highlight;
+pub mod icons;
 pub mod lsp;
 pub mod lsp_ext;
+pub mod mem_fs;
+pub mod metrics;
+pub mod outline;
+pub mod search;
+pub mod session;
+pub mod snippet;
+pub mod status;
 pub mod style_layer;
+pub mod tabs;
 pub mod theme;
 pub mod tree;
+pub mod watch;
 
 use crate::buffer::Buffer;
 use crate::config::Config;
-use crate::lsp::{lsp_send_with_lang, LspInput, LspLang};
+use crate::fs::lsp_root;
+use crate::lsp::{lsp_send_to, workspace_edits, LspInput, LspLang};
+use crate::status::{error, notify};
 use anyhow::Context;
 use fs::LocalFs;
 use lsp::LspSystem;
-use lsp_types::Url;
+use lsp_types::{Url, WorkspaceEdit};
 use parking_lot::RwLock;
-use theme::Theme;
+use std::sync::Arc;
+use theme::{Theme, DEFAULT_THEME};
 
 pub const FONT: Key<FontDescriptor> = Key::new("ui.font");
 pub const EDITOR_FONT: Key<FontDescriptor> = Key::new("editor.font");
 
 lazy_static::lazy_static! {
-    pub static ref THEME: Theme = toml::from_str(include_str!("../runtime/themes/gruvbox.toml")).unwrap();
+    // swapped when the theme file changes, see `Theme::reload`
+    pub static ref THEME: RwLock<Arc<Theme>> = RwLock::new(Arc::new(Theme::parse(DEFAULT_THEME).unwrap()));
     pub static ref FS: LocalFs = LocalFs::default();
     pub static ref LSP: RwLock<LspSystem> = RwLock::new(LspSystem::default());
     pub static ref BUFFERS: RwLock<Buffers> = RwLock::new(Buffers::default());
@@ -73,6 +90,10 @@ macro_rules! lock {
         // println!("global {} {}", file!(), line!());
         $crate::GLOBAL.write()
     }};
+    (theme) => {{
+        // the theme in use, the lock is not held
+        $crate::THEME.read().clone()
+    }};
 }
 
 #[macro_export]
@@ -85,6 +106,10 @@ macro_rules! curr_buf {
         let buffers = lock!(buffers);
         buffers.get_curr()?.lsp_lang.clone()
     }};
+    (has_completions) => {{
+        let buffers = lock!(buffers);
+        !buffers.get_curr()?.buffer.sorted_completions()?.is_empty()
+    }};
     (col) => {{
         let buffers = lock!(buffers);
         buffers.get_curr()?.buffer.col()
@@ -124,6 +149,34 @@ pub struct Global {
     pub root_path: LocalPath,
 }
 
+/// Make `root` the workspace, shown by the tree. The open files it now contains move to
+/// the language servers of the new root, they are started as needed.
+pub fn set_root(root: LocalPath) {
+    lock!(mut global).root_path = root;
+
+    let mut buffers = lock!(mut buffers);
+    for data in buffers.buffers.values_mut() {
+        let root = data.source.lsp_root();
+        if root == data.lsp_root {
+            continue;
+        }
+        let old_root = std::mem::replace(&mut data.lsp_root, root.clone());
+        let path = match &data.source {
+            BufferSource::File { path } if data.lsp_enabled() => path,
+            _ => continue,
+        };
+        let lang = data.lsp_lang.clone();
+        let uri = path.uri();
+        lsp_send_to(
+            old_root,
+            lang.clone(),
+            LspInput::CloseFile { uri: uri.clone() },
+        )
+        .ignore();
+        lsp_send_to(root, lang, data.buffer.open_input(uri)).ignore();
+    }
+}
+
 pub struct Buffers {
     counter: AtomicU32,
     pub current: Option<u32>,
@@ -148,7 +201,7 @@ impl Buffers {
     pub fn open_file(&mut self, path: LocalPath) -> anyhow::Result<u32> {
         for (id, b) in &self.buffers {
             if let BufferSource::File { path: p } = &b.source {
-                if &path == p {
+                if &path == p && !b.read_only {
                     self.current = Some(*id);
                     return Ok(*id);
                 }
@@ -158,37 +211,138 @@ impl Buffers {
         let id = self.new_id();
 
         let source = BufferSource::File { path: path.clone() };
+        let buffer = Buffer::from_reader(id, path.reader());
+        let mut data = BufferData::new(id, source, path.lsp_lang(), false, buffer);
+        if lock!(conf).render.indent_warning && !data.large_file {
+            data.buffer.indentation = Some(data.buffer.check_indentation());
+        }
 
-        let data = BufferData {
-            id,
-            source,
-            lsp_lang: path.lsp_lang(),
-            read_only: false,
-            modified: false,
-            buffer: Buffer::from_reader(id, path.reader()),
-        };
-
-        let text = data.buffer.text();
+        let open = data.buffer.open_input(path.uri());
+        let large_file = data.large_file;
+        let root = data.lsp_root.clone();
 
         self.buffers.insert(id, data);
 
         self.current = Some(id);
 
-        let failed = lsp_send_with_lang(
-            path.lsp_lang(),
-            LspInput::OpenFile {
-                uri: path.uri(),
-                content: text,
-            },
-        )
-        .is_err();
+        if large_file {
+            notify(format!(
+                "{} is a large file, highlighting and the language server are disabled",
+                path.name()
+            ));
+            return Ok(id);
+        }
+        let failed = lsp_send_to(root, path.lsp_lang(), open).is_err();
         if failed {
-            println!("lsp start failed")
+            error(format!(
+                "cannot start the language server of {}",
+                path.name()
+            ))
         }
 
         Ok(id)
     }
 
+    /// Close buffer `id`, the one opened last becomes the current one if it was.
+    /// The language server is told when it knew the file.
+    pub fn close(&mut self, id: u32) -> anyhow::Result<()> {
+        let data = self.buffers.remove(&id).context("no buffer")?;
+        if self.current == Some(id) {
+            self.current = self.buffers.keys().max().copied();
+        }
+        if let (BufferSource::File { path }, true) = (&data.source, data.lsp_enabled()) {
+            let input = LspInput::CloseFile { uri: path.uri() };
+            lsp_send_to(data.lsp_root, data.lsp_lang, input).ignore();
+        }
+        Ok(())
+    }
+
+    /// Open `path` in a new read-only buffer, even if it is already open.
+    /// The language server does not know about the copy.
+    pub fn open_file_copy(&mut self, path: LocalPath) -> anyhow::Result<u32> {
+        let id = self.new_id();
+        let buffer = Buffer::from_reader(id, path.reader());
+        let source = BufferSource::File { path: path.clone() };
+        let data = BufferData::new(id, source, path.lsp_lang(), true, buffer);
+
+        self.buffers.insert(id, data);
+        self.current = Some(id);
+        Ok(id)
+    }
+
+    pub fn open_text(&mut self, text: &str) -> u32 {
+        let id = self.new_id();
+
+        let buffer = Buffer::from_reader(id, text.as_bytes());
+        let data = BufferData::new(id, BufferSource::Text, LspLang::PlainText, false, buffer);
+
+        self.buffers.insert(id, data);
+        self.current = Some(id);
+        id
+    }
+
+    /// Make the buffer opened after the current one current, or the one before it
+    /// if `back`, wrapping around.
+    pub fn cycle(&mut self, back: bool) -> Option<u32> {
+        let mut ids: Vec<u32> = self.buffers.keys().copied().collect();
+        ids.sort_unstable();
+        let pos = ids.iter().position(|id| Some(*id) == self.current)?;
+        let next = if back {
+            ids[(pos + ids.len() - 1) % ids.len()]
+        } else {
+            ids[(pos + 1) % ids.len()]
+        };
+        self.current = Some(next);
+        Some(next)
+    }
+
+    /// Open an empty buffer without a file, until it is saved as one.
+    pub fn new_scratch(&mut self) -> u32 {
+        self.open_text("")
+    }
+
+    /// Write buffer `id` to `path`, which becomes its file. The language follows the new
+    /// name and the language servers are told, the one of the old file too.
+    pub fn save_as(&mut self, id: u32, path: &std::path::Path) -> anyhow::Result<()> {
+        // the file may not exist yet, the language comes from the name alone
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string());
+        let extension = path.extension().map(|e| e.to_string_lossy().to_string());
+        let lsp_lang = lang_for(&name.unwrap_or_default(), extension);
+        let indent = lock!(conf).indent_for(&lsp_lang);
+        let data = self.get_mut(id)?;
+        // the new language server opens the trimmed text
+        if indent.trim_trailing_whitespace {
+            data.buffer.trim_trailing_whitespace();
+        }
+        data.buffer.rope().write_to(std::fs::File::create(path)?)?;
+        data.buffer.mark_saved();
+        let path = FS.path(path.to_string_lossy());
+        let old = std::mem::replace(&mut data.source, BufferSource::File { path: path.clone() });
+        let old_root = std::mem::replace(&mut data.lsp_root, data.source.lsp_root());
+        if let (BufferSource::File { path: old }, true) = (old, data.lsp_enabled()) {
+            lsp_send_to(
+                old_root,
+                data.lsp_lang.clone(),
+                LspInput::CloseFile { uri: old.uri() },
+            )
+            .ignore();
+        }
+        data.read_only = false;
+        data.lsp_lang = lsp_lang;
+        data.buffer.pairs = lock!(conf).pairs_for(&data.lsp_lang);
+        data.buffer.indent = indent;
+        if data.large_file {
+            return Ok(());
+        }
+        lsp_send_to(
+            data.lsp_root.clone(),
+            data.lsp_lang.clone(),
+            data.buffer.open_input(path.uri()),
+        )
+        .ignore();
+        Ok(())
+    }
+
     pub fn new_id(&self) -> u32 {
         self.counter.fetch_add(1, Ordering::SeqCst)
     }
@@ -211,7 +365,7 @@ impl Buffers {
         self.buffers.get_mut(&id).context("no buffer")
     }
     pub fn get_by_uri(&self, uri: Url) -> Option<&BufferData> {
-        for (_, b) in &self.buffers {
+        for b in self.buffers.values().filter(|b| !b.read_only) {
             if let BufferSource::File { path } = &b.source {
                 if &path.uri().as_str().to_lowercase() == &uri.as_str().to_lowercase() {
                     return Some(b);
@@ -222,7 +376,7 @@ impl Buffers {
     }
 
     pub fn get_by_uri_mut(&mut self, uri: Url) -> Option<&mut BufferData> {
-        for (_, b) in &mut self.buffers {
+        for b in self.buffers.values_mut().filter(|b| !b.read_only) {
             if let BufferSource::File { path } = &b.source {
                 if path.uri().as_str().to_lowercase() == uri.as_str().to_lowercase() {
                     return Some(b);
@@ -231,6 +385,64 @@ impl Buffers {
         }
         None
     }
+
+    /// Read again the file of buffer `id` after another program changed it. A buffer with
+    /// unsaved changes is left as it is. Returns whether the text changed.
+    pub fn reload(&mut self, id: u32) -> anyhow::Result<bool> {
+        let buf = self.get_mut(id)?;
+        let path = match &buf.source {
+            BufferSource::File { path } if !buf.buffer.is_modified() => path.clone(),
+            _ => return Ok(false),
+        };
+        let mut text = String::new();
+        path.reader().read_to_string(&mut text)?;
+        match buf.buffer.reload(&text) {
+            Some(input) => {
+                // read-only copies and large files are unknown to the server
+                if buf.lsp_enabled() {
+                    lsp_send_to(buf.lsp_root.clone(), buf.lsp_lang.clone(), input).ignore();
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Apply a workspace edit of a language server. The files which are not open are
+    /// opened, edited and saved, the current buffer stays the same.
+    pub fn apply_workspace_edit(&mut self, edit: WorkspaceEdit) -> anyhow::Result<()> {
+        let current = self.current;
+        for (uri, edits) in workspace_edits(edit) {
+            let opened = self.get_by_uri(uri.clone()).is_none();
+            if opened {
+                let path = uri
+                    .to_file_path()
+                    .ok()
+                    .with_context(|| format!("not a file: {}", uri))?;
+                self.open_file(FS.path(path.to_string_lossy()))?;
+            }
+            let buf = self
+                .get_by_uri_mut(uri.clone())
+                .context("buffer not found")?;
+            buf.buffer.apply_edits(&edits);
+            let input = match &buf.source {
+                BufferSource::File { path } if opened => {
+                    buf.buffer.rope().write_to(path.writer())?;
+                    buf.buffer.mark_saved();
+                    LspInput::SavedFile {
+                        uri,
+                        content: buf.buffer.rope().clone(),
+                    }
+                }
+                _ => LspInput::Edit { buffer_id: buf.id },
+            };
+            if buf.lsp_enabled() {
+                lsp_send_to(buf.lsp_root.clone(), buf.lsp_lang.clone(), input).ignore();
+            }
+        }
+        self.current = current;
+        Ok(())
+    }
 }
 
 pub enum BufferSource {
@@ -245,17 +457,64 @@ impl BufferSource {
             BufferSource::File { path } => Some(path.clone()),
         }
     }
+
+    /// Root of the language server of the source, the workspace one without a file.
+    pub fn lsp_root(&self) -> Url {
+        match self {
+            BufferSource::File { path } => lsp_root(path).uri(),
+            BufferSource::Text => lock!(global).root_path.uri(),
+        }
+    }
 }
 
 pub struct BufferData {
     pub id: u32,
     pub source: BufferSource,
+    /// root of its language server, set when the source or the workspace change
+    pub lsp_root: Url,
     pub lsp_lang: LspLang,
     pub read_only: bool,
     pub modified: bool,
+    /// larger than `large_file_size`, it is neither highlighted nor sent to a language server
+    pub large_file: bool,
+    /// first line in view, kept by the editor while another buffer is shown
+    pub scroll_line: usize,
     pub buffer: Buffer,
 }
 
+impl BufferData {
+    /// Buffer `id` of `source`, with the pairs and the indentation configured for `lsp_lang`.
+    fn new(
+        id: u32,
+        source: BufferSource,
+        lsp_lang: LspLang,
+        read_only: bool,
+        mut buffer: Buffer,
+    ) -> Self {
+        let lsp_root = source.lsp_root();
+        let config = lock!(conf);
+        buffer.pairs = config.pairs_for(&lsp_lang);
+        buffer.indent = config.indent_for(&lsp_lang);
+        BufferData {
+            id,
+            lsp_root,
+            source,
+            lsp_lang,
+            read_only,
+            modified: false,
+            large_file: buffer.rope().len_bytes() > config.edit.large_file_size,
+            scroll_line: 0,
+            buffer,
+        }
+    }
+
+    /// Whether the language server knows the buffer, read-only copies and large files
+    /// are left out.
+    pub fn lsp_enabled(&self) -> bool {
+        !self.read_only && !self.large_file
+    }
+}
+
 pub trait Ignore {
     fn ignore(self);
 }
@@ -1,22 +1,44 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
 use std::process;
 use std::process::Command;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
 use jsonrpc_core::id::Id;
 use jsonrpc_core::Output;
 use lsp_types::request::Request;
 use lsp_types::*;
+use parking_lot::Mutex;
+use ropey::Rope;
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::process::ChildStdin;
+use tokio::process::{Child, ChildStdin};
 use tokio::sync::mpsc;
 
 use crate::buffer::{Bounds, IntoWithBuffer};
-use crate::lsp_ext::{InlayHint, InlayKind};
-use crate::{lock, lsp_ext, Path};
+use crate::config::LspTrace;
+use crate::lsp_ext::{InlayHint, InlayHintKind, InlayHintLabel, InlayKind};
+use crate::status::{error, notify};
+use crate::{lock, lsp_ext, snippet, Path};
+
+/// Time without edits after which their changes are sent, typing fast sends them at once.
+const EDIT_DELAY: Duration = Duration::from_millis(150);
+/// Requests answered for the text of a document at the time, a newer one for the same
+/// document cancels the previous one.
+const SUPERSEDED: &[&str] = &[
+    lsp_types::request::Completion::METHOD,
+    lsp_types::request::SignatureHelpRequest::METHOD,
+    lsp_types::request::CodeActionRequest::METHOD,
+    lsp_types::request::DocumentSymbolRequest::METHOD,
+    lsp_ext::InlayHints::METHOD,
+    lsp_ext::StandardInlayHints::METHOD,
+];
+/// Error codes of the requests the server stopped because they were stale.
+const REQUEST_CANCELLED: i64 = -32800;
+const CONTENT_MODIFIED: i64 = -32801;
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq, Deserialize, Serialize)]
 pub enum LspLang {
@@ -44,42 +66,88 @@ impl LspLang {
 }
 
 pub fn lsp_send(buffer_id: u32, input: LspInput) -> anyhow::Result<()> {
-    let global = lock!(global);
-    let root_path = &global.root_path;
-
     let buffers = lock!(buffers);
     let buffer = buffers.get(buffer_id)?;
+    if !buffer.lsp_enabled() {
+        anyhow::bail!("no language server for a read-only copy or a large file");
+    }
+    lsp_send_to(buffer.lsp_root.clone(), buffer.lsp_lang.clone(), input)
+}
+
+/// Whether the server of buffer `buffer_id` has `feature`, it is assumed while the server
+/// starts. Without a server nothing is supported.
+pub fn lsp_supports(buffer_id: u32, feature: LspFeature) -> bool {
+    let buffers = lock!(buffers);
+    let buffer = match buffers.get(buffer_id) {
+        Ok(buffer) if buffer.lsp_enabled() => buffer,
+        _ => return false,
+    };
+    let key = (buffer.lsp_root.clone(), buffer.lsp_lang.clone());
+    let lsp = lock!(lsp);
+    match lsp.clients.get(&key) {
+        Some(client) => client
+            .capabilities()
+            .map_or(true, |c| c.supports(&buffer.lsp_lang, feature)),
+        None => false,
+    }
+}
+
+/// Chars which request completion when typed in buffer `buffer_id`, none while its server
+/// is not running.
+pub fn lsp_trigger_characters(buffer_id: u32) -> Vec<String> {
+    let buffers = lock!(buffers);
+    let buffer = match buffers.get(buffer_id) {
+        Ok(buffer) if buffer.lsp_enabled() => buffer,
+        _ => return vec![],
+    };
+    let key = (buffer.lsp_root.clone(), buffer.lsp_lang.clone());
+    lock!(lsp)
+        .clients
+        .get(&key)
+        .map(|client| client.trigger_characters())
+        .unwrap_or_default()
+}
 
+/// Send `input` to the server of `lsp_lang` started in `root_path`.
+pub fn lsp_send_to(root_path: Url, lsp_lang: LspLang, input: LspInput) -> anyhow::Result<()> {
     let mut lsp = lock!(mut lsp);
-    let client = lsp
-        .get(root_path.uri(), &buffer.lsp_lang)
-        .context("no lsp client")?;
+    let client = lsp.get(root_path, &lsp_lang).context("no lsp client")?;
+    if client.is_dead() {
+        anyhow::bail!("the language server exited");
+    }
     client.input_channel.send(input)?;
     Ok(())
 }
 
-pub fn lsp_send_with_lang(lsp_lang: LspLang, input: LspInput) -> anyhow::Result<()> {
-    let global = lock!(global);
-    let root_path = &global.root_path;
-
+/// Restart the language servers of `lang`, with the files of the language open in them.
+pub fn lsp_restart(lang: LspLang) -> anyhow::Result<()> {
+    let mut roots: HashMap<Url, Vec<LspInput>> = HashMap::new();
+    roots.insert(lock!(global).root_path.uri(), vec![]);
+    {
+        let buffers = lock!(buffers);
+        for b in buffers.buffers.values() {
+            if let (Some(path), true) = (b.source.path(), b.lsp_lang == lang && b.lsp_enabled()) {
+                roots
+                    .entry(b.lsp_root.clone())
+                    .or_default()
+                    .push(b.buffer.open_input(path.uri()));
+            }
+        }
+    }
     let mut lsp = lock!(mut lsp);
-    let client = lsp
-        .get(root_path.uri(), &lsp_lang)
-        .context("no lsp client")?;
-    client.input_channel.send(input)?;
+    for (root_path, files) in roots {
+        lsp.restart(root_path, &lang, files)?;
+    }
     Ok(())
 }
 
 pub fn lsp_try_recv(buffer_id: u32) -> anyhow::Result<LspOutput> {
-    let global = lock!(global);
-    let root_path = &global.root_path;
-
     let buffers = lock!(buffers);
     let buffer = buffers.get(buffer_id)?;
 
     let mut lsp = lock!(mut lsp);
     let client = lsp
-        .get(root_path.uri(), &buffer.lsp_lang)
+        .get(buffer.lsp_root.clone(), &buffer.lsp_lang)
         .context("no lsp client found")?;
     let result = client.output_channel.try_recv()?;
     Ok(result)
@@ -89,18 +157,38 @@ pub fn lsp_try_recv(buffer_id: u32) -> anyhow::Result<LspOutput> {
 pub struct LspSystem {
     clients: HashMap<(Url, LspLang), LspClient>,
     counter: AtomicU64,
+    // of the last client started, a restarted client gets a new one
+    generation: u64,
     requests: HashMap<u64, SentRequest>,
+    // superseded before they were answered, their answer is dropped
+    cancelled: HashMap<u64, SentRequest>,
 }
 
 pub struct SentRequest {
     pub method: String,
     pub uri: Url,
+    /// generation of the client which sent it
+    pub client: Option<u64>,
+}
+
+tokio::task_local! {
+    // generation of the client of the task writing to a server, for the requests it sends
+    static CLIENT: u64;
 }
 
 impl LspSystem {
     pub fn new_request(&mut self, method: String, uri: Url) -> u64 {
-        let id = self.counter.fetch_add(1, Ordering::SeqCst);
-        self.requests.insert(id, SentRequest { method, uri });
+        // 0 is `initialize`
+        let id = self.counter.fetch_add(1, Ordering::SeqCst) + 1;
+        let client = CLIENT.try_with(|c| *c).ok();
+        self.requests.insert(
+            id,
+            SentRequest {
+                method,
+                uri,
+                client,
+            },
+        );
         id
     }
 
@@ -108,13 +196,79 @@ impl LspSystem {
         self.requests.remove(&id)
     }
 
+    /// Cancel the unanswered request of `method` about `uri` sent by the current client,
+    /// before a new one is sent. Returns its id, for `$/cancelRequest`.
+    pub fn supersede(&mut self, method: &str, uri: &Url) -> Option<u64> {
+        if !SUPERSEDED.contains(&method) {
+            return None;
+        }
+        let client = CLIENT.try_with(|c| *c).ok();
+        let id = *self
+            .requests
+            .iter()
+            .find(|(_, r)| r.method == method && &r.uri == uri && r.client == client)?
+            .0;
+        let request = self.requests.remove(&id)?;
+        self.cancelled.insert(id, request);
+        Some(id)
+    }
+
+    /// Whether request `id` was superseded, it is forgotten with its answer.
+    pub fn cancelled(&mut self, id: u64) -> bool {
+        self.cancelled.remove(&id).is_some()
+    }
+
+    /// Drop the requests of the client of `generation`, dead or replaced, they will never
+    /// be answered. A restarted client has another generation, its requests are kept.
+    pub fn forget_requests(&mut self, generation: u64) {
+        self.requests
+            .retain(|_, request| request.client != Some(generation));
+        self.cancelled
+            .retain(|_, request| request.client != Some(generation));
+    }
+
+    /// Start the server of `lang` again, even if it is still running, and open `files`
+    /// in it, the `didOpen` of the open buffers of the language.
+    pub fn restart(
+        &mut self,
+        root_path: Url,
+        lang: &LspLang,
+        files: Vec<LspInput>,
+    ) -> anyhow::Result<()> {
+        let key = (root_path.clone(), lang.clone());
+        // killed when dropped
+        if let Some(old) = self.clients.remove(&key) {
+            self.forget_requests(old.generation);
+        }
+        let cmd = lang.cmd().context("no language server")?;
+        self.generation += 1;
+        let client = LspClient::new(lang.clone(), root_path, cmd, self.generation)?;
+        for open in files {
+            client.input_channel.send(open)?;
+        }
+        self.clients.insert(key, client);
+        Ok(())
+    }
+
+    /// Ask every server to shut down then exit, returns their processes to wait for.
+    pub fn shutdown(&mut self) -> Vec<Child> {
+        self.clients
+            .drain()
+            .filter_map(|(_, client)| {
+                client.input_channel.send(LspInput::Shutdown).ok()?;
+                Some(client.process)
+            })
+            .collect()
+    }
+
     pub fn get(&mut self, root_path: Url, lang: &LspLang) -> Option<&mut LspClient> {
         let key = (root_path.clone(), lang.clone());
         if let Some(cmd) = lang.cmd() {
-            let client = self
-                .clients
-                .entry(key)
-                .or_insert_with(|| LspClient::new(lang.clone(), root_path.clone(), cmd).unwrap());
+            let generation = &mut self.generation;
+            let client = self.clients.entry(key).or_insert_with(|| {
+                *generation += 1;
+                LspClient::new(lang.clone(), root_path.clone(), cmd, *generation).unwrap()
+            });
             Some(client)
         } else {
             None
@@ -126,46 +280,352 @@ impl LspSystem {
 pub struct LspClient {
     pub input_channel: mpsc::UnboundedSender<LspInput>,
     pub output_channel: mpsc::UnboundedReceiver<LspOutput>,
+    // killed when dropped, after it was asked to exit
+    process: Child,
+    // set by the reader when the output of the server ends
+    dead: Arc<AtomicBool>,
+    // tells its requests from the ones of a restarted client
+    generation: u64,
+    // from the `initialize` result, `None` until the server answered it
+    capabilities: Arc<Mutex<Option<LspCapabilities>>>,
+}
+
+impl LspClient {
+    /// Whether the server exited, it has to be restarted to be used again.
+    pub fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::SeqCst)
+    }
+
+    /// What the server can do, `None` until it answered `initialize`.
+    pub fn capabilities(&self) -> Option<LspCapabilities> {
+        self.capabilities.lock().clone()
+    }
+
+    /// Chars which request completion when they are typed, none before the server is ready.
+    pub fn trigger_characters(&self) -> Vec<String> {
+        self.capabilities
+            .lock()
+            .as_ref()
+            .map(|c| trigger_characters(&c.server))
+            .unwrap_or_default()
+    }
+}
+
+/// What a server can do, from its `initialize` result.
+#[derive(Debug, Clone, Default)]
+pub struct LspCapabilities {
+    pub server: ServerCapabilities,
+    /// the changes are sent as edits, else as the whole text
+    pub incremental: bool,
+    /// `inlayHintProvider` of LSP 3.17, lsp-types does not have it yet
+    pub inlay_hints: bool,
+    /// the result could not be read, every request is sent
+    pub unknown: bool,
+}
+
+impl LspCapabilities {
+    fn parse(result: &serde_json::Value) -> Self {
+        let server = match serde_json::from_value::<InitializeResult>(result.clone()) {
+            Ok(result) => result.capabilities,
+            Err(e) => {
+                error(format!("language server: invalid capabilities: {}", e));
+                return LspCapabilities {
+                    unknown: true,
+                    ..Default::default()
+                };
+            }
+        };
+        let inlay_hints = !matches!(
+            result["capabilities"].get("inlayHintProvider"),
+            None | Some(serde_json::Value::Null) | Some(serde_json::Value::Bool(false))
+        );
+        LspCapabilities {
+            incremental: is_incremental(&server),
+            server,
+            inlay_hints,
+            unknown: false,
+        }
+    }
+
+    /// Whether the server of `lang` answers the requests of `feature`.
+    pub fn supports(&self, lang: &LspLang, feature: LspFeature) -> bool {
+        if self.unknown {
+            return true;
+        }
+        fn enabled<T>(provider: &Option<OneOf<bool, T>>) -> bool {
+            match provider {
+                Some(OneOf::Left(enabled)) => *enabled,
+                Some(OneOf::Right(_)) => true,
+                None => false,
+            }
+        }
+        let server = &self.server;
+        match feature {
+            LspFeature::Completion => server.completion_provider.is_some(),
+            LspFeature::CompletionResolve => server
+                .completion_provider
+                .as_ref()
+                .and_then(|p| p.resolve_provider)
+                .unwrap_or(false),
+            LspFeature::SignatureHelp => server.signature_help_provider.is_some(),
+            LspFeature::CodeActions => match &server.code_action_provider {
+                Some(CodeActionProviderCapability::Simple(enabled)) => *enabled,
+                Some(CodeActionProviderCapability::Options(_)) => true,
+                None => false,
+            },
+            LspFeature::CodeActionResolve => match &server.code_action_provider {
+                Some(CodeActionProviderCapability::Options(options)) => {
+                    options.resolve_provider.unwrap_or(false)
+                }
+                _ => false,
+            },
+            LspFeature::Rename => enabled(&server.rename_provider),
+            LspFeature::DocumentSymbols => enabled(&server.document_symbol_provider),
+            // rust-analyzer answers its own request without advertising it
+            LspFeature::InlayHints => self.inlay_hints || lang == &LspLang::Rust,
+        }
+    }
+}
+
+/// Requests which a server may not implement, they are only sent if it advertises them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LspFeature {
+    Completion,
+    CompletionResolve,
+    SignatureHelp,
+    CodeActions,
+    CodeActionResolve,
+    Rename,
+    DocumentSymbols,
+    InlayHints,
+}
+
+impl LspFeature {
+    /// Feature needed to send `input`, `None` for the notifications every server handles.
+    fn of(input: &LspInput) -> Option<LspFeature> {
+        Some(match input {
+            LspInput::RequestCompletion { .. } => LspFeature::Completion,
+            LspInput::RequestCompletionResolve { .. } => LspFeature::CompletionResolve,
+            LspInput::RequestSignatureHelp { .. } => LspFeature::SignatureHelp,
+            LspInput::RequestCodeActions { .. } => LspFeature::CodeActions,
+            LspInput::RequestCodeActionResolve { .. } => LspFeature::CodeActionResolve,
+            LspInput::RequestRename { .. } => LspFeature::Rename,
+            LspInput::RequestDocumentSymbols { .. } => LspFeature::DocumentSymbols,
+            LspInput::InlayHints { .. } => LspFeature::InlayHints,
+            _ => return None,
+        })
+    }
 }
 
 #[derive(Debug)]
 pub enum LspInput {
+    /// the buffer was edited, its pending changes are sent once the edits pause
     Edit {
         buffer_id: u32,
-        version: i32,
-        text: String,
     },
+    /// `trigger_character` is the char typed before the cursor which asked for it,
+    /// `None` when the user invoked it
     RequestCompletion {
         buffer_id: u32,
         row: u32,
         col: u32,
+        trigger_character: Option<String>,
     },
     RequestCompletionResolve {
         buffer_id: u32,
         item: CompletionItem,
     },
+    /// the text is a cheap copy of the rope, written to the server from its chunks
     OpenFile {
         uri: Url,
-        content: String,
+        content: Rope,
     },
     CloseFile {
         uri: Url,
     },
     SavedFile {
         uri: Url,
-        content: String,
+        content: Rope,
     },
     InlayHints {
         uri: Url,
     },
+    /// the diagnostics of the buffer in `range` are sent with it
+    RequestCodeActions {
+        buffer_id: u32,
+        range: Range,
+    },
+    RequestCodeActionResolve {
+        buffer_id: u32,
+        action: CodeAction,
+    },
+    RequestSignatureHelp {
+        buffer_id: u32,
+        row: u32,
+        col: u32,
+    },
+    RequestRename {
+        buffer_id: u32,
+        row: u32,
+        col: u32,
+        new_name: String,
+    },
+    RequestDocumentSymbols {
+        buffer_id: u32,
+    },
+    /// empty result to a request of the server
+    Respond {
+        id: serde_json::Value,
+    },
+    /// `shutdown`, the server answer sends `Exit`
+    Shutdown,
+    /// `exit`, nothing is sent after it
+    Exit,
+}
+
+/// Work done progress of the server, the completions that came back empty
+/// while it was indexing are requested again when it is done.
+#[derive(Default)]
+struct Progress {
+    running: HashSet<String>,
+    last_completion: Option<(u32, u32, u32)>,
+    retry: bool,
+}
+
+impl Progress {
+    fn indexing(&self) -> bool {
+        !self.running.is_empty()
+    }
+
+    /// Handle the params of `$/progress`, returns the completion to request again.
+    fn update(&mut self, params: &serde_json::Value) -> Option<LspInput> {
+        let token = params.get("token")?.to_string();
+        match params.get("value")?.get("kind")?.as_str()? {
+            "begin" => {
+                self.running.insert(token);
+            }
+            "end" => {
+                self.running.remove(&token);
+            }
+            _ => {}
+        }
+        if !self.indexing() && std::mem::take(&mut self.retry) {
+            let (buffer_id, row, col) = self.last_completion?;
+            return Some(LspInput::RequestCompletion {
+                buffer_id,
+                row,
+                col,
+                trigger_character: None,
+            });
+        }
+        None
+    }
+}
+
+/// Inputs of a server in the order the writer sends them: the edits wait for a pause,
+/// and of the completions queued meanwhile only the last one is requested.
+struct InputQueue {
+    rx: mpsc::UnboundedReceiver<LspInput>,
+    /// a full sync waits for the text to be needed, the edits are not sent on their own
+    incremental: bool,
+    /// buffers whose changes wait for the edits to pause
+    edited: HashSet<u32>,
+    /// taken from the channel while looking for a newer completion
+    next: Option<LspInput>,
+}
+
+/// Buffers whose changes are sent before `input`, it is `None` when the edits paused.
+struct InputBatch {
+    edited: Vec<u32>,
+    input: Option<LspInput>,
+}
+
+impl InputQueue {
+    fn new(rx: mpsc::UnboundedReceiver<LspInput>, incremental: bool) -> Self {
+        Self {
+            rx,
+            incremental,
+            edited: HashSet::new(),
+            next: None,
+        }
+    }
+
+    /// The next batch to send, `None` once the channel is closed.
+    async fn next(&mut self) -> Option<InputBatch> {
+        let mut input = loop {
+            let input = match self.next.take() {
+                Some(input) => input,
+                None if self.edited.is_empty() => self.rx.recv().await?,
+                None => match tokio::time::timeout(EDIT_DELAY, self.rx.recv()).await {
+                    Ok(input) => input?,
+                    Err(_) => return Some(self.batch(None)),
+                },
+            };
+            match input {
+                LspInput::Edit { buffer_id } if self.incremental => {
+                    self.edited.insert(buffer_id);
+                }
+                LspInput::Edit { .. } => {}
+                input => break input,
+            }
+        };
+        if let LspInput::RequestCompletion { .. } = input {
+            while let Ok(queued) = self.rx.try_recv() {
+                match queued {
+                    LspInput::RequestCompletion { .. } => input = queued,
+                    // the completions after an edit are for the new text
+                    other => {
+                        self.next = Some(other);
+                        break;
+                    }
+                }
+            }
+        }
+        Some(self.batch(Some(input)))
+    }
+
+    fn batch(&mut self, input: Option<LspInput>) -> InputBatch {
+        let mut edited: Vec<u32> = self.edited.drain().collect();
+        edited.sort_unstable();
+        InputBatch { edited, input }
+    }
 }
 
 #[derive(Debug)]
 pub enum LspOutput {
-    Completion(Vec<LspCompletion>),
+    /// completions and the number dropped by the cap
+    Completion(Vec<LspCompletion>, usize),
     CompletionResolve(LspCompletion),
     InlayHints,
     Diagnostics,
+    /// edits of a rename, they can touch files which are not open
+    WorkspaceEdit(WorkspaceEdit),
+    SignatureHelp(Option<LspSignature>),
+    CodeActions(Vec<CodeAction>),
+    /// outline of the document `Url`
+    DocumentSymbols(Url, Vec<LspSymbol>),
+    /// the request of the method about the document was answered with an error, it is
+    /// logged already
+    Failed(String, Url),
+}
+
+/// Symbol of the outline, with the ones defined inside of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LspSymbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// the whole definition, its children are in it
+    pub range: Range,
+    /// where the cursor jumps to, usually the name
+    pub selection: Range,
+    pub children: Vec<LspSymbol>,
+}
+
+/// Active signature of a call, `active` is the byte range of the active parameter in `label`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LspSignature {
+    pub label: String,
+    pub active: Option<(usize, usize)>,
 }
 
 #[derive(Debug, Clone)]
@@ -173,11 +633,68 @@ pub struct LspCompletion {
     pub original_item: CompletionItem,
     pub label: String,
     pub data: CompletionData,
+    /// typing one of them accepts the completion
+    pub commit_characters: Vec<String>,
+    /// tab stops of a snippet, from the start of the text it inserts
+    pub stops: Vec<Bounds>,
+    pub kind: Option<CompletionItemKind>,
+}
+
+impl LspCompletion {
+    /// Letter shown before the label for the kind of the item.
+    pub fn kind_letter(&self) -> &'static str {
+        match self.kind {
+            Some(CompletionItemKind::METHOD | CompletionItemKind::FUNCTION) => "f",
+            Some(CompletionItemKind::CONSTRUCTOR) => "c",
+            Some(CompletionItemKind::FIELD | CompletionItemKind::PROPERTY) => "p",
+            Some(CompletionItemKind::VARIABLE) => "v",
+            Some(CompletionItemKind::CLASS | CompletionItemKind::STRUCT) => "s",
+            Some(CompletionItemKind::INTERFACE) => "i",
+            Some(CompletionItemKind::MODULE) => "m",
+            Some(CompletionItemKind::ENUM) => "e",
+            Some(CompletionItemKind::ENUM_MEMBER) => "E",
+            Some(CompletionItemKind::KEYWORD) => "k",
+            Some(CompletionItemKind::SNIPPET) => "S",
+            Some(CompletionItemKind::CONSTANT) => "C",
+            Some(CompletionItemKind::TYPE_PARAMETER) => "t",
+            _ => " ",
+        }
+    }
+
+    /// Theme scope of the kind, under `ui.completion`.
+    pub fn kind_scope(&self) -> &'static str {
+        match self.kind {
+            Some(
+                CompletionItemKind::METHOD
+                | CompletionItemKind::FUNCTION
+                | CompletionItemKind::CONSTRUCTOR,
+            ) => "function",
+            Some(
+                CompletionItemKind::CLASS
+                | CompletionItemKind::STRUCT
+                | CompletionItemKind::INTERFACE
+                | CompletionItemKind::ENUM
+                | CompletionItemKind::TYPE_PARAMETER,
+            ) => "type",
+            Some(CompletionItemKind::KEYWORD) => "keyword",
+            _ => "other",
+        }
+    }
+
+    /// Documentation of the item, markdown is shown as it is.
+    pub fn documentation(&self) -> Option<&str> {
+        match self.original_item.documentation.as_ref()? {
+            Documentation::String(s) => Some(s),
+            Documentation::MarkupContent(content) => Some(&content.value),
+        }
+        .filter(|s| !s.trim().is_empty())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum CompletionData {
     Simple(String),
+    /// the first edit inserts the completion, the others are additional edits
     Edits(Vec<TextEdit>),
 }
 
@@ -187,8 +704,22 @@ pub struct TextEdit {
     pub new_text: String,
 }
 
+impl From<lsp_types::TextEdit> for TextEdit {
+    fn from(e: lsp_types::TextEdit) -> Self {
+        TextEdit {
+            range: e.range,
+            new_text: e.new_text,
+        }
+    }
+}
+
 impl LspClient {
-    fn new(lang: LspLang, root_path: Url, cmd: Command) -> anyhow::Result<LspClient> {
+    fn new(
+        lang: LspLang,
+        root_path: Url,
+        cmd: Command,
+        generation: u64,
+    ) -> anyhow::Result<LspClient> {
         let mut lsp = tokio::process::Command::from(cmd)
             .stdin(process::Stdio::piped())
             .stdout(process::Stdio::piped())
@@ -200,7 +731,7 @@ impl LspClient {
         let init = lsp_types::InitializeParams {
             process_id: Some(u32::from(process::id())),
             root_path: None,
-            root_uri: Some(root_path),
+            root_uri: Some(root_path.clone()),
             initialization_options: None,
             capabilities: lsp_types::ClientCapabilities {
                 workspace: None,
@@ -214,8 +745,8 @@ impl LspClient {
                     completion: Some(CompletionClientCapabilities {
                         dynamic_registration: Some(false),
                         completion_item: Some(CompletionItemCapability {
-                            snippet_support: Some(false),
-                            commit_characters_support: None,
+                            snippet_support: Some(true),
+                            commit_characters_support: Some(true),
                             documentation_format: None,
                             deprecated_support: None,
                             preselect_support: None,
@@ -230,10 +761,23 @@ impl LspClient {
                         context_support: Some(false),
                     }),
                     hover: None,
-                    signature_help: None,
+                    signature_help: Some(SignatureHelpClientCapabilities {
+                        dynamic_registration: Some(false),
+                        signature_information: Some(SignatureInformationSettings {
+                            documentation_format: None,
+                            parameter_information: Some(ParameterInformationSettings {
+                                label_offset_support: Some(true),
+                            }),
+                            active_parameter_support: Some(true),
+                        }),
+                        context_support: Some(false),
+                    }),
                     references: None,
                     document_highlight: None,
-                    document_symbol: None,
+                    document_symbol: Some(DocumentSymbolClientCapabilities {
+                        hierarchical_document_symbol_support: Some(true),
+                        ..Default::default()
+                    }),
                     formatting: None,
                     range_formatting: None,
                     on_type_formatting: None,
@@ -243,19 +787,36 @@ impl LspClient {
                     implementation: None,
                     code_action: Some(CodeActionClientCapabilities {
                         dynamic_registration: Some(false),
-                        code_action_literal_support: None,
+                        code_action_literal_support: Some(CodeActionLiteralSupport {
+                            code_action_kind: CodeActionKindLiteralSupport {
+                                value_set: vec![
+                                    "".into(),
+                                    "quickfix".into(),
+                                    "refactor".into(),
+                                    "refactor.extract".into(),
+                                    "refactor.inline".into(),
+                                    "refactor.rewrite".into(),
+                                    "source".into(),
+                                ],
+                            },
+                        }),
                         is_preferred_support: None,
                         disabled_support: None,
                         data_support: Some(true),
                         resolve_support: Some(CodeActionCapabilityResolveSupport {
-                            properties: vec![],
+                            properties: vec!["edit".into()],
                         }),
                         honors_change_annotations: None,
                     }),
                     code_lens: None,
                     document_link: None,
                     color_provider: None,
-                    rename: None,
+                    rename: Some(RenameClientCapabilities {
+                        dynamic_registration: Some(false),
+                        prepare_support: Some(false),
+                        prepare_support_default_behavior: None,
+                        honors_change_annotations: Some(false),
+                    }),
                     publish_diagnostics: Some(PublishDiagnosticsClientCapabilities {
                         related_information: Some(true),
                         tag_support: Some(TagSupport {
@@ -272,18 +833,30 @@ impl LspClient {
                     semantic_tokens: None,
                     moniker: None,
                 }),
-                window: None,
+                window: Some(WindowClientCapabilities {
+                    work_done_progress: Some(true),
+                    show_message: None,
+                    show_document: None,
+                }),
                 general: Some(GeneralClientCapabilities {
                     regular_expressions: None,
                     markdown: None,
                 }),
                 experimental: None,
             },
-            trace: Some(TraceOption::Verbose),
+            trace: Some(match trace_level() {
+                LspTrace::Off => TraceOption::Off,
+                LspTrace::Messages => TraceOption::Messages,
+                LspTrace::Verbose => TraceOption::Verbose,
+            }),
             workspace_folders: None,
             client_info: None,
             locale: None,
         };
+        // lsp-types does not have the inlay hints of LSP 3.17 yet
+        let mut init = serde_json::to_value(init)?;
+        init["capabilities"]["textDocument"]["inlayHint"] =
+            serde_json::json!({ "dynamicRegistration": false });
 
         let mut stdin = lsp.stdin.take().context("take stdin")?;
         let mut reader = tokio::io::BufReader::new(lsp.stdout.take().context("take stdout")?);
@@ -291,15 +864,25 @@ impl LspClient {
         let (init_tx, mut init_rx) = mpsc::unbounded_channel();
         let (tx, rx) = mpsc::unbounded_channel();
 
-        let (c_tx, mut c_rx) = mpsc::unbounded_channel::<LspInput>();
+        let (c_tx, c_rx) = mpsc::unbounded_channel::<LspInput>();
+        let reader_tx = c_tx.clone();
+
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let reader_progress = progress.clone();
+
+        let dead = Arc::new(AtomicBool::new(false));
+        let reader_dead = dead.clone();
+        let capabilities = Arc::new(Mutex::new(None));
+        let reader_capabilities = capabilities.clone();
+        let reader_lang = lang.clone();
 
         let lang_clone = lang.clone();
-        tokio::spawn(async move {
-            send_request_async_with_id::<_, lsp_types::request::Initialize>(&mut stdin, 0, init)
+        tokio::spawn(CLIENT.scope(generation, async move {
+            send_request_async_with_id::<_, lsp_ext::Initialize>(&mut stdin, 0, init)
                 .await
                 .unwrap();
             // Wait initialize
-            init_rx.recv().await.unwrap();
+            let capabilities = init_rx.recv().await.unwrap();
 
             send_notify_async::<_, lsp_types::notification::Initialized>(
                 &mut stdin,
@@ -308,102 +891,307 @@ impl LspClient {
             .await
             .unwrap();
 
-            while let Some(lsp_input) = c_rx.recv().await {
-                let r = Self::process_input(&lang_clone, &mut stdin, lsp_input).await;
+            let mut queue = InputQueue::new(c_rx, capabilities.incremental);
+            while let Some(batch) = queue.next().await {
+                // the server has the latest text before anything else, a save in particular
+                Self::flush_edits(&lang_clone, &mut stdin, batch.edited, &capabilities).await;
+                let lsp_input = match batch.input {
+                    Some(LspInput::Exit) => {
+                        send_notify_async::<_, lsp_types::notification::Exit>(&mut stdin, ())
+                            .await?;
+                        break;
+                    }
+                    Some(input) => input,
+                    None => continue,
+                };
+                if let LspInput::RequestCompletion {
+                    buffer_id,
+                    row,
+                    col,
+                    ..
+                } = &lsp_input
+                {
+                    progress.lock().last_completion = Some((*buffer_id, *row, *col));
+                }
+                let r =
+                    Self::process_input(&lang_clone, &mut stdin, lsp_input, &capabilities).await;
                 if let Err(e) = r {
-                    println!("{}", e);
+                    error(format!("{:?} language server: {}", lang_clone, e));
                 }
             }
             Ok::<(), anyhow::Error>(())
-        });
+        }));
 
         tokio::spawn(async move {
-            let mut headers = HashMap::new();
-            loop {
-                headers.clear();
+            let read = async {
+                let mut headers = HashMap::new();
                 loop {
-                    let mut header = String::new();
-                    if reader.read_line(&mut header).await? == 0 {
-                        return Ok::<(), anyhow::Error>(());
-                    }
-                    let header = header.trim();
-                    if header.is_empty() {
-                        break;
+                    headers.clear();
+                    loop {
+                        let mut header = String::new();
+                        if reader.read_line(&mut header).await? == 0 {
+                            // the server exited, or crashed
+                            return Ok::<(), anyhow::Error>(());
+                        }
+                        let header = header.trim();
+                        if header.is_empty() {
+                            break;
+                        }
+                        let parts: Vec<&str> = header.split(": ").collect();
+                        if parts.len() != 2 {
+                            error(format!("language server: invalid header `{}`", header));
+                            continue;
+                        }
+                        headers.insert(parts[0].to_string(), parts[1].to_string());
                     }
-                    let parts: Vec<&str> = header.split(": ").collect();
-                    assert_eq!(parts.len(), 2);
-                    headers.insert(parts[0].to_string(), parts[1].to_string());
-                }
-                let content_len = headers["Content-Length"].parse()?;
-                let mut content = vec![0; content_len];
-                reader.read_exact(&mut content).await?;
-                let msg = String::from_utf8(content)?;
-                let output: serde_json::Result<Output> = serde_json::from_str(&msg);
-                let notification: serde_json::Result<serde_json::Value> =
-                    serde_json::from_str(&msg);
-                if let Ok(Output::Success(suc)) = output {
-                    println!("{}", suc.result);
-                    if let Id::Num(id) = suc.id {
-                        if id == 0 {
-                            init_tx.send(())?;
-                        } else {
-                            let request = {
-                                let mut lsp = lock!(mut lsp);
-                                lsp.get_request(id).unwrap()
-                            };
-                            match request.method.as_str() {
-                                lsp_types::request::Completion::METHOD => {
-                                    let completion =
-                                        serde_json::from_value::<lsp_types::CompletionResponse>(
-                                            suc.result,
-                                        )?;
-                                    let completions = match completion {
-                                        CompletionResponse::Array(arr) => convert_completions(arr),
-                                        CompletionResponse::List(list) => {
-                                            convert_completions(list.items)
+                    let content_len = headers
+                        .get("Content-Length")
+                        .context("no Content-Length header")?
+                        .parse()?;
+                    let mut content = vec![0; content_len];
+                    reader.read_exact(&mut content).await?;
+                    let msg = String::from_utf8(content)?;
+                    let output: serde_json::Result<Output> = serde_json::from_str(&msg);
+                    let notification: serde_json::Result<serde_json::Value> =
+                        serde_json::from_str(&msg);
+                    if let Ok(Output::Success(suc)) = output {
+                        if trace_level() == LspTrace::Verbose {
+                            println!("{}", suc.result);
+                        }
+                        if let Id::Num(id) = suc.id {
+                            if id == 0 {
+                                let capabilities = LspCapabilities::parse(&suc.result);
+                                *reader_capabilities.lock() = Some(capabilities.clone());
+                                init_tx.send(capabilities)?;
+                            } else {
+                                let request = {
+                                    let mut lsp = lock!(mut lsp);
+                                    if lsp.cancelled(id) {
+                                        continue;
+                                    }
+                                    lsp.get_request(id)
+                                };
+                                let request = match request {
+                                    Some(request) => request,
+                                    None => {
+                                        error(format!("language server: unknown request {}", id));
+                                        continue;
+                                    }
+                                };
+                                // a response the editor cannot use is not fatal
+                                let handle = || -> anyhow::Result<()> {
+                                    match request.method.as_str() {
+                                        lsp_types::request::Completion::METHOD => {
+                                            let completion: Option<CompletionResponse> =
+                                                serde_json::from_value(suc.result)?;
+                                            let max = lock!(conf).lsp.max_completions;
+                                            let (completions, hidden) = match completion {
+                                                Some(CompletionResponse::Array(arr)) => {
+                                                    convert_completions(arr, max)
+                                                }
+                                                Some(CompletionResponse::List(list)) => {
+                                                    convert_completions(list.items, max)
+                                                }
+                                                None => (vec![], 0),
+                                            };
+                                            if completions.is_empty() {
+                                                let mut progress = reader_progress.lock();
+                                                progress.retry = progress.indexing();
+                                            }
+                                            tx.send(LspOutput::Completion(completions, hidden))?;
                                         }
-                                    };
-                                    tx.send(LspOutput::Completion(completions))?;
-                                }
-                                lsp_types::request::ResolveCompletionItem::METHOD => {
-                                    let item: CompletionItem = serde_json::from_value(suc.result)?;
-                                    tx.send(LspOutput::CompletionResolve(
-                                        convert_completion(item).unwrap(),
+                                        lsp_types::request::ResolveCompletionItem::METHOD => {
+                                            let item: CompletionItem =
+                                                serde_json::from_value(suc.result)?;
+                                            let completion = convert_completion(item)
+                                                .context("completion without text")?;
+                                            tx.send(LspOutput::CompletionResolve(completion))?;
+                                        }
+                                        lsp_ext::InlayHints::METHOD => {
+                                            let item: Vec<InlayHint> =
+                                                serde_json::from_value(suc.result)?;
+                                            process_inlay_hints(request.uri.clone(), item);
+                                            tx.send(LspOutput::InlayHints)?;
+                                        }
+                                        lsp_ext::StandardInlayHints::METHOD => {
+                                            let item: Option<Vec<lsp_ext::StandardInlayHint>> =
+                                                serde_json::from_value(suc.result)?;
+                                            let hints =
+                                                convert_inlay_hints(item.unwrap_or_default());
+                                            process_inlay_hints(request.uri.clone(), hints);
+                                            tx.send(LspOutput::InlayHints)?;
+                                        }
+                                        lsp_types::request::CodeActionRequest::METHOD => {
+                                            let response: Option<CodeActionResponse> =
+                                                serde_json::from_value(suc.result)?;
+                                            tx.send(LspOutput::CodeActions(convert_code_actions(
+                                                response.unwrap_or_default(),
+                                            )))?;
+                                        }
+                                        lsp_types::request::CodeActionResolveRequest::METHOD => {
+                                            let action: CodeAction =
+                                                serde_json::from_value(suc.result)?;
+                                            if let Some(edit) = action.edit {
+                                                tx.send(LspOutput::WorkspaceEdit(edit))?;
+                                            }
+                                        }
+                                        lsp_types::request::SignatureHelpRequest::METHOD => {
+                                            let help: Option<SignatureHelp> =
+                                                serde_json::from_value(suc.result)?;
+                                            tx.send(LspOutput::SignatureHelp(
+                                                help.and_then(convert_signature_help),
+                                            ))?;
+                                        }
+                                        lsp_types::request::DocumentSymbolRequest::METHOD => {
+                                            let response: Option<DocumentSymbolResponse> =
+                                                serde_json::from_value(suc.result)?;
+                                            tx.send(LspOutput::DocumentSymbols(
+                                                request.uri.clone(),
+                                                response.map(convert_symbols).unwrap_or_default(),
+                                            ))?;
+                                        }
+                                        lsp_types::request::Shutdown::METHOD => {
+                                            reader_tx.send(LspInput::Exit)?;
+                                        }
+                                        lsp_types::request::Rename::METHOD => {
+                                            let edit: Option<WorkspaceEdit> =
+                                                serde_json::from_value(suc.result)?;
+                                            if let Some(edit) = edit {
+                                                tx.send(LspOutput::WorkspaceEdit(edit))?;
+                                            }
+                                        }
+                                        _ => {}
+                                    }
+                                    Ok(())
+                                };
+                                if let Err(e) = handle() {
+                                    error(format!(
+                                        "language server: invalid {} response: {}",
+                                        request.method, e
+                                    ));
+                                    // the editor stops waiting for it
+                                    tx.send(LspOutput::Failed(
+                                        request.method.clone(),
+                                        request.uri.clone(),
                                     ))?;
                                 }
-                                lsp_ext::InlayHints::METHOD => {
-                                    let item: Vec<InlayHint> = serde_json::from_value(suc.result)?;
-                                    process_inlay_hints(request.uri, item);
-                                    tx.send(LspOutput::InlayHints)?;
-                                }
-                                _ => {}
                             }
                         }
-                    }
-                } else if let Ok(notification) = notification {
-                    if let Some(method) = notification.get("method") {
-                        if method == "textDocument/publishDiagnostics" {
-                            let params: PublishDiagnosticsParams =
-                                serde_json::from_value(notification.get("params").unwrap().clone())
-                                    .unwrap();
-                            let diagnostics = params.diagnostics;
-                            process_diagnostics(params.uri.clone(), diagnostics);
-                            tx.send(LspOutput::Diagnostics)?;
-                        } else {
-                            println!("{} {:?}", method, notification);
+                    } else if let Ok(Output::Failure(failure)) = &output {
+                        let (cancelled, request) = match failure.id {
+                            Id::Num(id) => {
+                                let mut lsp = lock!(mut lsp);
+                                (lsp.cancelled(id), lsp.get_request(id))
+                            }
+                            _ => (false, None),
+                        };
+                        let method = request.as_ref().map(|r| r.method.as_str());
+                        if method == Some(lsp_types::request::Shutdown::METHOD) {
+                            reader_tx.send(LspInput::Exit)?;
+                        }
+                        let code = failure.error.code.code();
+                        let stale =
+                            cancelled || code == REQUEST_CANCELLED || code == CONTENT_MODIFIED;
+                        if !stale {
+                            error(format!(
+                                "language server: {} failed ({}): {}",
+                                method.unwrap_or("request"),
+                                code,
+                                failure.error.message
+                            ));
+                        }
+                        // the editor stops waiting for it, a stale request was replaced
+                        match request {
+                            Some(request) if !stale => {
+                                tx.send(LspOutput::Failed(request.method, request.uri))?;
+                            }
+                            _ => {}
+                        }
+                    } else if let Ok(notification) = notification {
+                        if let Some(method) = notification.get("method") {
+                            let params = notification.get("params").cloned().unwrap_or_default();
+                            if method == "window/showMessage" {
+                                match serde_json::from_value::<ShowMessageParams>(params) {
+                                    Ok(params) if params.typ == MessageType::ERROR => {
+                                        error(format!("language server: {}", params.message))
+                                    }
+                                    Ok(params) => {
+                                        notify(format!("language server: {}", params.message))
+                                    }
+                                    Err(e) => error(format!("language server: {}", e)),
+                                }
+                            } else if method == "textDocument/publishDiagnostics" {
+                                match serde_json::from_value::<PublishDiagnosticsParams>(params) {
+                                    Ok(params) => {
+                                        process_diagnostics(params.uri.clone(), params.diagnostics);
+                                        tx.send(LspOutput::Diagnostics)?;
+                                    }
+                                    Err(e) => error(format!("language server diagnostics: {}", e)),
+                                }
+                            } else if method == "window/workDoneProgress/create" {
+                                if let Some(id) = notification.get("id") {
+                                    reader_tx.send(LspInput::Respond { id: id.clone() })?;
+                                }
+                            } else if method == "$/progress" {
+                                let retry = match notification.get("params") {
+                                    Some(params) => reader_progress.lock().update(params),
+                                    None => None,
+                                };
+                                if let Some(LspInput::RequestCompletion {
+                                    buffer_id,
+                                    row,
+                                    col,
+                                    ..
+                                }) = retry
+                                {
+                                    // only if the cursor did not move since
+                                    let same = {
+                                        let buffers = lock!(buffers);
+                                        buffers.get(buffer_id).map_or(false, |buf| {
+                                            buf.buffer.row() == row as usize
+                                                && buf.buffer.col() == col as usize
+                                        })
+                                    };
+                                    if same {
+                                        reader_tx.send(LspInput::RequestCompletion {
+                                            buffer_id,
+                                            row,
+                                            col,
+                                            trigger_character: None,
+                                        })?;
+                                    }
+                                }
+                            } else {
+                                match trace_level() {
+                                    LspTrace::Off => {}
+                                    LspTrace::Messages => println!("{}", method),
+                                    LspTrace::Verbose => println!("{} {:?}", method, notification),
+                                }
+                            }
+                        } else if trace_level() == LspTrace::Verbose {
+                            println!("{:?}", notification);
                         }
                     } else {
-                        println!("{:?}", notification);
+                        error(format!("language server: invalid message `{}`", msg));
                     }
-                } else {
-                    println!("fail : {}", msg);
                 }
+            };
+            let result = read.await;
+            // whatever ended the output, the server cannot be used anymore
+            reader_dead.store(true, Ordering::SeqCst);
+            lock!(mut lsp).forget_requests(generation);
+            if let Err(e) = result {
+                error(format!("{:?} language server: {}", reader_lang, e));
             }
         });
 
         Ok(Self {
             output_channel: rx,
             input_channel: c_tx,
+            process: lsp,
+            dead,
+            generation,
+            capabilities,
         })
     }
 
@@ -411,29 +1199,45 @@ impl LspClient {
         lang: &LspLang,
         mut stdin: &mut ChildStdin,
         lsp_input: LspInput,
+        capabilities: &LspCapabilities,
     ) -> anyhow::Result<()> {
+        let supports = |feature| capabilities.supports(lang, feature);
+        // the server would answer with an error
+        if let Some(feature) = LspFeature::of(&lsp_input) {
+            if !supports(feature) {
+                return Ok(());
+            }
+        }
+        let incremental = capabilities.incremental;
         match lsp_input {
             LspInput::RequestCompletion {
                 row,
                 col,
                 buffer_id,
+                trigger_character,
             } => {
-                let url = notify_did_change(&mut stdin, buffer_id).await.unwrap();
-                request_completion(&mut stdin, row, col, url).await;
+                let url = notify_did_change(&mut stdin, buffer_id, incremental).await?;
+                request_completion(&mut stdin, row, col, url, trigger_character).await;
             }
-            LspInput::RequestCompletionResolve { item, .. } => {
-                request_resolve_completion_item(&mut stdin, item)
-                    .await
-                    .unwrap();
+            LspInput::RequestCompletionResolve { buffer_id, item } => {
+                // the failure goes back to the buffer
+                let uri = {
+                    let buffers = lock!(buffers);
+                    buffers.get(buffer_id)?.source.path().context("path")?.uri()
+                };
+                request_resolve_completion_item(&mut stdin, uri, item).await?;
             }
             LspInput::OpenFile { uri: url, content } => {
-                notify_did_open(&mut stdin, url.clone(), content)
-                    .await
-                    .unwrap();
-                request_inlay_hints(&mut stdin, url).await.unwrap();
+                notify_did_open(&mut stdin, url.clone(), &content).await?;
+                if supports(LspFeature::DocumentSymbols) {
+                    request_document_symbols(&mut stdin, url.clone()).await?;
+                }
+                if supports(LspFeature::InlayHints) {
+                    request_inlay_hints(&mut stdin, lang, url).await?;
+                }
             }
             LspInput::CloseFile { uri } => {
-                notify_did_close(&mut stdin, uri).await.unwrap();
+                notify_did_close(&mut stdin, uri).await?;
             }
             LspInput::SavedFile { uri, content } => {
                 let id = {
@@ -443,27 +1247,79 @@ impl LspClient {
                         .context("buffer not found")?
                         .id
                 };
-                notify_did_change(&mut stdin, id).await.unwrap();
-                notify_did_save(&mut stdin, uri.clone(), content)
-                    .await
-                    .unwrap();
-                if let LspLang::Rust = lang {
-                    request_inlay_hints(&mut stdin, uri).await.unwrap();
+                notify_did_change(&mut stdin, id, incremental).await?;
+                notify_did_save(&mut stdin, uri.clone(), &content).await?;
+                if supports(LspFeature::InlayHints) {
+                    request_inlay_hints(&mut stdin, lang, uri).await?;
                 }
             }
             LspInput::InlayHints { uri } => {
-                if let LspLang::Rust = lang {
-                    request_inlay_hints(&mut stdin, uri).await.unwrap();
-                }
+                request_inlay_hints(&mut stdin, &lang, uri).await?;
+            }
+            LspInput::RequestCodeActions { buffer_id, range } => {
+                let url = notify_did_change(&mut stdin, buffer_id, incremental).await?;
+                let diagnostics = diagnostics_in(buffer_id, range)?;
+                request_code_actions(&mut stdin, url, range, diagnostics).await?;
+            }
+            LspInput::RequestCodeActionResolve { action, .. } => {
+                request_resolve_code_action(&mut stdin, action).await?;
+            }
+            LspInput::RequestSignatureHelp {
+                buffer_id,
+                row,
+                col,
+            } => {
+                let url = notify_did_change(&mut stdin, buffer_id, incremental).await?;
+                request_signature_help(&mut stdin, row, col, url).await;
+            }
+            LspInput::RequestRename {
+                buffer_id,
+                row,
+                col,
+                new_name,
+            } => {
+                let url = notify_did_change(&mut stdin, buffer_id, incremental).await?;
+                request_rename(&mut stdin, row, col, url, new_name).await;
+            }
+            LspInput::RequestDocumentSymbols { buffer_id } => {
+                let url = notify_did_change(&mut stdin, buffer_id, incremental).await?;
+                request_document_symbols(&mut stdin, url).await?;
             }
-            LspInput::Edit {
-                version: _v,
-                text: _,
-                buffer_id: _,
-            } => {}
+            LspInput::Respond { id } => {
+                send_response_async(&mut stdin, id).await?;
+            }
+            LspInput::Shutdown => {
+                send_request_async::<_, lsp_types::request::Shutdown>(
+                    &mut stdin,
+                    Url::parse("none://none")?,
+                    (),
+                )
+                .await?;
+            }
+            // handled by the writer loop
+            LspInput::Exit | LspInput::Edit { .. } => {}
         }
         Ok(())
     }
+
+    /// Send the changes of the `edited` buffers which are still open.
+    async fn flush_edits(
+        lang: &LspLang,
+        mut stdin: &mut ChildStdin,
+        edited: Vec<u32>,
+        capabilities: &LspCapabilities,
+    ) {
+        for buffer_id in edited {
+            let open = lock!(buffers).get(buffer_id).is_ok();
+            if !open {
+                continue;
+            }
+            let r = notify_did_change(&mut stdin, buffer_id, capabilities.incremental).await;
+            if let Err(e) = r {
+                error(format!("{:?} language server: {}", lang, e));
+            }
+        }
+    }
 }
 
 fn process_inlay_hints(uri: Url, hints: Vec<InlayHint>) {
@@ -484,14 +1340,64 @@ fn process_inlay_hints(uri: Url, hints: Vec<InlayHint>) {
     }
 }
 
-fn convert_completions(mut input: Vec<CompletionItem>) -> Vec<LspCompletion> {
-    input
+/// Standard hints drawn like the ones of rust-analyzer, whose labels have no `:`.
+fn convert_inlay_hints(hints: Vec<lsp_ext::StandardInlayHint>) -> Vec<InlayHint> {
+    hints
+        .into_iter()
+        .map(|hint| {
+            let label = match hint.label {
+                InlayHintLabel::String(label) => label,
+                InlayHintLabel::LabelParts(parts) => parts.into_iter().map(|p| p.value).collect(),
+            };
+            let kind = match hint.kind {
+                Some(InlayHintKind::PARAMETER) => InlayKind::ParameterHint,
+                _ => InlayKind::TypeHint,
+            };
+            InlayHint {
+                range: Range::new(hint.position, hint.position),
+                kind,
+                label: label.trim().trim_matches(':').trim().to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Keep the `max` best ranked items, returns the completions and the number dropped.
+fn convert_completions(mut input: Vec<CompletionItem>, max: usize) -> (Vec<LspCompletion>, usize) {
+    let hidden = input.len().saturating_sub(max);
+    if hidden > 0 {
+        // the server ranks with `sort_text`, preselected items first
+        input.sort_by(|a, b| {
+            let a_key = (
+                !a.preselect.unwrap_or(false),
+                a.sort_text.as_ref().unwrap_or(&a.label),
+            );
+            let b_key = (
+                !b.preselect.unwrap_or(false),
+                b.sort_text.as_ref().unwrap_or(&b.label),
+            );
+            a_key.cmp(&b_key)
+        });
+        input.truncate(max);
+    }
+    let completions = input
         .drain(..)
         .filter_map(|c| convert_completion(c))
-        .collect()
+        .collect();
+    (completions, hidden)
 }
 
-async fn request_completion(mut stdin: &mut &mut ChildStdin, row: u32, col: u32, uri: Url) {
+async fn request_completion(
+    mut stdin: &mut &mut ChildStdin,
+    row: u32,
+    col: u32,
+    uri: Url,
+    trigger_character: Option<String>,
+) {
+    let trigger_kind = match trigger_character {
+        Some(_) => CompletionTriggerKind::TRIGGER_CHARACTER,
+        None => CompletionTriggerKind::INVOKED,
+    };
     let completion = lsp_types::CompletionParams {
         text_document_position: lsp_types::TextDocumentPositionParams {
             text_document: lsp_types::TextDocumentIdentifier { uri: uri.clone() },
@@ -503,8 +1409,8 @@ async fn request_completion(mut stdin: &mut &mut ChildStdin, row: u32, col: u32,
         work_done_progress_params: Default::default(),
         partial_result_params: Default::default(),
         context: Some(CompletionContext {
-            trigger_kind: CompletionTriggerKind::INVOKED,
-            trigger_character: None,
+            trigger_kind,
+            trigger_character,
         }),
     };
     send_request_async::<_, lsp_types::request::Completion>(&mut stdin, uri, completion)
@@ -512,25 +1418,161 @@ async fn request_completion(mut stdin: &mut &mut ChildStdin, row: u32, col: u32,
         .unwrap();
 }
 
+async fn request_signature_help(mut stdin: &mut &mut ChildStdin, row: u32, col: u32, uri: Url) {
+    let params = lsp_types::SignatureHelpParams {
+        context: None,
+        text_document_position_params: lsp_types::TextDocumentPositionParams {
+            text_document: lsp_types::TextDocumentIdentifier { uri: uri.clone() },
+            position: lsp_types::Position {
+                line: row,
+                character: col,
+            },
+        },
+        work_done_progress_params: Default::default(),
+    };
+    send_request_async::<_, lsp_types::request::SignatureHelpRequest>(&mut stdin, uri, params)
+        .await
+        .unwrap();
+}
+
+/// The active signature of `help`, `None` when there is none.
+fn convert_signature_help(help: SignatureHelp) -> Option<LspSignature> {
+    let index = help.active_signature.unwrap_or(0) as usize;
+    let signature = help
+        .signatures
+        .get(index)
+        .or_else(|| help.signatures.first())?;
+    let label = &signature.label;
+    let param = signature
+        .active_parameter
+        .or(help.active_parameter)
+        .unwrap_or(0) as usize;
+    let active = signature
+        .parameters
+        .as_ref()
+        .and_then(|params| params.get(param))
+        .and_then(|param| match &param.label {
+            // the name can be the one of the function too, look inside the parenthesis
+            ParameterLabel::Simple(name) => {
+                let from = label.find('(').unwrap_or(0);
+                label[from..]
+                    .find(name.as_str())
+                    .map(|start| (from + start, from + start + name.len()))
+            }
+            ParameterLabel::LabelOffsets([start, end]) => Some((
+                utf16_to_byte(label, *start as usize),
+                utf16_to_byte(label, *end as usize),
+            )),
+        });
+    Some(LspSignature {
+        label: label.clone(),
+        active,
+    })
+}
+
+/// Byte offset in `text` of the utf-16 `offset`.
+fn utf16_to_byte(text: &str, offset: usize) -> usize {
+    let mut utf16 = 0;
+    for (byte, c) in text.char_indices() {
+        if utf16 >= offset {
+            return byte;
+        }
+        utf16 += c.len_utf16();
+    }
+    text.len()
+}
+
+async fn request_rename(
+    mut stdin: &mut &mut ChildStdin,
+    row: u32,
+    col: u32,
+    uri: Url,
+    new_name: String,
+) {
+    let rename = lsp_types::RenameParams {
+        text_document_position: lsp_types::TextDocumentPositionParams {
+            text_document: lsp_types::TextDocumentIdentifier { uri: uri.clone() },
+            position: lsp_types::Position {
+                line: row,
+                character: col,
+            },
+        },
+        new_name,
+        work_done_progress_params: Default::default(),
+    };
+    send_request_async::<_, lsp_types::request::Rename>(&mut stdin, uri, rename)
+        .await
+        .unwrap();
+}
+
+/// Edits of `edit` grouped by file, resource operations (create, rename, delete) are ignored.
+pub fn workspace_edits(edit: WorkspaceEdit) -> Vec<(Url, Vec<TextEdit>)> {
+    let mut files: Vec<(Url, Vec<TextEdit>)> = vec![];
+    let mut add = |uri: Url, edits: Vec<TextEdit>| match files.iter_mut().find(|(u, _)| u == &uri) {
+        Some((_, list)) => list.extend(edits),
+        None => files.push((uri, edits)),
+    };
+    // servers send document changes instead of changes when they can
+    if let Some(changes) = edit.document_changes {
+        let documents = match changes {
+            DocumentChanges::Edits(edits) => edits,
+            DocumentChanges::Operations(ops) => ops
+                .into_iter()
+                .filter_map(|op| match op {
+                    DocumentChangeOperation::Edit(edit) => Some(edit),
+                    DocumentChangeOperation::Op(_) => None,
+                })
+                .collect(),
+        };
+        for doc in documents {
+            let edits = doc
+                .edits
+                .into_iter()
+                .map(|e| match e {
+                    OneOf::Left(edit) => edit.into(),
+                    OneOf::Right(annotated) => annotated.text_edit.into(),
+                })
+                .collect();
+            add(doc.text_document.uri, edits);
+        }
+    } else if let Some(changes) = edit.changes {
+        for (uri, edits) in changes {
+            add(uri, edits.into_iter().map(TextEdit::from).collect());
+        }
+    }
+    files
+}
+
 fn convert_completion(c: CompletionItem) -> Option<LspCompletion> {
     let clone = c.clone();
+    // the text is expanded, its stops are kept aside
+    let snippet = c.insert_text_format == Some(InsertTextFormat::SNIPPET);
+    let expand = |text: String| {
+        if snippet {
+            let snippet = snippet::parse(&text);
+            (snippet.text, snippet.stops)
+        } else {
+            (text, vec![])
+        }
+    };
     if let Some(insert_text) = c.insert_text {
+        let (text, stops) = expand(insert_text);
         Some(LspCompletion {
             original_item: clone,
             label: c.label,
-            data: CompletionData::Simple(insert_text),
+            data: CompletionData::Simple(text),
+            commit_characters: c.commit_characters.unwrap_or_default(),
+            stops,
+            kind: c.kind,
         })
     } else if let Some(text_edit) = c.text_edit {
-        let mut edits = vec![];
-        match text_edit {
-            CompletionTextEdit::Edit(e) => edits.push(TextEdit {
-                range: e.range,
-                new_text: e.new_text,
-            }),
-            CompletionTextEdit::InsertAndReplace(_) => {
-                unimplemented!("insert and replace")
-            }
-        }
+        let (range, new_text) = match text_edit {
+            CompletionTextEdit::Edit(e) => (e.range, e.new_text),
+            // inserting keeps the rest of the word
+            CompletionTextEdit::InsertAndReplace(e) => (e.insert, e.new_text),
+        };
+        let (new_text, stops) = expand(new_text);
+        let mut edits = vec![TextEdit { range, new_text }];
         if let Some(additional_edits) = c.additional_text_edits {
             for edit in additional_edits {
                 edits.push(TextEdit {
@@ -543,20 +1585,60 @@ fn convert_completion(c: CompletionItem) -> Option<LspCompletion> {
             original_item: clone,
             label: c.label,
             data: CompletionData::Edits(edits),
+            commit_characters: c.commit_characters.unwrap_or_default(),
+            stops,
+            kind: c.kind,
         })
     } else {
         None
     }
 }
 
-async fn notify_did_change(mut stdin: &mut &mut ChildStdin, buffer_id: u32) -> anyhow::Result<Url> {
-    let (path, version, text) = {
+/// Chars after which a server with `capabilities` wants completion to be requested.
+fn trigger_characters(capabilities: &ServerCapabilities) -> Vec<String> {
+    capabilities
+        .completion_provider
+        .as_ref()
+        .and_then(|provider| provider.trigger_characters.clone())
+        .unwrap_or_default()
+}
+
+/// Whether a server with `capabilities` asks for incremental changes.
+fn is_incremental(capabilities: &ServerCapabilities) -> bool {
+    let kind = match &capabilities.text_document_sync {
+        Some(TextDocumentSyncCapability::Kind(kind)) => Some(*kind),
+        Some(TextDocumentSyncCapability::Options(options)) => options.change,
+        None => None,
+    };
+    kind == Some(TextDocumentSyncKind::INCREMENTAL)
+}
+
+async fn notify_did_change(
+    mut stdin: &mut &mut ChildStdin,
+    buffer_id: u32,
+    incremental: bool,
+) -> anyhow::Result<Url> {
+    let (path, version, content_changes) = {
         let buffers = lock!(buffers);
         let buffer = buffers.get(buffer_id)?;
+        let path = buffer.source.path().context("path")?;
+        if !buffer.buffer.dirty_since_sync.swap(false, Ordering::SeqCst) {
+            // the server already has this text
+            return Ok(path.uri());
+        }
+        let changes = match buffer.buffer.take_changes() {
+            Some(changes) if incremental => changes,
+            _ => vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: buffer.buffer.text(),
+            }],
+        };
         (
-            buffer.source.path().context("path")?,
-            buffer.buffer.version.fetch_add(1, Ordering::SeqCst),
-            buffer.buffer.text(),
+            path,
+            // `didOpen` sent version 0
+            buffer.buffer.version.fetch_add(1, Ordering::SeqCst) + 1,
+            changes,
         )
     };
     let url = path.uri();
@@ -565,17 +1647,17 @@ async fn notify_did_change(mut stdin: &mut &mut ChildStdin, buffer_id: u32) -> a
             uri: url.clone(),
             version,
         },
-        content_changes: vec![TextDocumentContentChangeEvent {
-            range: None,
-            range_length: None,
-            text,
-        }],
+        content_changes,
     };
     send_notify_async::<_, lsp_types::notification::DidChangeTextDocument>(&mut stdin, edits)
         .await?;
     Ok(url)
 }
 
+fn trace_level() -> LspTrace {
+    lock!(conf).lsp.trace
+}
+
 async fn send_request_async_with_id<
     T: AsyncWrite + std::marker::Unpin,
     R: lsp_types::request::Request,
@@ -587,15 +1669,19 @@ async fn send_request_async_with_id<
 where
     R::Params: serde::Serialize,
 {
-    if let serde_json::value::Value::Object(params) = serde_json::to_value(params)? {
+    if let Some(params) = json_params(serde_json::to_value(params)?) {
         let req = jsonrpc_core::Call::MethodCall(jsonrpc_core::MethodCall {
             jsonrpc: Some(jsonrpc_core::Version::V2),
             method: R::METHOD.to_string(),
-            params: jsonrpc_core::Params::Map(params),
+            params,
             id: Id::Num(id),
         });
         let request = serde_json::to_string(&req)?;
-        println!("REQUEST: {}", request);
+        match trace_level() {
+            LspTrace::Off => {}
+            LspTrace::Messages => println!("REQUEST: {}", R::METHOD),
+            LspTrace::Verbose => println!("REQUEST: {}", request),
+        }
         let mut buffer: Vec<u8> = Vec::new();
         write!(
             &mut buffer,
@@ -610,6 +1696,15 @@ where
     }
 }
 
+/// Params of a message, `shutdown` and `exit` have none.
+fn json_params(params: serde_json::Value) -> Option<jsonrpc_core::Params> {
+    match params {
+        serde_json::Value::Object(params) => Some(jsonrpc_core::Params::Map(params)),
+        serde_json::Value::Null => Some(jsonrpc_core::Params::None),
+        _ => None,
+    }
+}
+
 async fn send_request_async<T: AsyncWrite + std::marker::Unpin, R: lsp_types::request::Request>(
     t: &mut T,
     uri: Url,
@@ -618,11 +1713,19 @@ async fn send_request_async<T: AsyncWrite + std::marker::Unpin, R: lsp_types::re
 where
     R::Params: serde::Serialize,
 {
-    let id = {
+    let (id, superseded) = {
         let mut lsp = lock!(mut lsp);
-        let id = lsp.new_request(R::METHOD.into(), uri);
-        id
+        let superseded = lsp.supersede(R::METHOD, &uri);
+        (lsp.new_request(R::METHOD.into(), uri), superseded)
     };
+    // its answer is dropped anyway, the server may skip the work. The cancel takes an
+    // `i32`, an id past it cannot be named.
+    if let Some(superseded) = superseded.and_then(|id| i32::try_from(id).ok()) {
+        let params = CancelParams {
+            id: NumberOrString::Number(superseded),
+        };
+        send_notify_async::<_, lsp_types::notification::Cancel>(t, params).await?;
+    }
     send_request_async_with_id::<_, R>(t, id, params).await
 }
 
@@ -636,14 +1739,18 @@ async fn send_notify_async<
 where
     R::Params: serde::Serialize,
 {
-    if let serde_json::value::Value::Object(params) = serde_json::to_value(params)? {
+    if let Some(params) = json_params(serde_json::to_value(params)?) {
         let req = jsonrpc_core::Notification {
             jsonrpc: Some(jsonrpc_core::Version::V2),
             method: R::METHOD.to_string(),
-            params: jsonrpc_core::Params::Map(params),
+            params,
         };
         let request = serde_json::to_string(&req)?;
-        println!("NOTIFY: {}", request);
+        match trace_level() {
+            LspTrace::Off => {}
+            LspTrace::Messages => println!("NOTIFY: {}", R::METHOD),
+            LspTrace::Verbose => println!("NOTIFY: {}", request),
+        }
         let mut buf: Vec<u8> = Vec::new();
         write!(
             &mut buf,
@@ -658,17 +1765,105 @@ where
     }
 }
 
-// lsp notify dud save
+async fn send_response_async<T: AsyncWrite + std::marker::Unpin>(
+    t: &mut T,
+    id: serde_json::Value,
+) -> anyhow::Result<()> {
+    let response = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": null,
+    });
+    let response = serde_json::to_string(&response)?;
+    if trace_level() == LspTrace::Verbose {
+        println!("RESPONSE: {}", response);
+    }
+    let mut buf: Vec<u8> = Vec::new();
+    write!(
+        &mut buf,
+        "Content-Length: {}\r\n\r\n{}",
+        response.len(),
+        response
+    )?;
+    t.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Text of a document serialized from the chunks of its rope, a large file is not
+/// copied into a string first.
+struct RopeText<'a>(&'a Rope);
+
+impl Serialize for RopeText<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self.0)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DidOpenParams<'a> {
+    text_document: DidOpenItem<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DidOpenItem<'a> {
+    uri: Url,
+    language_id: &'static str,
+    version: i32,
+    text: RopeText<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DidSaveParams<'a> {
+    text_document: TextDocumentIdentifier,
+    text: RopeText<'a>,
+}
+
+#[derive(Serialize)]
+struct NotificationBody<P> {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: P,
+}
+
+/// Like `send_notify_async`, the params are written to the message as they are serialized.
+async fn send_notify_streamed<
+    T: AsyncWrite + std::marker::Unpin,
+    R: lsp_types::notification::Notification,
+>(
+    t: &mut T,
+    params: impl Serialize,
+) -> anyhow::Result<()> {
+    let body = serde_json::to_vec(&NotificationBody {
+        jsonrpc: "2.0",
+        method: R::METHOD,
+        params,
+    })?;
+    match trace_level() {
+        LspTrace::Off => {}
+        LspTrace::Messages => println!("NOTIFY: {}", R::METHOD),
+        LspTrace::Verbose => println!("NOTIFY: {}", String::from_utf8_lossy(&body)),
+    }
+    let mut buf: Vec<u8> = Vec::with_capacity(body.len() + 32);
+    write!(&mut buf, "Content-Length: {}\r\n\r\n", body.len())?;
+    buf.extend_from_slice(&body);
+    t.write_all(&buf).await?;
+    Ok(())
+}
+
+// lsp notify did save
 async fn notify_did_save<T: AsyncWrite + std::marker::Unpin>(
     stdin: &mut T,
     uri: Url,
-    content: String,
+    content: &Rope,
 ) -> anyhow::Result<()> {
-    let params = lsp_types::DidSaveTextDocumentParams {
+    let params = DidSaveParams {
         text_document: TextDocumentIdentifier { uri },
-        text: Some(content),
+        text: RopeText(content),
     };
-    send_notify_async::<_, lsp_types::notification::DidSaveTextDocument>(stdin, params).await
+    send_notify_streamed::<_, lsp_types::notification::DidSaveTextDocument>(stdin, params).await
 }
 
 // lsp notify did close
@@ -686,44 +1881,188 @@ async fn notify_did_close<T: AsyncWrite + std::marker::Unpin>(
 async fn notify_did_open<T: AsyncWrite + std::marker::Unpin>(
     stdin: &mut T,
     uri: Url,
-    text: String,
+    text: &Rope,
 ) -> anyhow::Result<()> {
-    let params = lsp_types::DidOpenTextDocumentParams {
-        text_document: TextDocumentItem {
+    let params = DidOpenParams {
+        text_document: DidOpenItem {
             uri,
-            language_id: "rust".to_string(),
+            language_id: "rust",
             version: 0,
-            text,
+            text: RopeText(text),
         },
     };
-    send_notify_async::<_, lsp_types::notification::DidOpenTextDocument>(stdin, params).await
+    send_notify_streamed::<_, lsp_types::notification::DidOpenTextDocument>(stdin, params).await
 }
 
 // lsp request resolve completion item
 async fn request_resolve_completion_item<T: AsyncWrite + std::marker::Unpin>(
     stdin: &mut T,
+    uri: Url,
     item: CompletionItem,
 ) -> anyhow::Result<()> {
-    send_request_async::<_, lsp_types::request::ResolveCompletionItem>(
+    send_request_async::<_, lsp_types::request::ResolveCompletionItem>(stdin, uri, item).await
+}
+
+// lsp code action request
+async fn request_code_actions<T: AsyncWrite + std::marker::Unpin>(
+    stdin: &mut T,
+    uri: Url,
+    range: Range,
+    diagnostics: Vec<Diagnostic>,
+) -> anyhow::Result<()> {
+    let params = lsp_types::CodeActionParams {
+        text_document: TextDocumentIdentifier { uri: uri.clone() },
+        range,
+        context: CodeActionContext {
+            diagnostics,
+            only: None,
+        },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    send_request_async::<_, lsp_types::request::CodeActionRequest>(stdin, uri, params).await
+}
+
+// lsp request resolve code action, for the actions sent without their edit
+async fn request_resolve_code_action<T: AsyncWrite + std::marker::Unpin>(
+    stdin: &mut T,
+    action: CodeAction,
+) -> anyhow::Result<()> {
+    send_request_async::<_, lsp_types::request::CodeActionResolveRequest>(
         stdin,
         Url::parse("none://none")?,
-        item,
+        action,
     )
     .await
 }
 
-// lsp inlay hint request
-async fn request_inlay_hints<T: AsyncWrite + std::marker::Unpin>(
+/// The actions applying an edit, commands would have to be executed by the server
+/// and that is not supported.
+fn convert_code_actions(response: CodeActionResponse) -> Vec<CodeAction> {
+    response
+        .into_iter()
+        .filter_map(|action| match action {
+            CodeActionOrCommand::CodeAction(action) if action.disabled.is_none() => Some(action),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Diagnostics of buffer `buffer_id` overlapping `range`, as the server sent them.
+fn diagnostics_in(buffer_id: u32, range: Range) -> anyhow::Result<Vec<Diagnostic>> {
+    let buffers = lock!(buffers);
+    let buffer = &buffers.get(buffer_id)?.buffer;
+    let bounds: Bounds = (&range).into_with_buf(buffer);
+    Ok(buffer
+        .diagnostics
+        .0
+        .iter()
+        .filter(|d| d.bounds.0 <= bounds.1 && bounds.0 <= d.bounds.1)
+        .map(|d| d.to_lsp(buffer))
+        .collect())
+}
+
+// lsp document symbol request, for the outline
+async fn request_document_symbols<T: AsyncWrite + std::marker::Unpin>(
     stdin: &mut T,
     uri: Url,
 ) -> anyhow::Result<()> {
-    let params = lsp_ext::InlayHintsParams {
+    let params = DocumentSymbolParams {
         text_document: TextDocumentIdentifier { uri: uri.clone() },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
     };
-    send_request_async::<_, lsp_ext::InlayHints>(stdin, uri, params).await
+    send_request_async::<_, lsp_types::request::DocumentSymbolRequest>(stdin, uri, params).await
+}
+
+/// Symbols of the outline as a tree, a flat response is nested by the ranges
+/// of its symbols.
+fn convert_symbols(response: DocumentSymbolResponse) -> Vec<LspSymbol> {
+    fn nested(symbol: DocumentSymbol) -> LspSymbol {
+        LspSymbol {
+            name: symbol.name,
+            kind: symbol.kind,
+            range: symbol.range,
+            selection: symbol.selection_range,
+            children: symbol
+                .children
+                .unwrap_or_default()
+                .into_iter()
+                .map(nested)
+                .collect(),
+        }
+    }
+
+    match response {
+        DocumentSymbolResponse::Nested(symbols) => symbols.into_iter().map(nested).collect(),
+        DocumentSymbolResponse::Flat(mut symbols) => {
+            // a container before what it contains
+            symbols.sort_by(|a, b| {
+                let (a, b) = (a.location.range, b.location.range);
+                a.start.cmp(&b.start).then(b.end.cmp(&a.end))
+            });
+            let mut tree = vec![];
+            for symbol in symbols {
+                insert_symbol(
+                    &mut tree,
+                    LspSymbol {
+                        name: symbol.name,
+                        kind: symbol.kind,
+                        range: symbol.location.range,
+                        selection: symbol.location.range,
+                        children: vec![],
+                    },
+                );
+            }
+            tree
+        }
+    }
+}
+
+/// Add `symbol` in the last symbol of `symbols` containing it, at any depth.
+fn insert_symbol(symbols: &mut Vec<LspSymbol>, symbol: LspSymbol) {
+    match symbols.last_mut() {
+        Some(last)
+            if last.range.start <= symbol.range.start && symbol.range.end <= last.range.end =>
+        {
+            insert_symbol(&mut last.children, symbol)
+        }
+        _ => symbols.push(symbol),
+    }
+}
+
+// lsp inlay hint request, rust-analyzer has its own
+async fn request_inlay_hints<T: AsyncWrite + std::marker::Unpin>(
+    stdin: &mut T,
+    lang: &LspLang,
+    uri: Url,
+) -> anyhow::Result<()> {
+    let text_document = TextDocumentIdentifier { uri: uri.clone() };
+    match lang {
+        LspLang::Rust => {
+            let params = lsp_ext::InlayHintsParams { text_document };
+            send_request_async::<_, lsp_ext::InlayHints>(stdin, uri, params).await
+        }
+        _ => {
+            let range = {
+                let buffers = lock!(buffers);
+                let buf = &buffers
+                    .get_by_uri(uri.clone())
+                    .context("buffer not found")?
+                    .buffer;
+                (&(0, buf.rope().len_chars())).into_with_buf(buf)
+            };
+            let params = lsp_ext::StandardInlayHintsParams {
+                text_document,
+                range,
+            };
+            send_request_async::<_, lsp_ext::StandardInlayHints>(stdin, uri, params).await
+        }
+    }
 }
 
 fn process_diagnostics(default_uri: Url, diagnostics: Vec<Diagnostic>) {
+    let max = lock!(conf).lsp.max_diagnostics;
     let mut buffers = lock!(mut buffers);
 
     let mut cleared = Vec::new();
@@ -745,8 +2084,497 @@ fn process_diagnostics(default_uri: Url, diagnostics: Vec<Diagnostic>) {
             buf.buffer.diagnostics.0.push(crate::buffer::Diagnostic {
                 bounds,
                 severity: diagnostic.severity.unwrap_or(DiagnosticSeverity::ERROR),
-                message: diagnostic.message,
+                message: diagnostic.message.clone(),
+                original: diagnostic,
             });
         }
     }
+
+    for id in cleared {
+        if let Ok(buf) = buffers.get_mut(id) {
+            buf.buffer.truncate_diagnostics(max);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{CompletionItem, InitializeResult, Position, SignatureHelp, Url};
+    use serde_json::json;
+
+    use crate::lsp::{
+        convert_code_actions, convert_completion, convert_completions, convert_inlay_hints,
+        convert_signature_help, convert_symbols, is_incremental, notify_did_open,
+        trigger_characters, workspace_edits, CompletionData, InputQueue, LspCapabilities,
+        LspFeature, LspInput, LspLang, LspSignature, LspSymbol, LspSystem, Progress, CLIENT,
+        EDIT_DELAY,
+    };
+    use crate::lsp_ext::InlayKind;
+
+    fn item(label: &str, sort_text: Option<&str>, preselect: bool) -> CompletionItem {
+        CompletionItem {
+            label: label.to_string(),
+            insert_text: Some(label.to_string()),
+            sort_text: sort_text.map(|s| s.to_string()),
+            preselect: Some(preselect),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn completions_cap() {
+        let items = vec![
+            item("c", Some("3"), false),
+            item("a", Some("1"), false),
+            item("b", None, true),
+            item("d", Some("2"), false),
+        ];
+
+        let (completions, hidden) = convert_completions(items.clone(), 10);
+        assert_eq!(hidden, 0);
+        let labels = completions
+            .iter()
+            .map(|c| c.label.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec!["c", "a", "b", "d"]);
+
+        let (completions, hidden) = convert_completions(items, 2);
+        assert_eq!(hidden, 2);
+        let labels = completions
+            .iter()
+            .map(|c| c.label.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec!["b", "a"]);
+    }
+
+    #[test]
+    fn snippet_completion() {
+        let range = |end: u32| {
+            json!({
+                "start": { "line": 0, "character": 0 },
+                "end": { "line": 0, "character": end },
+            })
+        };
+        let item = serde_json::from_value(json!({
+            "label": "new",
+            "insertTextFormat": 2,
+            "textEdit": { "insert": range(1), "replace": range(3), "newText": "new(${1:x})$0" },
+        }))
+        .unwrap();
+        let c = convert_completion(item).unwrap();
+        assert_eq!(c.stops, vec![(4, 5), (6, 6)]);
+        match c.data {
+            CompletionData::Edits(edits) => {
+                assert_eq!(edits[0].new_text, "new(x)");
+                assert_eq!(edits[0].range.end.character, 1);
+            }
+            _ => panic!("not edits"),
+        }
+    }
+
+    #[test]
+    fn completion_kind_and_documentation() {
+        let item = serde_json::from_value(json!({
+            "label": "len",
+            "kind": 2,
+            "insertText": "len",
+            "documentation": { "kind": "markdown", "value": "Returns the length." },
+        }))
+        .unwrap();
+        let c = convert_completion(item).unwrap();
+        assert_eq!(c.kind_letter(), "f");
+        assert_eq!(c.kind_scope(), "function");
+        assert_eq!(c.documentation(), Some("Returns the length."));
+
+        let item = serde_json::from_value(
+            json!({ "label": "x", "insertText": "x", "documentation": " " }),
+        )
+        .unwrap();
+        let c = convert_completion(item).unwrap();
+        assert_eq!(c.kind_letter(), " ");
+        assert_eq!(c.documentation(), None);
+    }
+
+    #[test]
+    fn sync_kind() {
+        let incremental = |value| {
+            let result =
+                serde_json::from_value::<InitializeResult>(json!({ "capabilities": value }));
+            is_incremental(&result.unwrap().capabilities)
+        };
+        assert!(incremental(json!({ "textDocumentSync": 2 })));
+        assert!(incremental(
+            json!({ "textDocumentSync": { "openClose": true, "change": 2 } })
+        ));
+        assert!(!incremental(json!({ "textDocumentSync": 1 })));
+        assert!(!incremental(json!({})));
+    }
+
+    #[test]
+    fn completion_trigger_characters() {
+        let capabilities = |value| {
+            serde_json::from_value::<InitializeResult>(json!({ "capabilities": value }))
+                .unwrap()
+                .capabilities
+        };
+        let rust = capabilities(json!({
+            "completionProvider": { "triggerCharacters": [":", ".", "'"] }
+        }));
+        assert_eq!(trigger_characters(&rust), vec![":", ".", "'"]);
+        // completion without triggers, and no completion at all
+        let invoked = capabilities(json!({ "completionProvider": {} }));
+        assert!(trigger_characters(&invoked).is_empty());
+        assert!(trigger_characters(&capabilities(json!({}))).is_empty());
+    }
+
+    #[test]
+    fn advertised_features() {
+        let capabilities = LspCapabilities::parse(&json!({ "capabilities": {
+            "textDocumentSync": 2,
+            "completionProvider": { "resolveProvider": true },
+            "renameProvider": false,
+            "documentSymbolProvider": true,
+            "codeActionProvider": { "codeActionKinds": ["quickfix"] },
+            "inlayHintProvider": {},
+        }}));
+        let supports = |feature| capabilities.supports(&LspLang::Python, feature);
+        assert!(capabilities.incremental);
+        assert!(supports(LspFeature::Completion));
+        assert!(supports(LspFeature::CompletionResolve));
+        assert!(supports(LspFeature::DocumentSymbols));
+        assert!(supports(LspFeature::CodeActions));
+        assert!(supports(LspFeature::InlayHints));
+        assert!(!supports(LspFeature::Rename));
+        assert!(!supports(LspFeature::CodeActionResolve));
+        assert!(!supports(LspFeature::SignatureHelp));
+
+        let nothing = LspCapabilities::parse(&json!({ "capabilities": {} }));
+        assert!(!nothing.supports(&LspLang::Python, LspFeature::InlayHints));
+        // rust-analyzer has its own inlay hints
+        assert!(nothing.supports(&LspLang::Rust, LspFeature::InlayHints));
+        assert!(!nothing.supports(&LspLang::Rust, LspFeature::Completion));
+
+        // unreadable, everything is tried with the whole text
+        let unknown = LspCapabilities::parse(&json!({ "capabilities": {
+            "textDocumentSync": "incremental",
+        }}));
+        assert!(unknown.supports(&LspLang::Python, LspFeature::Rename));
+        assert!(unknown.supports(&LspLang::Python, LspFeature::CodeActionResolve));
+        assert!(!unknown.incremental);
+    }
+
+    #[test]
+    fn code_actions() {
+        let response = serde_json::from_value(json!([
+            { "title": "Run", "command": "run" },
+            { "title": "Add `;`", "kind": "quickfix", "data": { "id": 1 } },
+            { "title": "Inline", "disabled": { "reason": "no" } },
+        ]))
+        .unwrap();
+        let titles = convert_code_actions(response)
+            .into_iter()
+            .map(|a| a.title)
+            .collect::<Vec<_>>();
+        assert_eq!(titles, vec!["Add `;`"]);
+    }
+
+    #[test]
+    fn document_symbols() {
+        fn names(symbols: &[LspSymbol]) -> Vec<String> {
+            symbols
+                .iter()
+                .map(|s| format!("{}{:?}", s.name, names(&s.children)))
+                .collect()
+        }
+        let range = |start: u32, end: u32| {
+            json!({
+                "start": { "line": start, "character": 0 },
+                "end": { "line": end, "character": 1 },
+            })
+        };
+        let location =
+            |start: u32, end: u32| json!({ "uri": "file:///a.rs", "range": range(start, end) });
+
+        let b =
+            json!({ "name": "b", "kind": 8, "range": range(1, 1), "selectionRange": range(1, 1) });
+        let nested = serde_json::from_value(json!([
+            {
+                "name": "A", "kind": 23, "range": range(0, 2), "selectionRange": range(0, 0),
+                "children": [b],
+            },
+            { "name": "c", "kind": 12, "range": range(4, 6), "selectionRange": range(4, 4) },
+        ]))
+        .unwrap();
+        let symbols = convert_symbols(nested);
+        assert_eq!(names(&symbols), vec!["A[\"b[]\"]", "c[]"]);
+        assert_eq!(symbols[0].selection.end.line, 0);
+
+        // in any order, the container comes first
+        let flat = serde_json::from_value(json!([
+            { "name": "f", "kind": 6, "location": location(2, 3) },
+            { "name": "d", "kind": 12, "location": location(6, 7) },
+            { "name": "I", "kind": 5, "location": location(0, 4) },
+            { "name": "e", "kind": 6, "location": location(1, 1) },
+        ]))
+        .unwrap();
+        let symbols = convert_symbols(flat);
+        assert_eq!(names(&symbols), vec!["I[\"e[]\", \"f[]\"]", "d[]"]);
+    }
+
+    #[test]
+    fn signature_help() {
+        let help = |params: serde_json::Value, active: u32| -> SignatureHelp {
+            serde_json::from_value(json!({
+                "signatures": [
+                    { "label": "fn other()" },
+                    { "label": "fn é(é: u8, b: &str)", "parameters": params },
+                ],
+                "activeSignature": 1,
+                "activeParameter": active,
+            }))
+            .unwrap()
+        };
+        let label = "fn é(é: u8, b: &str)";
+        // the name is found after the function one
+        let signature = convert_signature_help(help(json!([{ "label": "é: u8" }]), 0));
+        assert_eq!(
+            signature,
+            Some(LspSignature {
+                label: label.to_string(),
+                active: Some((6, 12)),
+            })
+        );
+        // utf-16 offsets
+        let params = json!([{ "label": [5, 10] }, { "label": [12, 19] }]);
+        let signature = convert_signature_help(help(params, 1)).unwrap();
+        let (start, end) = signature.active.unwrap();
+        assert_eq!(&label[start..end], "b: &str");
+        // past the last parameter
+        let signature = convert_signature_help(help(json!([]), 3)).unwrap();
+        assert_eq!(signature.active, None);
+    }
+
+    #[test]
+    fn workspace_edit_files() {
+        let range = |line: u32| {
+            json!({
+                "start": { "line": line, "character": 0 },
+                "end": { "line": line, "character": 1 },
+            })
+        };
+        let edit = serde_json::from_value(json!({ "documentChanges": [
+            { "textDocument": { "uri": "file:///a.rs", "version": 1 },
+              "edits": [{ "range": range(0), "newText": "x" }] },
+            { "kind": "create", "uri": "file:///c.rs" },
+            { "textDocument": { "uri": "file:///b.rs", "version": null },
+              "edits": [{ "range": range(1), "newText": "y", "annotationId": "rename" }] },
+            { "textDocument": { "uri": "file:///a.rs", "version": 1 },
+              "edits": [{ "range": range(2), "newText": "z" }] },
+        ]}))
+        .unwrap();
+        let files = workspace_edits(edit);
+        let names = files
+            .iter()
+            .map(|(uri, edits)| (uri.path(), edits.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![("/a.rs", 2), ("/b.rs", 1)]);
+        assert_eq!(files[1].1[0].new_text, "y");
+
+        let edit = serde_json::from_value(
+            json!({ "changes": { "file:///a.rs": [{ "range": range(0), "newText": "x" }] } }),
+        )
+        .unwrap();
+        assert_eq!(workspace_edits(edit)[0].1[0].range.start.line, 0);
+    }
+
+    #[test]
+    fn retry_completion_after_indexing() {
+        let mut progress = Progress::default();
+        progress.last_completion = Some((1, 2, 3));
+        let begin = json!({"token": "index", "value": {"kind": "begin", "title": "Indexing"}});
+        let report = json!({"token": "index", "value": {"kind": "report"}});
+        let end = json!({"token": "index", "value": {"kind": "end"}});
+
+        assert!(progress.update(&begin).is_none());
+        assert!(progress.indexing());
+        // an empty completion came back meanwhile
+        progress.retry = true;
+        assert!(progress.update(&report).is_none());
+        match progress.update(&end) {
+            Some(LspInput::RequestCompletion {
+                buffer_id: 1,
+                row: 2,
+                col: 3,
+                trigger_character: None,
+            }) => {}
+            other => panic!("{:?}", other),
+        }
+        // only once
+        assert!(progress.update(&begin).is_none());
+        assert!(progress.update(&end).is_none());
+    }
+
+    #[test]
+    fn standard_inlay_hints() {
+        let hints = serde_json::from_value(json!([
+            {"position": {"line": 0, "character": 5}, "label": ": int", "kind": 1},
+            {
+                "position": {"line": 1, "character": 4},
+                "label": [{"value": "name"}, {"value": ":"}],
+                "kind": 2
+            },
+            {"position": {"line": 2, "character": 0}, "label": "hint"}
+        ]))
+        .unwrap();
+        let hints = convert_inlay_hints(hints);
+        let labels: Vec<_> = hints
+            .iter()
+            .map(|h| (h.label.as_str(), h.kind.clone()))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                ("int", InlayKind::TypeHint),
+                ("name", InlayKind::ParameterHint),
+                ("hint", InlayKind::TypeHint),
+            ]
+        );
+        assert_eq!(hints[1].range.start, Position::new(1, 4));
+    }
+
+    #[tokio::test]
+    async fn forget_requests_of_dead_client() {
+        let uri = Url::parse("file:///root/main.rs").unwrap();
+
+        let mut lsp = LspSystem::default();
+        let dead_id = CLIENT
+            .scope(1, async { lsp.new_request("a".into(), uri.clone()) })
+            .await;
+        // the same server restarted, the dead reader ends after it started
+        let restarted_id = CLIENT
+            .scope(2, async { lsp.new_request("b".into(), uri.clone()) })
+            .await;
+        // sent from outside of a client
+        let other_id = lsp.new_request("c".into(), uri.clone());
+
+        lsp.forget_requests(1);
+        assert!(lsp.get_request(dead_id).is_none());
+        assert_eq!(lsp.get_request(restarted_id).unwrap().method, "b");
+        assert_eq!(lsp.get_request(other_id).unwrap().method, "c");
+    }
+
+    #[tokio::test]
+    async fn supersede_requests() {
+        let uri = Url::parse("file:///root/main.rs").unwrap();
+        let other = Url::parse("file:///root/lib.rs").unwrap();
+        let completion = lsp_types::request::Completion::METHOD;
+        let rename = lsp_types::request::Rename::METHOD;
+
+        let mut lsp = LspSystem::default();
+        CLIENT
+            .scope(1, async {
+                let first = lsp.new_request(completion.into(), uri.clone());
+                let elsewhere = lsp.new_request(completion.into(), other.clone());
+                let renamed = lsp.new_request(rename.into(), uri.clone());
+                assert_ne!(first, 0);
+
+                assert_eq!(lsp.supersede(completion, &uri), Some(first));
+                let second = lsp.new_request(completion.into(), uri.clone());
+                // the answer of the first one is dropped, once
+                assert!(lsp.get_request(first).is_none());
+                assert!(lsp.cancelled(first));
+                assert!(!lsp.cancelled(first));
+                assert!(!lsp.cancelled(second));
+                // other documents and the requests which are not stale are kept
+                assert_eq!(lsp.supersede(rename, &uri), None);
+                assert!(lsp.get_request(elsewhere).is_some());
+                assert!(lsp.get_request(renamed).is_some());
+                // answered already
+                assert!(lsp.get_request(second).is_some());
+                assert_eq!(lsp.supersede(completion, &uri), None);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn did_open_from_rope() {
+        let uri = Url::parse("file:///root/main.rs").unwrap();
+        let text = format!("{}\n\"é\"\t\\\n", "x".repeat(10_000));
+        let rope = ropey::Rope::from_str(&text);
+        assert!(rope.chunks().count() > 1);
+
+        let mut out = vec![];
+        notify_did_open(&mut out, uri.clone(), &rope).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let (header, body) = out.split_once("\r\n\r\n").unwrap();
+        assert_eq!(header, format!("Content-Length: {}", body.len()));
+        let message: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(message["method"], "textDocument/didOpen");
+        assert_eq!(message["params"]["textDocument"]["uri"], uri.as_str());
+        assert_eq!(message["params"]["textDocument"]["text"], text.as_str());
+    }
+
+    #[tokio::test]
+    async fn edits_wait_for_a_pause() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut queue = InputQueue::new(rx, true);
+        let edit = |buffer_id| LspInput::Edit { buffer_id };
+        let completion = |col| LspInput::RequestCompletion {
+            buffer_id: 1,
+            row: 0,
+            col,
+            trigger_character: None,
+        };
+
+        for id in [2, 1, 2] {
+            tx.send(edit(id)).unwrap();
+        }
+        let start = std::time::Instant::now();
+        let batch = queue.next().await.unwrap();
+        assert!(start.elapsed() >= EDIT_DELAY);
+        assert_eq!(batch.edited, vec![1, 2]);
+        assert!(batch.input.is_none());
+
+        // a save does not wait, the changes are sent before it
+        tx.send(edit(1)).unwrap();
+        let uri = Url::parse("file:///root/main.rs").unwrap();
+        let content = ropey::Rope::new();
+        tx.send(LspInput::SavedFile { uri, content }).unwrap();
+        let batch = queue.next().await.unwrap();
+        assert_eq!(batch.edited, vec![1]);
+        assert!(matches!(batch.input, Some(LspInput::SavedFile { .. })));
+
+        // the last completion queued, up to an edit
+        for input in [completion(1), completion(2), edit(1), completion(3)] {
+            tx.send(input).unwrap();
+        }
+        let batch = queue.next().await.unwrap();
+        assert!(batch.edited.is_empty());
+        assert!(matches!(
+            batch.input,
+            Some(LspInput::RequestCompletion { col: 2, .. })
+        ));
+        let batch = queue.next().await.unwrap();
+        assert_eq!(batch.edited, vec![1]);
+        assert!(matches!(
+            batch.input,
+            Some(LspInput::RequestCompletion { col: 3, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn full_sync_waits_for_a_request() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut queue = InputQueue::new(rx, false);
+        tx.send(LspInput::Edit { buffer_id: 1 }).unwrap();
+        tx.send(LspInput::RequestDocumentSymbols { buffer_id: 1 })
+            .unwrap();
+        let batch = queue.next().await.unwrap();
+        assert!(batch.edited.is_empty());
+        assert!(batch.input.is_some());
+
+        tx.send(LspInput::Edit { buffer_id: 1 }).unwrap();
+        drop(tx);
+        assert!(queue.next().await.is_none());
+    }
 }
@@ -1,5 +1,5 @@
 use lsp_types::request::Request;
-use lsp_types::{Range, TextDocumentIdentifier};
+use lsp_types::{InitializeResult, Position, Range, TextDocumentIdentifier};
 use serde::{Deserialize, Serialize};
 
 pub enum InlayHints {}
@@ -29,3 +29,56 @@ pub struct InlayHint {
     pub kind: InlayKind,
     pub label: String,
 }
+
+/// `initialize` with the params as json, to advertise capabilities lsp-types does not have.
+pub enum Initialize {}
+
+impl Request for Initialize {
+    type Params = serde_json::Value;
+    type Result = InitializeResult;
+    const METHOD: &'static str = "initialize";
+}
+
+/// Inlay hints of LSP 3.17, for the servers other than rust-analyzer.
+pub enum StandardInlayHints {}
+
+impl Request for StandardInlayHints {
+    type Params = StandardInlayHintsParams;
+    type Result = Option<Vec<StandardInlayHint>>;
+    const METHOD: &'static str = "textDocument/inlayHint";
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StandardInlayHintsParams {
+    pub text_document: TextDocumentIdentifier,
+    pub range: Range,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct InlayHintKind(i32);
+
+impl InlayHintKind {
+    pub const TYPE: InlayHintKind = InlayHintKind(1);
+    pub const PARAMETER: InlayHintKind = InlayHintKind(2);
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum InlayHintLabel {
+    String(String),
+    LabelParts(Vec<InlayHintLabelPart>),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct InlayHintLabelPart {
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StandardInlayHint {
+    pub position: Position,
+    pub label: InlayHintLabel,
+    pub kind: Option<InlayHintKind>,
+}
@@ -2,6 +2,7 @@ extern crate druid;
 
 use clap::{App, Arg};
 use std::ops::Sub;
+use std::time::Duration;
 
 use druid::widget::{Flex, Label, Padding, Painter, Split};
 use druid::*;
@@ -9,21 +10,26 @@ use druid::*;
 use ste_lib::config::Config;
 use ste_lib::editor::TextEditor;
 use ste_lib::fs::FileSystem;
+use ste_lib::outline::Outline;
+use ste_lib::session::Session;
+use ste_lib::status::{error, StatusLine};
+use ste_lib::tabs::{window_title, TabBar};
+use ste_lib::theme::Theme;
 use ste_lib::tree::TreeViewer;
-use ste_lib::{lock, AppState, EDITOR_FONT, FONT, FS};
+use ste_lib::{lock, set_root, AppState, EDITOR_FONT, FONT, FS};
 
-const WINDOW_TITLE: LocalizedString<AppState> = LocalizedString::new("Hello World!");
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     #[cfg(debug_assertions)]
     deadlocks();
 
-    init();
+    let restore = init();
 
     // describe the main window
     let main_window = WindowDesc::new(build_root_widget)
-        .title(WINDOW_TITLE)
+        .title(window_title())
         .window_size((1000.0, 600.0));
 
     // create the initial app state
@@ -34,10 +40,25 @@ async fn main() -> anyhow::Result<()> {
         .launch(initial_state)
         .expect("Failed to launch application");
 
+    if restore {
+        if let Err(e) = Session::capture().save() {
+            error(format!("failed to save session: {}", e));
+        }
+    }
+
+    // the servers still running when the timeout ends are killed
+    let servers = lock!(mut lsp).shutdown();
+    for mut server in servers {
+        tokio::time::timeout(SHUTDOWN_TIMEOUT, server.wait())
+            .await
+            .ok();
+    }
+
     Ok(())
 }
 
-fn init() {
+/// Returns whether the session should be saved on exit.
+fn init() -> bool {
     let matches = App::new("My Super Program")
         .version("1.0")
         .author("Theo E <theo.emeriau@gmail.com>")
@@ -58,6 +79,20 @@ fn init() {
                 .help("Open a file")
                 .required(false),
         )
+        .arg(
+            Arg::with_name("root")
+                .short("r")
+                .long("root")
+                .value_name("DIR")
+                .help("Sets the workspace folder, instead of the current directory")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("no-restore")
+                .long("no-restore")
+                .help("Start without restoring the last session"),
+        )
         .get_matches();
 
     let config_file = matches.value_of("config");
@@ -77,10 +112,36 @@ fn init() {
         }
     }
 
+    let theme = lock!(conf).theme.clone();
+    if let Err(e) = Theme::select(&theme) {
+        error(format!("{}, using the default theme", e));
+    }
+
+    let root = matches.value_of("root");
+    if let Some(root) = root {
+        if std::path::Path::new(root).is_dir() {
+            set_root(FS.path(root));
+        } else {
+            error(format!("{} is not a folder", root));
+        }
+    }
+
     let open_file = matches.value_of("file");
     if let Some(file) = open_file {
         let mut buffers = lock!(mut buffers);
         buffers.open_file(FS.path(file)).unwrap();
+        false
+    } else if matches.is_present("no-restore") || !lock!(conf).restore_session {
+        false
+    } else {
+        if let Some(mut session) = Session::load() {
+            // the folder given on the command line wins
+            if root.is_some() {
+                session.root = None;
+            }
+            session.restore();
+        }
+        true
     }
 }
 
@@ -92,14 +153,18 @@ fn build_root_widget() -> impl Widget<AppState> {
 
     // arrange the two widgets vertically, with some padding
     let layout = Flex::column()
+        .with_child(TabBar::default())
         .with_flex_child(editor, 1.0)
         .with_default_spacer()
         .with_child(button)
-        .with_default_spacer();
+        .with_default_spacer()
+        .with_child(StatusLine::default());
 
     let tree = TreeViewer::new(FS.clone());
+    let outline = TreeViewer::new(Outline);
+    let side = Split::rows(tree, outline).draggable(true).split_point(0.6);
 
-    let layout = Split::columns(tree, layout)
+    let layout = Split::columns(side, layout)
         .draggable(true)
         .split_point(0.3);
 
@@ -151,7 +216,11 @@ where
 }
 
 fn editor() -> impl Widget<AppState> {
-    let editor = TextEditor::new();
+    let mut editor = TextEditor::new();
+    let root = lock!(global).root_path.clone();
+    if let Err(e) = editor.watch(root.as_ref()) {
+        error(format!("cannot watch {}: {}", root.file_name(), e));
+    }
     editor
 }
 
@@ -0,0 +1,278 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+
+use lsp_types::Url;
+use parking_lot::RwLock;
+
+use crate::fs::{lang_for, FileSystem, Path};
+use crate::lsp::LspLang;
+
+/// Files kept in memory instead of on disk, for the tests. A folder exists as long as
+/// a file is in it. The clones share the same files.
+#[derive(Default, Clone)]
+pub struct MemFs {
+    files: Arc<RwLock<BTreeMap<String, Vec<u8>>>>,
+}
+
+/// Absolute path in a `MemFs`, `/` alone is the root.
+#[derive(Clone)]
+pub struct MemPath {
+    fs: MemFs,
+    path: String,
+}
+
+impl PartialEq for MemPath {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.fs.files, &other.fs.files) && self.path == other.path
+    }
+}
+
+impl MemFs {
+    /// Create or replace the file at `path`.
+    pub fn insert(&self, path: &str, content: &str) {
+        let path = self.path(path).path;
+        self.files.write().insert(path, content.as_bytes().to_vec());
+    }
+
+    pub fn remove(&self, path: &str) -> Option<Vec<u8>> {
+        let path = self.path(path).path;
+        self.files.write().remove(&path)
+    }
+
+    /// Remove the file or the folder at `path`, with its content.
+    pub fn remove_all(&self, path: &str) -> std::io::Result<()> {
+        let path = self.path(path);
+        let mut files = self.files.write();
+        let removed = path.under(&files);
+        if removed.is_empty() {
+            return Err(not_found());
+        }
+        for file in removed {
+            files.remove(&file);
+        }
+        Ok(())
+    }
+
+    /// Move the file or the folder at `from`, with its content, to `to`.
+    pub fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        let (from, to) = (self.path(from), self.path(to));
+        let mut files = self.files.write();
+        let moved = from.under(&files);
+        if moved.is_empty() {
+            return Err(not_found());
+        }
+        for file in moved {
+            let content = files.remove(&file).unwrap_or_default();
+            files.insert(format!("{}{}", to.path, &file[from.path.len()..]), content);
+        }
+        Ok(())
+    }
+}
+
+fn not_found() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "no such file or folder")
+}
+
+impl FileSystem for MemFs {
+    type Path = MemPath;
+
+    /// Relative paths start at the root, `.` and `..` are resolved.
+    fn path<S>(&self, path: S) -> MemPath
+    where
+        S: Into<String>,
+    {
+        let mut parts: Vec<String> = vec![];
+        for part in path.into().split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                _ => parts.push(part.into()),
+            }
+        }
+        MemPath {
+            fs: self.clone(),
+            path: format!("/{}", parts.join("/")),
+        }
+    }
+
+    fn list(&self, path: MemPath) -> Vec<MemPath> {
+        let prefix = match path.path.as_str() {
+            "/" => "/".to_string(),
+            p => format!("{}/", p),
+        };
+        let names: BTreeSet<String> = self
+            .files
+            .read()
+            .keys()
+            .filter_map(|p| p.strip_prefix(&prefix))
+            .filter_map(|rest| rest.split('/').next())
+            .map(String::from)
+            .collect();
+        names
+            .into_iter()
+            .map(|name| self.path(format!("{}{}", prefix, name)))
+            .collect()
+    }
+}
+
+impl MemPath {
+    pub fn is_file(&self) -> bool {
+        self.fs.files.read().contains_key(&self.path)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        !self.fs.list(self.clone()).is_empty()
+    }
+
+    pub fn extension(&self) -> Option<String> {
+        let name = self.name();
+        let (stem, ext) = name.rsplit_once('.')?;
+        Some(ext.to_string()).filter(|_| !stem.is_empty())
+    }
+
+    /// The file at this path, or the files of the folder.
+    fn under(&self, files: &BTreeMap<String, Vec<u8>>) -> Vec<String> {
+        let prefix = format!("{}/", self.path.trim_end_matches('/'));
+        files
+            .keys()
+            .filter(|p| **p == self.path || p.starts_with(&prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Replaces the content of a `MemFs` file as it is written.
+pub struct MemWriter {
+    path: MemPath,
+}
+
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut files = self.path.fs.files.write();
+        files
+            .entry(self.path.path.clone())
+            .or_default()
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Path for MemPath {
+    type Reader = Cursor<Vec<u8>>;
+    type Writer = MemWriter;
+
+    fn lsp_lang(&self) -> LspLang {
+        lang_for(&self.name(), self.extension())
+    }
+
+    fn name(&self) -> String {
+        self.path.rsplit('/').next().unwrap_or_default().into()
+    }
+
+    fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    fn uri(&self) -> Url {
+        Url::parse(&format!("memory://{}", self.path)).unwrap()
+    }
+
+    /// Empty for a missing file.
+    fn reader(&self) -> Self::Reader {
+        let content = self.fs.files.read().get(&self.path).cloned();
+        Cursor::new(content.unwrap_or_default())
+    }
+
+    /// Truncate the file, like creating it on disk.
+    fn writer(&self) -> Self::Writer {
+        self.fs.files.write().insert(self.path.clone(), vec![]);
+        MemWriter { path: self.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use crate::buffer::Buffer;
+    use crate::fs::{FileSystem, Path};
+    use crate::lsp::LspLang;
+    use crate::mem_fs::MemFs;
+
+    #[test]
+    fn files_and_folders() {
+        let fs = MemFs::default();
+        fs.insert("src/main.rs", "fn main() {}");
+        fs.insert("/src/lib/mod.rs", "");
+        fs.insert("README", "hi");
+
+        let names = |path: &str| {
+            fs.list(fs.path(path))
+                .iter()
+                .map(|p| p.name())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names("/"), vec!["README", "src"]);
+        assert_eq!(names("./src/../src/"), vec!["lib", "main.rs"]);
+        assert!(names("README").is_empty());
+
+        let main = fs.path("src/main.rs");
+        assert!(main == fs.path("/src/./main.rs"));
+        assert!(main.is_file() && !main.is_dir());
+        assert!(fs.path("src").is_dir());
+        assert_eq!(main.path(), "/src/main.rs");
+        assert_eq!(main.uri().as_str(), "memory:///src/main.rs");
+        assert_eq!(main.lsp_lang(), LspLang::Rust);
+        assert_eq!(fs.path("README").lsp_lang(), LspLang::PlainText);
+    }
+
+    #[test]
+    fn read_and_write() {
+        let fs = MemFs::default();
+        let read = |path: &str| {
+            let mut text = String::new();
+            fs.path(path).reader().read_to_string(&mut text).unwrap();
+            text
+        };
+        assert_eq!(read("a.txt"), "");
+
+        fs.insert("a.txt", "old text");
+        let buffer = Buffer::from_reader(1, fs.path("a.txt").reader());
+        assert_eq!(buffer.text(), "old text");
+        buffer.rope().write_to(fs.path("b.txt").writer()).unwrap();
+        assert_eq!(read("b.txt"), "old text");
+
+        // written from the start
+        write!(fs.path("a.txt").writer(), "new").unwrap();
+        assert_eq!(read("a.txt"), "new");
+        assert_eq!(fs.remove("a.txt"), Some(b"new".to_vec()));
+        assert!(!fs.path("a.txt").is_file());
+    }
+
+    #[test]
+    fn rename_and_remove_folders() {
+        let fs = MemFs::default();
+        fs.insert("src/main.rs", "main");
+        fs.insert("src/lib/mod.rs", "mod");
+        fs.insert("srcs.txt", "");
+
+        fs.rename("src", "app").unwrap();
+        assert!(!fs.path("src").is_dir());
+        assert!(fs.path("app/main.rs").is_file() && fs.path("app/lib/mod.rs").is_file());
+        // only the folder, not the names starting like it
+        assert!(fs.path("srcs.txt").is_file());
+        assert!(fs.rename("src", "other").is_err());
+
+        fs.remove_all("app/lib").unwrap();
+        assert!(!fs.path("app/lib/mod.rs").is_file());
+        assert!(fs.path("app/main.rs").is_file());
+        assert!(fs.remove_all("app/lib").is_err());
+    }
+}
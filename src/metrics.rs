@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use druid::piet::{Text, TextLayout, TextLayoutBuilder};
+use druid::{FontFamily, Point};
+
+use crate::buffer::{Index, TAB_WIDTH};
+use crate::editor::{DEFAULT_TEXT_FONT, DEFAULT_TEXT_SIZE};
+use crate::lock;
+
+/// Horizontal advance of the editor font, what places a text column on screen.
+/// A monospace font is measured once with a sample glyph, a proportional one glyph by glyph.
+pub struct Advance {
+    font: FontFamily,
+    size: f64,
+    // width of a `0`, the `ch` unit of CSS
+    sample: f64,
+    monospace: bool,
+    widths: HashMap<char, f64>,
+}
+
+impl Advance {
+    pub fn new<T: Text>(text: &mut T, font: FontFamily, size: f64) -> Self {
+        let sample = glyph_width(text, &font, size, '0');
+        let narrow = glyph_width(text, &font, size, 'i');
+        let wide = glyph_width(text, &font, size, 'M');
+        Self {
+            font,
+            size,
+            sample,
+            monospace: (narrow - sample).abs() < 0.01 && (wide - sample).abs() < 0.01,
+            widths: HashMap::new(),
+        }
+    }
+
+    /// Whether it measures `font` at `size`, the editor font can change with the config.
+    pub fn measures(&self, font: &FontFamily, size: f64) -> bool {
+        &self.font == font && self.size == size
+    }
+
+    pub fn is_monospace(&self) -> bool {
+        self.monospace
+    }
+
+    pub fn sample(&self) -> f64 {
+        self.sample
+    }
+
+    /// Width of `c` outside of a tab.
+    pub fn char_width<T: Text>(&mut self, text: &mut T, c: char) -> f64 {
+        if self.monospace {
+            return self.sample;
+        }
+        let (font, size) = (&self.font, self.size);
+        *self
+            .widths
+            .entry(c)
+            .or_insert_with(|| glyph_width(text, font, size, c))
+    }
+
+    /// Distance from the start of `line` to its column `col`, a tab goes to the next
+    /// tab stop, every `TAB_WIDTH` spaces.
+    pub fn column_x<T: Text>(&mut self, text: &mut T, line: &str, col: usize) -> f64 {
+        let tab = self.char_width(text, ' ') * TAB_WIDTH as f64;
+        let mut x = 0.0;
+        for c in line.chars().take(col) {
+            if c == '\t' {
+                x = ((x / tab).floor() + 1.0) * tab;
+            } else {
+                x += self.char_width(text, c);
+            }
+        }
+        x
+    }
+
+    /// Distance from the line start to a ruler at `col`. A ruler crosses all the lines,
+    /// with a proportional font it is placed as if all the chars were sample glyphs.
+    pub fn ruler_x(&self, col: usize) -> f64 {
+        col as f64 * self.sample
+    }
+}
+
+/// Text as it is laid out, a tab is replaced by the spaces up to the next tab stop,
+/// every `tab_width` columns from the line start. `col` is the column where `text` starts.
+/// Returns the laid out text and the byte of each char of `text` in it, with its end.
+pub fn expand_tabs(text: &str, col: usize, tab_width: usize) -> (String, Vec<usize>) {
+    let mut expanded = String::with_capacity(text.len());
+    let mut offsets = Vec::with_capacity(text.len() + 1);
+    let mut col = col;
+    for c in text.chars() {
+        offsets.push(expanded.len());
+        if c == '\t' {
+            let width = tab_width - col % tab_width;
+            expanded.extend(std::iter::repeat(' ').take(width));
+            col += width;
+        } else {
+            expanded.push(c);
+            col += 1;
+        }
+    }
+    offsets.push(expanded.len());
+    (expanded, offsets)
+}
+
+/// Where a line was painted, to find the char under the mouse.
+#[derive(Debug, Clone, Default)]
+pub struct PaintedLine {
+    pub top: f64,
+    pub bottom: f64,
+    /// x of the caret before each char and after the last one, from left to right
+    pub carets: Vec<(f64, Index)>,
+}
+
+/// Caret nearest to `pos`, on the line painted at its height. Above the lines is the first
+/// one, below them the end of the last one.
+pub fn caret_at(lines: &[PaintedLine], pos: Point) -> Option<Index> {
+    let n = lines.partition_point(|l| l.bottom <= pos.y);
+    let line = match lines.get(n) {
+        Some(line) => line,
+        None => return lines.last()?.carets.last().map(|(_, idx)| *idx),
+    };
+    let carets = &line.carets;
+    let k = carets.partition_point(|(x, _)| *x < pos.x);
+    let nearest = match (k.checked_sub(1).map(|i| carets[i]), carets.get(k)) {
+        (Some(before), Some(after)) if pos.x - before.0 <= after.0 - pos.x => before,
+        (_, Some(after)) => *after,
+        (before, None) => before?,
+    };
+    Some(nearest.1)
+}
+
+/// Font and size of the text of the editor, the ones of a span without a font of its own.
+pub fn editor_font() -> (FontFamily, f64) {
+    let scale = lock!(conf).render.text_scale;
+    (
+        FontFamily::new_unchecked(DEFAULT_TEXT_FONT.as_str()),
+        DEFAULT_TEXT_SIZE * scale,
+    )
+}
+
+fn glyph_width<T: Text>(text: &mut T, font: &FontFamily, size: f64, c: char) -> f64 {
+    text.new_text_layout(c.to_string())
+        .font(font.clone(), size)
+        .build()
+        // a blank has no ink, only its advance
+        .map(|layout| layout.trailing_whitespace_width())
+        .unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use druid::piet::{Device, RenderContext};
+    use druid::FontFamily;
+
+    use druid::Point;
+
+    use crate::buffer::TAB_WIDTH;
+    use crate::metrics::{caret_at, expand_tabs, Advance, PaintedLine};
+
+    #[test]
+    fn tab_stops() {
+        assert_eq!(
+            expand_tabs("a\tb", 0, 4),
+            ("a   b".into(), vec![0, 1, 4, 5])
+        );
+        // the stop depends on where the span starts in the line
+        assert_eq!(expand_tabs("\tb", 2, 4), ("  b".into(), vec![0, 2, 3]));
+        assert_eq!(expand_tabs("\t", 4, 4), ("    ".into(), vec![0, 4]));
+        // offsets are in bytes
+        assert_eq!(expand_tabs("é\t", 0, 4), ("é   ".into(), vec![0, 2, 5]));
+    }
+
+    #[test]
+    fn caret_under_the_mouse() {
+        let line = |top, start: usize, len: usize| PaintedLine {
+            top,
+            bottom: top + 10.0,
+            carets: (0..=len).map(|i| (i as f64 * 8.0, start + i)).collect(),
+        };
+        // `abc`, an empty line, `de`
+        let lines = vec![line(0.0, 0, 3), line(10.0, 4, 0), line(20.0, 5, 2)];
+        let at = |x, y| caret_at(&lines, Point::new(x, y));
+        assert_eq!(at(0.0, 5.0), Some(0));
+        // the nearest boundary
+        assert_eq!(at(11.0, 5.0), Some(1));
+        assert_eq!(at(13.0, 5.0), Some(2));
+        // past the end of the line
+        assert_eq!(at(200.0, 5.0), Some(3));
+        assert_eq!(at(50.0, 15.0), Some(4));
+        assert_eq!(at(9.0, 25.0), Some(6));
+        // above and below the text
+        assert_eq!(at(9.0, -3.0), Some(1));
+        assert_eq!(at(0.0, 100.0), Some(7));
+        assert_eq!(caret_at(&[], Point::new(0.0, 0.0)), None);
+    }
+
+    #[test]
+    fn proportional_columns() {
+        let mut device = Device::new().unwrap();
+        let mut target = device.bitmap_target(100, 100, 1.0).unwrap();
+        let mut ctx = target.render_context();
+        let text = ctx.text();
+
+        let mut advance = Advance::new(text, FontFamily::SANS_SERIF, 16.0);
+        assert!(!advance.is_monospace());
+        let narrow = advance.column_x(text, "iiii", 4);
+        let wide = advance.column_x(text, "MMMM", 4);
+        assert!(narrow < wide);
+        // the ruler does not depend on the text of a line
+        assert_eq!(advance.ruler_x(80), 80.0 * advance.sample());
+        assert!(advance.ruler_x(4) > narrow && advance.ruler_x(4) < wide);
+        // a tab stop after a narrow char
+        let space = advance.char_width(text, ' ');
+        assert_eq!(advance.column_x(text, "i\t", 2), space * TAB_WIDTH as f64);
+
+        let mut advance = Advance::new(text, FontFamily::MONOSPACE, 16.0);
+        assert!(advance.is_monospace());
+        assert_eq!(
+            advance.column_x(text, "iiii", 4),
+            advance.column_x(text, "MMMM", 4)
+        );
+        assert_eq!(advance.ruler_x(4), advance.column_x(text, "MMMM", 4));
+        ctx.finish().unwrap();
+    }
+}
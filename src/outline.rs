@@ -0,0 +1,141 @@
+use druid::KbKey;
+use lsp_types::{Position, SymbolKind};
+
+use crate::buffer::{Index, IntoWithBuffer, Movement};
+use crate::lock;
+use crate::lsp::LspSymbol;
+use crate::tree::{ItemStyle, ShouldRepaint, Tree};
+
+/// Outline of the current buffer, from the symbols of its language server.
+/// A key is the position of a symbol in the children of each of its ancestors,
+/// the root is the buffer itself.
+#[derive(Default, Clone)]
+pub struct Outline;
+
+fn symbol_at<'a>(symbols: &'a [LspSymbol], path: &[usize]) -> Option<&'a LspSymbol> {
+    let (first, rest) = path.split_first()?;
+    let symbol = symbols.get(*first)?;
+    if rest.is_empty() {
+        Some(symbol)
+    } else {
+        symbol_at(&symbol.children, rest)
+    }
+}
+
+/// Path of the innermost symbol containing `pos`.
+fn symbol_containing(symbols: &[LspSymbol], pos: Position) -> Vec<usize> {
+    let mut path = vec![];
+    let mut symbols = symbols;
+    while let Some(i) = symbols
+        .iter()
+        .position(|s| s.range.start <= pos && pos <= s.range.end)
+    {
+        path.push(i);
+        symbols = &symbols[i].children;
+    }
+    path
+}
+
+fn style_scope(kind: SymbolKind) -> &'static str {
+    match kind {
+        SymbolKind::FUNCTION | SymbolKind::METHOD | SymbolKind::CONSTRUCTOR => {
+            "tree.symbol.function"
+        }
+        SymbolKind::STRUCT
+        | SymbolKind::CLASS
+        | SymbolKind::ENUM
+        | SymbolKind::INTERFACE
+        | SymbolKind::TYPE_PARAMETER => "tree.symbol.type",
+        _ => "tree.symbol",
+    }
+}
+
+impl Tree for Outline {
+    type Key = Vec<usize>;
+
+    fn root(&self) -> Self::Key {
+        vec![]
+    }
+
+    fn children(&self, parent: &Self::Key) -> Vec<Self::Key> {
+        let buffers = lock!(buffers);
+        let symbols = match buffers.get_curr() {
+            Ok(buf) => &buf.buffer.symbols,
+            Err(_) => return vec![],
+        };
+        let len = if parent.is_empty() {
+            symbols.len()
+        } else {
+            symbol_at(symbols, parent).map_or(0, |s| s.children.len())
+        };
+        (0..len)
+            .map(|i| {
+                let mut key = parent.clone();
+                key.push(i);
+                key
+            })
+            .collect()
+    }
+
+    fn refresh(&self, _parent: &Self::Key) {}
+
+    fn item(&self, key: &Self::Key) -> ItemStyle {
+        let buffers = lock!(buffers);
+        let buf = buffers.get_curr().ok();
+        let (text, style_scope) = if key.is_empty() {
+            let name = buf.and_then(|b| b.source.path()).map(|p| p.file_name());
+            (name.unwrap_or_else(|| "Outline".into()), "tree.file")
+        } else {
+            match buf.and_then(|b| symbol_at(&b.buffer.symbols, key)) {
+                Some(symbol) => (symbol.name.clone(), style_scope(symbol.kind)),
+                None => (String::new(), "tree.symbol"),
+            }
+        };
+        ItemStyle {
+            text,
+            style_scope: style_scope.into(),
+            level: key.len(),
+            icon: None,
+        }
+    }
+
+    fn expandable(&self, key: &Self::Key) -> bool {
+        if key.is_empty() {
+            return true;
+        }
+        let buffers = lock!(buffers);
+        buffers.get_curr().map_or(false, |buf| {
+            symbol_at(&buf.buffer.symbols, key).map_or(false, |s| !s.children.is_empty())
+        })
+    }
+
+    fn parent(&self, key: &Self::Key) -> Option<Self::Key> {
+        let (_, parent) = key.split_last()?;
+        Some(parent.to_vec())
+    }
+
+    fn current(&self) -> Option<Self::Key> {
+        let buffers = lock!(buffers);
+        let buf = &buffers.get_curr().ok()?.buffer;
+        let pos: Position = (&buf.cursor().head).into_with_buf(buf);
+        Some(symbol_containing(&buf.symbols, pos))
+    }
+
+    fn key_down(&mut self, selected: &Self::Key, key: &KbKey) -> ShouldRepaint {
+        if key != &KbKey::Enter {
+            return false;
+        }
+        let mut buffers = lock!(mut buffers);
+        let buf = match buffers.get_mut_curr() {
+            Ok(buf) => &mut buf.buffer,
+            Err(_) => return false,
+        };
+        let start = match symbol_at(&buf.symbols, selected) {
+            Some(symbol) => symbol.selection.start,
+            None => return false,
+        };
+        let idx: Index = (&start).into_with_buf(&*buf);
+        buf.move_cursor(Movement::Index(idx), false);
+        true
+    }
+}
@@ -0,0 +1,266 @@
+use druid::Color;
+
+use crate::buffer::{Bounds, Buffer, Index};
+use crate::style_layer::{Span, StyleLayer};
+use crate::{lock, BufferData};
+
+pub const DEFAULT_SEARCH_COLOR: Color = Color::rgba8(0xfa, 0xbd, 0x2f, 0x50);
+
+#[derive(Default)]
+pub struct Search {
+    pub query: String,
+    pub ignore_case: bool,
+    pub matches: Vec<Bounds>,
+    /// selection when the search was opened, select all stays inside it
+    pub scope: Option<Bounds>,
+}
+
+impl Search {
+    /// Run the query again, cheap enough to be done after every keystroke.
+    pub fn update(&mut self, buffer: &Buffer) {
+        self.matches = buffer.find_all_with(&self.query, self.ignore_case);
+    }
+
+    /// First match starting at or after `idx`, wrapping to the start of the buffer.
+    pub fn next(&self, idx: Index) -> Option<Bounds> {
+        self.matches
+            .iter()
+            .find(|m| m.0 >= idx)
+            .or_else(|| self.matches.first())
+            .copied()
+    }
+
+    /// Last match starting before `idx`, wrapping to the end of the buffer.
+    pub fn prev(&self, idx: Index) -> Option<Bounds> {
+        self.matches
+            .iter()
+            .rev()
+            .find(|m| m.0 < idx)
+            .or_else(|| self.matches.last())
+            .copied()
+    }
+
+    /// Position of the match starting at `idx`, starting at 1.
+    pub fn position(&self, idx: Index) -> Option<usize> {
+        self.matches.iter().position(|m| m.0 == idx).map(|p| p + 1)
+    }
+}
+
+impl Buffer {
+    pub fn find_all(&self, needle: &str) -> Vec<Bounds> {
+        self.find_all_with(needle, false)
+    }
+
+    /// Non overlapping matches of `needle`, the text is read from the chunks of the rope
+    /// in a single pass.
+    pub fn find_all_with(&self, needle: &str, ignore_case: bool) -> Vec<Bounds> {
+        let fold = |c: char| {
+            if ignore_case {
+                c.to_lowercase().next().unwrap_or(c)
+            } else {
+                c
+            }
+        };
+        let needle = needle.chars().map(fold).collect::<Vec<_>>();
+        if needle.is_empty() {
+            return vec![];
+        }
+        // length of the longest proper prefix of `needle[..=i]` which is also a suffix of it
+        let mut fallback = vec![0; needle.len()];
+        let mut len = 0;
+        for i in 1..needle.len() {
+            while len > 0 && needle[i] != needle[len] {
+                len = fallback[len - 1];
+            }
+            if needle[i] == needle[len] {
+                len += 1;
+            }
+            fallback[i] = len;
+        }
+
+        let mut matches = vec![];
+        // chars of `needle` matched so far
+        let mut len = 0;
+        for (idx, c) in self.rope().chars().map(fold).enumerate() {
+            while len > 0 && c != needle[len] {
+                len = fallback[len - 1];
+            }
+            if c == needle[len] {
+                len += 1;
+            }
+            if len == needle.len() {
+                matches.push((idx + 1 - len, idx + 1));
+                len = 0;
+            }
+        }
+        matches
+    }
+
+    /// Select every match of `needle` inside `scope`, returns the number of selections.
+    pub fn select_all_occurrences(
+        &mut self,
+        needle: &str,
+        ignore_case: bool,
+        scope: Option<Bounds>,
+    ) -> usize {
+        let scope = scope.unwrap_or((0, self.rope().len_chars()));
+        let matches = self
+            .find_all_with(needle, ignore_case)
+            .into_iter()
+            .filter(|m| m.0 >= scope.0 && m.1 <= scope.1)
+            .collect::<Vec<_>>();
+        let count = matches.len();
+        self.set_selections(matches);
+        count
+    }
+
+    /// Select all the occurrences of the selection, or of the word under the cursor.
+    pub fn select_all_current(&mut self) -> usize {
+        let cursor = self.cursor();
+        let bounds = if cursor.same() {
+            match self.word_at(cursor.head) {
+                Some(bounds) => bounds,
+                None => return 0,
+            }
+        } else {
+            (cursor.min(), cursor.max())
+        };
+        match self.text_slice(bounds.0..bounds.1) {
+            Ok(needle) => self.select_all_occurrences(&needle, false, None),
+            Err(_) => 0,
+        }
+    }
+
+    /// Select the word under the cursor, or add a selection at the next occurrence of the
+    /// primary one, wrapping to the start. The new selection becomes the primary one,
+    /// returns false when there is nothing more to select.
+    pub fn add_selection_at_next_match(&mut self) -> bool {
+        let cursor = self.cursor();
+        if cursor.same() {
+            return match self.word_at(cursor.head) {
+                Some(bounds) => {
+                    self.set_selections(vec![bounds]);
+                    true
+                }
+                None => false,
+            };
+        }
+        let needle = match self.text_slice(cursor.min()..cursor.max()) {
+            Ok(needle) => needle,
+            Err(_) => return false,
+        };
+        let matches = self.find_all(&needle);
+        let (before, after): (Vec<Bounds>, Vec<Bounds>) =
+            matches.into_iter().partition(|m| m.0 < cursor.max());
+        after
+            .into_iter()
+            .chain(before)
+            .any(|m| self.add_selection(m, true))
+    }
+}
+
+pub struct SearchStyleLayer<'a>(pub &'a Search);
+
+impl StyleLayer for SearchStyleLayer<'_> {
+    fn spans(&mut self, _buf: &BufferData, min: Index, max: Index) -> anyhow::Result<Vec<Span>> {
+        let color = lock!(theme)
+            .scope("ui.search")
+            .background
+            .unwrap_or(DEFAULT_SEARCH_COLOR);
+        Ok(self
+            .0
+            .matches
+            .iter()
+            .filter(|m| m.1 >= min && m.0 <= max)
+            .map(|m| {
+                let mut span = Span::default();
+                span.start = m.0;
+                span.end = m.1;
+                span.style.background = Some(color.clone());
+                span
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::buffer::{Action, Buffer, Movement};
+    use crate::search::Search;
+
+    #[test]
+    fn find_all() {
+        let buf = Buffer::from_reader(1, Cursor::new("aaa Aa\nbaa"));
+        assert_eq!(buf.find_all("aa"), vec![(0, 2), (8, 10)]);
+        assert_eq!(buf.find_all_with("aa", true), vec![(0, 2), (4, 6), (8, 10)]);
+        assert!(buf.find_all("").is_empty());
+    }
+
+    #[test]
+    fn find_all_across_chunks() {
+        let text = "xy".repeat(3000) + "aab";
+        let buf = Buffer::from_reader(1, Cursor::new(text));
+        assert!(buf.rope().chunks().count() > 1);
+        assert_eq!(
+            buf.find_all_with("XYX", true),
+            (0..1500).map(|i| (i * 4, i * 4 + 3)).collect::<Vec<_>>()
+        );
+        assert_eq!(buf.find_all("ab"), vec![(6001, 6003)]);
+    }
+
+    #[test]
+    fn next_prev() {
+        let buf = Buffer::from_reader(1, Cursor::new("ab ab ab"));
+        let mut search = Search::default();
+        search.query = "ab".into();
+        search.update(&buf);
+        assert_eq!(search.next(1), Some((3, 5)));
+        assert_eq!(search.next(7), Some((0, 2)));
+        assert_eq!(search.prev(3), Some((0, 2)));
+        assert_eq!(search.prev(0), Some((6, 8)));
+        assert_eq!(search.position(6), Some(3));
+    }
+
+    #[test]
+    fn select_all() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("foo bar foo\nfoofoo"));
+        buf.move_cursor(Movement::Index(1), false);
+        assert_eq!(buf.select_all_current(), 4);
+        // the adjacent matches are merged
+        assert_eq!(buf.secondary_cursors().len(), 2);
+        buf.do_action(Action::Insert("x".into()));
+        assert_eq!(buf.text(), "x bar x\nx");
+        buf.do_action(Action::Insert("y".into()));
+        assert_eq!(buf.text(), "xy bar xy\nxy");
+        buf.do_action(Action::Undo);
+        assert!(buf.secondary_cursors().is_empty());
+
+        let mut buf = Buffer::from_reader(1, Cursor::new("a a a a"));
+        assert_eq!(buf.select_all_occurrences("a", false, Some((2, 5))), 2);
+        assert_eq!(buf.cursor().min(), 2);
+        assert!(buf.collapse_cursors());
+        assert!(buf.secondary_cursors().is_empty());
+    }
+
+    #[test]
+    fn select_next() {
+        let mut buf = Buffer::from_reader(1, Cursor::new("foo bar foo\nfoofoo"));
+        buf.move_cursor(Movement::Index(9), false);
+        // the word first, then the next occurrences
+        assert!(buf.add_selection_at_next_match());
+        assert_eq!((buf.cursor().min(), buf.cursor().max()), (8, 11));
+        assert!(buf.add_selection_at_next_match());
+        assert_eq!(buf.cursor().min(), 12);
+        // right after the previous one, then the search wraps
+        assert!(buf.add_selection_at_next_match());
+        assert_eq!(buf.cursor().min(), 15);
+        assert!(buf.add_selection_at_next_match());
+        assert_eq!(buf.cursor().min(), 0);
+        assert!(!buf.add_selection_at_next_match());
+
+        buf.do_action(Action::Insert("x".into()));
+        assert_eq!(buf.text(), "x bar x\nxx");
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::buffer::Movement;
+use crate::fs::{FileSystem, Path};
+use crate::status::error;
+use crate::{lock, set_root, BufferSource, FS};
+
+/// What is restored when the editor is launched without arguments.
+#[derive(Deserialize, Serialize, Default)]
+pub struct Session {
+    pub root: Option<String>,
+    pub current: Option<String>,
+    /// in the order they were opened
+    pub files: Vec<SessionFile>,
+}
+
+/// An open file and where it was left.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct SessionFile {
+    pub path: String,
+    /// char index of the cursor
+    pub cursor: usize,
+    pub scroll_line: usize,
+}
+
+impl Session {
+    pub fn file() -> Option<PathBuf> {
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("APPDATA"))?;
+        Some(PathBuf::from(home).join(".ste_session.toml"))
+    }
+
+    pub fn load() -> Option<Session> {
+        let content = std::fs::read_to_string(Self::file()?).ok()?;
+        toml::from_str(&content).ok()
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let file = Self::file().ok_or_else(|| anyhow::anyhow!("no home directory"))?;
+        std::fs::write(file, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Snapshot of the current workspace and open buffers.
+    pub fn capture() -> Session {
+        let root = {
+            let global = lock!(global);
+            global.root_path.path()
+        };
+
+        let buffers = lock!(buffers);
+        let mut opened = buffers
+            .buffers
+            .values()
+            .filter_map(|b| match &b.source {
+                BufferSource::File { path } => Some((
+                    b.id,
+                    SessionFile {
+                        path: path.path(),
+                        cursor: b.buffer.cursor().head,
+                        scroll_line: b.scroll_line,
+                    },
+                )),
+                BufferSource::Text => None,
+            })
+            .collect::<Vec<_>>();
+        opened.sort_by_key(|(id, _)| *id);
+
+        let current = buffers
+            .get_curr()
+            .ok()
+            .and_then(|b| b.source.path())
+            .map(|p| p.path());
+
+        Session {
+            root: Some(root),
+            files: opened.into_iter().map(|(_, file)| file).collect(),
+            current,
+        }
+    }
+
+    /// Set the workspace root and reopen the files which still exist, with their cursor
+    /// and scroll.
+    pub fn restore(&self) {
+        if let Some(root) = &self.root {
+            if std::path::Path::new(root).is_dir() {
+                set_root(FS.path(root.as_str()));
+            }
+        }
+
+        let mut buffers = lock!(mut buffers);
+        for file in &self.files {
+            if !std::path::Path::new(&file.path).is_file() {
+                continue;
+            }
+            match buffers.open_file(FS.path(file.path.as_str())) {
+                Ok(id) => {
+                    if let Ok(data) = buffers.get_mut(id) {
+                        // the file may have changed since
+                        data.buffer.move_cursor(Movement::Index(file.cursor), false);
+                        data.scroll_line = file.scroll_line.min(data.buffer.last_line());
+                    }
+                }
+                Err(e) => error(format!("failed to restore {} : {}", file.path, e)),
+            }
+        }
+        // already open, it becomes the current one
+        if let Some(current) = &self.current {
+            if std::path::Path::new(current).is_file() {
+                buffers.open_file(FS.path(current.as_str())).ok();
+            }
+        }
+    }
+}
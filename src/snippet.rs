@@ -0,0 +1,176 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::buffer::Bounds;
+
+/// Text of a snippet once expanded, with its tab stops.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Snippet {
+    pub text: String,
+    /// chars of each tab stop in `text`, in the order they are visited: `$1`, `$2`...
+    /// then `$0`, or the end of the text when there is no `$0`
+    pub stops: Vec<Bounds>,
+}
+
+/// Expand a snippet in the LSP syntax. Variables are replaced by their default value,
+/// a choice by its first option, and a stop appearing more than once is visited
+/// at its first occurrence.
+pub fn parse(snippet: &str) -> Snippet {
+    let mut text = vec![];
+    let mut stops = vec![];
+    parse_until(
+        &mut snippet.chars().peekable(),
+        &mut text,
+        &mut stops,
+        false,
+    );
+
+    // sorted by number, keeping the first occurrence
+    stops.sort_by_key(|(n, _)| *n);
+    stops.dedup_by_key(|(n, _)| *n);
+    let last = match stops.first() {
+        Some((0, _)) => stops.remove(0).1,
+        _ => (text.len(), text.len()),
+    };
+    let mut stops: Vec<Bounds> = stops.into_iter().map(|(_, bounds)| bounds).collect();
+    stops.push(last);
+    Snippet {
+        text: text.into_iter().collect(),
+        stops,
+    }
+}
+
+fn parse_until(
+    chars: &mut Peekable<Chars>,
+    text: &mut Vec<char>,
+    stops: &mut Vec<(usize, Bounds)>,
+    nested: bool,
+) {
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some('$' | '}' | '\\' | ',' | '|') => text.push(chars.next().unwrap()),
+                _ => text.push(c),
+            },
+            '}' if nested => return,
+            '$' => match chars.peek() {
+                Some(d) if d.is_ascii_digit() => {
+                    let n = number(chars);
+                    stops.push((n, (text.len(), text.len())));
+                }
+                Some('{') => {
+                    chars.next();
+                    placeholder(chars, text, stops);
+                }
+                Some(v) if v.is_alphabetic() || *v == '_' => {
+                    // a variable without a default is empty
+                    name(chars);
+                }
+                _ => text.push(c),
+            },
+            _ => text.push(c),
+        }
+    }
+}
+
+/// After `${`, up to and including the closing `}`.
+fn placeholder(
+    chars: &mut Peekable<Chars>,
+    text: &mut Vec<char>,
+    stops: &mut Vec<(usize, Bounds)>,
+) {
+    let start = text.len();
+    let stop = match chars.peek() {
+        Some(d) if d.is_ascii_digit() => Some(number(chars)),
+        _ => {
+            name(chars);
+            None
+        }
+    };
+    match chars.next() {
+        Some(':') => parse_until(chars, text, stops, true),
+        Some('|') => {
+            // a choice, the first option is inserted
+            let mut first = true;
+            while let Some(c) = chars.next() {
+                match c {
+                    '|' if chars.peek() == Some(&'}') => {
+                        chars.next();
+                        break;
+                    }
+                    ',' => first = false,
+                    '\\' => {
+                        if let Some(c) = chars.next() {
+                            if first {
+                                text.push(c);
+                            }
+                        }
+                    }
+                    _ if first => text.push(c),
+                    _ => {}
+                }
+            }
+        }
+        // `}`, or an unterminated snippet
+        _ => {}
+    }
+    if let Some(n) = stop {
+        stops.push((n, (start, text.len())));
+    }
+}
+
+fn number(chars: &mut Peekable<Chars>) -> usize {
+    let mut n = 0;
+    while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+        n = n * 10 + d as usize;
+        chars.next();
+    }
+    n
+}
+
+fn name(chars: &mut Peekable<Chars>) {
+    while chars
+        .peek()
+        .map_or(false, |c| c.is_alphanumeric() || *c == '_')
+    {
+        chars.next();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::snippet::{parse, Snippet};
+
+    #[test]
+    fn tab_stops() {
+        let snippet = parse("fn ${1:name}($2) {\n    $0\n}");
+        assert_eq!(snippet.text, "fn name() {\n    \n}");
+        assert_eq!(snippet.stops, vec![(3, 7), (8, 8), (16, 16)]);
+
+        // without `$0` the last stop is the end
+        let snippet = parse("foo($1, ${2:b})");
+        assert_eq!(snippet.text, "foo(, b)");
+        assert_eq!(snippet.stops, vec![(4, 4), (6, 7), (8, 8)]);
+
+        let snippet = parse("plain");
+        assert_eq!(
+            snippet,
+            Snippet {
+                text: "plain".into(),
+                stops: vec![(5, 5)],
+            }
+        );
+    }
+
+    #[test]
+    fn nested_and_escaped() {
+        let snippet = parse("${1:a${2:b}c} \\$1 \\} ${3|x,y|} ${TM_FILENAME:f}$X");
+        assert_eq!(snippet.text, "abc $1 } x f");
+        assert_eq!(snippet.stops, vec![(0, 3), (1, 2), (9, 10), (12, 12)]);
+
+        // a repeated stop is visited once
+        let snippet = parse("$1 = $1;$0");
+        assert_eq!(snippet.text, " = ;");
+        assert_eq!(snippet.stops, vec![(0, 0), (4, 4)]);
+    }
+}
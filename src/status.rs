@@ -0,0 +1,217 @@
+use std::time::{Duration, Instant};
+
+use druid::*;
+use parking_lot::RwLock;
+
+use crate::draw::{drawable_text, Drawable};
+use crate::editor::{DEFAULT_BACKGROUND_COLOR, LINE_SPACING};
+use crate::tabs::buffer_name;
+use crate::theme::{Style, THEME_CHANGED};
+use crate::{lock, AppState, LspLang};
+
+/// How long a message stays in the status line.
+const MESSAGE_DURATION: Duration = Duration::from_secs(5);
+/// Entries kept in the log, the oldest ones are dropped.
+const MAX_LOG_ENTRIES: usize = 500;
+/// How often the cursor and the message are checked, they change outside of this widget.
+const POLL: Duration = Duration::from_millis(250);
+const STATUS_HEIGHT: f64 = 24.0;
+pub const DEFAULT_ERROR_COLOR: Color = Color::rgb8(0xe0, 0x50, 0x50);
+
+lazy_static::lazy_static! {
+    static ref LOG: RwLock<Vec<LogEntry>> = RwLock::new(Vec::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub level: Level,
+    pub message: String,
+    /// when it was last logged
+    pub at: Instant,
+    /// times it was logged in a row, a failing paint logs the same error each frame
+    pub count: usize,
+}
+
+fn push(level: Level, message: String) {
+    let mut log = LOG.write();
+    match log.last_mut() {
+        Some(last) if last.level == level && last.message == message => {
+            last.at = Instant::now();
+            last.count += 1;
+        }
+        _ => {
+            log.push(LogEntry {
+                level,
+                message,
+                at: Instant::now(),
+                count: 1,
+            });
+            if log.len() > MAX_LOG_ENTRIES {
+                log.remove(0);
+            }
+        }
+    }
+}
+
+/// Log `message` and show it in the status line for a few seconds, it is printed too.
+pub fn notify(message: impl Into<String>) {
+    push(Level::Info, message.into());
+}
+
+/// Like `notify`, for a failure.
+pub fn error(message: impl Into<String>) {
+    push(Level::Error, message.into());
+}
+
+/// Entries of the log, the oldest first.
+pub fn log() -> Vec<LogEntry> {
+    LOG.read().clone()
+}
+
+/// The last entry, until it expires.
+pub fn message() -> Option<LogEntry> {
+    LOG.read()
+        .last()
+        .filter(|entry| entry.at.elapsed() < MESSAGE_DURATION)
+        .cloned()
+}
+
+/// What the status line shows, a repaint is needed when it changes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub name: String,
+    /// 1-based, like the line numbers
+    pub row: usize,
+    /// 1-based, in chars
+    pub col: usize,
+    pub lang: LspLang,
+    pub modified: bool,
+    /// without highlighting and language server
+    pub large_file: bool,
+    pub message: Option<LogEntry>,
+}
+
+/// Status of the current buffer, `None` without one.
+pub fn status() -> Option<Status> {
+    let buffers = lock!(buffers);
+    let buf = buffers.get_curr().ok()?;
+    Some(Status {
+        name: buffer_name(buf),
+        row: buf.buffer.row() + 1,
+        col: buf.buffer.col() + 1,
+        lang: buf.lsp_lang.clone(),
+        modified: buf.buffer.is_modified(),
+        large_file: buf.large_file,
+        message: message(),
+    })
+}
+
+/// Line under the editor with the file, the cursor position and the language of the current
+/// buffer, and the last message.
+#[derive(Default)]
+pub struct StatusLine {
+    status: Option<Status>,
+}
+
+impl Widget<AppState> for StatusLine {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, _data: &mut AppState, _env: &Env) {
+        match event {
+            Event::Timer(_) => {
+                if status() != self.status {
+                    ctx.request_paint();
+                }
+                ctx.request_timer(POLL);
+            }
+            Event::Command(cmd) if cmd.is(THEME_CHANGED) => ctx.request_paint(),
+            _ => {}
+        }
+    }
+
+    fn lifecycle(
+        &mut self,
+        ctx: &mut LifeCycleCtx,
+        event: &LifeCycle,
+        _data: &AppState,
+        _env: &Env,
+    ) {
+        if let LifeCycle::WidgetAdded = event {
+            ctx.request_timer(POLL);
+        }
+    }
+
+    fn update(&mut self, _ctx: &mut UpdateCtx, _old_data: &AppState, _data: &AppState, _env: &Env) {
+    }
+
+    fn layout(
+        &mut self,
+        _ctx: &mut LayoutCtx,
+        bc: &BoxConstraints,
+        _data: &AppState,
+        _env: &Env,
+    ) -> Size {
+        bc.constrain(Size::new(bc.max().width, STATUS_HEIGHT))
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, _data: &AppState, env: &Env) {
+        let rect = ctx.size().to_rect();
+        ctx.save().unwrap();
+        ctx.clip(rect);
+        let theme = lock!(theme);
+        let style = theme.scope("ui.statusline");
+        ctx.fill(
+            rect,
+            &style
+                .background
+                .or_else(|| theme.scope("ui.background").background)
+                .unwrap_or(DEFAULT_BACKGROUND_COLOR),
+        );
+
+        self.status = status();
+        if let Some(status) = &self.status {
+            let modified = if status.modified { " ●" } else { "" };
+            let name = drawable_text(ctx, env, &format!("{}{}", status.name, modified), &style);
+            let large_file = if status.large_file {
+                "   large file"
+            } else {
+                ""
+            };
+            let position = format!(
+                "Ln {}, Col {}   {:?}{}",
+                status.row, status.col, status.lang, large_file
+            );
+            let position = drawable_text(ctx, env, &position, &style);
+
+            let y = (rect.height() - name.height()) / 2.0;
+            let mut x = LINE_SPACING * 4.0;
+            name.draw(ctx, x, y);
+            x += name.width() + LINE_SPACING * 8.0;
+            if let Some(message) = &status.message {
+                let message =
+                    drawable_text(ctx, env, &message.message, &level_style(message.level));
+                message.draw(ctx, x, y);
+            }
+            let right = rect.width() - position.width() - LINE_SPACING * 4.0;
+            position.draw(ctx, right, y);
+        }
+        ctx.restore().unwrap();
+    }
+}
+
+/// Style of the messages of `level`.
+pub fn level_style(level: Level) -> Style {
+    let theme = lock!(theme);
+    match level {
+        Level::Info => theme.scope("ui.text"),
+        Level::Error => {
+            let mut style = theme.scope("error");
+            style.foreground = style.foreground.or(Some(DEFAULT_ERROR_COLOR));
+            style
+        }
+    }
+}
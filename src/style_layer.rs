@@ -1,6 +1,10 @@
+use druid::Color;
+use itertools::Itertools;
+
 use crate::buffer::Index;
+use crate::highlight::TreeSitterHighlight;
 use crate::theme::Style;
-use crate::BufferData;
+use crate::{lock, BufferData};
 
 #[derive(Default, Clone, Debug)]
 pub struct Span {
@@ -73,12 +77,78 @@ pub fn style_for_range(
     Ok(final_spans)
 }
 
+pub struct IndentStyleLayer();
+
+impl StyleLayer for IndentStyleLayer {
+    fn spans(&mut self, buf: &BufferData, min: Index, max: Index) -> anyhow::Result<Vec<Span>> {
+        let mut spans = Vec::new();
+        let use_tabs = match &buf.buffer.indentation {
+            Some(indentation) if indentation.is_mixed() => indentation.use_tabs(),
+            _ => return Ok(spans),
+        };
+        for line in buf.buffer.row_at(min)..=buf.buffer.row_at(max) {
+            if !buf.buffer.is_line_inconsistent(line, use_tabs) {
+                continue;
+            }
+            let bounds = buf.buffer.line_bounds(line);
+            let text = buf.buffer.text_slice(bounds.0..bounds.1)?;
+            let len = text.chars().take_while(|c| *c == ' ' || *c == '\t').count();
+            let color = lock!(theme)
+                .scope("ui.indent.mixed")
+                .foreground
+                .unwrap_or_else(|| Color::rgb8(255, 165, 0));
+            let mut span = Span::default();
+            span.start = bounds.0;
+            span.end = bounds.0 + len;
+            span.style.background = Some(color.clone().with_alpha(0.10));
+            span.style.wavy_underline = Some(color);
+            spans.push(span);
+        }
+        Ok(spans)
+    }
+}
+
+pub const DEFAULT_BRACKET_COLOR: Color = Color::rgba8(0x80, 0x80, 0x80, 0x60);
+
+/// The bracket next to the cursor and the one matching it between `min` and `max`,
+/// the brackets in the strings and comments of the highlight are skipped.
+pub struct BracketStyleLayer<'a>(pub Option<&'a TreeSitterHighlight>);
+
+impl StyleLayer for BracketStyleLayer<'_> {
+    fn spans(&mut self, buf: &BufferData, min: Index, max: Index) -> anyhow::Result<Vec<Span>> {
+        let rope = buf.buffer.rope();
+        let ignored = |idx| matches!(self.0, Some(h) if h.in_literal(rope, idx));
+        let (a, b) = match buf.buffer.bracket_match((min, max), ignored) {
+            Some(bounds) => bounds,
+            None => return Ok(vec![]),
+        };
+        let theme = lock!(theme);
+        let color = theme
+            .scope("bracket.match")
+            .background
+            .or_else(|| theme.scope("ui.cursor.match").background)
+            .unwrap_or(DEFAULT_BRACKET_COLOR);
+        Ok([a, b]
+            .iter()
+            .map(|idx| {
+                let mut span = Span::default();
+                span.start = *idx;
+                span.end = idx + 1;
+                span.style.background = Some(color.clone());
+                span
+            })
+            .collect())
+    }
+}
+
 pub struct DiagStyleLayer();
 
 impl StyleLayer for DiagStyleLayer {
     fn spans(&mut self, buf: &BufferData, _min: Index, _max: Index) -> anyhow::Result<Vec<Span>> {
         let mut spans = Vec::new();
-        for diag in buf.buffer.diagnostics.0.iter() {
+        // the last span wins an overlap, the most severe goes last
+        let diagnostics = buf.buffer.diagnostics.0.iter();
+        for diag in diagnostics.sorted_by(|a, b| b.severity.cmp(&a.severity)) {
             let mut span = Span::default();
             span.start = diag.bounds.0;
             span.end = diag.bounds.1;
@@ -92,3 +162,88 @@ impl StyleLayer for DiagStyleLayer {
         Ok(spans)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use lsp_types::{DiagnosticSeverity, Url};
+
+    use crate::buffer::{Buffer, Diagnostic, Movement};
+    use crate::style_layer::{style_for_range, BracketStyleLayer, DiagStyleLayer, StyleLayer};
+    use crate::{BufferData, BufferSource, LspLang};
+
+    #[test]
+    fn most_severe_diagnostic() {
+        let mut buffer = Buffer::from_reader(1, Cursor::new("abcdef"));
+        let diagnostic = |bounds, severity| Diagnostic {
+            bounds,
+            severity,
+            message: String::new(),
+            original: Default::default(),
+        };
+        buffer.diagnostics.0 = vec![
+            diagnostic((0, 4), DiagnosticSeverity::ERROR),
+            diagnostic((2, 6), DiagnosticSeverity::WARNING),
+        ];
+        let buf = BufferData {
+            id: 1,
+            source: BufferSource::Text,
+            lsp_root: Url::parse("file:///").unwrap(),
+            lsp_lang: LspLang::PlainText,
+            read_only: false,
+            modified: false,
+            large_file: false,
+            scroll_line: 0,
+            buffer,
+        };
+        let error = buf.buffer.diagnostics.0[0].color();
+        let warning = buf.buffer.diagnostics.0[1].color();
+
+        let spans = DiagStyleLayer().spans(&buf, 0, 6).unwrap();
+        let spans = style_for_range(&[&spans], 0, 6, vec![]).unwrap();
+        let underlines = spans
+            .iter()
+            .map(|s| (s.start, s.end, s.style.wavy_underline.clone().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            underlines,
+            vec![(0, 2, error.clone()), (2, 4, error), (4, 6, warning)]
+        );
+    }
+
+    #[test]
+    fn matching_brackets() {
+        let mut buffer = Buffer::from_reader(1, Cursor::new("f(a[0]) x"));
+        buffer.move_cursor(Movement::Index(7), false);
+        let mut buf = BufferData {
+            id: 1,
+            source: BufferSource::Text,
+            lsp_root: Url::parse("file:///").unwrap(),
+            lsp_lang: LspLang::PlainText,
+            read_only: false,
+            modified: false,
+            large_file: false,
+            scroll_line: 0,
+            buffer,
+        };
+        // right after the `)`
+        let spans = BracketStyleLayer(None).spans(&buf, 0, 9).unwrap();
+        let bounds = spans.iter().map(|s| (s.start, s.end)).collect::<Vec<_>>();
+        assert_eq!(bounds, vec![(6, 7), (1, 2)]);
+        assert!(spans[0].style.background.is_some());
+
+        buf.buffer.move_cursor(Movement::Index(9), false);
+        assert!(BracketStyleLayer(None)
+            .spans(&buf, 0, 9)
+            .unwrap()
+            .is_empty());
+
+        // out of the range painted
+        buf.buffer.move_cursor(Movement::Index(7), false);
+        assert!(BracketStyleLayer(None)
+            .spans(&buf, 3, 9)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+use std::time::Duration;
+
+use druid::*;
+
+use crate::draw::{drawable_text, Drawable};
+use crate::editor::{DEFAULT_BACKGROUND_COLOR, HALF_LINE_SPACING};
+use crate::fs::Path;
+use crate::icons::{draw_icon, icon_for, ICON_WIDTH};
+use crate::status::notify;
+use crate::theme::THEME_CHANGED;
+use crate::tree::{ITEMS_CHANGED, REVEAL_CURRENT};
+use crate::{lock, AppState, BufferData, BufferSource};
+
+/// The current buffer was changed outside of the editor, e.g. by a click on a tab.
+pub const BUFFER_CHANGED: Selector = Selector::new("ste.tabs.buffer-changed");
+
+/// How often the open buffers are checked for changes made by the other widgets.
+const POLL: Duration = Duration::from_millis(250);
+const TAB_HEIGHT: f64 = 28.0;
+const TAB_PADDING: f64 = 10.0;
+const APP_NAME: &str = "STE";
+
+/// What a tab shows, a repaint is needed when it changes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tab {
+    pub id: u32,
+    pub name: String,
+    /// of the file, for the icon
+    pub extension: Option<String>,
+    pub modified: bool,
+    pub current: bool,
+}
+
+/// Name of the file of `buffer`, shown in its tab and in the status line.
+pub fn buffer_name(buffer: &BufferData) -> String {
+    let mut name = match &buffer.source {
+        BufferSource::File { path } => path.name(),
+        BufferSource::Text => "untitled".into(),
+    };
+    if buffer.read_only {
+        name.push_str(" (read-only)");
+    }
+    name
+}
+
+/// Title of the window: the name of the current buffer, after a dot when it is modified.
+pub fn window_title() -> String {
+    let buffers = lock!(buffers);
+    match buffers.get_curr() {
+        Ok(buf) => {
+            let modified = if buf.buffer.is_modified() { "• " } else { "" };
+            format!("{}{} — {}", modified, buffer_name(buf), APP_NAME)
+        }
+        Err(_) => APP_NAME.to_string(),
+    }
+}
+
+/// Tabs of the open buffers, in the order they were opened.
+pub fn tabs() -> Vec<Tab> {
+    let buffers = lock!(buffers);
+    let mut tabs: Vec<Tab> = buffers
+        .buffers
+        .values()
+        .map(|b| Tab {
+            id: b.id,
+            name: buffer_name(b),
+            extension: b.source.path().and_then(|p| p.extension()),
+            modified: b.buffer.is_modified(),
+            current: buffers.current == Some(b.id),
+        })
+        .collect();
+    tabs.sort_by_key(|t| t.id);
+    tabs
+}
+
+/// Strip of the open buffers above the editor. A click on a tab makes its buffer current,
+/// a click on its `×` closes it. A modified buffer shows a dot instead, the first click
+/// on it warns about the unsaved changes and a second one closes it anyway.
+#[derive(Default)]
+pub struct TabBar {
+    tabs: Vec<Tab>,
+    // painted extent of each tab and of its close button
+    rects: Vec<(Rect, Rect, u32)>,
+    // modified buffer whose close button was clicked once
+    closing: Option<u32>,
+}
+
+impl TabBar {
+    /// Close buffer `id` from its close button, returns whether it was closed.
+    pub fn close_tab(&mut self, id: u32) -> bool {
+        let mut buffers = lock!(mut buffers);
+        let buf = match buffers.get(id) {
+            Ok(buf) => buf,
+            Err(_) => return false,
+        };
+        if buf.buffer.is_modified() && self.closing != Some(id) {
+            self.closing = Some(id);
+            notify(format!(
+                "{} has unsaved changes, click × again to close it anyway",
+                buffer_name(buf)
+            ));
+            return false;
+        }
+        self.closing = None;
+        buffers.close(id).ok();
+        if buffers.current.is_none() {
+            buffers.open_text("");
+        }
+        true
+    }
+
+    fn click(&mut self, ctx: &mut EventCtx, pos: Point) {
+        let (_, close, id) = match self.rects.iter().find(|(tab, _, _)| tab.contains(pos)) {
+            Some(rect) => *rect,
+            None => return,
+        };
+        if close.contains(pos) {
+            if !self.close_tab(id) {
+                ctx.request_paint();
+                return;
+            }
+        } else {
+            self.closing = None;
+            lock!(mut buffers).current = Some(id);
+        }
+        ctx.submit_command(BUFFER_CHANGED);
+        ctx.submit_command(REVEAL_CURRENT);
+        ctx.submit_command(ITEMS_CHANGED);
+        ctx.request_paint();
+    }
+}
+
+impl Widget<AppState> for TabBar {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, _data: &mut AppState, _env: &Env) {
+        match event {
+            Event::Timer(_) => {
+                if tabs() != self.tabs {
+                    ctx.request_paint();
+                }
+                ctx.request_timer(POLL);
+            }
+            Event::Command(cmd) if cmd.is(THEME_CHANGED) => ctx.request_paint(),
+            Event::MouseDown(e) if e.button.is_left() => self.click(ctx, e.pos),
+            _ => {}
+        }
+    }
+
+    fn lifecycle(
+        &mut self,
+        ctx: &mut LifeCycleCtx,
+        event: &LifeCycle,
+        _data: &AppState,
+        _env: &Env,
+    ) {
+        if let LifeCycle::WidgetAdded = event {
+            ctx.request_timer(POLL);
+        }
+    }
+
+    fn update(&mut self, _ctx: &mut UpdateCtx, _old_data: &AppState, _data: &AppState, _env: &Env) {
+    }
+
+    fn layout(
+        &mut self,
+        _ctx: &mut LayoutCtx,
+        bc: &BoxConstraints,
+        _data: &AppState,
+        _env: &Env,
+    ) -> Size {
+        bc.constrain(Size::new(bc.max().width, TAB_HEIGHT))
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, _data: &AppState, env: &Env) {
+        self.tabs = tabs();
+        let icons: Vec<_> = if lock!(conf).render.icons {
+            let icon = |tab: &Tab| Some(icon_for(tab.extension.as_deref(), false));
+            self.tabs.iter().map(icon).collect()
+        } else {
+            vec![None; self.tabs.len()]
+        };
+
+        let rect = ctx.size().to_rect();
+        ctx.save().unwrap();
+        ctx.clip(rect);
+        let theme = lock!(theme);
+        ctx.fill(
+            rect,
+            &theme
+                .scope("ui.window")
+                .background
+                .or_else(|| theme.scope("ui.background").background)
+                .unwrap_or(DEFAULT_BACKGROUND_COLOR),
+        );
+
+        self.rects.clear();
+        let mut x = 0.0;
+        for (tab, icon) in self.tabs.iter().zip(icons) {
+            let style = if tab.current {
+                theme.scope("ui.statusline")
+            } else {
+                theme.scope("ui.statusline.inactive")
+            };
+            let name = drawable_text(ctx, env, &tab.name, &style);
+            // the dot turns into a cross once the unsaved changes were warned about
+            let marker = if tab.modified && self.closing != Some(tab.id) {
+                "●"
+            } else {
+                "×"
+            };
+            let marker = drawable_text(ctx, env, marker, &style);
+            let icon_width = if icon.is_some() { ICON_WIDTH } else { 0.0 };
+
+            let width = TAB_PADDING * 3.0 + icon_width + name.width() + marker.width();
+            let tab_rect = Rect::new(x, 0.0, x + width, rect.height());
+            if let Some(bg) = &style.background {
+                ctx.fill(tab_rect, bg);
+            }
+            let y = (rect.height() - name.height()) / 2.0;
+            if let Some(icon) = &icon {
+                draw_icon(ctx, icon, x + TAB_PADDING, y, name.height());
+            }
+            name.draw(ctx, x + TAB_PADDING + icon_width, y);
+            let marker_x = x + TAB_PADDING * 2.0 + icon_width + name.width();
+            marker.draw(ctx, marker_x, y);
+
+            let close = Rect::new(
+                marker_x - HALF_LINE_SPACING,
+                0.0,
+                marker_x + marker.width() + HALF_LINE_SPACING,
+                rect.height(),
+            );
+            self.rects.push((tab_rect, close, tab.id));
+            x += width + 1.0;
+        }
+        ctx.restore().unwrap();
+    }
+}
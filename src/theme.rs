@@ -1,13 +1,62 @@
 use druid::Color;
+use druid::Selector;
 use itertools::Itertools;
+use parking_lot::RwLock;
+use serde::de::Error;
 use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use toml::Value;
 
-#[derive(Clone, Debug, Default)]
+use crate::THEME;
+
+/// Theme built in the binary, used until a theme file is loaded.
+pub const DEFAULT_THEME: &str = include_str!("../runtime/themes/gruvbox.toml");
+/// The theme in use changed, every widget repaints.
+pub const THEME_CHANGED: Selector = Selector::new("ste.theme.changed");
+
+lazy_static::lazy_static! {
+    /// Folder of the theme files, watched while the editor runs.
+    pub static ref THEMES_DIR: PathBuf = themes_dir();
+}
+
+/// `runtime/themes` next to the executable or above it, as in a cargo build,
+/// else `.ste/themes` in the home folder.
+fn themes_dir() -> PathBuf {
+    let beside_exe = std::env::current_exe().ok().and_then(|exe| {
+        exe.ancestors()
+            .skip(1)
+            .map(|dir| dir.join("runtime").join("themes"))
+            .find(|dir| dir.is_dir())
+    });
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("APPDATA"));
+    beside_exe
+        .or_else(|| Some(PathBuf::from(home?).join(".ste").join("themes")))
+        .unwrap_or_else(|| PathBuf::from("runtime/themes"))
+}
+
+/// File of the theme `name`.
+pub fn theme_path(name: &str) -> PathBuf {
+    THEMES_DIR.join(format!("{}.toml", name))
+}
+
+#[derive(Debug, Default)]
 pub struct Theme {
     scopes: Vec<String>,
     styles: HashMap<String, Style>,
+    // resolved query -> style, filled lazily by `scope`
+    cache: RwLock<HashMap<String, Style>>,
+}
+
+impl Clone for Theme {
+    fn clone(&self) -> Self {
+        Self {
+            scopes: self.scopes.clone(),
+            styles: self.styles.clone(),
+            cache: Default::default(),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -36,30 +85,94 @@ impl<'de> Deserialize<'de> for Theme {
     {
         let mut styles = HashMap::new();
 
-        if let Ok(mut colors) = HashMap::<String, Value>::deserialize(deserializer) {
-            // TODO: alert user of parsing failures in editor
-            let palette = colors
-                .remove("palette")
-                .map(|value| {
-                    ThemePalette::try_from(value).unwrap_or_else(|_| ThemePalette::default())
-                })
-                .unwrap_or_default();
-
-            styles.reserve(colors.len());
-            for (name, style_value) in colors {
-                let mut style = Style::default();
-                palette.parse_style(&mut style, style_value).unwrap();
-                styles.insert(name, style);
-            }
+        let mut colors = HashMap::<String, Value>::deserialize(deserializer)?;
+        let palette = colors
+            .remove("palette")
+            .map(ThemePalette::try_from)
+            .transpose()
+            .map_err(D::Error::custom)?
+            .unwrap_or_default();
+
+        styles.reserve(colors.len());
+        for (name, style_value) in colors {
+            let mut style = Style::default();
+            palette
+                .parse_style(&mut style, style_value)
+                .map_err(|e| D::Error::custom(format!("{} in `{}`", e, name)))?;
+            styles.insert(name, style);
         }
 
         let scopes = styles.keys().map(ToString::to_string).collect();
-        Ok(Self { scopes, styles })
+        Ok(Self {
+            scopes,
+            styles,
+            cache: Default::default(),
+        })
     }
 }
 
 impl Theme {
+    /// Parse the toml of a theme, the error tells what is malformed.
+    pub fn parse(toml: &str) -> Result<Theme, String> {
+        toml::from_str(toml).map_err(|e| format!("Theme: {}", e))
+    }
+
+    /// Read and parse the theme `name` of `THEMES_DIR`.
+    pub fn load(name: &str) -> Result<Theme, String> {
+        let path = theme_path(name);
+        let toml = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Theme: cannot read {}: {}", path.display(), e))?;
+        Theme::parse(&toml)
+    }
+
+    /// Use the theme `name`, the built-in one if it is missing or malformed.
+    pub fn select(name: &str) -> Result<(), String> {
+        let (theme, result) = match Theme::load(name) {
+            Ok(theme) => (theme, Ok(())),
+            Err(e) => (Theme::parse(DEFAULT_THEME)?, Err(e)),
+        };
+        *THEME.write() = Arc::new(theme);
+        result
+    }
+
+    /// Names of the themes of `THEMES_DIR`, sorted.
+    pub fn names() -> Vec<String> {
+        let entries = match std::fs::read_dir(&*THEMES_DIR) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|e| {
+                let path = e.ok()?.path();
+                if path.extension()? != "toml" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_str()?.to_string())
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Read the theme file at `path` and use it, the theme in use stays on failure.
+    pub fn reload(path: &Path) -> Result<(), String> {
+        let toml = std::fs::read_to_string(path)
+            .map_err(|e| format!("Theme: cannot read {}: {}", path.display(), e))?;
+        let theme = Theme::parse(&toml)?;
+        *THEME.write() = Arc::new(theme);
+        Ok(())
+    }
+
     pub fn scope(&self, query: &str) -> Style {
+        if let Some(style) = self.cache.read().get(query) {
+            return style.clone();
+        }
+        let style = self.resolve(query);
+        self.cache.write().insert(query.to_string(), style.clone());
+        style
+    }
+
+    fn resolve(&self, query: &str) -> Style {
         let parts = query.split('.').collect::<Vec<_>>();
         for i in (1..=parts.len()).rev() {
             let scope: String = parts[0..i].iter().join(".");
@@ -70,6 +183,11 @@ impl Theme {
         Style::default()
     }
 
+    /// Must be called whenever `styles` change (theme reload).
+    pub fn clear_cache(&self) {
+        self.cache.write().clear();
+    }
+
     #[inline]
     pub fn scopes(&self) -> &[String] {
         &self.scopes
@@ -102,14 +220,17 @@ impl ThemePalette {
         Self { palette: default }
     }
 
+    /// `#RRGGBB`, or `#RRGGBBAA` with an alpha.
     pub fn hex_string_to_rgb(s: &str) -> Result<Color, String> {
-        if s.starts_with('#') && s.len() >= 7 {
-            if let (Ok(red), Ok(green), Ok(blue)) = (
-                u8::from_str_radix(&s[1..3], 16),
-                u8::from_str_radix(&s[3..5], 16),
-                u8::from_str_radix(&s[5..7], 16),
-            ) {
-                return Ok(Color::rgb8(red, green, blue));
+        let hex = |i: usize| s.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok());
+        if s.starts_with('#') && (s.len() == 7 || s.len() == 9) {
+            if let (Some(red), Some(green), Some(blue)) = (hex(1), hex(3), hex(5)) {
+                if s.len() == 7 {
+                    return Ok(Color::rgb8(red, green, blue));
+                }
+                if let Some(alpha) = hex(7) {
+                    return Ok(Color::rgba8(red, green, blue, alpha));
+                }
             }
         }
 
@@ -195,3 +316,52 @@ impl TryFrom<Value> for ThemePalette {
         Ok(Self::new(palette))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::theme::{Theme, ThemePalette, DEFAULT_THEME};
+
+    #[test]
+    fn parse_errors() {
+        assert!(Theme::parse(DEFAULT_THEME).is_ok());
+        let error = Theme::parse("[palette]\nred = \"#ff\"").unwrap_err();
+        assert!(error.contains("malformed hexcode"), "{}", error);
+        let error = Theme::parse("keyword = { fg = \"#ff0000\", blink = true }").unwrap_err();
+        assert!(
+            error.contains("blink") && error.contains("keyword"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn hex_colors() {
+        let rgba = |s| ThemePalette::hex_string_to_rgb(s).map(|c| c.as_rgba_u32());
+        assert_eq!(rgba("#ff8000"), Ok(0xff8000ff));
+        assert_eq!(rgba("#AbCdEf"), Ok(0xabcdefff));
+        assert_eq!(rgba("#ff800080"), Ok(0xff800080));
+        assert_eq!(rgba("#eeeeeeb3"), Ok(0xeeeeeeb3));
+        for malformed in [
+            "#fff",
+            "#ff8000f",
+            "#ff8000801",
+            "ff8000",
+            "#gg8000",
+            "#ff8000zz",
+        ] {
+            assert_eq!(
+                rgba(malformed),
+                Err(format!("Theme: malformed hexcode: {}", malformed))
+            );
+        }
+    }
+
+    #[test]
+    fn load_by_name() {
+        let names = Theme::names();
+        assert!(names.contains(&"gruvbox".to_string()));
+        assert!(names.iter().all(|n| !n.ends_with(".md")));
+        assert!(Theme::load("gruvbox").is_ok());
+        assert!(Theme::load("no_such_theme").is_err());
+    }
+}
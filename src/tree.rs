@@ -1,23 +1,53 @@
 use crate::draw::{drawable_text, Drawable};
 use crate::editor::{DEFAULT_BACKGROUND_COLOR, HALF_LINE_SPACING, LINE_SPACING};
-use crate::{AppState, THEME};
+use crate::icons::{draw_icon, Icon, ICON_WIDTH};
+use crate::theme::THEME_CHANGED;
+use crate::{lock, AppState};
 use druid::*;
+use std::time::{Duration, Instant};
 
 pub type ShouldRepaint = bool;
 
+const TYPE_AHEAD_RESET: Duration = Duration::from_millis(1000);
+
+/// Select the item of the current buffer, its ancestors are expanded.
+pub const REVEAL_CURRENT: Selector = Selector::new("ste.tree.reveal-current");
+/// The items of a tree changed outside of it, e.g. the outline of the current buffer.
+pub const ITEMS_CHANGED: Selector = Selector::new("ste.tree.items-changed");
+/// A key of a tree moved the cursor of the editor, which scrolls to it.
+pub const REVEAL_CURSOR: Selector = Selector::new("ste.tree.reveal-cursor");
+/// The root of the trees changed, e.g. another workspace was opened, they start collapsed.
+pub const ROOT_CHANGED: Selector = Selector::new("ste.tree.root-changed");
+
 pub trait Tree {
     type Key: Clone + PartialEq;
     fn root(&self) -> Self::Key;
     fn children(&self, parent: &Self::Key) -> Vec<Self::Key>;
     fn refresh(&self, parent: &Self::Key);
     fn item(&self, key: &Self::Key) -> ItemStyle;
+    fn expandable(&self, key: &Self::Key) -> bool;
+    /// `None` for the root.
+    fn parent(&self, key: &Self::Key) -> Option<Self::Key>;
+    /// Item of the buffer open in the editor, if it is in the tree.
+    fn current(&self) -> Option<Self::Key>;
     fn key_down(&mut self, selected: &Self::Key, key: &KbKey) -> ShouldRepaint;
+    /// Whether an item is edited, e.g. renamed, it then gets all the keys.
+    fn editing(&self) -> bool {
+        false
+    }
+    /// Item to select after a key changed the tree, e.g. a renamed file.
+    fn take_selection(&mut self) -> Option<Self::Key> {
+        None
+    }
+    /// Another item was selected, e.g. a pending action on the previous one is cancelled.
+    fn selection_changed(&mut self, _selected: &Self::Key) {}
 }
 
 pub struct ItemStyle {
     pub(crate) text: String,
     pub(crate) style_scope: String,
     pub(crate) level: usize,
+    pub(crate) icon: Option<Icon>,
 }
 
 pub struct TreeViewer<T: Tree> {
@@ -26,6 +56,14 @@ pub struct TreeViewer<T: Tree> {
     selected: Option<T::Key>,
     items: Vec<T::Key>,
     opened: Vec<T::Key>,
+    visible_items: usize,
+    type_ahead: String,
+    last_type_ahead: Option<Instant>,
+    /// vertical extent of each painted item, for the clicks
+    item_rows: Vec<(f64, f64, T::Key)>,
+    /// selection the scroll last followed, the wheel scrolls away from it
+    followed: Option<T::Key>,
+    scroll_remainder: f64,
 }
 
 impl<T: Tree> TreeViewer<T> {
@@ -36,70 +74,26 @@ impl<T: Tree> TreeViewer<T> {
             selected: None,
             items: vec![],
             opened: vec![],
+            visible_items: 0,
+            type_ahead: String::new(),
+            last_type_ahead: None,
+            item_rows: vec![],
+            followed: None,
+            scroll_remainder: 0.0,
         }
     }
 }
 
 impl<T: Tree> Widget<AppState> for TreeViewer<T> {
-    fn event(&mut self, ctx: &mut EventCtx, event: &Event, _data: &mut AppState, _env: &Env) {
-        if let Event::KeyDown(e) = event {
-            match &e.key {
-                KbKey::Character(s) => match s.as_str() {
-                    " " => {
-                        if self.selected.is_some() {
-                            let selected = self.selected.as_ref().unwrap().clone();
-                            let index = self.opened.iter().position(|x| *x == selected);
-                            if let Some(index) = index {
-                                self.opened.remove(index);
-                            } else {
-                                self.opened.push(selected);
-                            }
-                            ctx.request_paint();
-                        }
-                    }
-                    _ => {}
-                },
-                KbKey::ArrowDown => {
-                    if self.selected.is_some() {
-                        let selected = self.selected.as_ref().unwrap().clone();
-                        let index = self.items.iter().position(|x| *x == selected);
-                        if let Some(index) = index {
-                            let next = self.items.get(index.saturating_add(1));
-                            if next.is_some() {
-                                let next = next.unwrap().clone();
-                                self.selected = Some(next);
-                                ctx.request_paint();
-                            }
-                        }
-                    }
-                }
-                KbKey::ArrowUp => {
-                    if self.selected.is_some() {
-                        let selected = self.selected.as_ref().unwrap().clone();
-                        let index = self.items.iter().position(|x| *x == selected);
-                        if let Some(index) = index {
-                            let prev = self.items.get(index.saturating_sub(1));
-                            if prev.is_some() {
-                                let prev = prev.unwrap().clone();
-                                self.selected = Some(prev);
-                                ctx.request_paint();
-                            }
-                        }
-                    }
-                }
-                key => {
-                    if self.selected.is_some() {
-                        let selected = self.selected.as_ref().unwrap();
-                        let repaint = self.tree.key_down(selected, key);
-                        if repaint {
-                            ctx.request_paint();
-                        }
-                    }
-                }
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut AppState, _env: &Env) {
+        let before = self.selected.clone();
+        self.handle_event(ctx, event, data);
+        if self.selected != before {
+            if let Some(selected) = &self.selected {
+                self.tree.selection_changed(selected);
             }
+            ctx.request_paint();
         }
-
-        ctx.request_focus()
     }
 
     fn lifecycle(
@@ -136,7 +130,7 @@ impl<T: Tree> Widget<AppState> for TreeViewer<T> {
         ctx.clip(rect.clone());
         ctx.fill(
             rect,
-            &THEME
+            &lock!(theme)
                 .scope("ui.background")
                 .background
                 .unwrap_or(DEFAULT_BACKGROUND_COLOR),
@@ -144,17 +138,29 @@ impl<T: Tree> Widget<AppState> for TreeViewer<T> {
 
         let root = self.tree.root();
         let items = self.displayed(data, &root);
+        if self.followed != self.selected {
+            self.scroll_to_selected(&items);
+            self.followed = self.selected.clone();
+        }
+        self.scroll = self.scroll.min(items.len().saturating_sub(1));
+        self.item_rows.clear();
+
+        let show_icons = {
+            let config = lock!(conf);
+            config.render.icons
+        };
 
         let mut y = HALF_LINE_SPACING;
+        let mut visible_items = 0;
 
         for key in items.iter().skip(self.scroll) {
             let item = self.tree.item(key);
 
-            let mut style = THEME.scope(&item.style_scope);
+            let mut style = lock!(theme).scope(&item.style_scope);
             let mut bg = None;
             if let Some(selected) = &self.selected {
                 if key == selected {
-                    style = THEME.scope("tree.selected");
+                    style = lock!(theme).scope("tree.selected");
                     bg = Some(
                         style
                             .background
@@ -179,21 +185,365 @@ impl<T: Tree> Widget<AppState> for TreeViewer<T> {
                 );
             }
 
-            let x = item.level as f64 * 20.0;
+            let mut x = item.level as f64 * 20.0;
+            if let Some(icon) = item.icon.as_ref().filter(|_| show_icons) {
+                draw_icon(ctx, icon, x, y, draw_text.height());
+                x += ICON_WIDTH;
+            }
             draw_text.draw(ctx, x, y);
+            self.item_rows
+                .push((y, y + draw_text.height() + LINE_SPACING, key.clone()));
             if y > ctx.size().height {
                 break;
             }
             y += draw_text.height() + LINE_SPACING;
+            if y <= ctx.size().height {
+                visible_items += 1;
+            }
         }
 
         ctx.restore().unwrap();
 
+        self.visible_items = visible_items;
+
         self.items = items;
     }
 }
 
 impl<T: Tree> TreeViewer<T> {
+    fn handle_event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut AppState) {
+        if let Event::Command(cmd) = event {
+            if cmd.is(REVEAL_CURRENT) {
+                if let Some(key) = self.tree.current() {
+                    self.reveal(key);
+                    ctx.request_paint();
+                }
+            } else if cmd.is(ITEMS_CHANGED) || cmd.is(THEME_CHANGED) {
+                ctx.request_paint();
+            } else if cmd.is(ROOT_CHANGED) {
+                self.scroll = 0;
+                self.selected = None;
+                self.items = vec![];
+                self.opened = vec![];
+                self.followed = None;
+                ctx.request_paint();
+            }
+            return;
+        }
+
+        match event {
+            Event::MouseDown(e) if e.button.is_left() => {
+                let clicked = self
+                    .item_rows
+                    .iter()
+                    .find(|(y0, y1, _)| *y0 <= e.pos.y && e.pos.y < *y1)
+                    .map(|(_, _, key)| key.clone());
+                if let Some(key) = clicked {
+                    // a second click on an item opens it
+                    let again = e.count > 1 || self.selected.as_ref() == Some(&key);
+                    self.selected = Some(key.clone());
+                    if again {
+                        self.activate(ctx, data, key);
+                    }
+                    ctx.request_paint();
+                }
+                ctx.request_focus();
+                return;
+            }
+            Event::Wheel(e) => {
+                if self.wheel(e.wheel_delta.y) {
+                    ctx.request_paint();
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        if let Event::KeyDown(e) = event {
+            if self.tree.editing() {
+                self.tree_key(ctx, data, &e.key);
+                ctx.request_focus();
+                return;
+            }
+            match &e.key {
+                KbKey::Character(s) => match s.as_str() {
+                    " " => {
+                        if self.selected.is_some() {
+                            let selected = self.selected.as_ref().unwrap().clone();
+                            let index = self.opened.iter().position(|x| *x == selected);
+                            if let Some(index) = index {
+                                self.opened.remove(index);
+                            } else {
+                                self.opened.push(selected);
+                            }
+                            ctx.request_paint();
+                        }
+                    }
+                    s => {
+                        // the keys of the tree come first, e.g. rename
+                        if self.tree_key(ctx, data, &e.key) || self.type_ahead(s) {
+                            ctx.request_paint();
+                        }
+                    }
+                },
+                KbKey::ArrowDown => {
+                    if self.selected.is_some() {
+                        let selected = self.selected.as_ref().unwrap().clone();
+                        let index = self.items.iter().position(|x| *x == selected);
+                        if let Some(index) = index {
+                            let next = self.items.get(index.saturating_add(1));
+                            if next.is_some() {
+                                let next = next.unwrap().clone();
+                                self.selected = Some(next);
+                                ctx.request_paint();
+                            }
+                        }
+                    }
+                }
+                KbKey::ArrowRight => {
+                    if let Some(selected) = self.selected.clone() {
+                        if self.tree.expandable(&selected) {
+                            if self.opened.contains(&selected) {
+                                // already expanded, go to the first child
+                                if let Some(child) = self.first_child(&selected) {
+                                    self.selected = Some(child);
+                                }
+                            } else {
+                                self.opened.push(selected);
+                            }
+                            ctx.request_paint();
+                        }
+                    }
+                }
+                KbKey::ArrowLeft => {
+                    if let Some(selected) = self.selected.clone() {
+                        let index = self.opened.iter().position(|x| *x == selected);
+                        if let Some(index) = index {
+                            self.opened.remove(index);
+                            ctx.request_paint();
+                        } else if let Some(parent) = self.parent(&selected) {
+                            self.selected = Some(parent);
+                            ctx.request_paint();
+                        }
+                    }
+                }
+                KbKey::ArrowUp => {
+                    if self.selected.is_some() {
+                        let selected = self.selected.as_ref().unwrap().clone();
+                        let index = self.items.iter().position(|x| *x == selected);
+                        if let Some(index) = index {
+                            let prev = self.items.get(index.saturating_sub(1));
+                            if prev.is_some() {
+                                let prev = prev.unwrap().clone();
+                                self.selected = Some(prev);
+                                ctx.request_paint();
+                            }
+                        }
+                    }
+                }
+                KbKey::Home | KbKey::End | KbKey::PageUp | KbKey::PageDown => {
+                    if self.navigate(&e.key) {
+                        ctx.request_paint();
+                    }
+                }
+                key => {
+                    self.tree_key(ctx, data, key);
+                }
+            }
+        }
+
+        ctx.request_focus()
+    }
+
+    /// Expand or collapse a folder, the tree handles Enter on the other items.
+    fn activate(&mut self, ctx: &mut EventCtx, data: &AppState, key: T::Key) {
+        if self.tree.expandable(&key) {
+            match self.opened.iter().position(|x| *x == key) {
+                Some(index) => {
+                    self.opened.remove(index);
+                }
+                None => self.opened.push(key),
+            }
+        } else {
+            self.tree_key(ctx, data, &KbKey::Enter);
+        }
+    }
+
+    /// Scroll by a wheel delta in pixels, returns whether the scroll changed.
+    fn wheel(&mut self, delta: f64) -> ShouldRepaint {
+        let invert = lock!(conf).scroll.invert;
+        let delta = if invert { -delta } else { delta };
+        let height = match self.item_rows.first() {
+            Some((y0, y1, _)) => y1 - y0,
+            None => return false,
+        };
+        self.scroll_remainder += delta / height;
+        let lines = self.scroll_remainder.trunc();
+        self.scroll_remainder -= lines;
+
+        let max_scroll = self.items.len().saturating_sub(self.visible_items);
+        let scroll = (self.scroll as isize + lines as isize).clamp(0, max_scroll as isize) as usize;
+        let changed = scroll != self.scroll;
+        self.scroll = scroll;
+        changed
+    }
+
+    /// A key handled by the tree itself, returns whether it was.
+    fn tree_key(&mut self, ctx: &mut EventCtx, data: &AppState, key: &KbKey) -> ShouldRepaint {
+        if self.selected.is_some() {
+            let selected = self.selected.as_ref().unwrap();
+            let level = self.tree.item(selected).level;
+            let repaint = self.tree.key_down(selected, key);
+            if repaint {
+                if let Some(key) = self.tree.take_selection() {
+                    self.selected = Some(key);
+                } else {
+                    self.keep_selection(data, level);
+                }
+                ctx.request_paint();
+                ctx.submit_command(REVEAL_CURSOR);
+            }
+            return repaint;
+        }
+        false
+    }
+
+    /// Home and End go to the first and last displayed items, the pages move by
+    /// the items that fit in the viewport.
+    fn navigate(&mut self, key: &KbKey) -> ShouldRepaint {
+        let index = self.selected_index().unwrap_or(0);
+        let target = match key {
+            KbKey::Home => 0,
+            KbKey::End => self.items.len().saturating_sub(1),
+            KbKey::PageUp => index.saturating_sub(self.page()),
+            KbKey::PageDown => index + self.page(),
+            _ => return false,
+        };
+        let before = self.selected.clone();
+        self.select_index(target);
+        before != self.selected
+    }
+
+    fn selected_index(&self) -> Option<usize> {
+        let selected = self.selected.as_ref()?;
+        self.items.iter().position(|x| x == selected)
+    }
+
+    fn select_index(&mut self, index: usize) {
+        let index = index.min(self.items.len().saturating_sub(1));
+        if let Some(key) = self.items.get(index) {
+            self.selected = Some(key.clone());
+        }
+    }
+
+    fn page(&self) -> usize {
+        self.visible_items.saturating_sub(1).max(1)
+    }
+
+    /// Select the next item starting with the typed prefix, the prefix is reset
+    /// after a short idle time.
+    fn type_ahead(&mut self, s: &str) -> ShouldRepaint {
+        let now = Instant::now();
+        let fresh = self
+            .last_type_ahead
+            .map(|last| now.duration_since(last) > TYPE_AHEAD_RESET)
+            .unwrap_or(true);
+        if fresh {
+            self.type_ahead.clear();
+        }
+        self.type_ahead.push_str(&s.to_lowercase());
+        self.last_type_ahead = Some(now);
+
+        let len = self.items.len();
+        // a new prefix jumps to the next match, a longer one may stay in place
+        let start = match self.selected_index() {
+            Some(index) if fresh => index + 1,
+            Some(index) => index,
+            None => 0,
+        };
+        for offset in 0..len {
+            let key = &self.items[(start + offset) % len];
+            if self
+                .tree
+                .item(key)
+                .text
+                .to_lowercase()
+                .starts_with(&self.type_ahead)
+            {
+                self.selected = Some(key.clone());
+                return true;
+            }
+        }
+        false
+    }
+
+    /// When the selected item is gone, e.g. a removed file, select its previous sibling,
+    /// or its parent if it was the first child.
+    fn keep_selection(&mut self, data: &AppState, level: usize) {
+        let items = self.displayed(data, &self.tree.root());
+        let index = match &self.selected {
+            Some(selected) if items.contains(selected) => return,
+            Some(selected) => self.items.iter().position(|x| x == selected),
+            None => None,
+        };
+        let previous = index.and_then(|index| {
+            self.items[..index]
+                .iter()
+                .rev()
+                .filter(|k| items.contains(k))
+                .find(|k| self.tree.item(k).level <= level)
+                .cloned()
+        });
+        self.selected = Some(previous.unwrap_or_else(|| self.tree.root()));
+        self.items = items;
+    }
+
+    /// Expand all the ancestors of `key` and select it, the scroll follows on paint.
+    fn reveal(&mut self, key: T::Key) {
+        let mut parent = self.tree.parent(&key);
+        while let Some(dir) = parent {
+            parent = self.tree.parent(&dir);
+            if !self.opened.contains(&dir) {
+                self.opened.push(dir);
+            }
+        }
+        self.selected = Some(key);
+    }
+
+    fn scroll_to_selected(&mut self, items: &[T::Key]) {
+        let selected = match &self.selected {
+            Some(selected) => selected,
+            None => return,
+        };
+        if let Some(index) = items.iter().position(|x| x == selected) {
+            if index < self.scroll {
+                self.scroll = index;
+            } else if self.visible_items > 0 && index >= self.scroll + self.visible_items {
+                self.scroll = index + 1 - self.visible_items;
+            }
+        }
+    }
+
+    fn first_child(&self, key: &T::Key) -> Option<T::Key> {
+        let index = self.items.iter().position(|x| x == key)?;
+        let next = self.items.get(index + 1)?;
+        if self.tree.item(next).level > self.tree.item(key).level {
+            Some(next.clone())
+        } else {
+            None
+        }
+    }
+
+    fn parent(&self, key: &T::Key) -> Option<T::Key> {
+        let index = self.items.iter().position(|x| x == key)?;
+        let level = self.tree.item(key).level;
+        self.items[..index]
+            .iter()
+            .rev()
+            .find(|k| self.tree.item(k).level < level)
+            .cloned()
+    }
+
     fn displayed(&self, data: &AppState, curr: &T::Key) -> Vec<T::Key> {
         let mut result = Vec::new();
         result.push(curr.clone());
@@ -206,3 +556,99 @@ impl<T: Tree> TreeViewer<T> {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A flat tree of names under a root.
+    struct Names(Vec<&'static str>);
+
+    impl Tree for Names {
+        type Key = usize;
+        fn root(&self) -> usize {
+            0
+        }
+        fn children(&self, parent: &usize) -> Vec<usize> {
+            match parent {
+                0 => (1..self.0.len()).collect(),
+                _ => vec![],
+            }
+        }
+        fn refresh(&self, _parent: &usize) {}
+        fn item(&self, key: &usize) -> ItemStyle {
+            ItemStyle {
+                text: self.0[*key].to_string(),
+                style_scope: String::new(),
+                level: (*key != 0) as usize,
+                icon: None,
+            }
+        }
+        fn expandable(&self, key: &usize) -> bool {
+            *key == 0
+        }
+        fn parent(&self, key: &usize) -> Option<usize> {
+            if *key == 0 {
+                None
+            } else {
+                Some(0)
+            }
+        }
+        fn current(&self) -> Option<usize> {
+            None
+        }
+        fn key_down(&mut self, _selected: &usize, _key: &KbKey) -> ShouldRepaint {
+            false
+        }
+    }
+
+    fn viewer(names: Vec<&'static str>, visible_items: usize) -> TreeViewer<Names> {
+        let mut viewer = TreeViewer::new(Names(names));
+        viewer.items = (0..viewer.tree.0.len()).collect();
+        viewer.visible_items = visible_items;
+        viewer.selected = Some(0);
+        viewer
+    }
+
+    #[test]
+    fn home_end_and_pages() {
+        let mut viewer = viewer(vec!["root", "a", "b", "c", "d", "e", "f"], 4);
+        assert!(viewer.navigate(&KbKey::End));
+        assert_eq!(viewer.selected, Some(6));
+        assert!(!viewer.navigate(&KbKey::End));
+        assert!(viewer.navigate(&KbKey::Home));
+        assert_eq!(viewer.selected, Some(0));
+
+        // a page keeps one item of the previous viewport
+        assert!(viewer.navigate(&KbKey::PageDown));
+        assert_eq!(viewer.selected, Some(3));
+        assert!(viewer.navigate(&KbKey::PageDown));
+        assert_eq!(viewer.selected, Some(6));
+        assert!(!viewer.navigate(&KbKey::PageDown));
+        assert!(viewer.navigate(&KbKey::PageUp));
+        assert_eq!(viewer.selected, Some(3));
+        assert!(viewer.navigate(&KbKey::PageUp));
+        assert_eq!(viewer.selected, Some(0));
+    }
+
+    #[test]
+    fn type_ahead_prefix() {
+        let mut viewer = viewer(vec!["root", "bar", "baz", "main.rs", "build.rs"], 4);
+        assert!(viewer.type_ahead("b"));
+        assert_eq!(viewer.selected, Some(1));
+        // a longer prefix stays on a match
+        assert!(viewer.type_ahead("a"));
+        assert!(viewer.type_ahead("Z"));
+        assert_eq!(viewer.selected, Some(2));
+        assert!(!viewer.type_ahead("x"));
+        assert_eq!(viewer.selected, Some(2));
+
+        // after the idle time a new prefix goes to the next match and wraps
+        viewer.last_type_ahead = Some(Instant::now() - TYPE_AHEAD_RESET * 2);
+        assert!(viewer.type_ahead("b"));
+        assert_eq!(viewer.selected, Some(4));
+        viewer.last_type_ahead = None;
+        assert!(viewer.type_ahead("b"));
+        assert_eq!(viewer.selected, Some(1));
+    }
+}
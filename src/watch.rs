@@ -0,0 +1,47 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// Events on a same path closer than this are reported once.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Changes made on disk under a folder, by the editor or by other programs.
+pub struct FsWatcher {
+    // stops watching when dropped
+    _watcher: RecommendedWatcher,
+    events: Receiver<DebouncedEvent>,
+}
+
+impl FsWatcher {
+    pub fn new(root: &Path) -> notify::Result<Self> {
+        let (tx, events) = channel();
+        let mut watcher = watcher(tx, DEBOUNCE)?;
+        watcher.watch(root, RecursiveMode::Recursive)?;
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Paths created, written, removed or renamed since the last call, without waiting.
+    pub fn changed(&self) -> Vec<PathBuf> {
+        let mut paths = vec![];
+        for event in self.events.try_iter() {
+            match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Remove(path) => paths.push(path),
+                DebouncedEvent::Rename(from, to) => {
+                    paths.push(from);
+                    paths.push(to);
+                }
+                _ => {}
+            }
+        }
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+}
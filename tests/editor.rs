@@ -0,0 +1,1225 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use druid::{Code, KbKey, KeyEvent, Modifiers};
+use lsp_types::request::{Completion, Request, ResolveCompletionItem};
+use lsp_types::{Position, Range, SymbolKind, TextEdit, Url, WorkspaceEdit};
+use ste_lib::config::KeymapConfig;
+use ste_lib::editor::{typed_text, TextEditor};
+use ste_lib::fs::{lsp_root, swap_fs, FileSystem, LocalFs, Path};
+use ste_lib::lsp::{CompletionData, LspCompletion, LspLang, LspOutput, LspSignature, LspSymbol};
+use ste_lib::mem_fs::MemFs;
+use ste_lib::outline::Outline;
+use ste_lib::session::Session;
+use ste_lib::status::{error, log, notify, status, Level};
+use ste_lib::tabs::{tabs, window_title, TabBar};
+use ste_lib::tree::Tree;
+use ste_lib::{lock, set_root, AppState, FS};
+
+lazy_static::lazy_static! {
+    // the editor works on the global buffers, run the tests one at a time
+    static ref SERIAL: Mutex<()> = Mutex::new(());
+}
+
+fn key(mods: Modifiers, key: KbKey, code: Code) -> KeyEvent {
+    let mut event = KeyEvent::for_test(mods, key);
+    event.code = code;
+    event
+}
+
+fn char_key(c: &str) -> KeyEvent {
+    KeyEvent::for_test(Modifiers::empty(), c)
+}
+
+fn press(editor: &mut TextEditor, event: KeyEvent) {
+    editor.set_visible_lines(10);
+    editor.key_down(&event, &mut AppState).unwrap();
+}
+
+fn text() -> String {
+    let buffers = lock!(buffers);
+    buffers.get_curr().unwrap().buffer.text()
+}
+
+fn row() -> usize {
+    let buffers = lock!(buffers);
+    buffers.get_curr().unwrap().buffer.row()
+}
+
+fn head() -> usize {
+    let buffers = lock!(buffers);
+    buffers.get_curr().unwrap().buffer.cursor().head
+}
+
+#[test]
+fn type_in_new_buffer() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let content = (0..30)
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    lock!(mut buffers).open_text(&content);
+    let mut editor = TextEditor::new();
+    for _ in 0..30 {
+        press(
+            &mut editor,
+            key(Modifiers::empty(), KbKey::ArrowDown, Code::ArrowDown),
+        );
+    }
+
+    press(
+        &mut editor,
+        key(Modifiers::CONTROL, KbKey::Character("n".into()), Code::KeyN),
+    );
+    assert_eq!(text(), "");
+    assert_eq!(editor.scroll_line(), 0);
+    press(&mut editor, char_key("x"));
+    assert_eq!(text(), "x");
+    assert_eq!(head(), 1);
+}
+
+#[test]
+fn type_text() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut buffers).open_text("");
+    let mut editor = TextEditor::new();
+
+    press(&mut editor, char_key("a"));
+    press(&mut editor, char_key("b"));
+    press(&mut editor, char_key("c"));
+    assert_eq!(text(), "abc");
+    assert_eq!(head(), 3);
+
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Enter, Code::Enter),
+    );
+    press(&mut editor, char_key("d"));
+    assert_eq!(text(), "abc\nd");
+}
+
+#[test]
+fn enter_keeps_indentation() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut buffers).open_text("    fn a() {");
+    let mut editor = TextEditor::new();
+
+    for _ in 0..12 {
+        press(
+            &mut editor,
+            key(Modifiers::empty(), KbKey::ArrowRight, Code::ArrowRight),
+        );
+    }
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Enter, Code::Enter),
+    );
+    press(&mut editor, char_key("b"));
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Enter, Code::Enter),
+    );
+    assert_eq!(text(), "    fn a() {\n        b\n        ");
+}
+
+#[test]
+fn shift_selection_and_backspace() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut buffers).open_text("hello");
+    let mut editor = TextEditor::new();
+
+    for _ in 0..5 {
+        press(
+            &mut editor,
+            key(Modifiers::empty(), KbKey::ArrowRight, Code::ArrowRight),
+        );
+    }
+    assert_eq!(head(), 5);
+
+    for _ in 0..3 {
+        press(
+            &mut editor,
+            key(Modifiers::SHIFT, KbKey::ArrowLeft, Code::ArrowLeft),
+        );
+    }
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Backspace, Code::Backspace),
+    );
+    assert_eq!(text(), "he");
+    assert_eq!(head(), 2);
+}
+
+#[test]
+fn scroll_follows_cursor() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let content = (0..30)
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    lock!(mut buffers).open_text(&content);
+    let mut editor = TextEditor::new();
+
+    for _ in 0..20 {
+        press(
+            &mut editor,
+            key(Modifiers::empty(), KbKey::ArrowDown, Code::ArrowDown),
+        );
+    }
+    assert_eq!(editor.scroll_line(), 14);
+
+    // past the end the cursor stops scrolling, like the pages
+    for _ in 0..20 {
+        press(
+            &mut editor,
+            key(Modifiers::empty(), KbKey::ArrowDown, Code::ArrowDown),
+        );
+    }
+    assert_eq!(editor.scroll_line(), 20);
+
+    for _ in 0..30 {
+        press(
+            &mut editor,
+            key(Modifiers::empty(), KbKey::ArrowUp, Code::ArrowUp),
+        );
+    }
+    assert_eq!(editor.scroll_line(), 0);
+}
+
+#[test]
+fn page_down_up() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let content = (0..30)
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    lock!(mut buffers).open_text(&content);
+    let mut editor = TextEditor::new();
+
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::PageDown, Code::PageDown),
+    );
+    assert_eq!(head(), "0\n1\n2\n3\n4\n5\n6\n7\n8\n9\n".len());
+    for _ in 0..5 {
+        press(
+            &mut editor,
+            key(Modifiers::empty(), KbKey::PageDown, Code::PageDown),
+        );
+    }
+    assert_eq!(row(), 29);
+    // the last page fills the viewport
+    assert_eq!(editor.scroll_line(), 20);
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::PageUp, Code::PageUp),
+    );
+    assert_eq!(row(), 19);
+}
+
+#[test]
+fn ctrl_s_writes_file() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = std::env::temp_dir().join("ste_editor_ctrl_s.txt");
+    std::fs::write(&file, "old").unwrap();
+    lock!(mut buffers)
+        .open_file(FS.path(file.to_str().unwrap()))
+        .unwrap();
+    let mut editor = TextEditor::new();
+
+    press(&mut editor, char_key("x"));
+    press(
+        &mut editor,
+        key(Modifiers::CONTROL, KbKey::Character("s".into()), Code::KeyS),
+    );
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "xold");
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn scratch_saved_as() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = std::env::temp_dir().join("ste_editor_save_as.txt");
+    lock!(mut buffers).open_text("other");
+    let mut editor = TextEditor::new();
+
+    press(
+        &mut editor,
+        key(Modifiers::CONTROL, KbKey::Character("n".into()), Code::KeyN),
+    );
+    assert_eq!(text(), "");
+    press(&mut editor, char_key("x"));
+    // a buffer without a file asks where to save it
+    let outcome = editor
+        .key_down(
+            &key(Modifiers::CONTROL, KbKey::Character("s".into()), Code::KeyS),
+            &mut AppState,
+        )
+        .unwrap();
+    assert_eq!(outcome.commands.len(), 1);
+
+    editor.save_as(&file).unwrap();
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "x");
+    {
+        let buffers = lock!(buffers);
+        let buf = buffers.get_curr().unwrap();
+        assert!(buf.source.path() == Some(FS.path(file.to_str().unwrap())));
+        assert!(!buf.buffer.is_modified());
+    }
+
+    // then Ctrl+S writes the file
+    press(&mut editor, char_key("y"));
+    press(
+        &mut editor,
+        key(Modifiers::CONTROL, KbKey::Character("s".into()), Code::KeyS),
+    );
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "xy");
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn saved_as_new_file() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = std::env::temp_dir().join("ste_editor_save_as_new.json");
+    let _ = std::fs::remove_file(&file);
+    lock!(mut buffers).open_text("{}");
+    let mut editor = TextEditor::new();
+
+    // the language and the indentation come from the name of a file not created yet
+    editor.save_as(&file).unwrap();
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "{}");
+    {
+        let buffers = lock!(buffers);
+        let buf = buffers.get_curr().unwrap();
+        assert_eq!(buf.lsp_lang, LspLang::Json);
+        assert_eq!(buf.buffer.indent.indent_width, 2);
+    }
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn ctrl_tab_cycles_buffers() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let ids = {
+        let mut buffers = lock!(mut buffers);
+        buffers.buffers.clear();
+        vec![
+            buffers.open_text("a"),
+            buffers.open_text("b"),
+            buffers.open_text("c"),
+        ]
+    };
+    lock!(mut buffers).current = Some(ids[1]);
+    let mut editor = TextEditor::new();
+    let ctrl_tab = |mods| key(mods, KbKey::Tab, Code::Tab);
+
+    press(&mut editor, ctrl_tab(Modifiers::CONTROL));
+    assert_eq!(text(), "c");
+    // wraps around
+    press(&mut editor, ctrl_tab(Modifiers::CONTROL));
+    assert_eq!(text(), "a");
+    press(&mut editor, ctrl_tab(Modifiers::CONTROL | Modifiers::SHIFT));
+    assert_eq!(text(), "c");
+
+    let names = tabs()
+        .into_iter()
+        .map(|t| (t.name, t.current))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec![
+            ("untitled".to_string(), false),
+            ("untitled".to_string(), false),
+            ("untitled".to_string(), true),
+        ]
+    );
+}
+
+#[test]
+fn close_modified_tab() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let (clean, modified) = {
+        let mut buffers = lock!(mut buffers);
+        (buffers.open_text("a"), buffers.open_text("b"))
+    };
+    lock!(mut buffers)
+        .get_mut(modified)
+        .unwrap()
+        .buffer
+        .insert(0, "x");
+    let mut bar = TabBar::default();
+
+    assert!(bar.close_tab(clean));
+    assert!(lock!(buffers).get(clean).is_err());
+    // warned first, the second click closes it anyway
+    assert!(!bar.close_tab(modified));
+    assert!(log().pop().unwrap().message.contains("unsaved changes"));
+    assert!(lock!(buffers).get(modified).is_ok());
+    assert!(bar.close_tab(modified));
+    assert!(lock!(buffers).get(modified).is_err());
+}
+
+#[test]
+fn window_title_follows_buffer() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = std::env::temp_dir().join("ste_editor_title.txt");
+    std::fs::write(&file, "title\n").unwrap();
+    let id = lock!(mut buffers)
+        .open_file(FS.path(file.to_str().unwrap()))
+        .unwrap();
+    assert_eq!(window_title(), "ste_editor_title.txt — STE");
+
+    let mut editor = TextEditor::new();
+    press(&mut editor, char_key("a"));
+    assert_eq!(window_title(), "• ste_editor_title.txt — STE");
+
+    let scratch = lock!(mut buffers).new_scratch();
+    assert_eq!(window_title(), "untitled — STE");
+
+    let mut buffers = lock!(mut buffers);
+    buffers.close(scratch).unwrap();
+    buffers.close(id).unwrap();
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn save_as_drops_the_pending_changes() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = std::env::temp_dir().join("ste_editor_pending.txt");
+    let copy = std::env::temp_dir().join("ste_editor_pending_copy.txt");
+    std::fs::write(&file, "pending\n").unwrap();
+    let id = lock!(mut buffers)
+        .open_file(FS.path(file.to_str().unwrap()))
+        .unwrap();
+    let mut editor = TextEditor::new();
+    press(&mut editor, char_key("a"));
+    {
+        let buffers = lock!(buffers);
+        let buffer = &buffers.get(id).unwrap().buffer;
+        assert!(buffer.dirty_since_sync.load(Ordering::SeqCst));
+    }
+
+    // the didOpen of the new file has the edit, no didChange follows it
+    let mut buffers = lock!(mut buffers);
+    buffers.save_as(id, &copy).unwrap();
+    let buffer = &buffers.get(id).unwrap().buffer;
+    assert!(!buffer.dirty_since_sync.load(Ordering::SeqCst));
+    assert_eq!(buffer.take_changes(), Some(vec![]));
+
+    buffers.close(id).unwrap();
+    std::fs::remove_file(file).unwrap();
+    std::fs::remove_file(copy).unwrap();
+}
+
+#[test]
+fn configured_indent_in_every_buffer() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut conf).indent.indent_width = 2;
+    let file = std::env::temp_dir().join("ste_editor_indent.txt");
+    std::fs::write(&file, "indent\n").unwrap();
+    let mut buffers = lock!(mut buffers);
+    let text = buffers.open_text("");
+    let copy = buffers
+        .open_file_copy(FS.path(file.to_str().unwrap()))
+        .unwrap();
+    for id in [text, copy] {
+        assert_eq!(buffers.get(id).unwrap().buffer.indent.indent_width, 2);
+    }
+
+    buffers.close(text).unwrap();
+    buffers.close(copy).unwrap();
+    lock!(mut conf).indent = Default::default();
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn spans_for_range() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    {
+        let mut buffers = lock!(mut buffers);
+        buffers.open_text("fn main() {}");
+        buffers.get_mut_curr().unwrap().lsp_lang = LspLang::Rust;
+    }
+    let mut editor = TextEditor::new();
+
+    let spans = editor.spans_for_range(0, 9).unwrap();
+    assert_eq!((spans[0].start, spans[0].end), (0, 2));
+    assert_eq!(
+        spans[0].style.foreground.as_ref().map(|c| c.as_rgba_u32()),
+        lock!(theme)
+            .scope("keyword")
+            .foreground
+            .map(|c| c.as_rgba_u32())
+    );
+    assert_eq!(spans.last().unwrap().end, 9);
+    assert!(spans.windows(2).all(|w| w[0].end == w[1].start));
+}
+
+#[test]
+fn highlight_out_of_view() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let content = (0..1000)
+        .map(|i| format!("fn f{}() {{}}\n", i))
+        .collect::<String>();
+    {
+        let mut buffers = lock!(mut buffers);
+        buffers.open_text(&content);
+        buffers.get_mut_curr().unwrap().lsp_lang = LspLang::Rust;
+    }
+    let mut editor = TextEditor::new();
+    editor.spans_for_range(0, 1).unwrap();
+
+    // far below the highlighted lines
+    let start = content.find("fn f900").unwrap();
+    let spans = editor.spans_for_range(start, start + 2).unwrap();
+    assert_eq!(
+        spans[0].style.foreground.as_ref().map(|c| c.as_rgba_u32()),
+        lock!(theme)
+            .scope("keyword")
+            .foreground
+            .map(|c| c.as_rgba_u32())
+    );
+}
+
+#[test]
+fn large_file_mode() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = std::env::temp_dir().join("ste_editor_large.rs");
+    std::fs::write(&file, "fn main() {}\n".repeat(10)).unwrap();
+    let size = std::mem::replace(&mut lock!(mut conf).edit.large_file_size, 64);
+    lock!(mut buffers)
+        .open_file(FS.path(file.to_str().unwrap()))
+        .unwrap();
+    lock!(mut conf).edit.large_file_size = size;
+    let mut editor = TextEditor::new();
+
+    assert!(status().unwrap().large_file);
+    // plain text, still editable
+    let spans = editor.spans_for_range(0, 9).unwrap();
+    assert!(spans.iter().all(|s| s.style.foreground.is_none()));
+    press(&mut editor, char_key("x"));
+    assert!(text().starts_with("xfn main"));
+
+    let id = lock!(buffers).curr().unwrap();
+    lock!(mut buffers).close(id).unwrap();
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn session_restores_cursors() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = std::env::temp_dir().join("ste_editor_session.txt");
+    let content = (0..30)
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    std::fs::write(&file, &content).unwrap();
+    let path = file.to_str().unwrap().to_string();
+    let id = lock!(mut buffers)
+        .open_file(FS.path(path.as_str()))
+        .unwrap();
+    let mut editor = TextEditor::new();
+    editor.spans_for_range(0, 1).unwrap();
+    for _ in 0..20 {
+        press(
+            &mut editor,
+            key(Modifiers::empty(), KbKey::ArrowDown, Code::ArrowDown),
+        );
+    }
+    // the scroll is kept in the buffer when it is painted
+    editor.spans_for_range(0, 1).unwrap();
+
+    let session = Session::capture();
+    let saved = session
+        .files
+        .iter()
+        .find(|f| f.path == path)
+        .unwrap()
+        .clone();
+    assert_eq!((saved.cursor, saved.scroll_line), (head(), 14));
+    lock!(mut buffers).close(id).unwrap();
+
+    Session {
+        root: None,
+        current: Some(path.clone()),
+        files: vec![saved],
+    }
+    .restore();
+    assert_eq!(row(), 20);
+    let mut editor = TextEditor::new();
+    editor.spans_for_range(0, 1).unwrap();
+    assert_eq!(editor.scroll_line(), 14);
+
+    let id = lock!(buffers).curr().unwrap();
+    lock!(mut buffers).close(id).unwrap();
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn open_file_copy() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = std::env::temp_dir().join("ste_editor_copy.txt");
+    std::fs::write(&file, "text").unwrap();
+    let path = FS.path(file.to_str().unwrap());
+    let (first, copy) = {
+        let mut buffers = lock!(mut buffers);
+        let first = buffers.open_file(path.clone()).unwrap();
+        (first, buffers.open_file_copy(path.clone()).unwrap())
+    };
+    assert_ne!(first, copy);
+    let mut editor = TextEditor::new();
+
+    // the copy is read-only
+    press(&mut editor, char_key("x"));
+    assert_eq!(text(), "text");
+    // and not reused when the file is opened again
+    assert_eq!(lock!(mut buffers).open_file(path).unwrap(), first);
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn control_chars_are_not_typed() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut buffers).open_text("");
+    let mut editor = TextEditor::new();
+
+    press(&mut editor, char_key("\u{1}"));
+    press(&mut editor, char_key("\u{7f}"));
+    press(
+        &mut editor,
+        key(Modifiers::CONTROL, KbKey::Character("q".into()), Code::KeyQ),
+    );
+    press(&mut editor, char_key("é"));
+    press(&mut editor, key(Modifiers::empty(), KbKey::Tab, Code::Tab));
+    // AltGr
+    press(
+        &mut editor,
+        key(
+            Modifiers::CONTROL | Modifiers::ALT,
+            KbKey::Character("@".into()),
+            Code::Digit0,
+        ),
+    );
+    // spaces up to the tab stop, by default
+    assert_eq!(text(), "é   @");
+
+    assert_eq!(typed_text(&char_key("a\u{8}b")), Some("ab".into()));
+    assert_eq!(
+        typed_text(&key(Modifiers::empty(), KbKey::Escape, Code::Escape)),
+        None
+    );
+}
+
+#[test]
+fn composition_keys_are_ignored() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut buffers).open_text("a");
+    let mut editor = TextEditor::new();
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::ArrowRight, Code::ArrowRight),
+    );
+
+    // dead key then the composed char
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Dead, Code::Quote),
+    );
+    press(&mut editor, char_key("é"));
+    // the backspace is for the input method
+    let mut event = key(Modifiers::empty(), KbKey::Backspace, Code::Backspace);
+    event.is_composing = true;
+    press(&mut editor, event);
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Process, Code::KeyN),
+    );
+    press(&mut editor, char_key("日本"));
+    assert_eq!(text(), "aé日本");
+}
+
+#[test]
+fn reveal_scrolls_into_view() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let content = (0..100)
+        .map(|n| format!("line {}\n", n))
+        .collect::<String>();
+    lock!(mut buffers).open_text(&content);
+    let mut editor = TextEditor::new();
+    editor.set_visible_lines(10);
+
+    let start = content.find("line 60").unwrap();
+    editor.reveal((start, start + 7), true).unwrap();
+    assert!(editor.scroll_line() <= 60 && 60 < editor.scroll_line() + 10);
+    assert_eq!(row(), 60);
+    assert_eq!(head(), start + 7);
+
+    // already comfortably visible, the view does not move
+    let scroll = editor.scroll_line();
+    let start = content.find("line 59").unwrap();
+    editor.reveal((start, start), false).unwrap();
+    assert_eq!(editor.scroll_line(), scroll);
+
+    editor.reveal((0, 0), false).unwrap();
+    assert_eq!(editor.scroll_line(), 0);
+}
+
+fn set_completion(insert: &str, commit_characters: &[&str]) {
+    let mut buffers = lock!(mut buffers);
+    buffers.get_mut_curr().unwrap().buffer.completions = vec![LspCompletion {
+        original_item: Default::default(),
+        label: insert.to_string(),
+        data: CompletionData::Simple(insert.to_string()),
+        commit_characters: commit_characters.iter().map(|c| c.to_string()).collect(),
+        stops: vec![],
+        kind: None,
+    }];
+}
+
+#[test]
+fn accept_completion() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut buffers).open_text("");
+    let mut editor = TextEditor::new();
+
+    set_completion("foo", &["("]);
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Enter, Code::Enter),
+    );
+    assert_eq!(text(), "foo");
+
+    // a commit character accepts and is typed
+    set_completion("bar", &["("]);
+    press(&mut editor, char_key("."));
+    assert_eq!(text(), "foo.");
+    press(&mut editor, char_key("("));
+    assert_eq!(text(), "foo.bar(");
+
+    // enter not accepting breaks the line
+    lock!(mut conf).edit.completion_accept = vec![];
+    set_completion("baz", &[]);
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Enter, Code::Enter),
+    );
+    assert_eq!(text(), "foo.bar(\n");
+    lock!(mut conf).edit = Default::default();
+}
+
+#[test]
+fn failed_completion_requests() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = std::env::temp_dir().join("ste_editor_failed.txt");
+    std::fs::write(&file, "").unwrap();
+    let path = FS.path(file.to_str().unwrap());
+    let uri = path.uri();
+    lock!(mut buffers).open_file(path).unwrap();
+    let mut editor = TextEditor::new();
+    let completions = || lock!(buffers).get_curr().unwrap().buffer.completions.len();
+    let failed = |method: &str, uri: &Url| LspOutput::Failed(method.to_string(), uri.clone());
+
+    // the list is for an older text, unless another document failed
+    set_completion("foo", &[]);
+    let other = Url::parse("file:///elsewhere.txt").unwrap();
+    editor
+        .apply_lsp_output(failed(Completion::METHOD, &other))
+        .unwrap();
+    assert_eq!(completions(), 1);
+    editor
+        .apply_lsp_output(failed(Completion::METHOD, &uri))
+        .unwrap();
+    assert_eq!(completions(), 0);
+
+    // the completion is applied without its details
+    set_completion("bar", &[]);
+    editor
+        .apply_lsp_output(failed(ResolveCompletionItem::METHOD, &uri))
+        .unwrap();
+    assert_eq!(text(), "bar");
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn escape_dismisses_completions() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut buffers).open_text("");
+    let mut editor = TextEditor::new();
+
+    set_completion("foo", &[]);
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Escape, Code::Escape),
+    );
+    assert!(lock!(buffers)
+        .get_curr()
+        .unwrap()
+        .buffer
+        .completions
+        .is_empty());
+    // enter breaks the line again
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Enter, Code::Enter),
+    );
+    assert_eq!(text(), "\n");
+}
+
+#[test]
+fn rename_prompt() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut buffers).open_text("a  b");
+    let mut editor = TextEditor::new();
+    for _ in 0..2 {
+        press(
+            &mut editor,
+            key(Modifiers::empty(), KbKey::ArrowRight, Code::ArrowRight),
+        );
+    }
+
+    // nothing to rename between blanks
+    press(&mut editor, key(Modifiers::empty(), KbKey::F2, Code::F2));
+    press(&mut editor, char_key("x"));
+    assert_eq!(text(), "a x b");
+
+    // typed in the prompt
+    press(&mut editor, key(Modifiers::empty(), KbKey::F2, Code::F2));
+    press(&mut editor, char_key("y"));
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Backspace, Code::Backspace),
+    );
+    assert_eq!(text(), "a x b");
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Escape, Code::Escape),
+    );
+    press(&mut editor, char_key("z"));
+    assert_eq!(text(), "a xz b");
+}
+
+#[test]
+fn apply_workspace_edit() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let open = std::env::temp_dir().join("ste_editor_rename_open.rs");
+    let closed = std::env::temp_dir().join("ste_editor_rename_closed.rs");
+    std::fs::write(&open, "fn a() {}").unwrap();
+    std::fs::write(&closed, "use a;\na();").unwrap();
+    let current = lock!(mut buffers)
+        .open_file(FS.path(open.to_str().unwrap()))
+        .unwrap();
+
+    let edit = |line, start, end| TextEdit {
+        range: Range::new(Position::new(line, start), Position::new(line, end)),
+        new_text: "b".to_string(),
+    };
+    let mut changes = HashMap::new();
+    changes.insert(Url::from_file_path(&open).unwrap(), vec![edit(0, 3, 4)]);
+    changes.insert(
+        Url::from_file_path(&closed).unwrap(),
+        vec![edit(1, 0, 1), edit(0, 4, 5)],
+    );
+    lock!(mut buffers)
+        .apply_workspace_edit(WorkspaceEdit::new(changes))
+        .unwrap();
+
+    // the open file is edited but not saved
+    assert_eq!(lock!(buffers).curr().unwrap(), current);
+    assert_eq!(text(), "fn b() {}");
+    assert_eq!(std::fs::read_to_string(&open).unwrap(), "fn a() {}");
+    // the other one is opened and saved
+    assert_eq!(std::fs::read_to_string(&closed).unwrap(), "use b;\nb();");
+    std::fs::remove_file(&open).unwrap();
+    std::fs::remove_file(&closed).unwrap();
+}
+
+#[test]
+fn signature_dismissed() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut buffers).open_text("");
+    let mut editor = TextEditor::new();
+    let set_signature = || {
+        lock!(mut buffers).get_mut_curr().unwrap().buffer.signature = Some(LspSignature {
+            label: "fn f(a: u8)".to_string(),
+            active: Some((5, 10)),
+        });
+    };
+    let signature = || lock!(buffers).get_curr().unwrap().buffer.signature.clone();
+
+    set_signature();
+    press(&mut editor, char_key("a"));
+    assert!(signature().is_some());
+    press(&mut editor, char_key(")"));
+    assert_eq!(signature(), None);
+
+    set_signature();
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Escape, Code::Escape),
+    );
+    assert_eq!(signature(), None);
+}
+
+#[test]
+fn undo_past_save() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = std::env::temp_dir().join("ste_editor_undo_save.txt");
+    std::fs::write(&file, "old").unwrap();
+    let id = lock!(mut buffers)
+        .open_file(FS.path(file.to_str().unwrap()))
+        .unwrap();
+    let mut editor = TextEditor::new();
+
+    press(&mut editor, char_key("x"));
+    press(
+        &mut editor,
+        key(Modifiers::CONTROL, KbKey::Character("s".into()), Code::KeyS),
+    );
+    press(
+        &mut editor,
+        key(Modifiers::CONTROL, KbKey::Character("z".into()), Code::KeyZ),
+    );
+    assert_eq!(text(), "old");
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "xold");
+
+    // not reloaded over the unsaved undo
+    std::fs::write(&file, "new").unwrap();
+    assert!(!lock!(mut buffers).reload(id).unwrap());
+    press(
+        &mut editor,
+        key(
+            Modifiers::CONTROL | Modifiers::SHIFT,
+            KbKey::Character("Z".into()),
+            Code::KeyZ,
+        ),
+    );
+    // clean, the reload can be undone
+    assert!(lock!(mut buffers).reload(id).unwrap());
+    assert_eq!(text(), "new");
+    press(
+        &mut editor,
+        key(Modifiers::CONTROL, KbKey::Character("z".into()), Code::KeyZ),
+    );
+    assert_eq!(text(), "xold");
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn sticky_headers() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let body = "    let x = 1;\n".repeat(30);
+    let content = format!("impl A {{\n    fn b() {{\n{}    }}\n}}\n", body);
+    {
+        let mut buffers = lock!(mut buffers);
+        buffers.open_text(&content);
+        buffers.get_mut_curr().unwrap().lsp_lang = LspLang::Rust;
+    }
+    let mut editor = TextEditor::new();
+    // highlights the buffer
+    editor.spans_for_range(0, 1).unwrap();
+    assert!(editor.sticky_headers().unwrap().is_empty());
+
+    for _ in 0..20 {
+        press(
+            &mut editor,
+            key(Modifiers::empty(), KbKey::ArrowDown, Code::ArrowDown),
+        );
+    }
+    assert!(editor.scroll_line() > 2);
+    let starts = editor
+        .sticky_headers()
+        .unwrap()
+        .iter()
+        .map(|s| &content[s.0..s.0 + 4])
+        .collect::<Vec<_>>();
+    assert_eq!(starts, vec!["impl", "fn b"]);
+
+    lock!(mut conf).render.sticky_lines = 1;
+    assert_eq!(editor.sticky_headers().unwrap().len(), 1);
+    lock!(mut conf).render = Default::default();
+}
+
+#[test]
+fn outline() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let range = |line: u32, start: u32, end: u32| Range {
+        start: Position::new(line, start),
+        end: Position::new(line, end),
+    };
+    let symbol = |name: &str, kind, range: Range, children| LspSymbol {
+        name: name.into(),
+        kind,
+        range,
+        selection: range,
+        children,
+    };
+    {
+        let mut buffers = lock!(mut buffers);
+        buffers.open_text("struct A {\n    b: u8,\n}\nfn c() {}\n");
+        let b = symbol("b", SymbolKind::FIELD, range(1, 4, 10), vec![]);
+        let a = Range {
+            start: Position::new(0, 0),
+            end: Position::new(2, 1),
+        };
+        buffers.get_mut_curr().unwrap().buffer.symbols = vec![
+            symbol("A", SymbolKind::STRUCT, a, vec![b]),
+            symbol("c", SymbolKind::FUNCTION, range(3, 0, 9), vec![]),
+        ];
+    }
+    let mut outline = Outline;
+    let root = outline.root();
+    assert_eq!(outline.children(&root), vec![vec![0], vec![1]]);
+    assert_eq!(outline.children(&vec![0]), vec![vec![0, 0]]);
+    assert!(outline.expandable(&vec![0]) && !outline.expandable(&vec![1]));
+    assert_eq!(outline.parent(&vec![0, 0]), Some(vec![0]));
+    assert_eq!(outline.current(), Some(vec![0]));
+
+    assert!(outline.key_down(&vec![0, 0], &KbKey::Enter));
+    assert_eq!((row(), head()), (1, 15));
+    assert_eq!(outline.current(), Some(vec![0, 0]));
+    assert!(outline.key_down(&vec![1], &KbKey::Enter));
+    assert_eq!(row(), 3);
+    assert_eq!(outline.current(), Some(vec![1]));
+}
+
+#[test]
+fn remapped_shortcut() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut buffers).open_text("a");
+    let mut editor = TextEditor::new();
+    let mut keymap = BTreeMap::new();
+    keymap.insert("duplicate_lines".to_string(), "ctrl+alt+down".to_string());
+    lock!(mut conf).keymap = KeymapConfig::from(keymap);
+
+    // the old chord is free again, the new one runs the command
+    press(
+        &mut editor,
+        key(
+            Modifiers::CONTROL | Modifiers::SHIFT,
+            KbKey::Character("D".into()),
+            Code::KeyD,
+        ),
+    );
+    assert_eq!(text(), "a");
+    press(
+        &mut editor,
+        key(
+            Modifiers::CONTROL | Modifiers::ALT,
+            KbKey::ArrowDown,
+            Code::ArrowDown,
+        ),
+    );
+    assert_eq!(text(), "a\na");
+
+    lock!(mut conf).keymap = Default::default();
+}
+
+#[test]
+fn status_line() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    lock!(mut buffers).open_text("ab\ncd");
+    let mut editor = TextEditor::new();
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::ArrowDown, Code::ArrowDown),
+    );
+    press(&mut editor, char_key("x"));
+
+    let status = status().unwrap();
+    assert_eq!((status.row, status.col), (2, 2));
+    assert_eq!(status.name, "untitled");
+    assert_eq!(status.lang, LspLang::PlainText);
+    assert!(status.modified);
+
+    notify("Saved");
+    let message = status().unwrap().message.unwrap();
+    assert_eq!(
+        (message.message.as_str(), message.level),
+        ("Saved", Level::Info)
+    );
+
+    // repeated errors are one entry
+    error("failed");
+    error("failed");
+    let last = log().pop().unwrap();
+    assert_eq!((last.message.as_str(), last.count), ("failed", 2));
+    assert_eq!(status().unwrap().message.unwrap().level, Level::Error);
+}
+
+#[test]
+fn close_buffer() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let (first, second, third) = {
+        let mut buffers = lock!(mut buffers);
+        (
+            buffers.open_text("a"),
+            buffers.open_text("b"),
+            buffers.open_text("c"),
+        )
+    };
+    let mut buffers = lock!(mut buffers);
+    // the last opened one follows
+    buffers.close(third).unwrap();
+    assert_eq!(buffers.current, Some(second));
+    buffers.current = Some(first);
+    buffers.close(second).unwrap();
+    assert_eq!(buffers.current, Some(first));
+    assert!(buffers.close(second).is_err());
+}
+
+#[test]
+fn set_workspace_root() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let mem = MemFs::default();
+    mem.insert("/workspace/src/lib.rs", "");
+    swap_fs(Some(mem));
+    let path = |p: &str| FS.path(format!("/workspace/{}", p));
+    // opened before, it moves to the new root
+    let id = lock!(mut buffers).open_text("");
+
+    set_root(FS.path("/workspace"));
+    let root = FS.root();
+    assert_eq!(root.uri(), path("").uri());
+    let children: Vec<String> = FS.children(&root).iter().map(|c| c.name()).collect();
+    assert_eq!(children, vec!["src".to_string()]);
+    assert_eq!(lsp_root(&path("src/lib.rs")).uri(), root.uri());
+    // a scratch buffer belongs to the workspace
+    assert_eq!(lock!(buffers).get(id).unwrap().lsp_root, root.uri());
+    let other = lock!(mut buffers).open_text("");
+    assert_eq!(lock!(buffers).get(other).unwrap().lsp_root, root.uri());
+
+    lock!(mut buffers).close(id).unwrap();
+    lock!(mut buffers).close(other).unwrap();
+    swap_fs(None);
+    set_root(FS.path("."));
+}
+
+#[test]
+fn delete_from_tree() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let mem = MemFs::default();
+    mem.insert("/delete/open.txt", "a");
+    swap_fs(Some(mem.clone()));
+    let file = FS.path("/delete/open.txt");
+    let id = lock!(mut buffers).open_file(file.clone()).unwrap();
+
+    let mut fs = FS.clone();
+    let key = FS.path("/delete");
+    // asks first
+    assert!(fs.key_down(&key, &KbKey::Delete));
+    assert!(fs.key_down(&key, &KbKey::Escape));
+    assert!(fs.key_down(&key, &KbKey::Delete));
+    assert!(key.is_dir());
+    // any other key or another selection cancels too
+    assert!(fs.key_down(&key, &KbKey::Tab));
+    assert!(!fs.key_down(&key, &KbKey::Tab));
+    assert!(fs.key_down(&key, &KbKey::Delete));
+    fs.selection_changed(&file);
+    assert!(fs.key_down(&key, &KbKey::Delete));
+    assert!(key.is_dir());
+    assert!(lock!(buffers).get(id).is_ok());
+
+    assert!(fs.key_down(&key, &KbKey::Delete));
+    assert!(!key.exists() && !file.exists());
+    assert!(lock!(buffers).get(id).is_err());
+
+    // the buffer stays open when the removal fails
+    mem.insert("/delete/open.txt", "a");
+    let id = lock!(mut buffers).open_file(file.clone()).unwrap();
+    mem.remove("/delete/open.txt");
+    assert!(fs.key_down(&file, &KbKey::Delete));
+    assert!(fs.key_down(&file, &KbKey::Delete));
+    assert!(lock!(buffers).get(id).is_ok());
+    lock!(mut buffers).close(id).unwrap();
+    swap_fs(None);
+}
+
+#[test]
+fn rename_from_tree() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let mem = MemFs::default();
+    mem.insert("/rename/a.txt", "a");
+    mem.insert("/rename/b.txt", "b");
+    swap_fs(Some(mem.clone()));
+    let a = FS.path("/rename/a.txt");
+    let id = lock!(mut buffers).open_file(a.clone()).unwrap();
+
+    let mut fs = FS.clone();
+    let type_name = |fs: &mut LocalFs, name: &str| {
+        for _ in 0..5 {
+            fs.key_down(&a, &KbKey::Backspace);
+        }
+        for c in name.chars() {
+            fs.key_down(&a, &KbKey::Character(c.to_string()));
+        }
+        fs.key_down(&a, &KbKey::Enter);
+    };
+    assert!(fs.key_down(&a, &KbKey::Character("r".into())));
+    assert!(fs.editing());
+    // a sibling has the name
+    type_name(&mut fs, "b.txt");
+    assert!(fs.editing() && a.exists());
+    type_name(&mut fs, "c.txt");
+    assert!(!fs.editing());
+
+    let c = FS.path("/rename/c.txt");
+    assert!(!a.exists());
+    assert_eq!(mem.remove("/rename/c.txt"), Some(b"a".to_vec()));
+    assert!(fs.take_selection() == Some(c.clone()));
+    let mut buffers = lock!(mut buffers);
+    assert!(buffers.get(id).unwrap().source.path() == Some(c));
+    buffers.close(id).unwrap();
+    swap_fs(None);
+}
+
+#[test]
+fn changed_on_disk() {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = std::env::temp_dir().join("ste_editor_changed_on_disk.txt");
+    std::fs::write(&file, "old").unwrap();
+    lock!(mut buffers)
+        .open_file(FS.path(file.to_str().unwrap()))
+        .unwrap();
+    let mut editor = TextEditor::new();
+
+    // reloaded when there is nothing to lose
+    std::fs::write(&file, "new").unwrap();
+    editor.files_changed(&[file.clone()]).unwrap();
+    assert_eq!(text(), "new");
+
+    press(&mut editor, char_key("x"));
+    std::fs::write(&file, "other").unwrap();
+    editor.files_changed(&[file.clone()]).unwrap();
+    // asked first, typing goes on, Enter and `y` do not answer
+    assert_eq!(text(), "xnew");
+    press(&mut editor, char_key("y"));
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Enter, Code::Enter),
+    );
+    assert_eq!(text(), "xy\nnew");
+    press(
+        &mut editor,
+        key(Modifiers::empty(), KbKey::Escape, Code::Escape),
+    );
+    assert_eq!(text(), "xy\nnew");
+
+    editor.files_changed(&[file.clone()]).unwrap();
+    let ctrl_r = || key(Modifiers::CONTROL, KbKey::Character("r".into()), Code::KeyR);
+    press(&mut editor, ctrl_r());
+    assert_eq!(text(), "other");
+
+    // the event of our own save comes after more typing, it is not a change
+    press(&mut editor, char_key("x"));
+    press(
+        &mut editor,
+        key(Modifiers::CONTROL, KbKey::Character("s".into()), Code::KeyS),
+    );
+    let saved = text();
+    press(&mut editor, char_key("y"));
+    editor.files_changed(&[file.clone()]).unwrap();
+    press(&mut editor, ctrl_r());
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), saved);
+    assert_eq!(text().len(), saved.len() + 1);
+}